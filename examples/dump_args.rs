@@ -17,10 +17,8 @@ fn main() {
 
     let ctx = ProfileContext {
         file,
-        pre_args: vec![],
-        input_ctx: Default::default(),
-        output_ctx: Default::default(),
         ffmpeg_bin: "/usr/bin/ffmpeg".into(),
+        ..Default::default()
     };
 
     println!("{}", profile.build(ctx).unwrap().join(" "));
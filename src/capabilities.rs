@@ -0,0 +1,154 @@
+use crate::profiles::TranscodingProfile;
+use crate::NightfallError;
+
+use std::collections::HashSet;
+use std::fs;
+use std::path::Path;
+use std::path::PathBuf;
+use std::process::Command;
+use std::process::Stdio;
+
+/// What the ffmpeg build and the hardware of this host can do. `StateManager::create` drops the
+/// profiles of a chain which cant run here, see `StateManager::with_capabilities`.
+#[derive(Clone, Debug, Default)]
+pub struct Capabilities {
+    /// Encoders ffmpeg was built with, ex. `libx264` or `h264_nvenc`.
+    pub encoders: HashSet<String>,
+    /// Hardware acceleration methods ffmpeg was built with, ex. `cuda` or `vaapi`.
+    pub hwaccels: HashSet<String>,
+    /// DRI render nodes used by vaapi, ex. `/dev/dri/renderD128`.
+    pub render_nodes: Vec<PathBuf>,
+    /// Nvidia device nodes used by cuda, ex. `/dev/nvidia0`.
+    pub nvidia_devices: Vec<PathBuf>,
+}
+
+impl Capabilities {
+    /// Function runs `ffmpeg -encoders` and `ffmpeg -hwaccels` and looks for GPU device nodes.
+    /// This blocks for as long as ffmpeg runs, thus should be called once at startup.
+    ///
+    /// # Arguments
+    /// * `ffmpeg_bin` - path to the ffmpeg binary sessions will use.
+    pub fn detect(ffmpeg_bin: &str) -> Result<Self, NightfallError> {
+        let encoders = run(ffmpeg_bin, "-encoders")?;
+        let hwaccels = run(ffmpeg_bin, "-hwaccels")?;
+
+        Ok(Self {
+            encoders: parse_encoders(&encoders),
+            hwaccels: parse_hwaccels(&hwaccels),
+            render_nodes: device_nodes(Path::new("/dev/dri"), "renderD"),
+            nvidia_devices: device_nodes(Path::new("/dev"), "nvidia")
+                .into_iter()
+                .filter(|x| {
+                    x.file_name()
+                        .and_then(|x| x.to_str())
+                        .and_then(|x| x.strip_prefix("nvidia"))
+                        .is_some_and(|x| x.parse::<u32>().is_ok())
+                })
+                .collect(),
+        })
+    }
+
+    /// Returns an error explaining why `profile` cant run on this host, if it cant.
+    pub fn check(&self, profile: &dyn TranscodingProfile) -> Result<(), NightfallError> {
+        if let Some(encoder) = profile.encoder() {
+            if !self.encoders.contains(encoder) {
+                return Err(NightfallError::ProfileNotSupported(format!(
+                    "ffmpeg doesnt ship the {} encoder.",
+                    encoder
+                )));
+            }
+        }
+
+        let hwaccel = match profile.hwaccel() {
+            Some(x) => x,
+            None => return Ok(()),
+        };
+
+        if !self.hwaccels.contains(hwaccel) {
+            return Err(NightfallError::ProfileNotSupported(format!(
+                "ffmpeg doesnt support the {} hwaccel.",
+                hwaccel
+            )));
+        }
+
+        // device nodes only exist on linux.
+        if cfg!(target_os = "linux") {
+            let devices = match hwaccel {
+                "cuda" => &self.nvidia_devices,
+                "vaapi" => &self.render_nodes,
+                _ => return Ok(()),
+            };
+
+            if devices.is_empty() {
+                return Err(NightfallError::ProfileNotSupported(format!(
+                    "No device found for the {} hwaccel.",
+                    hwaccel
+                )));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+fn run(ffmpeg_bin: &str, arg: &str) -> Result<String, NightfallError> {
+    let output = Command::new(ffmpeg_bin)
+        .args(["-hide_banner", arg])
+        .stdin(Stdio::null())
+        .output()
+        .map_err(|e| {
+            NightfallError::InvalidConfig(format!("Failed to run {}: {}", ffmpeg_bin, e))
+        })?;
+
+    if !output.status.success() {
+        return Err(NightfallError::InvalidConfig(format!(
+            "{} {} exited with {}",
+            ffmpeg_bin, arg, output.status
+        )));
+    }
+
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+/// Parses the output of `ffmpeg -encoders`, where every encoder is listed after a `------` line
+/// as ` V....D libx264  description`.
+fn parse_encoders(output: &str) -> HashSet<String> {
+    output
+        .lines()
+        .skip_while(|x| x.trim() != "------")
+        .skip(1)
+        .filter_map(|x| x.split_whitespace().nth(1))
+        .map(ToString::to_string)
+        .collect()
+}
+
+/// Parses the output of `ffmpeg -hwaccels`, which lists a method per line after a header.
+fn parse_hwaccels(output: &str) -> HashSet<String> {
+    output
+        .lines()
+        .skip_while(|x| !x.starts_with("Hardware acceleration methods"))
+        .skip(1)
+        .map(str::trim)
+        .filter(|x| !x.is_empty())
+        .map(ToString::to_string)
+        .collect()
+}
+
+/// Returns the sorted entries of `dir` whose name starts with `prefix`.
+fn device_nodes(dir: &Path, prefix: &str) -> Vec<PathBuf> {
+    let mut nodes = fs::read_dir(dir)
+        .map(|x| {
+            x.filter_map(Result::ok)
+                .map(|x| x.path())
+                .filter(|x| {
+                    x.file_name()
+                        .and_then(|x| x.to_str())
+                        .is_some_and(|x| x.starts_with(prefix))
+                })
+                .collect::<Vec<_>>()
+        })
+        .unwrap_or_default();
+
+    nodes.sort();
+    nodes
+}
@@ -0,0 +1,151 @@
+use std::fmt::Write;
+
+/// Scheme used to signal that an adaptation set is a trick-mode track for another adaptation set.
+/// see: DASH-IF IOP v4.3, section 3.2.9
+const TRICKMODE_SCHEME: &str = "http://dashif.org/guidelines/trickmode";
+
+/// A single representation of an adaptation set.
+#[derive(Clone, Debug, Default)]
+pub struct Representation {
+    pub id: String,
+    pub bandwidth: u64,
+    pub codecs: String,
+    pub width: Option<i64>,
+    pub height: Option<i64>,
+    pub frame_rate: Option<String>,
+    /// For trick-mode representations, the maximum playout rate relative to the main
+    /// representation, ex. `24` for a 1 fps track of a 24 fps stream.
+    pub max_playout_rate: Option<u32>,
+    /// Template used to build the url of the init segment, ex. `session/$RepresentationID$/init.mp4`.
+    pub initialization: String,
+    /// Template used to build the url of a segment, ex. `session/$RepresentationID$/$Number$.m4s`.
+    pub media: String,
+    pub start_number: u32,
+}
+
+/// A set of interchangeable representations.
+#[derive(Clone, Debug, Default)]
+pub struct AdaptationSet {
+    pub id: u32,
+    pub content_type: String,
+    pub mime_type: String,
+    pub lang: Option<String>,
+    /// If set, this adaptation set is a I-frame only trick-mode track for the adaptation set with
+    /// the given id. Both adaptation sets must share the same segment timeline.
+    pub trick_mode_for: Option<u32>,
+    pub representations: Vec<Representation>,
+}
+
+/// Function builds a static MPD where every segment is `segment_duration` seconds long and is
+/// addressed through a `$Number$` based `SegmentTemplate`.
+///
+/// # Arguments
+/// * `duration` - Duration of the media in seconds, `mediaPresentationDuration` is left out when
+///   unknown rather than advertising an empty presentation.
+/// * `segment_duration` - Duration of a segment in seconds, usually `target_gop`.
+/// * `adaptation_sets` - Adaptation sets to include in the period.
+pub fn build_mpd(
+    duration: Option<f64>,
+    segment_duration: u32,
+    adaptation_sets: &[AdaptationSet],
+) -> String {
+    let mut mpd = String::new();
+
+    let duration = duration
+        .map(|x| format!(r#" mediaPresentationDuration="{}""#, format_duration(x)))
+        .unwrap_or_default();
+
+    let _ = writeln!(mpd, r#"<?xml version="1.0" encoding="utf-8"?>"#);
+    let _ = writeln!(
+        mpd,
+        r#"<MPD xmlns="urn:mpeg:dash:schema:mpd:2011" profiles="urn:mpeg:dash:profile:isoff-live:2011" type="static"{} minBufferTime="PT{}S">"#,
+        duration, segment_duration
+    );
+    let _ = writeln!(mpd, r#"  <Period id="0" start="PT0S">"#);
+
+    for set in adaptation_sets {
+        let lang = set
+            .lang
+            .as_ref()
+            .map(|x| format!(r#" lang="{}""#, x))
+            .unwrap_or_default();
+
+        let _ = writeln!(
+            mpd,
+            r#"    <AdaptationSet id="{}" contentType="{}" mimeType="{}" segmentAlignment="true"{}>"#,
+            set.id, set.content_type, set.mime_type, lang
+        );
+
+        if let Some(main) = set.trick_mode_for {
+            let _ = writeln!(
+                mpd,
+                r#"      <EssentialProperty schemeIdUri="{}" value="{}"/>"#,
+                TRICKMODE_SCHEME, main
+            );
+        }
+
+        for repr in set.representations.iter() {
+            let mut attrs = format!(
+                r#"id="{}" bandwidth="{}" codecs="{}""#,
+                repr.id, repr.bandwidth, repr.codecs
+            );
+
+            if let (Some(width), Some(height)) = (repr.width, repr.height) {
+                let _ = write!(attrs, r#" width="{}" height="{}""#, width, height);
+            }
+
+            if let Some(frame_rate) = repr.frame_rate.as_ref() {
+                let _ = write!(attrs, r#" frameRate="{}""#, frame_rate);
+            }
+
+            // trick-mode tracks are I-frame only thus every sample can be decoded on its own.
+            if set.trick_mode_for.is_some() {
+                attrs.push_str(r#" codingDependency="false""#);
+
+                if let Some(rate) = repr.max_playout_rate {
+                    let _ = write!(attrs, r#" maxPlayoutRate="{}""#, rate);
+                }
+            }
+
+            let _ = writeln!(mpd, r#"      <Representation {}>"#, attrs);
+            let _ = writeln!(
+                mpd,
+                r#"        <SegmentTemplate timescale="1" duration="{}" startNumber="{}" initialization="{}" media="{}"/>"#,
+                segment_duration, repr.start_number, repr.initialization, repr.media
+            );
+            let _ = writeln!(mpd, "      </Representation>");
+        }
+
+        let _ = writeln!(mpd, "    </AdaptationSet>");
+    }
+
+    let _ = writeln!(mpd, "  </Period>");
+    let _ = writeln!(mpd, "</MPD>");
+
+    mpd
+}
+
+/// Formats a duration in seconds as a ISO 8601 duration.
+fn format_duration(duration: f64) -> String {
+    let total = duration.max(0.0);
+    let hours = (total / 3600.0).floor();
+    let minutes = ((total % 3600.0) / 60.0).floor();
+    let seconds = total % 60.0;
+
+    format!("PT{}H{}M{:.3}S", hours, minutes, seconds)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unknown_durations_are_left_out() {
+        let mpd = build_mpd(Some(3725.5), 5, &[]);
+        assert!(mpd.contains(r#" mediaPresentationDuration="PT1H2M5.500S" "#));
+
+        let mpd = build_mpd(None, 5, &[]);
+        assert!(!mpd.contains("mediaPresentationDuration"));
+        assert!(mpd.contains(r#"type="static" minBufferTime="PT5S""#));
+    }
+}
@@ -0,0 +1,177 @@
+use crate::utils::is_newer;
+use crate::NightfallError;
+
+use std::fmt;
+use std::fs;
+
+use aes::Aes128;
+use cbc::cipher::block_padding::Pkcs7;
+use cbc::cipher::generic_array::GenericArray;
+use cbc::cipher::BlockEncryptMut;
+use cbc::cipher::KeyIvInit;
+
+/// Suffix appended to the path of a chunk to get the path of its encrypted copy.
+pub const ENCRYPTED_SUFFIX: &str = "enc";
+
+/// URI playlists reference the key of a session by, relative to the playlist. Servers should
+/// respond to it with `StateManager::encryption_key`.
+pub const KEY_URI: &str = "key";
+
+/// Per-session AES-128 key chunks get encrypted with before being handed out, see
+/// `OutputCtx::encrypt`. Chunks are encrypted as described by `METHOD=AES-128` in RFC 8216, with
+/// AES-128-CBC, PKCS7 padding and the index of the chunk as IV, which is the IV players use when
+/// the `EXT-X-KEY` tag doesnt carry one and the media sequence numbers match the chunk indices.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct SegmentKey {
+    key: [u8; 16],
+}
+
+impl fmt::Debug for SegmentKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // keep the key out of logs.
+        f.debug_struct("SegmentKey").finish_non_exhaustive()
+    }
+}
+
+impl SegmentKey {
+    /// Function generates a random key out of the randomness source of the OS.
+    pub fn generate() -> Result<Self, NightfallError> {
+        let mut key = [0; 16];
+
+        getrandom::getrandom(&mut key).map_err(|e| {
+            NightfallError::InvalidConfig(format!("Failed to generate an encryption key: {}", e))
+        })?;
+
+        Ok(Self { key })
+    }
+
+    pub fn from_bytes(key: [u8; 16]) -> Self {
+        Self { key }
+    }
+
+    /// Returns the raw key, which is what the server has to respond with on the `URI` of the
+    /// `EXT-X-KEY` tag.
+    pub fn key(&self) -> [u8; 16] {
+        self.key
+    }
+
+    /// Returns the IV `chunk` is encrypted with, its index as a 128-bit big endian integer.
+    pub fn iv(&self, chunk: u32) -> [u8; 16] {
+        let mut iv = [0; 16];
+        iv[12..].copy_from_slice(&chunk.to_be_bytes());
+        iv
+    }
+
+    /// Returns the `EXT-X-KEY` tag for a playlist whose segments are the chunks of the session,
+    /// `uri` being where the server hands out `key`. Init segments stay in the clear, thus the
+    /// tag has to come after `EXT-X-MAP`.
+    ///
+    /// Players derive the IV from the media sequence number, which only matches the chunk index
+    /// as long as no chunk is skipped. Past that, every segment needs its own tag carrying the IV
+    /// of `chunk`.
+    pub fn ext_x_key(&self, uri: &str, chunk: Option<u32>) -> String {
+        match chunk {
+            Some(chunk) => format!(
+                "#EXT-X-KEY:METHOD=AES-128,URI=\"{}\",IV=0x{}",
+                uri,
+                self.iv(chunk)
+                    .iter()
+                    .map(|x| format!("{:02x}", x))
+                    .collect::<String>()
+            ),
+            None => format!("#EXT-X-KEY:METHOD=AES-128,URI=\"{}\"", uri),
+        }
+    }
+
+    /// Function encrypts `data` as the content of `chunk`.
+    pub fn encrypt(&self, data: &[u8], chunk: u32) -> Vec<u8> {
+        // PKCS7 always pads, by a whole block when the data is block aligned.
+        let iv = self.iv(chunk);
+
+        cbc::Encryptor::<Aes128>::new(
+            GenericArray::from_slice(&self.key),
+            GenericArray::from_slice(&iv),
+        )
+        .encrypt_padded_vec_mut::<Pkcs7>(data)
+    }
+}
+
+/// Function writes an encrypted copy of the chunk at `path` next to it and returns its path. The
+/// copy gets written into a temporary file first, so that a concurrent request never reads a
+/// partial copy. A copy written after the chunk was last modified is reused as is.
+pub(crate) async fn encrypt_chunk(
+    path: String,
+    key: SegmentKey,
+    chunk: u32,
+) -> Result<String, NightfallError> {
+    tokio::task::spawn_blocking(move || {
+        let out = format!("{}.{}", path, ENCRYPTED_SUFFIX);
+
+        if is_newer(&out, &path) {
+            return Ok(out);
+        }
+
+        let data = fs::read(&path)?;
+        let tmp = format!("{}.tmp", out);
+
+        fs::write(&tmp, key.encrypt(&data, chunk))?;
+        fs::rename(&tmp, &out)?;
+
+        Ok(out)
+    })
+    .await
+    .map_err(|_| NightfallError::Aborted)?
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KEY: [u8; 16] = [
+        0x00, 0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07, 0x08, 0x09, 0x0a, 0x0b, 0x0c, 0x0d, 0x0e,
+        0x0f,
+    ];
+
+    fn hex(data: &[u8]) -> String {
+        data.iter().map(|x| format!("{:02x}", x)).collect()
+    }
+
+    #[test]
+    fn cbc_pads_partial_blocks() {
+        let key = SegmentKey::from_bytes(KEY);
+
+        assert_eq!(
+            hex(&key.encrypt(b"nightfall", 7)),
+            "945ed78aa5572812ff2f7e7715b73bd5"
+        );
+    }
+
+    #[test]
+    fn cbc_pads_aligned_data_with_a_whole_block() {
+        let key = SegmentKey::from_bytes(KEY);
+        let data = [
+            0x00, 0x11, 0x22, 0x33, 0x44, 0x55, 0x66, 0x77, 0x88, 0x99, 0xaa, 0xbb, 0xcc, 0xdd,
+            0xee, 0xff,
+        ];
+
+        // with a zero IV the first block is the plain AES output of appendix C.1.
+        assert_eq!(
+            hex(&key.encrypt(&data, 0)),
+            "69c4e0d86a7b0430d8cdb78070b4c55a9e978e6d16b086570ef794ef97984232"
+        );
+    }
+
+    #[test]
+    fn ext_x_key_carries_the_iv_of_the_chunk() {
+        let key = SegmentKey::from_bytes(KEY);
+
+        assert_eq!(
+            key.ext_x_key(KEY_URI, None),
+            "#EXT-X-KEY:METHOD=AES-128,URI=\"key\""
+        );
+        assert_eq!(
+            key.ext_x_key(KEY_URI, Some(258)),
+            "#EXT-X-KEY:METHOD=AES-128,URI=\"key\",IV=0x00000000000000000000000000000102"
+        );
+    }
+}
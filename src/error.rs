@@ -23,6 +23,34 @@ pub enum NightfallError {
     ProfileNotSupported(String),
     #[error(display = "Profile chain exhausted.")]
     ProfileChainExhausted,
+    #[error(display = "Session manager is overloaded")]
+    Overloaded,
+    #[error(display = "Invalid profile context: {}", 0)]
+    InvalidProfileContext(String),
+    #[error(display = "Invalid configuration: {}", 0)]
+    InvalidConfig(String),
+    #[error(display = "Session expired")]
+    SessionExpired,
+    #[error(display = "Session has already started")]
+    SessionAlreadyStarted,
+    #[error(display = "Direct play unavailable: {}", 0)]
+    DirectPlayUnavailable(String),
+    #[error(display = "Chunk {} is past the end of the stream", 0)]
+    ChunkOutOfRange(u32),
+    #[error(display = "Warmup timed out with {} chunks ready", 0)]
+    WarmupTimedOut(u32),
+    #[error(display = "ffmpeg failed during warmup with {} chunks ready", 0)]
+    WarmupFailed(u32),
+    #[error(display = "Session is queued at position {}", position)]
+    Queued { position: usize },
+    #[error(display = "Owner reached the limit of {} sessions", 0)]
+    OwnerSessionLimit(usize),
+    #[error(display = "ffprobe timed out")]
+    ProbeTimeout,
+    #[error(display = "Failed to index thumbnails {}", 0)]
+    ThumbnailIndexError(String),
+    #[error(display = "Failed to extract screenshot {}", 0)]
+    ScreenshotError(String),
     #[error(display = "Parsed a partial segment.")]
     #[serde(skip_serializing)]
     PartialSegment(crate::patch::segment::Segment),
@@ -35,7 +63,15 @@ impl From<mp4::Error> for NightfallError {
 }
 
 impl From<std::io::Error> for NightfallError {
-    fn from(_: std::io::Error) -> Self {
+    fn from(e: std::io::Error) -> Self {
+        let is_probe_timeout = e
+            .get_ref()
+            .is_some_and(|x| x.is::<crate::ffprobe::ProbeTimeout>());
+
+        if is_probe_timeout {
+            return Self::ProbeTimeout;
+        }
+
         Self::IoError
     }
 }
@@ -0,0 +1,29 @@
+use serde::Serialize;
+
+/// How many events a subscriber can lag behind before it starts missing events, see
+/// `StateManager::subscribe`.
+pub const EVENT_CAPACITY: usize = 256;
+
+/// Lifecycle events of the sessions of a `StateManager`, published to every subscriber.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+#[serde(tag = "type")]
+pub enum SessionEvent {
+    /// A session got created, or restored with `restore_sessions`.
+    SessionCreated { id: String, profile: String },
+    /// ffmpeg failed and the session moved on to the next profile of its chain.
+    ProfileFallback {
+        id: String,
+        from: String,
+        to: String,
+    },
+    /// A chunk is complete on disk and can be requested. Published by `garbage_collect`, thus
+    /// up to one collection interval after ffmpeg wrote the chunk.
+    ChunkReady { id: String, chunk: u32 },
+    /// ffmpeg got paused, because it is far enough ahead of the player, because the session is
+    /// idle or to free up resources for a higher priority session.
+    Paused { id: String },
+    /// The session got removed along with its ffmpeg process.
+    Reaped { id: String },
+    /// ffmpeg exited with an error, contains the tail of its stderr when available.
+    Errored { id: String, error: String },
+}
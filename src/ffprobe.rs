@@ -1,7 +1,19 @@
+use crate::profiles::SideDataList;
 use serde_derive::{Deserialize, Serialize};
-use std::{path::Path, process::Command, str, time::Duration};
+use std::collections::HashMap;
+use std::io::Read;
+use std::path::PathBuf;
+use std::process::{Output, Stdio};
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+use std::time::SystemTime;
+use std::{fs, path::Path, process::Command, str, time::Duration};
+use tokio::sync::mpsc;
+use tokio::sync::Semaphore;
+use tokio_stream::wrappers::ReceiverStream;
+use tokio_stream::Stream as AsyncStream;
 
-#[derive(Default, Debug, Clone, PartialEq)]
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct FFPWrapper {
     ffpstream: Option<FFPStream>,
     corrupt: Option<bool>,
@@ -46,8 +58,22 @@ pub struct Stream {
     pub bit_rate: Option<String>,
     pub duration_ts: Option<i64>,
     pub duration: Option<String>,
+    /// Pixel format of the video stream, ex. `yuv420p10le`.
+    pub pix_fmt: Option<String>,
     pub color_range: Option<String>,
     pub color_space: Option<String>,
+    pub color_transfer: Option<String>,
+    pub color_primaries: Option<String>,
+    /// Field order of the video stream, ex. `progressive`, `tt` or `bb`, see `Stream::field_order`.
+    pub field_order: Option<String>,
+    pub side_data_list: Option<Vec<SideDataList>>,
+    pub disposition: Option<Disposition>,
+}
+
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Disposition {
+    pub default: i64,
+    pub forced: i64,
 }
 
 #[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
@@ -72,6 +98,19 @@ pub struct Tags {
     pub mimetype: Option<String>,
 }
 
+/// How the frames of a video stream are scanned.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum FieldOrder {
+    Progressive,
+    /// Interlaced with the top field first.
+    TopFirst,
+    /// Interlaced with the bottom field first.
+    BottomFirst,
+    /// Progressive film which got telecined into interlaced frames, ex. 24fps film broadcast as
+    /// NTSC. Only reported by `detect_field_order`.
+    Telecined,
+}
+
 #[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct Format {
     pub filename: String,
@@ -85,12 +124,620 @@ pub struct Format {
     pub bit_rate: String,
 }
 
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Packets {
+    pub packets: Vec<Packet>,
+}
+
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Packet {
+    pub pts_time: Option<String>,
+    pub flags: Option<String>,
+}
+
+impl Chapter {
+    /// Returns the title of the chapter, if it has one.
+    pub fn title(&self) -> Option<&str> {
+        self.tags.as_ref()?.title.as_deref()
+    }
+
+    /// Returns when the chapter starts in seconds.
+    pub fn start_secs(&self) -> f64 {
+        self.start_time
+            .parse()
+            .unwrap_or_else(|_| rescale(self.start, &self.time_base))
+    }
+
+    /// Returns when the chapter ends in seconds.
+    pub fn end_secs(&self) -> f64 {
+        self.end_time
+            .parse()
+            .unwrap_or_else(|_| rescale(self.end, &self.time_base))
+    }
+}
+
+/// Converts a timestamp in `time_base`, ex. `1/1000000000`, to seconds.
+fn rescale(ts: i64, time_base: &str) -> f64 {
+    let (num, den) = time_base.split_once('/').unwrap_or(("1", "1"));
+    let num = num.parse::<f64>().unwrap_or(1.0);
+    let den = den.parse::<f64>().unwrap_or(1.0);
+
+    if den == 0.0 {
+        return 0.0;
+    }
+
+    ts as f64 * num / den
+}
+
+impl Stream {
+    /// Returns the name of the file embedded as this attachment, stripped of any directory so
+    /// that it is safe to write it out under that name.
+    pub fn attachment_filename(&self) -> Option<&str> {
+        let filename = self.tags.as_ref()?.filename.as_deref()?;
+
+        Path::new(filename).file_name()?.to_str()
+    }
+
+    /// Returns the field order reported by the container or the codec, `None` when it is
+    /// unknown. Containers often report interlaced sources as progressive, see
+    /// `detect_field_order` for a more reliable way to tell.
+    pub fn field_order(&self) -> Option<FieldOrder> {
+        match self.field_order.as_deref()? {
+            "progressive" => Some(FieldOrder::Progressive),
+            "tt" | "tb" => Some(FieldOrder::TopFirst),
+            "bb" | "bt" => Some(FieldOrder::BottomFirst),
+            _ => None,
+        }
+    }
+
+    /// Returns the bitrate of the stream in bits per second. Matroska files usually dont carry
+    /// it in the stream header, in which case the statistics tags written by mkvmerge are used.
+    /// See `FFPWrapper::stream_bitrate` for an estimate when neither is present.
+    pub fn bitrate(&self) -> Option<u64> {
+        let parse = |x: &str| x.parse::<u64>().ok().filter(|x| *x > 0);
+
+        self.bit_rate
+            .as_deref()
+            .and_then(parse)
+            .or_else(|| self.tags.as_ref()?.bps_eng.as_deref().and_then(parse))
+    }
+
+    /// Returns the mimetype of the file embedded as this attachment.
+    pub fn attachment_mimetype(&self) -> Option<&str> {
+        self.tags.as_ref()?.mimetype.as_deref()
+    }
+
+    /// Returns the Dolby Vision configuration of the stream, if it is Dolby Vision. This carries
+    /// the profile along with whether the RPU, the enhancement layer and the base layer are
+    /// present.
+    pub fn dolby_vision(&self) -> Option<&SideDataList> {
+        self.side_data_list
+            .as_deref()?
+            .iter()
+            .find(|x| x.is_dolby_vision())
+    }
+
+    /// Returns the mastering display metadata of a HDR10 stream, the chromaticities of the
+    /// display primaries and its luminance range.
+    pub fn mastering_display(&self) -> Option<&SideDataList> {
+        self.side_data_list
+            .as_deref()?
+            .iter()
+            .find(|x| x.is_mastering_display())
+    }
+
+    /// Returns the content light level metadata of a HDR10 stream, `max_content` and
+    /// `max_average` hold MaxCLL and MaxFALL.
+    pub fn content_light_level(&self) -> Option<&SideDataList> {
+        self.side_data_list
+            .as_deref()?
+            .iter()
+            .find(|x| x.is_content_light_level())
+    }
+
+    /// Returns whether the stream is HDR, either through its transfer characteristics (HDR10,
+    /// HLG) or because it is Dolby Vision.
+    pub fn is_hdr(&self) -> bool {
+        let is_hdr_transfer = matches!(
+            self.color_transfer.as_deref(),
+            Some("smpte2084") | Some("arib-std-b67")
+        );
+
+        is_hdr_transfer || self.dolby_vision().is_some()
+    }
+}
+
+impl FFPWrapper {
+    /// Builds a probe result out of streams and a format gathered without ffprobe, see
+    /// `native_probe::probe`.
+    pub(crate) fn from_parts(streams: Vec<Stream>, format: Format) -> Self {
+        Self {
+            ffpstream: Some(FFPStream {
+                chapters: Vec::new(),
+                streams,
+                format,
+            }),
+            corrupt: None,
+        }
+    }
+
+    /// Returns all the streams found in the file.
+    pub fn streams(&self) -> &[Stream] {
+        self.ffpstream
+            .as_ref()
+            .map(|x| x.streams.as_slice())
+            .unwrap_or_default()
+    }
+
+    /// Returns the chapters of the file, ordered by start time.
+    pub fn chapters(&self) -> &[Chapter] {
+        self.ffpstream
+            .as_ref()
+            .map(|x| x.chapters.as_slice())
+            .unwrap_or_default()
+    }
+
+    /// Returns all the attachment streams of the file, ex. fonts or cover art. The `filename` and
+    /// `mimetype` tags describe each of them, see `StateManager::extract_attachment`.
+    pub fn attachments(&self) -> Vec<Stream> {
+        self.streams()
+            .iter()
+            .filter(|x| x.codec_type == "attachment")
+            .cloned()
+            .collect()
+    }
+
+    /// Returns the attachment streams which contain fonts, these are needed by libass to render
+    /// stylized ASS subtitles correctly.
+    pub fn font_attachments(&self) -> Vec<Stream> {
+        self.attachments()
+            .into_iter()
+            .filter(is_font_attachment)
+            .collect()
+    }
+
+    /// Returns the bitrate of the stream `index` in bits per second. When the stream doesnt
+    /// report its bitrate it is estimated from the size and duration of the file, minus the
+    /// bitrate of the other streams that do report theirs.
+    pub fn stream_bitrate(&self, index: i64) -> Option<u64> {
+        let stream = self.streams().iter().find(|x| x.index == index)?;
+
+        if let Some(bitrate) = stream.bitrate() {
+            return Some(bitrate);
+        }
+
+        let others = self
+            .streams()
+            .iter()
+            .filter(|x| x.index != index)
+            .filter_map(Stream::bitrate)
+            .sum::<u64>();
+
+        self.bitrate()?.checked_sub(others).filter(|x| *x > 0)
+    }
+
+    /// Returns the overall bitrate of the file in bits per second, computed from its size and
+    /// duration when the container doesnt report it.
+    pub fn bitrate(&self) -> Option<u64> {
+        let format = &self.ffpstream.as_ref()?.format;
+
+        if let Some(bitrate) = format.bit_rate.parse::<u64>().ok().filter(|x| *x > 0) {
+            return Some(bitrate);
+        }
+
+        let size = format.size.parse::<f64>().ok()?;
+        let duration = self.duration().filter(|x| *x > 0.0)?;
+
+        Some((size * 8.0 / duration) as u64)
+    }
+
+    /// Returns the duration of the file in seconds.
+    pub fn duration(&self) -> Option<f64> {
+        self.ffpstream.as_ref()?.format.duration.parse().ok()
+    }
+
+    /// Returns whether ffprobe failed to parse the file.
+    pub fn is_corrupt(&self) -> bool {
+        self.corrupt.unwrap_or(false)
+    }
+}
+
+fn is_font_attachment(stream: &Stream) -> bool {
+    let tags = match stream.tags.as_ref() {
+        Some(x) => x,
+        None => return false,
+    };
+
+    let is_font_mime = tags
+        .mimetype
+        .as_deref()
+        .is_some_and(|x| x.contains("font") || x.contains("truetype") || x.contains("opentype"));
+
+    let is_font_file = tags
+        .filename
+        .as_deref()
+        .and_then(|x| Path::new(x).extension())
+        .and_then(|x| x.to_str())
+        .is_some_and(|x| ["ttf", "otf", "ttc"].contains(&x.to_lowercase().as_str()));
+
+    is_font_mime || is_font_file
+}
+
+/// Amount of frames the `idet` pass of `detect_field_order` looks at.
+const IDET_FRAMES: u32 = 500;
+
+/// Function runs a short `idet` pass over the first frames of the video stream `stream` and
+/// returns how its frames are scanned, or `None` if ffmpeg didnt report any statistics. A stream
+/// is considered telecined when a fifth or more of its frames repeat a field.
+pub fn detect_field_order(
+    ffmpeg_bin: &str,
+    file: &Path,
+    stream: usize,
+) -> Result<Option<FieldOrder>, std::io::Error> {
+    let output = Command::new(ffmpeg_bin)
+        .arg("-hide_banner")
+        .arg("-i")
+        .arg(file.to_str().unwrap())
+        .arg("-map")
+        .arg(format!("0:{}", stream))
+        .arg("-vf")
+        .arg("idet")
+        .arg("-frames:v")
+        .arg(IDET_FRAMES.to_string())
+        .arg("-an")
+        .arg("-f")
+        .arg("null")
+        .arg("-")
+        .output()?;
+
+    let stderr = String::from_utf8_lossy(output.stderr.as_slice());
+
+    // ex. `Multi frame detection: TFF:    12 BFF:     0 Progressive:   480 Undetermined:     8`
+    let counts = |prefix: &str| -> Option<Vec<u64>> {
+        let line = stderr.lines().rev().find(|x| x.contains(prefix))?;
+        let (_, stats) = line.split_once(prefix)?;
+
+        Some(
+            stats
+                .split_whitespace()
+                .filter_map(|x| x.parse::<u64>().ok())
+                .collect(),
+        )
+    };
+
+    let (tff, bff, progressive) = match counts("Multi frame detection:").as_deref() {
+        Some([tff, bff, progressive, ..]) => (*tff, *bff, *progressive),
+        _ => return Ok(None),
+    };
+
+    // ex. `Repeated Fields: Neither:   400 Top:    50 Bottom:    50`
+    if let Some([neither, top, bottom, ..]) = counts("Repeated Fields:").as_deref() {
+        let repeated = top + bottom;
+
+        if repeated > 0 && repeated * 5 >= neither + repeated {
+            return Ok(Some(FieldOrder::Telecined));
+        }
+    }
+
+    let order = if progressive >= tff + bff {
+        FieldOrder::Progressive
+    } else if tff >= bff {
+        FieldOrder::TopFirst
+    } else {
+        FieldOrder::BottomFirst
+    };
+
+    Ok(Some(order))
+}
+
+/// Function picks the audio stream that best matches the ordered list of preferred `languages`.
+/// If none of the streams are tagged with any of the languages we fall back to the stream with the
+/// default disposition, and finally to the first audio stream.
+pub fn select_audio_stream<'a>(streams: &'a [Stream], languages: &[String]) -> Option<&'a Stream> {
+    let audio = streams
+        .iter()
+        .filter(|x| x.codec_type == "audio")
+        .collect::<Vec<_>>();
+
+    for language in languages {
+        let found = audio.iter().find(|x| {
+            x.tags
+                .as_ref()
+                .and_then(|x| x.language.as_deref())
+                .is_some_and(|x| x.eq_ignore_ascii_case(language))
+        });
+
+        if let Some(x) = found {
+            return Some(x);
+        }
+    }
+
+    audio
+        .iter()
+        .find(|x| x.disposition.as_ref().is_some_and(|x| x.default == 1))
+        .or_else(|| audio.first())
+        .copied()
+}
+
+/// A subtitle track available for a media file, either embedded or as a sidecar file.
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SubtitleTrack {
+    /// Index of the stream for embedded subtitles.
+    pub index: Option<i64>,
+    /// Path to the subtitle file for sidecar subtitles.
+    pub path: Option<String>,
+    pub codec: String,
+    pub language: Option<String>,
+    pub title: Option<String>,
+}
+
+/// Returns the codec name ffmpeg uses for a sidecar subtitle file based on its extension.
+pub fn sidecar_codec(file: &Path) -> Option<&'static str> {
+    let ext = file.extension()?.to_str()?.to_ascii_lowercase();
+
+    match ext.as_str() {
+        "srt" => Some("subrip"),
+        "ass" => Some("ass"),
+        "ssa" => Some("ssa"),
+        "vtt" => Some("webvtt"),
+        _ => None,
+    }
+}
+
+/// Function will look for subtitle files next to `file` that share its file stem, ex.
+/// `movie.srt` or `movie.en.srt` for `movie.mkv`. The language is taken from the part between the
+/// stem and the extension if present.
+pub fn find_sidecar_subtitles(file: &Path) -> Vec<SubtitleTrack> {
+    let (dir, stem) = match (file.parent(), file.file_stem().and_then(|x| x.to_str())) {
+        (Some(dir), Some(stem)) => (dir, stem),
+        _ => return Vec::new(),
+    };
+
+    // `Path::parent` returns an empty path for bare file names.
+    let dir = if dir.as_os_str().is_empty() {
+        Path::new(".")
+    } else {
+        dir
+    };
+
+    let entries = match fs::read_dir(dir) {
+        Ok(x) => x,
+        Err(_) => return Vec::new(),
+    };
+
+    let mut tracks = entries
+        .filter_map(Result::ok)
+        .map(|x| x.path())
+        .filter_map(|path| {
+            let codec = sidecar_codec(&path)?;
+            let name = path.file_stem()?.to_str()?;
+            let rest = name.strip_prefix(stem)?;
+
+            let language = match rest.strip_prefix('.') {
+                Some(lang) if !lang.is_empty() => Some(lang.to_string()),
+                _ if rest.is_empty() => None,
+                _ => return None,
+            };
+
+            Some(SubtitleTrack {
+                index: None,
+                path: Some(path.to_string_lossy().into_owned()),
+                codec: codec.into(),
+                language,
+                title: None,
+            })
+        })
+        .collect::<Vec<_>>();
+
+    tracks.sort_by(|a, b| a.path.cmp(&b.path));
+
+    tracks
+}
+
+/// Probe results stored by `ProbeCache` on disk, along with the file they belong to so that hash
+/// collisions are caught.
+#[derive(Serialize, Deserialize)]
+struct StoredProbe {
+    path: PathBuf,
+    modified: SystemTime,
+    probe: FFPWrapper,
+}
+
+/// Default amount of probes `ProbeCache` keeps in memory, see `ProbeCache::with_capacity`.
+const PROBE_CACHE_CAPACITY: usize = 4096;
+
+/// Probe of a file held in memory by `ProbeCache`.
+#[derive(Debug)]
+struct CachedProbe {
+    modified: SystemTime,
+    probe: FFPWrapper,
+    last_used: Instant,
+}
+
+/// Cache of `FFProbeCtx::get_meta` results keyed by the path and the modification time of the
+/// probed file, so that a file which changed on disk gets probed again. Results can also be
+/// persisted as json files in a directory, so that they survive restarts. Only probes ffprobe
+/// could parse get cached, and at most `capacity` of them are kept in memory, the least recently
+/// used ones getting evicted first.
+#[derive(Debug)]
+pub struct ProbeCache {
+    entries: Mutex<HashMap<PathBuf, CachedProbe>>,
+    store: Option<PathBuf>,
+    capacity: usize,
+}
+
+impl Default for ProbeCache {
+    fn default() -> Self {
+        Self {
+            entries: Mutex::default(),
+            store: None,
+            capacity: PROBE_CACHE_CAPACITY,
+        }
+    }
+}
+
+impl ProbeCache {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Creates a cache which also persists its entries into `dir`.
+    pub fn with_store(dir: impl Into<PathBuf>) -> Self {
+        Self {
+            store: Some(dir.into()),
+            ..Self::default()
+        }
+    }
+
+    /// Sets how many probes are kept in memory, `PROBE_CACHE_CAPACITY` by default. Persisted
+    /// probes dont count towards it.
+    pub fn with_capacity(mut self, capacity: usize) -> Self {
+        self.capacity = capacity.max(1);
+        self
+    }
+
+    /// Returns the cached probe of `file`, if it didnt change since it got probed.
+    pub fn get(&self, file: &Path) -> Option<FFPWrapper> {
+        let (path, modified) = Self::key(file)?;
+
+        if let Some(cached) = self.entries.lock().unwrap().get_mut(&path) {
+            if cached.modified == modified {
+                cached.last_used = Instant::now();
+                return Some(cached.probe.clone());
+            }
+        }
+
+        let stored = fs::read(self.store_path(&path)?).ok()?;
+        let stored: StoredProbe = serde_json::from_slice(&stored).ok()?;
+
+        if stored.path != path || stored.modified != modified || stored.probe.is_corrupt() {
+            return None;
+        }
+
+        self.remember(path, modified, stored.probe.clone());
+
+        Some(stored.probe)
+    }
+
+    /// Caches `probe` as the probe of `file`, replacing the probe of an older version of it.
+    /// Corrupt probes are ignored, so that a file ffprobe failed on gets probed again.
+    pub fn insert(&self, file: &Path, probe: FFPWrapper) {
+        if probe.is_corrupt() {
+            return;
+        }
+
+        let (path, modified) = match Self::key(file) {
+            Some(x) => x,
+            None => return,
+        };
+
+        if let Some(store_path) = self.store_path(&path) {
+            let stored = StoredProbe {
+                path: path.clone(),
+                modified,
+                probe: probe.clone(),
+            };
+
+            if let Ok(json) = serde_json::to_vec(&stored) {
+                if let Some(dir) = store_path.parent() {
+                    let _ = fs::create_dir_all(dir);
+                }
+
+                let _ = fs::write(store_path, json);
+            }
+        }
+
+        self.remember(path, modified, probe);
+    }
+
+    /// Drops every cached probe of `file`.
+    pub fn invalidate(&self, file: &Path) {
+        let path = fs::canonicalize(file).unwrap_or_else(|_| file.to_path_buf());
+
+        self.entries.lock().unwrap().remove(&path);
+
+        if let Some(store_path) = self.store_path(&path) {
+            let _ = fs::remove_file(store_path);
+        }
+    }
+
+    /// Keeps `probe` in memory, evicting the least recently used probe if the cache is full.
+    fn remember(&self, path: PathBuf, modified: SystemTime, probe: FFPWrapper) {
+        let mut entries = self.entries.lock().unwrap();
+
+        if entries.len() >= self.capacity && !entries.contains_key(&path) {
+            let oldest = entries
+                .iter()
+                .min_by_key(|(_, x)| x.last_used)
+                .map(|(k, _)| k.clone());
+
+            if let Some(oldest) = oldest {
+                entries.remove(&oldest);
+            }
+        }
+
+        // keyed by path only, thus the probe of an older version of the file gets replaced.
+        entries.insert(
+            path,
+            CachedProbe {
+                modified,
+                probe,
+                last_used: Instant::now(),
+            },
+        );
+    }
+
+    fn key(file: &Path) -> Option<(PathBuf, SystemTime)> {
+        let modified = fs::metadata(file).ok()?.modified().ok()?;
+        let path = fs::canonicalize(file).unwrap_or_else(|_| file.to_path_buf());
+
+        Some((path, modified))
+    }
+
+    /// Returns where the probe of `path` is persisted. The name is a FNV-1a hash of the path, as
+    /// unlike `DefaultHasher` it is the same across builds and thus across restarts.
+    fn store_path(&self, path: &Path) -> Option<PathBuf> {
+        let hash = path
+            .to_string_lossy()
+            .bytes()
+            .fold(0xcbf2_9ce4_8422_2325u64, |hash, x| {
+                (hash ^ x as u64).wrapping_mul(0x0100_0000_01b3)
+            });
+
+        Some(self.store.as_ref()?.join(format!("{:016x}.json", hash)))
+    }
+}
+
+/// Error wrapped into the `TimedOut` io errors returned when ffprobe hangs, see
+/// `FFProbeCtx::with_timeout`.
+#[derive(Clone, Debug)]
+pub struct ProbeTimeout(pub Duration);
+
+impl std::fmt::Display for ProbeTimeout {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "ffprobe didnt finish within {:?}", self.0)
+    }
+}
+
+impl std::error::Error for ProbeTimeout {}
+
+#[derive(Clone)]
 pub struct FFProbeCtx {
     ffprobe_bin: String,
+    /// How much of the input ffprobe analyzes to detect streams, ffprobe defaults to 5 seconds.
+    /// Raise this for files with streams that only start late into the file.
+    pub probe_analyze_duration: Option<Duration>,
+    /// How many bytes of the input ffprobe reads to detect streams, ffprobe defaults to 5MB.
+    pub probe_probesize: Option<u64>,
+    /// How long ffprobe may run before it gets killed, ffprobe can hang forever on some corrupt
+    /// files.
+    pub timeout: Option<Duration>,
+    /// Cache `get_meta` goes through, see `with_cache`.
+    pub cache: Option<Arc<ProbeCache>>,
 }
 
-fn format_timecode(nanos: i64) -> String {
-    let d = Duration::from_nanos(nanos as u64);
+fn format_timecode(secs: f64) -> String {
+    let d = Duration::from_secs_f64(secs.max(0.0));
 
     let total_secs = d.as_secs();
 
@@ -103,23 +750,122 @@ fn format_timecode(nanos: i64) -> String {
 }
 
 impl FFProbeCtx {
-    pub fn new(ffprobe_bin: &'static str) -> Self {
+    pub fn new(ffprobe_bin: impl Into<String>) -> Self {
         Self {
-            ffprobe_bin: ffprobe_bin.to_owned(),
+            ffprobe_bin: ffprobe_bin.into(),
+            probe_analyze_duration: None,
+            probe_probesize: None,
+            timeout: None,
+            cache: None,
+        }
+    }
+
+    pub fn with_analyze_duration(mut self, duration: Duration) -> Self {
+        self.probe_analyze_duration = Some(duration);
+        self
+    }
+
+    pub fn with_probesize(mut self, probesize: u64) -> Self {
+        self.probe_probesize = Some(probesize);
+        self
+    }
+
+    /// Kills ffprobe when it runs for longer than `timeout`. The probe then fails with a
+    /// `TimedOut` io error wrapping `ProbeTimeout`, which converts into
+    /// `NightfallError::ProbeTimeout`.
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    /// Runs `cmd` to completion and collects its stdout, killing it once `timeout` elapses.
+    fn output(&self, cmd: &mut Command) -> Result<Output, std::io::Error> {
+        let timeout = match self.timeout {
+            Some(x) => x,
+            None => return cmd.output(),
+        };
+
+        let mut child = cmd
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null())
+            .spawn()?;
+
+        // stdout has to be drained while waiting, otherwise ffprobe blocks once the pipe is full.
+        let mut stdout = child.stdout.take().expect("stdout is piped");
+        let reader = std::thread::spawn(move || {
+            let mut buf = Vec::new();
+            let _ = stdout.read_to_end(&mut buf);
+            buf
+        });
+
+        let deadline = Instant::now() + timeout;
+        let status = loop {
+            if let Some(status) = child.try_wait()? {
+                break status;
+            }
+
+            if Instant::now() >= deadline {
+                let _ = child.kill();
+                let _ = child.wait();
+
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::TimedOut,
+                    ProbeTimeout(timeout),
+                ));
+            }
+
+            std::thread::sleep(Duration::from_millis(10));
+        };
+
+        Ok(Output {
+            status,
+            stdout: reader.join().unwrap_or_default(),
+            stderr: Vec::new(),
+        })
+    }
+
+    /// Caches the results of `get_meta` in `cache`, which can be shared between several contexts
+    /// as long as they analyze the input equally deep.
+    pub fn with_cache(mut self, cache: Arc<ProbeCache>) -> Self {
+        self.cache = Some(cache);
+        self
+    }
+
+    /// Returns the args controlling how deep ffprobe analyzes the input.
+    fn analysis_args(&self) -> Vec<String> {
+        let mut args = Vec::new();
+
+        if let Some(duration) = self.probe_analyze_duration {
+            args.push("-analyzeduration".into());
+            args.push(duration.as_micros().to_string());
+        }
+
+        if let Some(probesize) = self.probe_probesize {
+            args.push("-probesize".into());
+            args.push(probesize.to_string());
         }
+
+        args
     }
 
     pub fn get_meta(&self, file: &Path) -> Result<FFPWrapper, std::io::Error> {
-        let probe = Command::new(self.ffprobe_bin.clone())
-            .arg(file.to_str().unwrap())
-            .arg("-v")
-            .arg("quiet")
-            .arg("-print_format")
-            .arg("json")
-            .arg("-show_chapters")
-            .arg("-show_streams")
-            .arg("-show_format")
-            .output()?;
+        if let Some(probe) = self.cache.as_ref().and_then(|x| x.get(file)) {
+            return Ok(probe);
+        }
+
+        let probe = self.output(
+            Command::new(self.ffprobe_bin.clone())
+                .args(self.analysis_args())
+                .arg(file.to_str().unwrap())
+                .arg("-v")
+                .arg("quiet")
+                .arg("-print_format")
+                .arg("json")
+                .arg("-show_chapters")
+                .arg("-show_streams")
+                .arg("-show_format"),
+        )?;
 
         let json = String::from_utf8_lossy(probe.stdout.as_slice());
 
@@ -134,19 +880,185 @@ impl FFProbeCtx {
             },
         );
 
+        if let Some(cache) = self.cache.as_ref() {
+            cache.insert(file, de.clone());
+        }
+
         Ok(de)
     }
 
+    /// Method probes `files` concurrently, running at most `concurrency` ffprobe processes at a
+    /// time, for library scans. Results are yielded as each probe finishes, thus not necessarily
+    /// in the order of `files`. Must be called from within a tokio runtime. Files left once the
+    /// stream is dropped dont get probed.
+    pub fn probe_many(
+        &self,
+        files: Vec<PathBuf>,
+        concurrency: usize,
+    ) -> impl AsyncStream<Item = (PathBuf, Result<FFPWrapper, std::io::Error>)> {
+        let concurrency = concurrency.max(1);
+        let (tx, rx) = mpsc::channel(concurrency);
+        let ctx = self.clone();
+
+        tokio::spawn(async move {
+            let semaphore = Arc::new(Semaphore::new(concurrency));
+
+            for file in files {
+                let permit = match semaphore.clone().acquire_owned().await {
+                    Ok(x) => x,
+                    Err(_) => break,
+                };
+
+                // nobody is reading the results anymore.
+                if tx.is_closed() {
+                    break;
+                }
+
+                let ctx = ctx.clone();
+                let tx = tx.clone();
+                let semaphore = semaphore.clone();
+
+                tokio::task::spawn_blocking(move || {
+                    if tx.is_closed() {
+                        return;
+                    }
+
+                    let result = ctx.get_meta(&file);
+
+                    if tx.blocking_send((file, result)).is_err() {
+                        semaphore.close();
+                    }
+
+                    drop(permit);
+                });
+            }
+        });
+
+        ReceiverStream::new(rx)
+    }
+
+    /// Same as `get_meta` except that MP4 and Matroska files get their headers parsed natively
+    /// first, which is much faster than spawning ffprobe but only reports codecs, dimensions,
+    /// durations and the layout of the tracks. ffprobe is used for any other file, when the
+    /// headers cant be parsed or when a video stream lacks its pixel format or color metadata,
+    /// which profiles rely on to pick tonemapping and pixel format conversions.
+    pub fn get_meta_fast(&self, file: &Path) -> Result<FFPWrapper, std::io::Error> {
+        if let Some(probe) = crate::native_probe::probe(file) {
+            let is_complete = probe
+                .streams()
+                .iter()
+                .filter(|x| x.codec_type == "video")
+                .all(|x| x.pix_fmt.is_some() && x.color_transfer.is_some());
+
+            if is_complete {
+                return Ok(probe);
+            }
+        }
+
+        self.get_meta(file)
+    }
+
+    /// Method will analyze the packets of the first two minutes of `stream` and return the
+    /// average interval in seconds between keyframes, or `None` if less than two keyframes were
+    /// found.
+    pub fn get_keyframe_interval(
+        &self,
+        file: &Path,
+        stream: usize,
+    ) -> Result<Option<f64>, std::io::Error> {
+        let keyframes = self.keyframe_times(file, stream, Some("%+120"))?;
+
+        if keyframes.len() < 2 {
+            return Ok(None);
+        }
+
+        let span = keyframes[keyframes.len() - 1] - keyframes[0];
+
+        Ok(Some(span / (keyframes.len() - 1) as f64))
+    }
+
+    /// Method returns the sorted timestamps in seconds of every keyframe of `stream`. This has to
+    /// read every packet of the file so it should be cached by the caller, the result is meant to
+    /// be passed along as `InputCtx::keyframes`.
+    pub fn get_keyframes(&self, file: &Path, stream: usize) -> Result<Vec<f64>, std::io::Error> {
+        let mut keyframes = self.keyframe_times(file, stream, None)?;
+        keyframes.sort_by(|a, b| a.total_cmp(b));
+        keyframes.dedup();
+
+        Ok(keyframes)
+    }
+
+    fn keyframe_times(
+        &self,
+        file: &Path,
+        stream: usize,
+        read_intervals: Option<&str>,
+    ) -> Result<Vec<f64>, std::io::Error> {
+        let mut cmd = Command::new(self.ffprobe_bin.clone());
+        cmd.args(self.analysis_args())
+            .arg(file.to_str().unwrap())
+            .arg("-v")
+            .arg("quiet")
+            .arg("-select_streams")
+            .arg(stream.to_string());
+
+        if let Some(read_intervals) = read_intervals {
+            cmd.arg("-read_intervals").arg(read_intervals);
+        }
+
+        let probe = self.output(
+            cmd.arg("-show_entries")
+                .arg("packet=pts_time,flags")
+                .arg("-print_format")
+                .arg("json"),
+        )?;
+
+        let json = String::from_utf8_lossy(probe.stdout.as_slice());
+        let packets: Packets = serde_json::from_str(&json).unwrap_or_default();
+
+        Ok(packets
+            .packets
+            .iter()
+            .filter(|x| x.flags.as_deref().is_some_and(|x| x.starts_with('K')))
+            .filter_map(|x| x.pts_time.as_ref()?.parse::<f64>().ok())
+            .collect())
+    }
+
+    /// Method lists all subtitle tracks available for `file`, both embedded streams and sidecar
+    /// files sitting next to it.
+    pub fn list_subtitles(&self, file: &Path) -> Result<Vec<SubtitleTrack>, std::io::Error> {
+        let meta = self.get_meta(file)?;
+
+        let mut tracks = meta
+            .streams()
+            .iter()
+            .filter(|x| x.codec_type == "subtitle")
+            .map(|x| SubtitleTrack {
+                index: Some(x.index),
+                path: None,
+                codec: x.codec_name.clone(),
+                language: x.tags.as_ref().and_then(|x| x.language.clone()),
+                title: x.tags.as_ref().and_then(|x| x.title.clone()),
+            })
+            .collect::<Vec<_>>();
+
+        tracks.append(&mut find_sidecar_subtitles(file));
+
+        Ok(tracks)
+    }
+
+    /// Method returns the chapters of `file`, ex. to implement skip intro or next chapter
+    /// controls. Chapters without a title should be named after their position.
+    pub fn get_chapters(&self, file: &Path) -> Result<Vec<Chapter>, std::io::Error> {
+        Ok(self.get_meta(file)?.chapters().to_vec())
+    }
+
     pub fn get_chapters_webvtt(&self, file: &Path) -> Result<String, std::io::Error> {
-        let chapters = self
-            .get_meta(&file)?
-            .ffpstream
-            .map(|s| s.chapters)
-            .unwrap_or_default();
+        let chapters = self.get_chapters(file)?;
 
         let mut output = String::new();
 
-        if chapters.len() == 0 {
+        if chapters.is_empty() {
             return Ok(output);
         };
 
@@ -154,18 +1066,13 @@ impl FFProbeCtx {
 
         for (i, chapter) in chapters.iter().enumerate() {
             let default_title = format!("Chapter {}", i + 1);
-
-            let title = chapter
-                .tags
-                .as_ref()
-                .and_then(|tags| tags.title.as_deref())
-                .unwrap_or(&default_title);
+            let title = chapter.title().unwrap_or(&default_title);
 
             output.push_str(&format!(
                 "{}\n{} --> {}\n{}\n\n",
                 i + 1,
-                format_timecode(chapter.start),
-                format_timecode(chapter.end),
+                format_timecode(chapter.start_secs()),
+                format_timecode(chapter.end_secs()),
                 title,
             ));
         }
@@ -173,3 +1080,152 @@ impl FFProbeCtx {
         Ok(output)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_file() -> PathBuf {
+        let path = std::env::temp_dir().join(format!(
+            "nightfall-cache-{}.mkv",
+            uuid::Uuid::new_v4().hyphenated()
+        ));
+        fs::write(&path, b"").unwrap();
+        path
+    }
+
+    fn probe(duration: &str) -> FFPWrapper {
+        FFPWrapper::from_parts(
+            Vec::new(),
+            Format {
+                duration: duration.into(),
+                ..Default::default()
+            },
+        )
+    }
+
+    fn corrupt() -> FFPWrapper {
+        FFPWrapper {
+            ffpstream: None,
+            corrupt: Some(true),
+        }
+    }
+
+    #[test]
+    fn corrupt_probes_arent_cached() {
+        let store = std::env::temp_dir().join(format!(
+            "nightfall-store-{}",
+            uuid::Uuid::new_v4().hyphenated()
+        ));
+        let cache = ProbeCache::with_store(&store);
+        let file = temp_file();
+
+        cache.insert(&file, corrupt());
+        assert_eq!(cache.get(&file), None);
+        assert!(!store.exists());
+
+        cache.insert(&file, probe("10.0"));
+        assert_eq!(cache.get(&file), Some(probe("10.0")));
+        // picked back up by a new cache, ie. after a restart.
+        assert_eq!(
+            ProbeCache::with_store(&store).get(&file),
+            Some(probe("10.0"))
+        );
+
+        let _ = fs::remove_file(file);
+        let _ = fs::remove_dir_all(store);
+    }
+
+    #[test]
+    fn modified_files_replace_their_stale_probe() {
+        let cache = ProbeCache::new();
+        let file = temp_file();
+
+        cache.insert(&file, probe("10.0"));
+
+        let modified = SystemTime::now() + Duration::from_secs(60);
+        fs::File::options()
+            .write(true)
+            .open(&file)
+            .unwrap()
+            .set_modified(modified)
+            .unwrap();
+
+        assert_eq!(cache.get(&file), None);
+
+        cache.insert(&file, probe("20.0"));
+        assert_eq!(cache.get(&file), Some(probe("20.0")));
+        assert_eq!(cache.entries.lock().unwrap().len(), 1);
+
+        let _ = fs::remove_file(file);
+    }
+
+    #[test]
+    fn least_recently_used_probes_get_evicted() {
+        let cache = ProbeCache::new().with_capacity(2);
+        let files = [temp_file(), temp_file(), temp_file()];
+
+        cache.insert(&files[0], probe("1.0"));
+        cache.insert(&files[1], probe("2.0"));
+        // keeps the first probe around rather than the second.
+        assert!(cache.get(&files[0]).is_some());
+
+        cache.insert(&files[2], probe("3.0"));
+
+        assert!(cache.get(&files[0]).is_some());
+        assert!(cache.get(&files[1]).is_none());
+        assert!(cache.get(&files[2]).is_some());
+
+        for file in files {
+            let _ = fs::remove_file(file);
+        }
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn probe_many_stops_once_the_stream_is_dropped() {
+        use std::os::unix::fs::PermissionsExt;
+        use tokio_stream::StreamExt;
+
+        let dir = std::env::temp_dir().join(format!(
+            "nightfall-probe-many-{}",
+            uuid::Uuid::new_v4().hyphenated()
+        ));
+        fs::create_dir_all(&dir).unwrap();
+
+        // stands in for ffprobe, logging every run.
+        let log = dir.join("runs");
+        let bin = dir.join("ffprobe");
+        fs::write(
+            &bin,
+            format!("#!/bin/sh\necho run >> {}\nsleep 0.05\n", log.display()),
+        )
+        .unwrap();
+        fs::set_permissions(&bin, fs::Permissions::from_mode(0o755)).unwrap();
+
+        let ctx = FFProbeCtx::new(bin.to_string_lossy());
+        let files = (0..50).map(|x| dir.join(x.to_string())).collect();
+
+        let mut results = Box::pin(ctx.probe_many(files, 2));
+        assert!(results.next().await.is_some());
+        drop(results);
+
+        tokio::time::sleep(Duration::from_millis(500)).await;
+
+        // the runs in flight when the stream got dropped finish, nothing else gets spawned.
+        let runs = fs::read_to_string(&log).unwrap().lines().count();
+        assert!(runs <= 4, "ffprobe ran {} times", runs);
+
+        let _ = fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn store_paths_are_stable() {
+        let cache = ProbeCache::with_store("/cache");
+
+        assert_eq!(
+            cache.store_path(Path::new("/media/movie.mkv")),
+            Some(PathBuf::from("/cache/f97d337c9447d4dd.json"))
+        );
+    }
+}
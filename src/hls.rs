@@ -0,0 +1,174 @@
+use std::fmt::Write;
+
+/// Placeholder substituted with the session id in the uris of a master playlist.
+pub const SESSION_ID_TEMPLATE: &str = "$SessionID$";
+
+/// A single variant stream of a master playlist.
+#[derive(Clone, Debug, Default)]
+pub struct Variant {
+    pub uri: String,
+    /// Peak bitrate of the variant in bits per second, renditions included.
+    pub bandwidth: u64,
+    /// RFC 6381 codecs of the variant, ex. `avc1.640028`.
+    pub codecs: Vec<String>,
+    pub width: Option<i64>,
+    pub height: Option<i64>,
+    pub frame_rate: Option<f64>,
+}
+
+/// An alternative rendition, ex. a audio or subtitle track, shared by all variants.
+#[derive(Clone, Debug, Default)]
+pub struct Rendition {
+    /// Either `AUDIO` or `SUBTITLES`.
+    pub media_type: String,
+    pub group_id: String,
+    pub name: String,
+    pub lang: Option<String>,
+    pub uri: String,
+    pub codecs: Option<String>,
+    pub bandwidth: u64,
+    pub default: bool,
+}
+
+/// Function builds a master playlist listing `variants`. Renditions get referenced by every
+/// variant through their group, and the codecs as well as the bandwidth of the audio renditions
+/// are added to the ones of the variants as required by RFC 8216.
+pub fn build_master_playlist(variants: &[Variant], renditions: &[Rendition]) -> String {
+    let mut playlist = String::new();
+
+    let _ = writeln!(playlist, "#EXTM3U");
+    let _ = writeln!(playlist, "#EXT-X-VERSION:7");
+    let _ = writeln!(playlist, "#EXT-X-INDEPENDENT-SEGMENTS");
+
+    for rendition in renditions {
+        let mut attrs = format!(
+            r#"TYPE={},GROUP-ID="{}",NAME="{}""#,
+            rendition.media_type, rendition.group_id, rendition.name
+        );
+
+        if let Some(lang) = rendition.lang.as_ref() {
+            let _ = write!(attrs, r#",LANGUAGE="{}""#, lang);
+        }
+
+        let default = if rendition.default { "YES" } else { "NO" };
+        let _ = write!(
+            attrs,
+            r#",DEFAULT={},AUTOSELECT=YES,URI="{}""#,
+            default, rendition.uri
+        );
+
+        let _ = writeln!(playlist, "#EXT-X-MEDIA:{}", attrs);
+    }
+
+    let audio = renditions
+        .iter()
+        .filter(|x| x.media_type == "AUDIO")
+        .collect::<Vec<_>>();
+    let subtitles = renditions.iter().find(|x| x.media_type == "SUBTITLES");
+
+    for variant in variants {
+        let audio_bandwidth = audio.iter().map(|x| x.bandwidth).max().unwrap_or(0);
+        let mut codecs = variant.codecs.clone();
+
+        for codec in audio.iter().filter_map(|x| x.codecs.as_ref()) {
+            if !codecs.contains(codec) {
+                codecs.push(codec.clone());
+            }
+        }
+
+        let mut attrs = format!("BANDWIDTH={}", variant.bandwidth + audio_bandwidth);
+
+        if !codecs.is_empty() {
+            let _ = write!(attrs, r#",CODECS="{}""#, codecs.join(","));
+        }
+
+        if let (Some(width), Some(height)) = (variant.width, variant.height) {
+            let _ = write!(attrs, ",RESOLUTION={}x{}", width, height);
+        }
+
+        if let Some(frame_rate) = variant.frame_rate {
+            let _ = write!(attrs, ",FRAME-RATE={:.3}", frame_rate);
+        }
+
+        if let Some(rendition) = audio.first() {
+            let _ = write!(attrs, r#",AUDIO="{}""#, rendition.group_id);
+        }
+
+        if let Some(rendition) = subtitles {
+            let _ = write!(attrs, r#",SUBTITLES="{}""#, rendition.group_id);
+        }
+
+        let _ = writeln!(playlist, "#EXT-X-STREAM-INF:{}", attrs);
+        let _ = writeln!(playlist, "{}", variant.uri);
+    }
+
+    playlist
+}
+
+/// Function returns the RFC 6381 codecs string of a stream encoded with `codec`, as named by
+/// ffmpeg. For h264 and hevc the profile and level are read from the decoder configuration in
+/// `init_segment` when given, otherwise we fall back to a sensible guess.
+pub fn codecs_string(codec: &str, init_segment: Option<&[u8]>) -> Option<String> {
+    let config = |name: &[u8]| {
+        init_segment.and_then(|data| {
+            data.windows(4)
+                .position(|x| x == name)
+                .and_then(|x| data.get(x + 4..))
+        })
+    };
+
+    let codecs = match codec {
+        "h264" => match config(b"avcC") {
+            // configurationVersion, AVCProfileIndication, profile_compatibility, AVCLevelIndication
+            Some([_, profile, compat, level, ..]) => {
+                format!("avc1.{:02X}{:02X}{:02X}", profile, compat, level)
+            }
+            _ => "avc1.640028".into(),
+        },
+        "hevc" => config(b"hvcC")
+            .and_then(hvcc_codecs_string)
+            .unwrap_or_else(|| "hvc1.1.6.L120.90".into()),
+        "av1" => "av01.0.08M.08".into(),
+        "vp9" => "vp09.00.40.08".into(),
+        "aac" => "mp4a.40.2".into(),
+        "mp3" => "mp4a.40.34".into(),
+        "ac3" => "ac-3".into(),
+        "eac3" => "ec-3".into(),
+        "flac" => "fLaC".into(),
+        "opus" => "Opus".into(),
+        "wvtt" | "webvtt" => "wvtt".into(),
+        "stpp" => "stpp.ttml.im1t".into(),
+        _ => return None,
+    };
+
+    Some(codecs)
+}
+
+/// Builds the codecs string of a hevc stream from its `HEVCDecoderConfigurationRecord`.
+/// see: ISO/IEC 14496-15, Annex E.3
+fn hvcc_codecs_string(record: &[u8]) -> Option<String> {
+    let record = record.get(..13)?;
+    let profile_space = ["", "A", "B", "C"][(record[1] >> 6) as usize];
+    let tier = if record[1] & 0x20 != 0 { "H" } else { "L" };
+    let profile_idc = record[1] & 0x1F;
+    // the compatibility flags are written in reverse bit order.
+    let compat = u32::from_be_bytes([record[2], record[3], record[4], record[5]]).reverse_bits();
+    let level = record[12];
+
+    let mut codecs = format!(
+        "hvc1.{}{}.{:X}.{}{}",
+        profile_space, profile_idc, compat, tier, level
+    );
+
+    // trailing constraint bytes which are zero are omitted.
+    let constraints = &record[6..12];
+    let len = constraints
+        .iter()
+        .rposition(|x| *x != 0)
+        .map_or(0, |x| x + 1);
+    for byte in &constraints[..len] {
+        let _ = write!(codecs, ".{:X}", byte);
+    }
+
+    Some(codecs)
+}
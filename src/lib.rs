@@ -1,42 +1,515 @@
 #![doc = include_str!("../README.md")]
 
+/// Contains the detection of the encoders and hardware available to ffmpeg.
+pub mod capabilities;
+/// Contains helpers to build MPEG-DASH manifests.
+pub mod dash;
+/// Contains the AES-128 encryption of chunks handed out to HLS players.
+pub mod encryption;
 /// Contains all the error types for this crate.
 pub mod error;
+/// Contains the lifecycle events published by the state manager.
+pub mod events;
 /// Helper methods to probe a mediafile for metadata.
 pub mod ffprobe;
+/// Contains helpers to build HLS master playlists.
+pub mod hls;
+/// Contains the backpressure mechanism for the state manager mailbox.
+pub mod mailbox;
+/// Contains the metrics snapshot exposed by the state manager.
+pub mod metrics;
+/// Contains a pure Rust fallback for ffprobe reading MP4 and Matroska headers.
+pub mod native_probe;
 /// Contains utils that patch segments to make them appear continuous.
 pub mod patch;
+/// Contains the abstraction over spawning ffmpeg processes.
+pub mod process;
 /// Contains all profiles currently implemented.
 pub mod profiles;
 /// Contains the struct representing a streaming session.
 mod session;
+/// Contains the packaging of trickplay thumbnails into sprite sheets and BIF files.
+pub mod thumbnails;
 /// Contains utils that make my life easier.
 pub mod utils;
+/// Contains helpers to split WebVTT subtitles into chunks.
+pub mod webvtt;
 
+use crate::capabilities::Capabilities;
+use crate::encryption::SegmentKey;
 use crate::error::*;
+use crate::events::SessionEvent;
+use crate::events::EVENT_CAPACITY;
+use crate::ffprobe::Stream;
+use crate::mailbox::MailboxGuard;
+use crate::metrics::Counters;
+use crate::metrics::Metrics;
+use crate::metrics::Progress;
+use crate::metrics::SessionStats;
+use crate::metrics::SessionSummary;
+use crate::metrics::StreamStats;
+use crate::patch::cenc::protect_init_segment;
+use crate::patch::cenc::SampleFormat;
 use crate::patch::init_segment::patch_init_segment;
+use crate::patch::mpegts::patch_ts_chunk;
+use crate::patch::mpegts::TS_START_OFFSET;
+use crate::patch::mpegts::TS_TIMESCALE;
+use crate::patch::segment::file_fragment_ranges;
 use crate::patch::segment::patch_segment;
+use crate::patch::sidx::index_single_file;
+use crate::patch::sidx::SegmentIndex;
+use crate::patch::webm::header_timestamp_scale;
+use crate::patch::webm::patch_webm_chunk;
+use crate::patch::webm::DEFAULT_TIMESTAMP_SCALE;
+use crate::process::FfmpegSpawner;
+use crate::process::ProcessSpawner;
 use crate::profiles::*;
+use crate::session::find_persisted;
+use crate::session::LadderState;
+use crate::session::PersistedSession;
 use crate::session::Session;
-
+use crate::thumbnails::prune_screenshots;
+use crate::thumbnails::screenshot_name;
+use crate::thumbnails::write_index;
+use crate::thumbnails::ScreenshotOptions;
+use crate::thumbnails::SCREENSHOT_DIR;
+use crate::thumbnails::THUMBNAIL_NICE;
+
+use std::collections::BTreeMap;
 use std::collections::HashMap;
+use std::collections::VecDeque;
 use std::fmt;
+use std::future::Future;
+use std::future::IntoFuture;
 use std::path::Path;
+use std::pin::Pin;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
 use std::time::Duration;
 use std::time::Instant;
 
 use async_trait::async_trait;
+use tokio::sync::broadcast;
+use tokio::sync::watch;
 use tracing::debug;
 use tracing::info;
+use tracing::info_span;
 use tracing::warn;
+use tracing::Instrument;
+use tracing::Span;
 use xtra_proc::actor;
 use xtra_proc::handler;
 
-pub use tokio::process::ChildStdout;
+pub use process::ProcessOutput;
+
+/// Controls how far ahead of the player a session is allowed to encode. A session encodes at
+/// full speed until `high_watermark` chunks are buffered past the last requested chunk, then gets
+/// paused until the buffer drains to `low_watermark` chunks.
+#[derive(Clone, Copy, Debug)]
+pub struct Pacing {
+    pub high_watermark: u32,
+    pub low_watermark: u32,
+}
+
+impl Default for Pacing {
+    fn default() -> Self {
+        Self {
+            high_watermark: 15,
+            low_watermark: 2,
+        }
+    }
+}
+
+/// Controls when `garbage_collect` pauses and reaps sessions nobody requests chunks from anymore.
+/// Set for every session with `StateManager::with_gc_policy` and overridden for single sessions
+/// with `StateManager::set_session_gc_policy`.
+#[derive(Clone, Copy, Debug)]
+pub struct GcPolicy {
+    /// Pause ffmpeg once no chunk has been requested for this long, the next request resumes it.
+    /// Idle sessions keep encoding until `reap_after` when `None`.
+    pub pause_after: Option<Duration>,
+    /// Kill ffmpeg and reap the session once no chunk has been requested for this long.
+    pub reap_after: Duration,
+    /// Keep the outdir of reaped sessions on disk instead of deleting it, ex. to serve the
+    /// segments from a cache afterwards.
+    pub keep_segments: bool,
+}
+
+impl Default for GcPolicy {
+    fn default() -> Self {
+        Self {
+            pause_after: None,
+            reap_after: Duration::from_secs(30 * 60),
+            keep_segments: false,
+        }
+    }
+}
+
+/// Closure computing the outdir of a session out of the base outdir, the session id and the
+/// profile context.
+pub type OutdirFn = dyn Fn(&str, &str, &ProfileContext) -> String + Send + Sync;
+
+/// Hook invoked with the index and path of every chunk once `chunk_request` hands it out, see
+/// `StateManager::set_on_segment`.
+pub type SegmentHook = dyn Fn(u32, &Path) + Send + Sync;
+
+/// Decides where the artifacts of a session get written to, relative to `StateManager::outdir`.
+/// Everything under the computed directory is owned by the session and gets deleted when it is
+/// reaped.
+#[derive(Clone, Default)]
+pub enum OutdirLayout {
+    /// `outdir/session_id`.
+    #[default]
+    PerSession,
+    /// `outdir/label/session_id`, where `label` is the value of the given key in
+    /// `ProfileContext::labels`. Sessions without the label are grouped under `default`.
+    Grouped(String),
+    /// Closure which receives the base outdir, the session id and the profile context and must
+    /// return a directory unique to the session.
+    Custom(Arc<OutdirFn>),
+}
+
+impl OutdirLayout {
+    pub fn outdir_for(&self, outdir: &str, session_id: &str, ctx: &ProfileContext) -> String {
+        match self {
+            Self::PerSession => format!("{}/{}", outdir, session_id),
+            Self::Grouped(key) => {
+                let group = ctx
+                    .labels
+                    .get(key)
+                    .map(|x| sanitize_path_component(x))
+                    .unwrap_or_else(|| "default".into());
+
+                format!("{}/{}/{}", outdir, group, session_id)
+            }
+            Self::Custom(f) => f(outdir, session_id, ctx),
+        }
+    }
+}
+
+impl fmt::Debug for OutdirLayout {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::PerSession => f.write_str("PerSession"),
+            Self::Grouped(key) => f.debug_tuple("Grouped").field(key).finish(),
+            Self::Custom(_) => f.write_str("Custom"),
+        }
+    }
+}
+
+/// Function makes sure a user supplied label cant escape the directory it gets joined onto.
+fn sanitize_path_component(x: &str) -> String {
+    let sanitized = x
+        .chars()
+        .map(|c| match c {
+            '/' | '\\' | '\0' => '_',
+            c => c,
+        })
+        .collect::<String>();
+
+    match sanitized.as_str() {
+        "" | "." | ".." => "_".into(),
+        _ => sanitized,
+    }
+}
+
+/// How many chunks ahead of a started ladder variant the player may be before the variant gets
+/// moved over to it, see `sync_ladder`.
+const LADDER_RESYNC_DISTANCE: u32 = 15;
+
+/// How long a one-off ffmpeg run, ex. a screenshot, may take before it gets killed.
+const ONESHOT_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Function runs a one-off ffmpeg to completion on its own task. ffmpeg gets killed if it hasnt
+/// exited after `ONESHOT_TIMEOUT`, in which case `Aborted` is returned.
+async fn run_oneshot(mut command: tokio::process::Command) -> Result<std::process::Output> {
+    command
+        .stdin(std::process::Stdio::null())
+        .kill_on_drop(true);
+
+    let task =
+        tokio::spawn(async move { tokio::time::timeout(ONESHOT_TIMEOUT, command.output()).await });
+
+    match task.await {
+        Ok(Ok(output)) => Ok(output?),
+        Ok(Err(_)) => {
+            warn!(timeout = ?ONESHOT_TIMEOUT, "Killed ffmpeg as it didnt exit in time.");
+            Err(NightfallError::Aborted)
+        }
+        Err(_) => Err(NightfallError::Aborted),
+    }
+}
+
+/// Describes the session `create` just set up.
+#[derive(Clone, Debug)]
+pub struct CreateResult {
+    /// Id of the new session.
+    pub session_id: String,
+    /// Tag of the profile the session will start with.
+    pub active_profile_tag: String,
+    /// Whether the active profile transmuxes the stream instead of transcoding it.
+    pub is_direct_play: bool,
+    /// Tags of the profile chain in the order they get tried in, starting with the active profile.
+    pub resolved_chain: Vec<String>,
+}
+
+/// A session `create` validated its chain and context for, but didnt set up yet.
+struct PreparedSession {
+    profile_chain: Vec<Arc<dyn TranscodingProfile>>,
+    profile_args: ProfileContext,
+    encryption: Option<SegmentKey>,
+}
+
+/// Why the ffmpeg process of a session exited.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ExitReason {
+    /// ffmpeg finished transcoding the whole input.
+    Success,
+    /// ffmpeg failed, contains the exit code and the tail of its stderr.
+    Failed { code: Option<i32>, stderr: String },
+    /// ffmpeg was killed, either by us or by a signal.
+    Killed,
+    /// the session got reaped because its deadline, see `set_expiry`, passed.
+    Expired,
+}
+
+/// Future which resolves once the ffmpeg process of a session exits. Sessions get restarted when
+/// seeking or falling back to another profile, this only resolves once the session as a whole is
+/// finished, killed or reaped, or once the last profile of its chain failed.
+pub struct Completion {
+    rx: watch::Receiver<Option<ExitReason>>,
+}
+
+impl Completion {
+    pub async fn wait(mut self) -> ExitReason {
+        loop {
+            if let Some(reason) = self.rx.borrow().clone() {
+                return reason;
+            }
+
+            // the sender only gets dropped if the session got dropped without being reaped.
+            if self.rx.changed().await.is_err() {
+                return self.rx.borrow().clone().unwrap_or(ExitReason::Killed);
+            }
+        }
+    }
+}
+
+impl IntoFuture for Completion {
+    type Output = ExitReason;
+    type IntoFuture = Pin<Box<dyn Future<Output = ExitReason> + Send>>;
+
+    fn into_future(self) -> Self::IntoFuture {
+        Box::pin(self.wait())
+    }
+}
+
+/// Future which resolves once the artifacts of a session have been moved, see
+/// `StateManager::relocate`.
+pub struct Relocation {
+    rx: watch::Receiver<Option<Result<()>>>,
+}
+
+impl Relocation {
+    pub async fn wait(mut self) -> Result<()> {
+        loop {
+            if let Some(result) = self.rx.borrow().clone() {
+                return result;
+            }
+
+            // the sender only gets dropped if the blocking task panicked.
+            if self.rx.changed().await.is_err() {
+                return Err(NightfallError::Aborted);
+            }
+        }
+    }
+}
+
+impl IntoFuture for Relocation {
+    type Output = Result<()>;
+    type IntoFuture = Pin<Box<dyn Future<Output = Result<()>> + Send>>;
+
+    fn into_future(self) -> Self::IntoFuture {
+        Box::pin(self.wait())
+    }
+}
+
+/// Future which resolves once the first chunks of a session are ready, see
+/// `StateManager::warmup_chunks`.
+pub struct Warmup {
+    id: String,
+    events: broadcast::Receiver<SessionEvent>,
+    completion: watch::Receiver<Option<ExitReason>>,
+    /// Chunks which arent ready yet, mapped to their path.
+    pending: BTreeMap<u32, String>,
+    ready: u32,
+    deadline: Instant,
+}
+
+impl Warmup {
+    /// Resolves once every chunk is ready, or once ffmpeg finished the whole input. Fails with
+    /// `WarmupTimedOut` past the deadline and with `WarmupFailed` if ffmpeg failed.
+    pub async fn wait(mut self) -> Result<()> {
+        let deadline = tokio::time::Instant::from_std(self.deadline);
+
+        while !self.pending.is_empty() {
+            tokio::select! {
+                event = self.events.recv() => match event {
+                    Ok(SessionEvent::ChunkReady { id, chunk }) if id == self.id => {
+                        if self.pending.remove(&chunk).is_some() {
+                            self.ready += 1;
+                        }
+                    }
+                    Ok(SessionEvent::Errored { id, .. }) if id == self.id => {
+                        return Err(NightfallError::WarmupFailed(self.ready));
+                    }
+                    Ok(SessionEvent::Reaped { id }) if id == self.id => {
+                        return Err(NightfallError::SessionDoesntExist);
+                    }
+                    Ok(_) => {}
+                    Err(broadcast::error::RecvError::Lagged(_)) => {
+                        // we missed events, look at the disk instead.
+                        let before = self.pending.len();
+                        self.pending.retain(|_, path| !Path::new(path).is_file());
+                        self.ready += (before - self.pending.len()) as u32;
+                    }
+                    Err(broadcast::error::RecvError::Closed) => {
+                        return Err(NightfallError::SessionManagerDied);
+                    }
+                },
+                changed = self.completion.changed() => {
+                    if changed.is_err() {
+                        return Err(NightfallError::SessionDoesntExist);
+                    }
+
+                    match self.completion.borrow().clone() {
+                        // the input is shorter than the chunks we wait for.
+                        Some(ExitReason::Success) => return Ok(()),
+                        Some(_) => return Err(NightfallError::WarmupFailed(self.ready)),
+                        None => {}
+                    }
+                }
+                _ = tokio::time::sleep_until(deadline) => {
+                    return Err(NightfallError::WarmupTimedOut(self.ready));
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl IntoFuture for Warmup {
+    type Output = Result<()>;
+    type IntoFuture = Pin<Box<dyn Future<Output = Result<()>> + Send>>;
+
+    fn into_future(self) -> Self::IntoFuture {
+        Box::pin(self.wait())
+    }
+}
+
+/// Future which resolves once a part of a chunk has been written, used to implement the blocking
+/// playlist reloads of Low-Latency HLS, see `StateManager::await_part`.
+pub struct PartAvailability {
+    path: String,
+    part: Option<u32>,
+    deadline: Instant,
+}
+
+impl PartAvailability {
+    /// Resolves to whether the part is available, or `false` if it didnt show up in time.
+    pub async fn wait(self) -> bool {
+        loop {
+            let (path, part) = (self.path.clone(), self.part);
+            let is_available = tokio::task::spawn_blocking(move || Self::is_available(&path, part))
+                .await
+                .unwrap_or(false);
+
+            if is_available {
+                return true;
+            }
+
+            if Instant::now() >= self.deadline {
+                return false;
+            }
+
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+    }
+
+    fn is_available(path: &str, part: Option<u32>) -> bool {
+        // once ffmpeg renamed the chunk all of its parts are there.
+        if Path::new(path).is_file() {
+            return true;
+        }
+
+        // only the box headers are read, the parts written so far dont have to be.
+        part.is_some_and(|part| {
+            std::fs::File::open(format!("{}.tmp", path))
+                .is_ok_and(|mut x| file_fragment_ranges(&mut x).len() > part as usize)
+        })
+    }
+}
+
+impl IntoFuture for PartAvailability {
+    type Output = bool;
+    type IntoFuture = Pin<Box<dyn Future<Output = bool> + Send>>;
+
+    fn into_future(self) -> Self::IntoFuture {
+        Box::pin(self.wait())
+    }
+}
+
+/// Future which resolves to the path of a file written off the actor, ex. by a one-off ffmpeg
+/// run, see `StateManager::screenshot`, `StateManager::extract_attachment` and
+/// `StateManager::thumbnail_index`. The work runs on its own task, thus the actor isnt held up
+/// while it does.
+pub struct Oneshot {
+    task: tokio::task::JoinHandle<Result<String>>,
+}
+
+impl Oneshot {
+    fn spawn(run: impl Future<Output = Result<String>> + Send + 'static) -> Self {
+        Self {
+            task: tokio::spawn(run),
+        }
+    }
+
+    pub async fn wait(self) -> Result<String> {
+        self.task.await.map_err(|_| NightfallError::Aborted)?
+    }
+}
+
+impl IntoFuture for Oneshot {
+    type Output = Result<String>;
+    type IntoFuture = Pin<Box<dyn Future<Output = Result<String>> + Send>>;
+
+    fn into_future(self) -> Self::IntoFuture {
+        Box::pin(self.wait())
+    }
+}
+
+/// Outcome of a chunk request.
+#[derive(Clone, Debug)]
+pub enum ChunkOutcome {
+    /// The chunk has been fully written and patched, contains the path to the chunk.
+    Ready(String),
+    /// The chunk is not done yet, `eta` is the estimated time until it will be.
+    Pending { eta: Duration },
+}
 
 pub struct StreamStat {
     hard_seeked_at: u32,
     last_hard_seek: Instant,
+    hard_seeks: u64,
+}
+
+impl StreamStat {
+    fn record_hard_seek(&mut self, chunk: u32) {
+        self.hard_seeked_at = chunk;
+        self.last_hard_seek = Instant::now();
+        self.hard_seeks += 1;
+    }
 }
 
 impl Default for StreamStat {
@@ -44,6 +517,7 @@ impl Default for StreamStat {
         Self {
             hard_seeked_at: 0,
             last_hard_seek: Instant::now(),
+            hard_seeks: 0,
         }
     }
 }
@@ -60,6 +534,37 @@ pub struct StateManager {
     pub stream_stats: HashMap<String, StreamStat>,
     /// Contains the exit status of dead sessions
     pub exit_statuses: HashMap<String, String>,
+    /// Counters accumulated over the lifetime of this actor.
+    pub counters: Counters,
+    /// Bounds the amount of in-flight messages so that callers can shed load, see
+    /// `MailboxGuard::send`.
+    pub mailbox: MailboxGuard,
+    /// Watermarks used to throttle sessions which are far ahead of the player.
+    pub pacing: Pacing,
+    /// Decides where each session writes its artifacts to.
+    pub layout: OutdirLayout,
+    /// Amount of "Non-monotonous DTS" warnings after which a session gets restarted with
+    /// regenerated timestamps (`-fflags +genpts`). Disabled when `None`.
+    pub dts_warning_threshold: Option<u64>,
+    /// Spawns the ffmpeg process of every session.
+    pub spawner: Arc<dyn ProcessSpawner>,
+    /// Maximum amount of ffmpeg processes running at once, see `set_max_running`.
+    pub max_running: Option<usize>,
+    /// Sessions waiting for a free slot to start, in order.
+    pub admission_queue: VecDeque<String>,
+    /// Load average per cpu above which background sessions get paused, see `set_max_load`.
+    pub max_load: Option<f64>,
+    /// Maximum amount of live sessions a single owner can have, see `ProfileContext::owner`.
+    pub max_sessions_per_owner: Option<usize>,
+    /// Whether sessions persist their state to their outdir, see `set_persist_sessions`.
+    pub persist_sessions: bool,
+    /// When idle sessions get paused and reaped, unless overridden by the session.
+    pub gc_policy: GcPolicy,
+    /// Publishes the lifecycle events of every session, see `subscribe`.
+    pub events: broadcast::Sender<SessionEvent>,
+    /// What ffmpeg and this host support, profiles outside of it are dropped from new chains.
+    /// Checking is disabled when `None`, see `with_capabilities`.
+    pub capabilities: Option<Capabilities>,
 }
 
 impl fmt::Debug for __ActorStateManager::StateManager {
@@ -69,6 +574,19 @@ impl fmt::Debug for __ActorStateManager::StateManager {
             .field("ffmpeg", &self.ffmpeg)
             .field("sessions", &self.sessions)
             .field("exit_statuses", &self.exit_statuses)
+            .field("counters", &self.counters)
+            .field("mailbox_depth", &self.mailbox.depth())
+            .field("pacing", &self.pacing)
+            .field("layout", &self.layout)
+            .field("dts_warning_threshold", &self.dts_warning_threshold)
+            .field("spawner", &self.spawner)
+            .field("max_running", &self.max_running)
+            .field("admission_queue", &self.admission_queue)
+            .field("max_load", &self.max_load)
+            .field("max_sessions_per_owner", &self.max_sessions_per_owner)
+            .field("persist_sessions", &self.persist_sessions)
+            .field("gc_policy", &self.gc_policy)
+            .field("capabilities", &self.capabilities)
             .finish()
     }
 }
@@ -88,25 +606,284 @@ impl StateManager {
             sessions: HashMap::new(),
             stream_stats: HashMap::new(),
             exit_statuses: HashMap::new(),
+            counters: Counters::default(),
+            mailbox: MailboxGuard::default(),
+            pacing: Pacing::default(),
+            layout: OutdirLayout::default(),
+            dts_warning_threshold: None,
+            spawner: Arc::new(FfmpegSpawner),
+            max_running: None,
+            admission_queue: VecDeque::new(),
+            max_load: None,
+            max_sessions_per_owner: None,
+            persist_sessions: false,
+            gc_policy: GcPolicy::default(),
+            events: broadcast::channel(EVENT_CAPACITY).0,
+            capabilities: None,
         }
     }
 
+    /// Replaces the spawner used to start ffmpeg, for example with `process::mock::MockSpawner`
+    /// to run sessions without a ffmpeg binary.
+    pub fn with_spawner(mut self, spawner: Arc<dyn ProcessSpawner>) -> Self {
+        self.spawner = spawner;
+        self
+    }
+
+    /// Replaces the default policy deciding when idle sessions get paused and reaped.
+    pub fn with_gc_policy(mut self, gc_policy: GcPolicy) -> Self {
+        self.gc_policy = gc_policy;
+        self
+    }
+
+    /// Validates the chain of every new session against `capabilities`, usually obtained with
+    /// `Capabilities::detect` at startup.
+    pub fn with_capabilities(mut self, capabilities: Capabilities) -> Self {
+        self.capabilities = Some(capabilities);
+        self
+    }
+
     #[handler]
     async fn create(
         &mut self,
-        profile_chain: Vec<&'static dyn TranscodingProfile>,
+        profile_chain: Vec<Arc<dyn TranscodingProfile>>,
         profile_args: ProfileContext,
-    ) -> Result<String> {
+    ) -> Result<CreateResult> {
+        let prepared = self.prepare(profile_chain, profile_args)?;
+
+        Ok(self.register(prepared))
+    }
+
+    /// Validates and resolves the chain and context of a new session, without setting anything
+    /// up, so that `create_ladder` can check every variant before creating any of them.
+    fn prepare(
+        &self,
+        profile_chain: Vec<Arc<dyn TranscodingProfile>>,
+        profile_args: ProfileContext,
+    ) -> Result<PreparedSession> {
         let mut profile_args = profile_args;
+        let mut profile_chain = profile_chain;
 
-        let first_tag = if let Some(x) = profile_chain.first() {
-            x.tag()
-        } else {
+        if let Some(movflags) = profile_args.movflags.as_ref() {
+            validate_movflags(movflags)?;
+        }
+
+        validate_metadata(&profile_args.metadata)?;
+
+        if let (Some(owner), Some(limit)) =
+            (profile_args.owner.as_ref(), self.max_sessions_per_owner)
+        {
+            if self.owner_session_ids(owner).len() >= limit {
+                return Err(NightfallError::OwnerSessionLimit(limit));
+            }
+        }
+
+        if !profile_args.input_ctx.audio_languages.is_empty()
+            && profile_chain
+                .iter()
+                .all(|x| x.stream_type() == StreamType::Audio)
+        {
+            let input_ctx = &mut profile_args.input_ctx;
+
+            if let Some(stream) =
+                ffprobe::select_audio_stream(&input_ctx.audio_streams, &input_ctx.audio_languages)
+            {
+                info!(
+                    stream = stream.index,
+                    language = ?stream.tags.as_ref().and_then(|x| x.language.as_ref()),
+                    preferred = ?input_ctx.audio_languages,
+                    "Selected audio stream"
+                );
+
+                input_ctx.stream = stream.index as usize;
+                input_ctx.codec = stream.codec_name.clone();
+                input_ctx.audio_channels = stream.channels.unwrap_or(2) as u64;
+            }
+
+            // The selected stream might have a different codec than the one the chain was built
+            // for, so we drop the profiles that can no longer handle it.
+            profile_chain.retain(|x| x.supports(&profile_args).is_ok());
+        }
+
+        if let Some(capabilities) = self.capabilities.as_ref() {
+            profile_chain.retain(|x| match capabilities.check(x.as_ref()) {
+                Ok(()) => true,
+                Err(e) => {
+                    warn!(profile = x.name(), reason = %e, "Dropping profile unsupported by this host");
+                    false
+                }
+            });
+        }
+
+        let output_ctx = &profile_args.output_ctx;
+
+        // the on-disk playlist would point players at the chunks in the clear.
+        if output_ctx.encrypt
+            && (output_ctx.part_duration.is_some()
+                || output_ctx.single_file
+                || output_ctx.progressive
+                || output_ctx.live_playlist)
+        {
+            return Err(NightfallError::InvalidProfileContext(
+                "Only whole chunks can be encrypted.".into(),
+            ));
+        }
+
+        if let Some(cenc) = output_ctx.cenc.as_ref() {
+            if cenc.key.is_none() {
+                return Err(NightfallError::InvalidProfileContext(
+                    "Common Encryption needs a content key.".into(),
+                ));
+            }
+
+            if output_ctx.encrypt
+                || output_ctx.part_duration.is_some()
+                || output_ctx.single_file
+                || output_ctx.progressive
+                || output_ctx.live_playlist
+            {
+                return Err(NightfallError::InvalidProfileContext(
+                    "Only whole chunks can be protected, without AES-128 encryption.".into(),
+                ));
+            }
+
+            let is_video = profile_chain
+                .last()
+                .is_some_and(|x| x.stream_type() == StreamType::Video);
+
+            if SampleFormat::for_codec(&output_ctx.codec, is_video).is_none() {
+                return Err(NightfallError::InvalidProfileContext(format!(
+                    "Samples of {} cant be protected.",
+                    output_ctx.codec
+                )));
+            }
+        }
+
+        if let Some(mix) = profile_args.output_ctx.audio_mix.as_ref() {
+            let input_ctx = &profile_args.input_ctx;
+            let exists = |index: usize| {
+                input_ctx
+                    .audio_streams
+                    .iter()
+                    .any(|x| x.index as usize == index)
+            };
+
+            if !exists(input_ctx.stream) || !exists(mix.stream) {
+                return Err(NightfallError::InvalidProfileContext(format!(
+                    "Cannot mix audio streams {} and {}, both must be audio streams of the input.",
+                    input_ctx.stream, mix.stream
+                )));
+            }
+
+            if input_ctx.stream == mix.stream {
+                return Err(NightfallError::InvalidProfileContext(
+                    "Cannot mix an audio stream with itself.".into(),
+                ));
+            }
+        }
+
+        // with a keyframe index transmuxed chunks get cut on the source keyframes instead.
+        let keyframe_interval = profile_args
+            .input_ctx
+            .keyframe_interval
+            .filter(|_| profile_args.input_ctx.keyframes.is_none());
+
+        if let Some(interval) = keyframe_interval {
+            let is_transmux = profile_chain
+                .iter()
+                .any(|x| x.profile_type() == ProfileType::Transmux);
+
+            if is_transmux && !profile_args.output_ctx.is_gop_aligned(interval) {
+                match profile_args.output_ctx.gop_mismatch {
+                    GopMismatchPolicy::Warn => {
+                        warn!(
+                            keyframe_interval = interval,
+                            target_gop = profile_args.output_ctx.target_gop,
+                            "Source keyframes dont line up with segment boundaries"
+                        );
+                    }
+                    GopMismatchPolicy::Adjust => {
+                        let target_gop = profile_args.output_ctx.aligned_gop(interval);
+
+                        info!(
+                            keyframe_interval = interval,
+                            from = profile_args.output_ctx.target_gop,
+                            to = target_gop,
+                            "Adjusting segment duration to match source keyframes"
+                        );
+
+                        profile_args.output_ctx.target_gop = target_gop;
+                    }
+                    GopMismatchPolicy::Refuse => {
+                        profile_chain.retain(|x| x.profile_type() != ProfileType::Transmux);
+
+                        if profile_chain.is_empty() {
+                            return Err(NightfallError::ProfileNotSupported(format!(
+                                "Source keyframe interval {:.3}s doesnt align with {}s segments.",
+                                interval, profile_args.output_ctx.target_gop
+                            )));
+                        }
+                    }
+                }
+            }
+        }
+
+        if profile_args.output_ctx.direct_play_only {
+            let stream_type = profile_chain.first().map(|x| x.stream_type());
+            profile_chain.retain(|x| x.profile_type() == ProfileType::Transmux);
+
+            if profile_chain.is_empty() {
+                let reason = profiles::direct_play_unavailable_reason(
+                    stream_type.unwrap_or(StreamType::Video),
+                    &profile_args,
+                );
+
+                info!(reason = %reason, "Direct play unavailable");
+
+                return Err(NightfallError::DirectPlayUnavailable(reason));
+            }
+        }
+
+        // chunks of other containers cant be protected and would be handed out in the clear.
+        if profile_args.output_ctx.cenc.is_some()
+            && profile_chain
+                .iter()
+                .any(|x| x.container() != Container::Fmp4)
+        {
+            return Err(NightfallError::InvalidProfileContext(
+                "Common Encryption needs every profile of the chain to write fMP4.".into(),
+            ));
+        }
+
+        if profile_chain.is_empty() {
             tracing::error!(profile = ?profile_args, "Supplied profile chain is empty");
 
             return Err(NightfallError::ProfileChainExhausted);
+        }
+
+        let encryption = if profile_args.output_ctx.encrypt {
+            Some(SegmentKey::generate()?)
+        } else {
+            None
         };
 
+        Ok(PreparedSession {
+            profile_chain,
+            profile_args,
+            encryption,
+        })
+    }
+
+    /// Sets up a session out of what `prepare` resolved.
+    fn register(&mut self, prepared: PreparedSession) -> CreateResult {
+        let PreparedSession {
+            profile_chain,
+            mut profile_args,
+            encryption,
+        } = prepared;
+
+        let first_tag = profile_chain[0].tag();
+
         let chain = profile_chain
             .iter()
             .map(|x| x.tag())
@@ -137,286 +914,2033 @@ impl StateManager {
             &session_id, profile_args.input_ctx.stream, tag
         );
 
-        profile_args.output_ctx.outdir = format!("{}/{}", &self.outdir, session_id);
+        profile_args.output_ctx.outdir =
+            self.layout
+                .outdir_for(&self.outdir, &session_id, &profile_args);
         profile_args.ffmpeg_bin = self.ffmpeg.clone();
 
-        info!("Session {} chain {}", &session_id, chain);
+        info!("Session {} chain {}", &session_id, chain);
+
+        // `Session` pops profiles off the end of the chain, thus the last profile is the active one.
+        let resolved_chain = profile_chain
+            .iter()
+            .rev()
+            .map(|x| x.tag().to_string())
+            .collect::<Vec<_>>();
+
+        let mut new_session = Session::new(
+            session_id.clone(),
+            profile_chain,
+            profile_args,
+            self.spawner.clone(),
+        );
+        new_session.encryption = encryption;
+
+        let result = CreateResult {
+            session_id: session_id.clone(),
+            active_profile_tag: new_session.profile.tag().to_string(),
+            is_direct_play: new_session.profile.profile_type() == ProfileType::Transmux,
+            resolved_chain,
+        };
+
+        self.emit(SessionEvent::SessionCreated {
+            id: session_id.clone(),
+            profile: result.active_profile_tag.clone(),
+        });
+        self.sessions.insert(session_id, new_session);
+
+        result
+    }
+
+    /// Creates one session per quality variant of the same input, linked so that they share
+    /// their seek state. When the player switches to another variant mid-stream, the variant
+    /// starts at the chunk the player is at instead of chunk 0. Every variant must use the same
+    /// segment duration so that their chunks line up.
+    ///
+    /// Returns the variants in the order they were given. Every variant is validated before any
+    /// of them is created, thus either all of them are created or none is.
+    #[handler]
+    async fn create_ladder(
+        &mut self,
+        variants: Vec<(Vec<Arc<dyn TranscodingProfile>>, ProfileContext)>,
+    ) -> Result<Vec<CreateResult>> {
+        let prepared = variants
+            .into_iter()
+            .map(|(profile_chain, profile_args)| self.prepare(profile_chain, profile_args))
+            .collect::<Result<Vec<_>>>()?;
+
+        // `prepare` might have adjusted the segment duration to the source keyframes.
+        let target_gop = |x: &PreparedSession| x.profile_args.output_ctx.target_gop;
+        if let Some(first) = prepared.first() {
+            if prepared.iter().any(|x| target_gop(x) != target_gop(first)) {
+                return Err(NightfallError::InvalidProfileContext(
+                    "Variants of a ladder must share the same segment duration.".into(),
+                ));
+            }
+        }
+
+        // `prepare` only checks each variant against the sessions that already exist.
+        if let Some(limit) = self.max_sessions_per_owner {
+            for owner in prepared
+                .iter()
+                .filter_map(|x| x.profile_args.owner.as_ref())
+            {
+                let new = prepared
+                    .iter()
+                    .filter(|x| x.profile_args.owner.as_ref() == Some(owner))
+                    .count();
+
+                if self.owner_session_ids(owner).len() + new > limit {
+                    return Err(NightfallError::OwnerSessionLimit(limit));
+                }
+            }
+        }
+
+        let created = prepared
+            .into_iter()
+            .map(|x| self.register(x))
+            .collect::<Vec<_>>();
+
+        let ladder = Arc::new(LadderState::default());
+        for x in created.iter() {
+            if let Some(session) = self.sessions.get_mut(&x.session_id) {
+                session.ladder = Some(ladder.clone());
+            }
+        }
+
+        Ok(created)
+    }
+
+    /// Returns the last chunk requested from any variant of the ladder session `id` belongs to,
+    /// `None` if the session isnt part of a ladder.
+    #[handler]
+    async fn ladder_position(&self, id: String) -> Result<Option<u32>> {
+        let session = self
+            .sessions
+            .get(&id)
+            .ok_or(NightfallError::SessionDoesntExist)?;
+
+        Ok(session
+            .ladder
+            .as_ref()
+            .map(|x| x.position.load(Ordering::Relaxed)))
+    }
+
+    /// Records `chunk` as the position of the ladder session `id` belongs to, and moves the
+    /// variants which havent started yet, `id` included, over to it. Started siblings whose run
+    /// is too far from `chunk` to reach it soon get stopped and moved over as well, so that
+    /// switching back to them doesnt serve them from where the player was before.
+    async fn sync_ladder(&mut self, id: &str, chunk: u32) {
+        let ladder = match self.sessions.get(id).and_then(|x| x.ladder.clone()) {
+            Some(x) => x,
+            None => return,
+        };
+
+        ladder.position.store(chunk, Ordering::Relaxed);
+
+        for (k, session) in self.sessions.iter_mut() {
+            let is_sibling = session
+                .ladder
+                .as_ref()
+                .is_some_and(|x| Arc::ptr_eq(x, &ladder));
+
+            if !is_sibling {
+                continue;
+            }
+
+            // the requested variant hard seeks on its own if it has to, see `serve_chunk`.
+            let is_behind = k != id
+                && session.has_started()
+                && !session.is_chunk_done(chunk)
+                && (chunk < session.start_num()
+                    || chunk > session.current_chunk() + LADDER_RESYNC_DISTANCE);
+
+            if is_behind {
+                debug!(session = %k, chunk, "Moving ladder variant over to the player");
+                session.join().await;
+                session.reset_to(chunk);
+            } else if !session.has_started() && session.start_num() != chunk {
+                session.reset_to(chunk);
+            }
+        }
+    }
+
+    /// Fails with `ChunkOutOfRange` if `chunk` lies past the end of the input of the session
+    /// `id`. Players tend to overshoot by a chunk near the end of the stream, seeking past the end
+    /// would only restart ffmpeg for nothing.
+    fn check_range(&self, id: &str, chunk: u32) -> Result<()> {
+        let session = self
+            .sessions
+            .get(id)
+            .ok_or(NightfallError::SessionDoesntExist)?;
+
+        if session.chunk_count().is_some_and(|count| chunk >= count) {
+            return Err(NightfallError::ChunkOutOfRange(chunk));
+        }
+
+        Ok(())
+    }
+
+    /// Returns whether the session `id` may start ffmpeg. When `max_running` processes are
+    /// already running the session gets queued and `Queued` is returned, queued sessions are
+    /// started in order by `garbage_collect` as slots free up. Foreground sessions are queued
+    /// ahead of background ones and take the slot of a running background session if there is
+    /// one. Preempted sessions are resumed once admitted, as a client requesting their chunks
+    /// means they arent just working in the background anymore.
+    fn admit(&mut self, id: &str) -> Result<()> {
+        let session = self
+            .sessions
+            .get(id)
+            .ok_or(NightfallError::SessionDoesntExist)?;
+        let priority = session.priority();
+
+        let limit = match self.max_running {
+            // resuming a preempted session takes up a slot again.
+            Some(x) if !session.has_started() || session.is_preempted => x,
+            _ => {
+                self.resume_preempted(id);
+                return Ok(());
+            }
+        };
+
+        if priority == Priority::Foreground && self.running_sessions() >= limit {
+            self.preempt_background();
+        }
+
+        let slots = limit.saturating_sub(self.running_sessions());
+        let queued = self.admission_queue.iter().position(|x| x == id);
+        let position = queued.unwrap_or_else(|| {
+            self.admission_queue
+                .iter()
+                .position(|x| {
+                    self.sessions
+                        .get(x)
+                        .is_some_and(|x| x.priority() < priority)
+                })
+                .unwrap_or(self.admission_queue.len())
+        });
+
+        // sessions queued earlier get the free slots first.
+        if position < slots {
+            if queued.is_some() {
+                self.admission_queue.remove(position);
+            }
+
+            self.resume_preempted(id);
+
+            return Ok(());
+        }
+
+        if queued.is_none() {
+            self.admission_queue.insert(position, id.to_string());
+        }
+
+        Err(NightfallError::Queued { position })
+    }
+
+    /// Returns how many sessions have an ffmpeg process which is actually encoding, ie. which
+    /// isnt preempted, paused because it is far enough ahead of its player, or idle.
+    fn running_sessions(&self) -> usize {
+        self.sessions
+            .values()
+            .filter(|x| x.has_started() && !x.is_dead() && !x.is_preempted && !x.is_throttled)
+            .filter(|x| !x.is_idle(&x.gc_policy.unwrap_or(self.gc_policy)))
+            .count()
+    }
+
+    /// Returns whether the cpu load is above `max_load`.
+    fn is_overloaded(&self) -> bool {
+        self.max_load
+            .is_some_and(|max| utils::cpu_load().is_some_and(|x| x > max))
+    }
+
+    /// Pauses the running background session which is the furthest ahead of its player. Returns
+    /// whether a session got paused.
+    fn preempt_background(&mut self) -> bool {
+        let session = self
+            .sessions
+            .values_mut()
+            .filter(|x| x.priority() == Priority::Background)
+            .filter(|x| x.has_started() && !x.is_dead() && !x.is_preempted)
+            .max_by_key(|x| x.buffered_chunks());
+
+        if let Some(session) = session {
+            info!(session = %session.id, "Preempting background session");
+            session.preempt();
+
+            let id = session.id.clone();
+            self.emit(SessionEvent::Paused { id });
+
+            return true;
+        }
+
+        false
+    }
+
+    /// Resumes the session `id` if it got preempted, only `admit` should call this.
+    fn resume_preempted(&mut self, id: &str) {
+        if let Some(session) = self.sessions.get_mut(id).filter(|x| x.is_preempted) {
+            info!(session = %id, "Resuming preempted session on request");
+            session.resume();
+        }
+    }
+
+    /// Returns the span of the session `id`, requests for a session which doesnt exist get logged
+    /// outside of any session.
+    fn session_span(&self, id: &str) -> Span {
+        self.sessions
+            .get(id)
+            .map(|x| x.span.clone())
+            .unwrap_or_else(Span::none)
+    }
+
+    /// Publishes `event` to the subscribers, if there are any.
+    fn emit(&self, event: SessionEvent) {
+        let _ = self.events.send(event);
+    }
+
+    /// Returns a receiver of the lifecycle events of every session, so that callers can push
+    /// updates to clients instead of polling. A receiver lagging more than `EVENT_CAPACITY`
+    /// events behind misses the oldest ones.
+    #[handler]
+    async fn subscribe(&self) -> Result<broadcast::Receiver<SessionEvent>> {
+        Ok(self.events.subscribe())
+    }
+
+    /// Starts queued sessions, in order, for as long as there are free slots. Background sessions
+    /// which got preempted are resumed once the queued foreground sessions are running and the
+    /// cpu isnt overloaded.
+    async fn start_queued(&mut self) {
+        let sessions = &self.sessions;
+        self.admission_queue.retain(|x| {
+            sessions
+                .get(x)
+                .is_some_and(|x| !x.has_started() || x.is_preempted)
+        });
+
+        let limit = self.max_running.unwrap_or(usize::MAX);
+
+        while self.running_sessions() < limit {
+            let id = match self.admission_queue.front() {
+                Some(x) if self.sessions[x].priority() == Priority::Foreground => x.clone(),
+                _ if self.sessions.values().any(|x| x.is_preempted) => break,
+                Some(x) => x.clone(),
+                None => break,
+            };
+
+            self.admission_queue.pop_front();
+
+            if let Some(session) = self.sessions.get_mut(&id) {
+                if session.is_preempted {
+                    info!(session = %id, "Resuming queued session");
+                    session.resume();
+                } else {
+                    info!(session = %id, "Starting queued session");
+                    let _ = session.start().await;
+                }
+            }
+        }
+
+        if self.running_sessions() < limit && !self.is_overloaded() {
+            // resumed one at a time so that the load average can catch up.
+            if let Some(session) = self.sessions.values_mut().find(|x| x.is_preempted) {
+                info!(session = %session.id, "Resuming preempted session");
+                session.resume();
+            }
+        }
+    }
+
+    /// Limits how many ffmpeg processes encode at once, sessions which would start past the limit
+    /// fail with `Queued` and get started automatically once a slot frees up. Paused and idle
+    /// sessions dont count against the limit. `None` lifts the limit.
+    #[handler]
+    async fn set_max_running(&mut self, max_running: Option<usize>) -> Result<()> {
+        if max_running == Some(0) {
+            return Err(NightfallError::InvalidConfig(
+                "At least one session must be allowed to run.".into(),
+            ));
+        }
+
+        self.max_running = max_running;
+        self.start_queued().await;
+
+        Ok(())
+    }
+
+    /// Pauses background sessions, one per `garbage_collect`, while the 1 minute load average
+    /// per cpu is above `max_load`. `None` disables the check.
+    #[handler]
+    async fn set_max_load(&mut self, max_load: Option<f64>) -> Result<()> {
+        self.max_load = max_load;
+
+        Ok(())
+    }
+
+    /// Returns the position of `id` in the admission queue, or `None` if it isnt queued.
+    #[handler]
+    async fn queue_position(&self, id: String) -> Result<Option<usize>> {
+        Ok(self.admission_queue.iter().position(|x| *x == id))
+    }
+
+    #[handler]
+    async fn hls_playlist_request(&mut self, id: String, _chunk: u32) -> Result<String> {
+        let session = self
+            .sessions
+            .get_mut(&id)
+            .ok_or(NightfallError::SessionDoesntExist)?;
+
+        let path = format!("{}/playlist.m3u8", session.profile_ctx.output_ctx.outdir);
+        if Path::new(&path).exists() {
+            return Ok(path);
+        }
+
+        Err(NightfallError::ChunkNotDone)
+    }
+
+    #[handler]
+    async fn chunk_init_request(&mut self, id: String, chunk: u32) -> Result<String> {
+        let span = info_span!(parent: &self.session_span(&id), "chunk_init_request", chunk);
+        self.serve_init(id, chunk).instrument(span).await
+    }
+
+    async fn serve_init(&mut self, id: String, chunk: u32) -> Result<String> {
+        self.check_range(&id, chunk)?;
+        self.admit(&id)?;
+
+        self.sync_ladder(&id, chunk).await;
+
+        // If ffmpeg abrupty closes we want to move down the profile chain and try other profiles
+        // until we get something that works or we exhaust all our profiles.
+        let fallback = self
+            .sessions
+            .get_mut(&id)
+            .ok_or(NightfallError::SessionDoesntExist)?
+            .fall_back()?;
+
+        if let Some((from, to)) = fallback {
+            info!("Session {} chunk={} trying profile {}", &id, chunk, to);
+            self.counters.profile_fallbacks += 1;
+
+            self.emit(SessionEvent::ProfileFallback {
+                id: id.clone(),
+                from,
+                to,
+            });
+        }
+
+        let session = self
+            .sessions
+            .get_mut(&id)
+            .ok_or(NightfallError::SessionDoesntExist)?;
+
+        if !session.is_chunk_done(chunk) {
+            if session.start_num() != chunk {
+                session.join().await;
+                session.reset_to(chunk);
+                let _ = session.start().await;
+
+                let stat = self.stream_stats.entry(id).or_default();
+                stat.record_hard_seek(chunk);
+                self.counters.hard_seeks += 1;
+            }
+
+            session.cont();
+        }
+
+        if !session.has_started() {
+            let _ = session.start().await;
+        }
+
+        if session.is_chunk_done(chunk) {
+            // reset chunk since init counter
+            session.chunks_since_init = 0;
+            let init = session.resolve_init_seg(chunk);
+
+            return match session.profile_ctx.output_ctx.cenc.clone() {
+                Some(config) => protect_init_segment(init, config).await.map_err(|e| {
+                    warn!(error = %e, chunk, "Failed to protect init segment.");
+                    e
+                }),
+                _ => Ok(init),
+            };
+        }
+
+        Err(NightfallError::ChunkNotDone)
+    }
+
+    /// Starts a session and returns a future which resolves once its first `n` chunks are ready,
+    /// so that playback can start without stalling. The session is started through
+    /// `chunk_init_request`, thus seeks happen just like when a player requests it. The future
+    /// fails with `WarmupTimedOut` if ffmpeg cant produce the chunks within `timeout`, and with
+    /// `WarmupFailed` if ffmpeg fails, in which case requesting the init segment again moves down
+    /// the profile chain.
+    ///
+    /// Chunks are awaited through `SessionEvent::ChunkReady`, thus the future only resolves as
+    /// often as `garbage_collect` runs.
+    #[handler]
+    async fn warmup_chunks(&mut self, id: String, n: u32, timeout: Duration) -> Result<Warmup> {
+        // subscribe first so that no chunk becomes ready unnoticed.
+        let events = self.events.subscribe();
+
+        match self.chunk_init_request(id.clone(), 0).await {
+            Ok(_) | Err(NightfallError::ChunkNotDone) => {}
+            Err(e) => return Err(e),
+        }
+
+        let session = self
+            .sessions
+            .get(&id)
+            .ok_or(NightfallError::SessionDoesntExist)?;
+
+        // the input may be shorter than `n` chunks.
+        let n = session.chunk_count().map_or(n, |x| x.min(n));
+        let pending = (0..n)
+            .filter(|x| !session.is_chunk_done(*x))
+            .map(|x| (x, session.chunk_to_path(x)))
+            .collect::<BTreeMap<_, _>>();
+
+        Ok(Warmup {
+            id,
+            events,
+            completion: session.completion().rx,
+            ready: n - pending.len() as u32,
+            pending,
+            deadline: Instant::now() + timeout,
+        })
+    }
+
+    /// Serves `chunk` of a direct play session without hard seeking. Chunks the main process
+    /// hasnt produced yet are transmuxed by a short lived preview process, which keeps serving
+    /// the seek target while the main process catches up.
+    #[handler]
+    async fn direct_play_seek(&mut self, id: String, chunk: u32) -> Result<String> {
+        self.admit(&id)?;
+        self.check_range(&id, chunk)?;
+
+        let session = self
+            .sessions
+            .get_mut(&id)
+            .ok_or(NightfallError::SessionDoesntExist)?;
+
+        if session.profile.profile_type() != ProfileType::Transmux
+            || session.profile.container() != Container::Fmp4
+        {
+            return Err(NightfallError::ProfileNotSupported(
+                "Session isnt a direct play session.".into(),
+            ));
+        }
+
+        if !session.has_started() {
+            let _ = session.start().await;
+        }
+
+        if session.is_chunk_done(chunk) {
+            return self.chunk_request(id, chunk).await;
+        }
+
+        let path = session.preview_chunk_to_path(chunk);
+
+        if session.preview_covers(chunk) {
+            if !Path::new(&path).is_file() {
+                return Err(NightfallError::ChunkNotDone);
+            }
+
+            if let Err(e) = session.patch_preview_chunk(chunk).await {
+                warn!(error = %e, "Failed to patch preview segment.");
+                self.counters.patch_failures += 1;
+            }
+
+            session.reset_timeout(chunk);
+
+            return session.protected_chunk(path, chunk, true).await;
+        }
+
+        session
+            .spawn_preview(chunk)
+            .map_err(|_| NightfallError::Aborted)?;
+
+        Err(NightfallError::ChunkNotDone)
+    }
+
+    /// Same as `chunk_request` except that instead of failing with `ChunkNotDone` it returns the
+    /// estimated time until the chunk will be ready, so that callers can schedule their retry.
+    #[handler]
+    async fn chunk_outcome(&mut self, id: String, chunk: u32) -> Result<ChunkOutcome> {
+        match self.chunk_request(id.clone(), chunk).await {
+            Ok(path) => Ok(ChunkOutcome::Ready(path)),
+            Err(NightfallError::ChunkNotDone) => {
+                let session = self
+                    .sessions
+                    .get(&id)
+                    .ok_or(NightfallError::SessionDoesntExist)?;
+
+                Ok(ChunkOutcome::Pending {
+                    eta: session.eta_for(chunk),
+                })
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Patches every segment a session has written so far again, without restarting ffmpeg. Used
+    /// to heal sessions whose segments ended up with out of order sequence numbers or decode
+    /// times. Returns how many segments got patched.
+    #[handler]
+    async fn repatch(&mut self, id: String) -> Result<usize> {
+        let session = self
+            .sessions
+            .get_mut(&id)
+            .ok_or(NightfallError::SessionDoesntExist)?;
+
+        if session.profile.container() != Container::Fmp4 {
+            return Err(NightfallError::ProfileNotSupported(
+                "Only fMP4 segments can be repatched.".into(),
+            ));
+        }
+
+        Ok(session.repatch().await)
+    }
+
+    /// Returns the bytes of the init segment of the current run of a session, ex. to embed it
+    /// in a manifest. The init segment is served through `chunk_init_request` at the chunk the
+    /// run started at, thus it is the same one players get handed.
+    #[handler]
+    async fn init_segment_bytes(&mut self, id: String) -> Result<Vec<u8>> {
+        let chunk = self
+            .sessions
+            .get(&id)
+            .ok_or(NightfallError::SessionDoesntExist)?
+            .start_num();
+
+        let path = self.serve_init(id.clone(), chunk).await?;
+
+        if let Some(session) = self.sessions.get_mut(&id) {
+            session.reset_timeout(chunk);
+        }
+
+        Ok(tokio::fs::read(path).await?)
+    }
+
+    #[handler]
+    async fn chunk_request(&mut self, id: String, chunk: u32) -> Result<String> {
+        let span = info_span!(parent: &self.session_span(&id), "chunk_request", chunk);
+        self.serve_chunk(id, chunk).instrument(span).await
+    }
+
+    async fn serve_chunk(&mut self, id: String, chunk: u32) -> Result<String> {
+        self.check_range(&id, chunk)?;
+        self.admit(&id)?;
+
+        self.sync_ladder(&id, chunk).await;
+
+        let session = self
+            .sessions
+            .get_mut(&id)
+            .ok_or(NightfallError::SessionDoesntExist)?;
+        let stats = self.stream_stats.entry(id.clone()).or_default();
+
+        if !session.has_started() {
+            let _ = session.start().await;
+        }
+
+        if !session.is_chunk_done(chunk) {
+            let should_hard_seek =
+                session.should_hard_seek(chunk, stats.last_hard_seek, stats.hard_seeked_at);
+
+            session.cont();
+
+            if should_hard_seek {
+                session.join().await;
+                session.reset_to(chunk);
+                let _ = session.start().await;
+
+                stats.record_hard_seek(chunk);
+                self.counters.hard_seeks += 1;
+
+                debug!("Resetting {} to chunk {} because user seeked.", &id, chunk);
+            }
+
+            Err(NightfallError::ChunkNotDone)
+        } else {
+            let chunk_path = session.chunk_to_path(chunk);
+            let path = chunk_path.clone();
+            let real_segment = session.fragment_seq(chunk);
+
+            // hint that we should probably unpause ffmpeg for a bit
+            if chunk + self.pacing.low_watermark >= session.current_chunk() {
+                session.cont();
+            }
+
+            // the on-disk playlist is patching the chunk, it can be handed out once done.
+            if session.is_patching(chunk) {
+                return Err(NightfallError::ChunkNotDone);
+            }
+
+            // chunks are only patched once, so that handing them out again serves the same file.
+            let patched = if session.patched_chunks.contains(&chunk) {
+                true
+            } else if session.profile.container() == Container::WebM {
+                // WebM chunks dont carry any sequence numbers, they only need their clusters
+                // shifted onto the timeline.
+                let scale = header_timestamp_scale(session.init_seg())
+                    .unwrap_or(DEFAULT_TIMESTAMP_SCALE)
+                    .max(1);
+                let start = chunk as u64 * session.chunk_size as u64 * 1_000_000_000 / scale;
+
+                match patch_webm_chunk(path, start).await {
+                    Ok(()) => true,
+                    Err(e) => {
+                        warn!(error = %e, "Failed to patch chunk.");
+                        self.counters.patch_failures += 1;
+                        false
+                    }
+                }
+            } else if session.profile.container() == Container::MpegTs {
+                session.refresh_segment_times();
+
+                let start = TS_START_OFFSET
+                    + (session.chunk_start(chunk) * TS_TIMESCALE as f64).round() as u64;
+                // counters only carry over when the chunk follows the one patched last.
+                let continuity = session
+                    .ts_continuity
+                    .take()
+                    .filter(|(last, _)| last + 1 == chunk)
+                    .map(|(_, x)| x);
+
+                match patch_ts_chunk(path, start, continuity).await {
+                    Ok(continuity) => {
+                        session.ts_continuity = Some((chunk, continuity));
+                        true
+                    }
+                    Err(e) => {
+                        warn!(error = %e, "Failed to patch chunk.");
+                        self.counters.patch_failures += 1;
+                        false
+                    }
+                }
+            } else {
+                match patch_segment(path, real_segment, session.target_timescale()).await {
+                    Ok(seq) => {
+                        session.real_segment = seq;
+                        true
+                    }
+                    // Sometimes we get partial chunks, when playback goes linearly (no hard seeks have
+                    // occured) we can ignore this, but when the user seeks, the player doesnt query
+                    // `init.mp4` again, so we have to move the video data from `init.mp4` into
+                    // `N.m4s`.
+                    Err(NightfallError::PartialSegment(_)) => {
+                        if session.chunks_since_init >= 1 {
+                            debug!("Got a partial segment, patching because the user has most likely seeked.");
+
+                            match patch_init_segment(
+                                session.init_seg(),
+                                chunk_path.clone(),
+                                real_segment,
+                                session.target_timescale(),
+                            )
+                            .await
+                            {
+                                Ok(seq) => {
+                                    session.real_segment = seq;
+                                    true
+                                }
+                                Err(e) => {
+                                    warn!(
+                                        error = %e,
+                                        "Failed to patch init segment."
+                                    );
+                                    self.counters.patch_failures += 1;
+                                    false
+                                }
+                            }
+                        } else {
+                            false
+                        }
+                    }
+                    Err(e) => {
+                        warn!(error = %e, "Failed to patch segment.");
+                        self.counters.patch_failures += 1;
+                        false
+                    }
+                }
+            };
+
+            if patched {
+                session.patched_chunks.insert(chunk);
+            }
+
+            if let Ok(meta) = std::fs::metadata(&chunk_path) {
+                self.counters.bytes_served += meta.len();
+            }
+
+            self.counters.chunks_served += 1;
+
+            session.reset_timeout(chunk);
+            session.chunks_since_init += 1;
+
+            let is_video = session.profile.stream_type() == StreamType::Video;
+            let chunk_path = session
+                .protected_chunk(chunk_path, chunk, is_video)
+                .await
+                .map_err(|e| {
+                    warn!(error = %e, chunk, "Failed to protect chunk.");
+                    e
+                })?;
+
+            // the hook only fires once per chunk, and only for chunks patched successfully.
+            let hook = session
+                .on_segment
+                .clone()
+                .filter(|_| patched && session.hooked_chunks.insert(chunk));
+
+            if let Some(hook) = hook {
+                let path = chunk_path.clone();
+
+                // the hook might do blocking io, thus we run it on the blocking pool and only
+                // log if it panics.
+                tokio::spawn(async move {
+                    let result =
+                        tokio::task::spawn_blocking(move || hook(chunk, Path::new(&path))).await;
+
+                    if let Err(e) = result {
+                        warn!(error = %e, chunk, "Segment hook failed.");
+                    }
+                });
+            }
+
+            Ok(chunk_path)
+        }
+    }
+
+    /// Returns the AES-128 key the chunks of the session are encrypted with, or `None` if the
+    /// session doesnt encrypt its chunks, see `OutputCtx::encrypt`. Servers hand it out on the
+    /// `URI` of the `EXT-X-KEY` tag, see `SegmentKey::ext_x_key`.
+    #[handler]
+    async fn encryption_key(&mut self, id: String) -> Result<Option<SegmentKey>> {
+        let session = self
+            .sessions
+            .get(&id)
+            .ok_or(NightfallError::SessionDoesntExist)?;
+
+        Ok(session.encryption)
+    }
+
+    /// Supplies the Common Encryption content key of a session again, see `OutputCtx::cenc`. Keys
+    /// arent persisted, thus sessions brought back by `restore_sessions` cant hand out protected
+    /// segments until this is called.
+    #[handler]
+    async fn set_cenc_key(&mut self, id: String, key: [u8; 16]) -> Result<()> {
+        let session = self
+            .sessions
+            .get_mut(&id)
+            .ok_or(NightfallError::SessionDoesntExist)?;
+
+        match session.profile_ctx.output_ctx.cenc.as_mut() {
+            Some(cenc) => {
+                cenc.key = Some(key);
+                Ok(())
+            }
+            None => Err(NightfallError::InvalidProfileContext(
+                "Session doesnt use Common Encryption.".into(),
+            )),
+        }
+    }
+
+    /// Sets a hook invoked with the index and path of every chunk of the session once it has been
+    /// patched, ex. to push it to an origin store. The hook fires the first time a chunk is handed
+    /// out, and again only once ffmpeg rewrote the chunk after a seek. Chunks which failed to patch
+    /// dont fire it. The hook runs in the background and cant fail the chunk request.
+    #[handler]
+    async fn set_on_segment(&mut self, id: String, hook: Option<Arc<SegmentHook>>) -> Result<()> {
+        let session = self
+            .sessions
+            .get_mut(&id)
+            .ok_or(NightfallError::SessionDoesntExist)?;
+
+        session.on_segment = hook;
+        Ok(())
+    }
+
+    /// Restart the session at `chunk` while trying to keep the current init segment valid, so
+    /// that clients dont have to fetch a new one after seeking.
+    #[handler]
+    async fn reset_preserving_init(&mut self, id: String, chunk: u32) -> Result<()> {
+        let session = self
+            .sessions
+            .get_mut(&id)
+            .ok_or(NightfallError::SessionDoesntExist)?;
+
+        session.reset_preserving_init(chunk).await;
+        session.start().await.map_err(|_| NightfallError::Aborted)
+    }
+
+    /// Change the bitrate of a running session. Encoders cant change the bitrate mid stream
+    /// cleanly, thus the session is restarted right after the last requested chunk while
+    /// preserving the init segment, see `reset_preserving_init`. Returns the first chunk encoded
+    /// at the new bitrate.
+    ///
+    /// Clients can keep using the init segment they have as long as ffmpeg writes an identical
+    /// one, which is usually the case when only the bitrate changes. Otherwise
+    /// `chunk_init_request` for the returned chunk hands out a new init segment, which clients
+    /// must fetch before playing that chunk.
+    #[handler]
+    async fn set_bitrate(&mut self, id: String, bitrate: u64) -> Result<u32> {
+        let session = self
+            .sessions
+            .get_mut(&id)
+            .ok_or(NightfallError::SessionDoesntExist)?;
+
+        if session.profile.profile_type() == ProfileType::Transmux {
+            return Err(NightfallError::ProfileNotSupported(
+                "Transmuxed sessions cant change their bitrate.".into(),
+            ));
+        }
+
+        let unchanged = session.profile_ctx.output_ctx.bitrate == Some(bitrate);
+        session.profile_ctx.output_ctx.bitrate = Some(bitrate);
+
+        if unchanged || !session.has_started() {
+            return Ok(session.start_num());
+        }
+
+        let chunk = session.last_chunk() + 1;
+        session.reset_preserving_init(chunk).await;
+        session.start().await.map_err(|_| NightfallError::Aborted)?;
+
+        Ok(chunk)
+    }
+
+    /// Moves the artifacts of a session into `outdir`, ex. to free up space on the disk it was
+    /// created on. The files are moved in the background, the returned future resolves once they
+    /// are. A running ffmpeg process is paused meanwhile and keeps writing through a link left at
+    /// the old location afterwards, so that playback continues uninterrupted. Chunk paths and the
+    /// on-disk playlist point into the new location once the move is done.
+    #[handler]
+    async fn relocate(&mut self, id: String, outdir: String) -> Result<Relocation> {
+        let session = self
+            .sessions
+            .get_mut(&id)
+            .ok_or(NightfallError::SessionDoesntExist)?;
+
+        if session.profile_ctx.output_ctx.single_file && !session.is_dead() {
+            return Err(NightfallError::ProfileNotSupported(
+                "Single file sessions can only be relocated once finished.".into(),
+            ));
+        }
+
+        if session.is_relocating() {
+            return Err(NightfallError::InvalidConfig(
+                "Session is already being relocated.".into(),
+            ));
+        }
+
+        let is_occupied = std::fs::read_dir(&outdir).is_ok_and(|mut x| x.next().is_some());
+        if is_occupied {
+            return Err(NightfallError::InvalidConfig(format!(
+                "Outdir {} already exists and is not empty.",
+                outdir
+            )));
+        }
+
+        debug!(session = %id, %outdir, "Relocating session");
+
+        Ok(Relocation {
+            rx: session.relocate(outdir),
+        })
+    }
+
+    #[handler]
+    async fn burned_subtitle(&self, id: String) -> Result<Option<usize>> {
+        let session = self
+            .sessions
+            .get(&id)
+            .ok_or(NightfallError::SessionDoesntExist)?;
+        Ok(session.profile_ctx.output_ctx.burn_subtitle)
+    }
+
+    /// Change the subtitle burned into the video. As the subtitle is baked into the encode, this
+    /// discards all segments and restarts the session at the last requested chunk, whose number
+    /// gets returned. Clients must re-fetch the init segment for that chunk.
+    #[handler]
+    async fn set_burned_subtitle(&mut self, id: String, subtitle: Option<usize>) -> Result<u32> {
+        let session = self
+            .sessions
+            .get_mut(&id)
+            .ok_or(NightfallError::SessionDoesntExist)?;
+
+        if let Some(subtitle) = subtitle {
+            let streams = &session.profile_ctx.input_ctx.subtitle_streams;
+
+            if subtitle >= streams.len() {
+                return Err(NightfallError::ProfileNotSupported(format!(
+                    "Subtitle stream {} doesnt exist, input has {} subtitle streams.",
+                    subtitle,
+                    streams.len()
+                )));
+            }
+        }
+
+        let mut profile_ctx = session.profile_ctx.clone();
+        profile_ctx.output_ctx.burn_subtitle = subtitle;
+
+        // burning in requires a different profile, which would warrant a new session.
+        if !session.profile.burns_in() || session.profile.supports(&profile_ctx).is_err() {
+            return Err(NightfallError::ProfileNotSupported(
+                "Session profile cant change the burned in subtitle.".into(),
+            ));
+        }
+
+        let chunk = session.last_chunk();
+
+        session.profile_ctx = profile_ctx;
+        session.reset_discarding_segments(chunk).await;
+        session.start().await.map_err(|_| NightfallError::Aborted)?;
+
+        info!(session = %id, ?subtitle, chunk, "Changed burned in subtitle");
+
+        Ok(chunk)
+    }
+
+    /// Returns the path of `chunk` for one of the audio renditions of a session created with
+    /// several `audio_renditions`. Seeking is driven by `chunk_request` which serves the first
+    /// rendition.
+    #[handler]
+    async fn rendition_chunk_request(
+        &mut self,
+        id: String,
+        rendition: String,
+        chunk: u32,
+    ) -> Result<String> {
+        self.check_range(&id, chunk)?;
+        self.admit(&id)?;
+
+        let session = self
+            .sessions
+            .get_mut(&id)
+            .ok_or(NightfallError::SessionDoesntExist)?;
+
+        if !session
+            .profile_ctx
+            .output_ctx
+            .audio_renditions
+            .contains(&rendition)
+        {
+            return Err(NightfallError::ProfileNotSupported(format!(
+                "Session has no rendition {}",
+                rendition
+            )));
+        }
+
+        let path = session.rendition_chunk_to_path(&rendition, chunk);
+        if !Path::new(&path).is_file() {
+            return Err(NightfallError::ChunkNotDone);
+        }
+
+        if let Err(e) = session.patch_rendition_chunk(&rendition, chunk).await {
+            warn!(error = %e, "Failed to patch segment.");
+            self.counters.patch_failures += 1;
+        }
+
+        session.reset_timeout(chunk);
+
+        session.protected_chunk(path, chunk, false).await
+    }
+
+    /// Returns the sorted indices of the chunks of a session which are fully written to disk.
+    #[handler]
+    async fn available_chunks(&self, id: String) -> Result<Vec<u32>> {
+        let session = self
+            .sessions
+            .get(&id)
+            .ok_or(NightfallError::SessionDoesntExist)?;
+        Ok(session.available_chunks())
+    }
+
+    /// Returns an EVENT style HLS media playlist listing the chunks of a session which are done
+    /// right now. Segments are named `N.m4s` (or `N.webm`) and map to `chunk_request(id, N)`, init
+    /// segments are named `N_init.mp4` and map to `chunk_init_request(id, N)`. The playlist gets
+    /// ended once ffmpeg finished transcoding the whole input. Encrypted sessions reference their
+    /// key as `key`, which maps to `encryption_key(id)`.
+    #[handler]
+    async fn playlist(&mut self, id: String) -> Result<String> {
+        let session = self
+            .sessions
+            .get_mut(&id)
+            .ok_or(NightfallError::SessionDoesntExist)?;
+
+        session.refresh_segment_times();
+
+        Ok(session.event_playlist())
+    }
+
+    /// Returns a master playlist for several sessions transcoding the same input, ex. different
+    /// bitrates or resolutions of one video stream. Video sessions become variant streams, audio
+    /// and subtitle sessions become renditions shared by all variants.
+    ///
+    /// # Arguments
+    /// * `ids` - sessions to include, renditions are ordered as given and the first of each type
+    ///   is the default one. Renditions are named after the title or language of their input
+    ///   stream.
+    /// * `uri_template` - uri of the media playlist of a session, where `$SessionID$` gets
+    ///   replaced with its id, ex. `/api/stream/$SessionID$/playlist.m3u8`.
+    #[handler]
+    async fn master_playlist(&self, ids: Vec<String>, uri_template: String) -> Result<String> {
+        let mut variants = Vec::new();
+        let mut renditions: Vec<hls::Rendition> = Vec::new();
+
+        for id in ids {
+            let session = self
+                .sessions
+                .get(&id)
+                .ok_or(NightfallError::SessionDoesntExist)?;
+            let output_ctx = &session.profile_ctx.output_ctx;
+            let uri = uri_template.replace(hls::SESSION_ID_TEMPLATE, &id);
+
+            let (media_type, group_id, kind) = match session.profile.stream_type() {
+                StreamType::Video => {
+                    variants.push(hls::Variant {
+                        uri,
+                        bandwidth: session.bandwidth(),
+                        codecs: session.codecs().into_iter().collect(),
+                        width: output_ctx.width,
+                        height: output_ctx.height,
+                        frame_rate: output_ctx
+                            .fps
+                            .map(f64::from)
+                            .or(Some(session.profile_ctx.input_ctx.fps))
+                            .filter(|x| *x > 0.0),
+                    });
+                    continue;
+                }
+                StreamType::Audio => ("AUDIO", "audio", "Audio"),
+                StreamType::Subtitle => ("SUBTITLES", "subs", "Subtitles"),
+                // thumbnails arent streamed, thus have no place in a playlist.
+                StreamType::Thumbnail => continue,
+            };
+
+            let tags = session.stream_tags();
+            let lang = tags.and_then(|x| x.language.clone()).filter(|x| x != "und");
+            let label = tags
+                .and_then(|x| x.title.clone())
+                .or_else(|| lang.clone())
+                .unwrap_or_else(|| kind.to_string());
+
+            // players tell renditions apart by their name, which has to be unique in a group.
+            let mut name = label.clone();
+            for n in 2.. {
+                if !renditions
+                    .iter()
+                    .any(|x| x.group_id == group_id && x.name == name)
+                {
+                    break;
+                }
+
+                name = format!("{} ({})", label, n);
+            }
+
+            renditions.push(hls::Rendition {
+                media_type: media_type.into(),
+                group_id: group_id.into(),
+                name,
+                lang,
+                uri,
+                codecs: session.codecs(),
+                bandwidth: session.bandwidth(),
+                default: !renditions.iter().any(|x| x.media_type == media_type),
+            });
+        }
+
+        if variants.is_empty() {
+            return Err(NightfallError::ProfileNotSupported(
+                "Master playlist requires at least one video session.".into(),
+            ));
+        }
+
+        // players pick the first variant to start playback, list them from the lowest bitrate.
+        variants.sort_by_key(|x| x.bandwidth);
+
+        Ok(hls::build_master_playlist(&variants, &renditions))
+    }
+
+    /// Returns a static MPEG-DASH manifest for several sessions transcoding the same input. Every
+    /// session becomes a representation whose id is the session id, grouped into adaptation sets
+    /// by stream type and container. Trickplay sessions get signalled as trick-mode tracks of the
+    /// video adaptation set.
+    ///
+    /// # Arguments
+    /// * `ids` - sessions to include.
+    /// * `initialization` - template of the init segment url, ex.
+    ///   `/api/stream/$RepresentationID$/init.mp4`, which should map to
+    ///   `chunk_init_request(id, 0)`.
+    /// * `media` - template of the segment urls, ex.
+    ///   `/api/stream/$RepresentationID$/$Number$.m4s`, which should map to
+    ///   `chunk_request(id, $Number$)`.
+    #[handler]
+    async fn mpd(&self, ids: Vec<String>, initialization: String, media: String) -> Result<String> {
+        let mut sets: Vec<dash::AdaptationSet> = Vec::new();
+        let mut trick_sets = Vec::new();
+        let mut duration = None;
+        let mut segment_duration = None;
+
+        for id in ids {
+            let session = self
+                .sessions
+                .get(&id)
+                .ok_or(NightfallError::SessionDoesntExist)?;
+            let output_ctx = &session.profile_ctx.output_ctx;
+            let input_ctx = &session.profile_ctx.input_ctx;
+            let container = session.profile.container();
+
+            // segments of every representation have to line up for players to switch between them.
+            match segment_duration {
+                None => segment_duration = Some(session.chunk_size),
+                Some(x) if x != session.chunk_size => {
+                    return Err(NightfallError::ProfileNotSupported(
+                        "Sessions dont share the same segment duration.".into(),
+                    ))
+                }
+                _ => {}
+            }
+
+            duration = duration.or(input_ctx.duration);
+
+            let content_type = match session.profile.stream_type() {
+                StreamType::Video => "video",
+                StreamType::Audio => "audio",
+                // only subtitles muxed into fMP4 segments can be addressed through a template.
+                StreamType::Subtitle if ["wvtt", "stpp"].contains(&output_ctx.codec.as_str()) => {
+                    "text"
+                }
+                _ => continue,
+            };
+            let mime_type = match content_type {
+                "text" => "application/mp4".to_string(),
+                x => format!("{}/{}", x, container.mime_subtype()),
+            };
+
+            let output_fps = session.profile.output_fps();
+            let frame_rate = output_fps
+                .or(output_ctx.fps.map(f64::from))
+                .or(Some(input_ctx.fps))
+                .filter(|_| content_type == "video")
+                .filter(|x| *x > 0.0);
+
+            let representation = dash::Representation {
+                id: id.clone(),
+                bandwidth: session.bandwidth(),
+                codecs: session.codecs().unwrap_or_default(),
+                width: output_ctx.width,
+                height: output_ctx.height,
+                // frameRate has to be either a integer or a fraction.
+                frame_rate: frame_rate.map(|x| match x.fract() {
+                    0.0 => format!("{}", x),
+                    _ => format!("{}/1000", (x * 1000.0).round()),
+                }),
+                max_playout_rate: output_fps
+                    .filter(|x| *x > 0.0)
+                    .map(|x| (input_ctx.fps / x).round().max(1.0) as u32),
+                initialization: initialization.clone(),
+                media: media.clone(),
+                start_number: 0,
+            };
+
+            let target = if output_ctx.codec == "trickplay" {
+                &mut trick_sets
+            } else {
+                &mut sets
+            };
+
+            match target
+                .iter_mut()
+                .find(|x| x.content_type == content_type && x.mime_type == mime_type)
+            {
+                Some(set) => set.representations.push(representation),
+                None => target.push(dash::AdaptationSet {
+                    content_type: content_type.into(),
+                    mime_type,
+                    representations: vec![representation],
+                    ..Default::default()
+                }),
+            }
+        }
+
+        let main = sets.iter().position(|x| x.content_type == "video");
+        if !trick_sets.is_empty() && main.is_none() {
+            return Err(NightfallError::ProfileNotSupported(
+                "Trick-mode tracks require a video session.".into(),
+            ));
+        }
+
+        for (idx, set) in sets.iter_mut().enumerate() {
+            set.id = idx as u32;
+            // the lowest bitrate is usually picked to start playback.
+            set.representations.sort_by_key(|x| x.bandwidth);
+        }
+
+        let offset = sets.len();
+        for (idx, mut set) in trick_sets.into_iter().enumerate() {
+            set.id = (offset + idx) as u32;
+            set.trick_mode_for = main.map(|x| x as u32);
+            sets.push(set);
+        }
+
+        Ok(dash::build_mpd(
+            duration,
+            segment_duration.unwrap_or_default(),
+            &sets,
+        ))
+    }
+
+    /// Returns a Low-Latency HLS media playlist for a session created with
+    /// `OutputCtx::part_duration`. On top of what `playlist` lists, parts are named `N.M.m4s` and
+    /// map to `part_request(id, N, M)`.
+    #[handler]
+    async fn low_latency_playlist(&mut self, id: String) -> Result<String> {
+        let session = self
+            .sessions
+            .get_mut(&id)
+            .ok_or(NightfallError::SessionDoesntExist)?;
+
+        if session.profile_ctx.output_ctx.part_duration.is_none()
+            || session.profile.container() != Container::Fmp4
+        {
+            return Err(NightfallError::ProfileNotSupported(
+                "Session doesnt write partial segments.".into(),
+            ));
+        }
+
+        session.refresh_segment_times();
+
+        Ok(session.low_latency_playlist())
+    }
+
+    /// Returns the data of part `part` of `chunk`, which can be requested while ffmpeg is still
+    /// writing the chunk. Fails with `ChunkNotDone` if the part hasnt been written yet.
+    #[handler]
+    async fn part_request(&mut self, id: String, chunk: u32, part: u32) -> Result<Vec<u8>> {
+        let span = info_span!(parent: &self.session_span(&id), "part_request", chunk, part);
+        self.serve_part(id, chunk, part).instrument(span).await
+    }
+
+    async fn serve_part(&mut self, id: String, chunk: u32, part: u32) -> Result<Vec<u8>> {
+        self.check_range(&id, chunk)?;
+        self.admit(&id)?;
+
+        let session = self
+            .sessions
+            .get_mut(&id)
+            .ok_or(NightfallError::SessionDoesntExist)?;
+
+        if session.profile_ctx.output_ctx.part_duration.is_none()
+            || session.profile.container() != Container::Fmp4
+        {
+            return Err(NightfallError::ProfileNotSupported(
+                "Session doesnt write partial segments.".into(),
+            ));
+        }
+
+        if !session.has_started() {
+            let _ = session.start().await;
+        }
+
+        session.cont();
+        session.reset_timeout(chunk);
+
+        session
+            .read_part(chunk, part)?
+            .ok_or(NightfallError::ChunkNotDone)
+    }
+
+    /// Returns a future which resolves once `part` of `chunk` has been written, or the whole chunk
+    /// when `part` is `None`. Servers should hold `_HLS_msn`/`_HLS_part` playlist requests until
+    /// it resolves, it gives up after three target durations as the spec suggests.
+    #[handler]
+    async fn await_part(
+        &mut self,
+        id: String,
+        chunk: u32,
+        part: Option<u32>,
+    ) -> Result<PartAvailability> {
+        let session = self
+            .sessions
+            .get_mut(&id)
+            .ok_or(NightfallError::SessionDoesntExist)?;
+
+        if session.profile_ctx.output_ctx.part_duration.is_none() {
+            return Err(NightfallError::ProfileNotSupported(
+                "Session doesnt write partial segments.".into(),
+            ));
+        }
+
+        session.cont();
+
+        Ok(PartAvailability {
+            path: session.chunk_to_path(chunk),
+            part,
+            deadline: Instant::now() + Duration::from_secs(3 * session.chunk_size as u64),
+        })
+    }
+
+    /// Returns the byte-range index of a session created in `single_file` mode. The index can
+    /// only be built once ffmpeg has finished writing the file.
+    #[handler]
+    async fn segment_index(&mut self, id: String) -> Result<SegmentIndex> {
+        self.admit(&id)?;
+
+        let session = self
+            .sessions
+            .get_mut(&id)
+            .ok_or(NightfallError::SessionDoesntExist)?;
+
+        if !session.profile_ctx.output_ctx.single_file {
+            return Err(NightfallError::ProfileNotSupported(
+                "Session doesnt write a single file.".into(),
+            ));
+        }
+
+        if let Some(index) = session.segment_index.as_ref() {
+            return Ok(index.clone());
+        }
+
+        if !session.has_started() {
+            let _ = session.start().await;
+        }
+
+        if !session.is_dead() {
+            return Err(NightfallError::ChunkNotDone);
+        }
+
+        let index = index_single_file(session.profile_ctx.output_ctx.single_file_path()).await?;
+        session.segment_index = Some(index.clone());
+
+        Ok(index)
+    }
+
+    #[handler]
+    async fn chunk_eta(&mut self, id: String, chunk: u32) -> Result<u64> {
+        let session = self
+            .sessions
+            .get_mut(&id)
+            .ok_or(NightfallError::SessionDoesntExist)?;
+        Ok(session.eta_for(chunk).as_secs())
+    }
+
+    #[handler]
+    async fn stream_stats(&mut self, id: String) -> Result<StreamStats> {
+        let session = self
+            .sessions
+            .get(&id)
+            .ok_or(NightfallError::SessionDoesntExist)?;
+        let stats = self.stream_stats.entry(id).or_default();
+
+        Ok(StreamStats {
+            current_chunk: session.current_chunk(),
+            start_num: session.start_num(),
+            raw_speed: session.raw_speed(),
+            smoothed_speed: session.smoothed_speed(),
+            since_last_hard_seek: stats.last_hard_seek.elapsed(),
+            hard_seeks: stats.hard_seeks,
+            chunks_since_init: session.chunks_since_init,
+        })
+    }
+
+    /// Returns the encoding progress of a session, ex. to show "transcoding at 3.4x".
+    #[handler]
+    async fn session_stats(&self, id: String) -> Result<SessionStats> {
+        let session = self
+            .sessions
+            .get(&id)
+            .ok_or(NightfallError::SessionDoesntExist)?;
+
+        Ok(session.stats())
+    }
+
+    #[handler]
+    async fn should_hard_seek(&mut self, id: String, chunk: u32) -> Result<bool> {
+        let session = self
+            .sessions
+            .get(&id)
+            .ok_or(NightfallError::SessionDoesntExist)?;
+
+        let stats = self.stream_stats.entry(id).or_default();
+
+        Ok(session.should_hard_seek(chunk, stats.last_hard_seek, stats.hard_seeked_at))
+    }
+
+    #[handler]
+    async fn die(&mut self, id: String) -> Result<()> {
+        let session = self
+            .sessions
+            .get_mut(&id)
+            .ok_or(NightfallError::SessionDoesntExist)?;
+        info!("Killing session {}", id);
+        session.join().await;
+        session.finish(ExitReason::Killed);
+        session.set_timeout();
+        session.forget_persisted();
+
+        self.admission_queue.retain(|x| *x != id);
+
+        Ok(())
+    }
+
+    /// Makes every started session write the state needed to resume it to its outdir on each
+    /// `garbage_collect`, so that it can be picked back up with `restore_sessions` after the
+    /// host process restarts.
+    #[handler]
+    async fn set_persist_sessions(&mut self, persist: bool) -> Result<()> {
+        self.persist_sessions = persist;
+
+        Ok(())
+    }
+
+    /// Re-adopts the sessions persisted under `outdir` by a previous process, see
+    /// `set_persist_sessions`. Sessions keep their id and outdir and resume at the chunk following
+    /// the last one completed on disk instead of transcoding from zero. Sessions whose profiles
+    /// arent available anymore are skipped.
+    #[handler]
+    async fn restore_sessions(&mut self) -> Result<Vec<CreateResult>> {
+        let profiles = get_active_profiles();
+        let mut restored = Vec::new();
+
+        // `OutdirLayout::Grouped` nests session directories one level deeper.
+        for file in find_persisted(Path::new(&self.outdir), 2) {
+            let state = std::fs::read(&file)
+                .ok()
+                .and_then(|x| serde_json::from_slice::<PersistedSession>(&x).ok());
+
+            let state = match state {
+                Some(x) if !self.sessions.contains_key(&x.id) => x,
+                Some(_) => continue,
+                None => {
+                    warn!(file = ?file, "Failed to read persisted session");
+                    continue;
+                }
+            };
+
+            let chain = state
+                .chain
+                .iter()
+                .rev()
+                .map(|tag| profiles.iter().find(|x| x.tag() == tag).cloned())
+                .collect::<Option<Vec<_>>>();
+
+            let chain = match chain {
+                Some(x) if !x.is_empty() => x,
+                _ => {
+                    warn!(session = %state.id, chain = ?state.chain, "Profiles of persisted session are unavailable");
+                    continue;
+                }
+            };
+
+            let mut profile_ctx = state.profile_ctx;
+            profile_ctx.ffmpeg_bin = self.ffmpeg.clone();
+
+            if let Some(last) = state.last_chunk {
+                profile_ctx.output_ctx.start_num = last + 1;
+            }
+
+            info!(
+                session = %state.id,
+                start = profile_ctx.output_ctx.start_num,
+                "Restored persisted session"
+            );
+
+            // keys arent persisted, players fetch the new one along with the playlist.
+            let encryption = if profile_ctx.output_ctx.encrypt {
+                Some(SegmentKey::generate()?)
+            } else {
+                None
+            };
+
+            if profile_ctx.output_ctx.cenc.is_some() {
+                info!(session = %state.id, "Restored session waits for its content key");
+            }
+
+            let mut session =
+                Session::new(state.id.clone(), chain, profile_ctx, self.spawner.clone());
+            session.encryption = encryption;
+
+            self.emit(SessionEvent::SessionCreated {
+                id: state.id.clone(),
+                profile: session.profile.tag().to_string(),
+            });
+
+            restored.push(CreateResult {
+                session_id: state.id.clone(),
+                active_profile_tag: session.profile.tag().to_string(),
+                is_direct_play: session.profile.profile_type() == ProfileType::Transmux,
+                resolved_chain: state.chain,
+            });
+
+            self.sessions.insert(state.id, session);
+        }
+
+        Ok(restored)
+    }
+
+    /// Returns the ids of the live sessions of `owner`, sorted.
+    fn owner_session_ids(&self, owner: &str) -> Vec<String> {
+        let mut ids = self
+            .sessions
+            .values()
+            .filter(|x| x.profile_ctx.owner.as_deref() == Some(owner))
+            .filter(|x| !x.has_started() || !x.is_dead())
+            .map(|x| x.id.clone())
+            .collect::<Vec<_>>();
+
+        ids.sort();
+        ids
+    }
+
+    /// Returns the ids of the live sessions created with `ProfileContext::owner` set to `owner`.
+    #[handler]
+    async fn owner_sessions(&self, owner: String) -> Result<Vec<String>> {
+        Ok(self.owner_session_ids(&owner))
+    }
+
+    /// Kills every live session of `owner`, ex. when a client disconnects. Returns how many
+    /// sessions got killed.
+    #[handler]
+    async fn die_owner(&mut self, owner: String) -> Result<usize> {
+        let ids = self.owner_session_ids(&owner);
+
+        for id in ids.iter() {
+            self.die(id.clone()).await?;
+        }
+
+        Ok(ids.len())
+    }
+
+    /// Limits how many live sessions a single owner can have, `create` fails with
+    /// `OwnerSessionLimit` past the limit. Sessions without an owner arent limited. `None` lifts
+    /// the limit.
+    #[handler]
+    async fn set_max_sessions_per_owner(&mut self, max_sessions: Option<usize>) -> Result<()> {
+        self.max_sessions_per_owner = max_sessions;
+
+        Ok(())
+    }
+
+    /// Kills ffmpeg, deletes the outdir of the session and forgets about it right away instead of
+    /// waiting for `garbage_collect`. Returns how many bytes got freed on disk.
+    #[handler]
+    async fn destroy(&mut self, id: String) -> Result<u64> {
+        let mut session = self
+            .sessions
+            .remove(&id)
+            .ok_or(NightfallError::SessionDoesntExist)?;
+
+        info!("Destroying session {}", id);
+        session.join().await;
+        session.finish(ExitReason::Killed);
+
+        let reclaimed = session.measure_outdir().await;
+        session.forget_persisted();
+        session.delete_tmp();
+        self.counters.ffmpeg_restarts += session.restarts();
+        self.counters.reaps += 1;
+
+        self.stream_stats.remove(&id);
+        self.exit_statuses.remove(&id);
+        self.admission_queue.retain(|x| *x != id);
+        self.emit(SessionEvent::Reaped { id });
+
+        Ok(reclaimed)
+    }
+
+    /// Returns the ids of the sessions waiting in the admission queue for a free slot, in the
+    /// order they get started in.
+    #[handler]
+    async fn list_queued(&self) -> Result<Vec<String>> {
+        Ok(self.admission_queue.iter().cloned().collect())
+    }
+
+    /// Drop a session which is waiting in the admission queue. Sessions which already got a slot
+    /// have to be killed with `die` instead.
+    #[handler]
+    async fn cancel_queued(&mut self, id: String) -> Result<()> {
+        if !self.sessions.contains_key(&id) {
+            return Err(NightfallError::SessionDoesntExist);
+        }
+
+        if !self.admission_queue.contains(&id) {
+            return Err(NightfallError::SessionAlreadyStarted);
+        }
 
-        let new_session = Session::new(session_id.clone(), profile_chain, profile_args);
+        if let Some(mut session) = self.sessions.remove(&id) {
+            info!("Cancelling queued session {}", id);
+            // preempted sessions still have their paused ffmpeg around.
+            session.join().await;
+            session.finish(ExitReason::Killed);
+            session.forget_persisted();
+            session.delete_tmp();
+        }
 
-        self.sessions.insert(session_id.clone(), new_session);
+        self.stream_stats.remove(&id);
+        self.admission_queue.retain(|x| *x != id);
+        self.emit(SessionEvent::Reaped { id });
 
-        Ok(session_id)
+        Ok(())
     }
 
     #[handler]
-    async fn hls_playlist_request(&mut self, id: String, _chunk: u32) -> Result<String> {
+    async fn die_ignore_gc(&mut self, id: String) -> Result<()> {
         let session = self
             .sessions
             .get_mut(&id)
             .ok_or(NightfallError::SessionDoesntExist)?;
+        info!("Killing session {}", id);
+        session.join().await;
 
-        let path = format!("{}/playlist.m3u8", session.profile_ctx.output_ctx.outdir);
-        if Path::new(&path).exists() {
-            return Ok(path);
-        }
-
-        Err(NightfallError::ChunkNotDone)
+        Ok(())
     }
 
     #[handler]
-    async fn chunk_init_request(&mut self, id: String, chunk: u32) -> Result<String> {
+    async fn get_sub(&mut self, id: String, name: String) -> Result<String> {
+        self.admit(&id)?;
+
         let session = self
             .sessions
             .get_mut(&id)
             .ok_or(NightfallError::SessionDoesntExist)?;
 
-        // If ffmpeg abrupty closes we want to move down the profile chain and try other profiles
-        // until we get something that works or we exhaust all our profiles.
-        if let Some(status) = session.exit_status.take() {
-            if !status.success() {
-                if let Some(x) = session.next_profile() {
-                    info!("Session {} chunk={} trying profile {}", &id, chunk, x);
-                    session.reset_to(session.start_num());
-                } else {
-                    return Err(NightfallError::ProfileChainExhausted);
-                }
-            }
+        if !session.has_started() {
+            let _ = session.start().await;
         }
 
-        if !session.is_chunk_done(chunk) {
-            if session.start_num() != chunk {
-                session.join().await;
-                session.reset_to(chunk);
-                let _ = session.start().await;
+        session.subtitle(name).ok_or(NightfallError::ChunkNotDone)
+    }
 
-                let stat = self.stream_stats.entry(id).or_default();
-                stat.hard_seeked_at = chunk;
-                stat.last_hard_seek = Instant::now();
-            }
+    #[handler]
+    async fn get_thumbnail(&mut self, id: String, name: String) -> Result<String> {
+        self.admit(&id)?;
 
-            session.cont();
-        }
+        let session = self
+            .sessions
+            .get_mut(&id)
+            .ok_or(NightfallError::SessionDoesntExist)?;
 
         if !session.has_started() {
             let _ = session.start().await;
         }
 
-        if session.is_chunk_done(chunk) {
-            // reset chunk since init counter
-            session.chunks_since_init = 0;
-            return Ok(session.custom_init_seg(chunk));
+        session.thumbnail(name).ok_or(NightfallError::ChunkNotDone)
+    }
+
+    /// Creates a session generating the trickplay thumbnails of `profile_args.file` as configured
+    /// by `OutputCtx::thumbnails`. The session runs in the background with a niceness of
+    /// `THUMBNAIL_NICE` unless `ProfileContext::nice` is set, and gets reaped like any other
+    /// session. There is only one job per media file and config, if one exists already it is
+    /// returned instead.
+    #[handler]
+    async fn create_thumbnails(&mut self, profile_args: ProfileContext) -> Result<CreateResult> {
+        let mut profile_args = profile_args;
+
+        profile_args.output_ctx.codec = "jpg".into();
+        profile_args.output_ctx.priority = Priority::Background;
+        profile_args.nice = profile_args.nice.or(Some(THUMBNAIL_NICE));
+
+        // a job that failed or got killed wont ever write its index, thus gets replaced.
+        let existing = self.sessions.values().find(|x| {
+            x.profile.stream_type() == StreamType::Thumbnail
+                && x.profile_ctx.file == profile_args.file
+                && x.profile_ctx.output_ctx.thumbnails == profile_args.output_ctx.thumbnails
+                && !matches!(
+                    x.exit_reason(),
+                    Some(ExitReason::Failed { .. }) | Some(ExitReason::Killed)
+                )
+        });
+
+        if let Some(session) = existing {
+            let tag = session.profile.tag().to_string();
+
+            return Ok(CreateResult {
+                session_id: session.id.clone(),
+                active_profile_tag: tag.clone(),
+                is_direct_play: false,
+                resolved_chain: vec![tag],
+            });
         }
 
-        Err(NightfallError::ChunkNotDone)
+        let profile: Arc<dyn TranscodingProfile> = Arc::new(ThumbnailProfile);
+        profile.supports(&profile_args)?;
+
+        self.create(vec![profile], profile_args).await
     }
 
+    /// Returns the path of the index of the thumbnails of a session created by
+    /// `create_thumbnails`, either the WebVTT index of the sprite sheets or the BIF file. The
+    /// index is written once ffmpeg is done, until then `ChunkNotDone` is returned. The returned
+    /// `Oneshot` resolves to the path once the index is written, which happens off the actor.
     #[handler]
-    async fn chunk_request(&mut self, id: String, chunk: u32) -> Result<String> {
+    async fn thumbnail_index(&mut self, id: String) -> Result<Oneshot> {
+        self.admit(&id)?;
+
         let session = self
             .sessions
             .get_mut(&id)
             .ok_or(NightfallError::SessionDoesntExist)?;
-        let stats = self.stream_stats.entry(id.clone()).or_default();
+
+        if session.profile.stream_type() != StreamType::Thumbnail {
+            return Err(NightfallError::InvalidProfileContext(
+                "Session doesnt generate thumbnails.".into(),
+            ));
+        }
 
         if !session.has_started() {
             let _ = session.start().await;
         }
 
-        if !session.is_chunk_done(chunk) {
-            let eta = session.eta_for(chunk).as_millis() as f64;
-            let eta_tol = (10_000.0 / session.raw_speed()).max(8_000.0);
-
-            // FIXME: When we hard seek and start a new ffmpeg session for some reason ffmpeg
-            // reports invalid speed but then evens out. The problem is that causes seeking
-            // multiple times in a row to be very slow.
-            // thus for like the first 10s after a hard seek we exclusively hard seek if the
-            // target is over 10 chunks into the future.
-            let should_hard_seek = chunk < session.start_num()
-                || (chunk > session.current_chunk() + 15
-                    && Instant::now() < stats.last_hard_seek + Duration::from_secs(15)
-                    && chunk > stats.hard_seeked_at)
-                || eta > eta_tol;
+        let config = session.profile_ctx.output_ctx.thumbnails.clone();
 
-            session.cont();
+        if let Some(path) = session.thumbnail(config.index_file().into()) {
+            return Ok(Oneshot::spawn(async move { Ok(path) }));
+        }
 
-            if should_hard_seek {
-                session.join().await;
-                session.reset_to(chunk);
-                let _ = session.start().await;
+        session.try_wait();
 
-                stats.last_hard_seek = Instant::now();
-                stats.hard_seeked_at = chunk;
+        match session.exit_reason() {
+            Some(ExitReason::Success) => {}
+            Some(ExitReason::Failed { .. }) => return Err(NightfallError::ProfileChainExhausted),
+            Some(ExitReason::Killed) => return Err(NightfallError::Aborted),
+            None => return Err(NightfallError::ChunkNotDone),
+        }
 
-                debug!("Resetting {} to chunk {} because user seeked.", &id, chunk);
-            }
+        let outdir = session.profile_ctx.output_ctx.outdir.clone();
+        let duration = session.profile_ctx.input_ctx.duration;
 
-            Err(NightfallError::ChunkNotDone)
-        } else {
-            let chunk_path = session.chunk_to_path(chunk);
-            let path = chunk_path.clone();
-            let real_segment = session.real_segment;
+        Ok(Oneshot::spawn(async move {
+            tokio::task::spawn_blocking(move || write_index(outdir, &config, duration))
+                .await
+                .map_err(|_| NightfallError::Aborted)?
+        }))
+    }
 
-            // hint that we should probably unpause ffmpeg for a bit
-            if chunk + 2 >= session.current_chunk() {
-                session.cont();
-            }
+    /// Extracts the frame of `file` at `timestamp` seconds into a JPEG, ex. for a poster or a
+    /// preview. The returned `Oneshot` resolves to its path once ffmpeg is done. Screenshots are
+    /// written into `SCREENSHOT_DIR` under `outdir` with the configured ffmpeg, the same frame is
+    /// only extracted once. They get deleted by `garbage_collect` after `GcPolicy::reap_after`.
+    #[handler]
+    async fn screenshot(
+        &mut self,
+        file: String,
+        timestamp: f64,
+        options: ScreenshotOptions,
+    ) -> Result<Oneshot> {
+        if !timestamp.is_finite() || timestamp < 0.0 {
+            return Err(NightfallError::InvalidProfileContext(format!(
+                "Cannot take a screenshot at {}s.",
+                timestamp
+            )));
+        }
 
-            match patch_segment(path, real_segment).await {
-                Ok(seq) => session.real_segment = seq,
-                // Sometimes we get partial chunks, when playback goes linearly (no hard seeks have
-                // occured) we can ignore this, but when the user seeks, the player doesnt query
-                // `init.mp4` again, so we have to move the video data from `init.mp4` into
-                // `N.m4s`.
-                Err(NightfallError::PartialSegment(_)) => {
-                    if session.chunks_since_init >= 1 {
-                        debug!("Got a partial segment, patching because the user has most likely seeked.");
-
-                        match patch_init_segment(
-                            session.init_seg(),
-                            chunk_path.clone(),
-                            real_segment,
-                        )
-                        .await
-                        {
-                            Ok(seq) => session.real_segment = seq,
-                            Err(e) => {
-                                warn!(
-                                    error = %e,
-                                    "Failed to patch init segment."
-                                )
-                            }
-                        }
-                    }
-                }
+        options.validate()?;
+
+        let dir = format!("{}/{}", self.outdir, SCREENSHOT_DIR);
+        let name = screenshot_name(&file, timestamp, &options);
+        let path = format!("{}/{}.jpg", dir, name);
+
+        if Path::new(&path).is_file() {
+            return Ok(Oneshot::spawn(async move { Ok(path) }));
+        }
+
+        // ffmpeg writes into a temporary file so that a concurrent request never reads a partial
+        // image.
+        let tmp = format!("{}/{}.tmp.jpg", dir, name);
+        let mut command = tokio::process::Command::new(&self.ffmpeg);
+        command.args(options.args(&file, timestamp, &tmp));
+
+        Ok(Oneshot::spawn(async move {
+            tokio::fs::create_dir_all(&dir).await?;
+
+            let output = match run_oneshot(command).await {
+                Ok(x) => x,
                 Err(e) => {
-                    warn!(error = %e, "Failed to patch segment.")
+                    let _ = tokio::fs::remove_file(&tmp).await;
+                    return Err(e);
                 }
+            };
+
+            let is_written = tokio::fs::metadata(&tmp).await.is_ok_and(|x| x.is_file());
+
+            // seeking past the end succeeds without writing anything.
+            if !output.status.success() || !is_written {
+                let _ = tokio::fs::remove_file(&tmp).await;
+                let stderr = String::from_utf8_lossy(&output.stderr);
+
+                return Err(NightfallError::ScreenshotError(
+                    stderr
+                        .lines()
+                        .last()
+                        .unwrap_or("No frame at the timestamp.")
+                        .to_string(),
+                ));
             }
 
-            session.reset_timeout(chunk);
-            session.chunks_since_init += 1;
+            tokio::fs::rename(&tmp, &path).await?;
 
-            Ok(chunk_path)
-        }
+            Ok(path)
+        }))
     }
 
+    /// Extracts the attachment `attachment` of the input of a session, as returned by
+    /// `FFPWrapper::attachments`, into the `attachments` directory of the session outdir. The
+    /// returned `Oneshot` resolves to its path once ffmpeg is done. Attachments are only
+    /// extracted once.
     #[handler]
-    async fn chunk_eta(&mut self, id: String, chunk: u32) -> Result<u64> {
+    async fn extract_attachment(&mut self, id: String, attachment: Stream) -> Result<Oneshot> {
         let session = self
             .sessions
-            .get_mut(&id)
+            .get(&id)
             .ok_or(NightfallError::SessionDoesntExist)?;
-        Ok(session.eta_for(chunk).as_secs())
-    }
 
-    #[handler]
-    async fn should_hard_seek(&mut self, id: String, chunk: u32) -> Result<bool> {
-        let session = self
-            .sessions
-            .get_mut(&id)
-            .ok_or(NightfallError::SessionDoesntExist)?;
+        if attachment.codec_type != "attachment" {
+            return Err(NightfallError::InvalidProfileContext(format!(
+                "Stream {} isnt an attachment.",
+                attachment.index
+            )));
+        }
 
-        let stats = self.stream_stats.entry(id).or_default();
+        let dir = format!("{}/attachments", session.profile_ctx.output_ctx.outdir);
+        let filename = attachment
+            .attachment_filename()
+            .map(ToString::to_string)
+            .unwrap_or_else(|| format!("{}.bin", attachment.index));
+        let path = format!("{}/{}", dir, filename);
 
-        if !session.has_started() {
-            return Ok(false);
-        }
-        // if we are seeking backwards we always want to restart the stream
-        // This is because our init.mp4 gets overwritten if we seeked forward at some point
-        // Furthermore we want to hard seek anyway if the player is browser based.
-        if chunk < session.start_num() {
-            return Ok(true);
-        }
-
-        // FIXME: When we hard seek and start a new ffmpeg session for some reason ffmpeg
-        // reports invalid speed but then evens out. The problem is that causes seeking
-        // multiple times in a row to be very slow.
-        // thus for like the first 10s after a hard seek we exclusively hard seek if the
-        // target is over 10 chunks into the future.
-        if chunk > session.current_chunk() + 15
-            && Instant::now() < stats.last_hard_seek + Duration::from_secs(15)
-        {
-            return Ok(true);
+        if Path::new(&path).is_file() {
+            return Ok(Oneshot::spawn(async move { Ok(path) }));
         }
 
-        Ok((session.eta_for(chunk).as_millis() as f64)
-            > (10_000.0 / session.raw_speed()).max(5_000.0))
+        // ffmpeg dumps attachments when opening the input, it then fails as there is no output
+        // which is why only the file is checked.
+        let mut command = tokio::process::Command::new(&self.ffmpeg);
+        command
+            .arg("-y")
+            .arg(format!("-dump_attachment:{}", attachment.index))
+            .arg(&path)
+            .arg("-i")
+            .arg(&session.profile_ctx.file);
+
+        Ok(Oneshot::spawn(async move {
+            tokio::fs::create_dir_all(&dir).await?;
+
+            let _ = run_oneshot(command).await?;
+
+            if !tokio::fs::metadata(&path).await.is_ok_and(|x| x.is_file()) {
+                return Err(NightfallError::IoError);
+            }
+
+            Ok(path)
+        }))
     }
 
     #[handler]
-    async fn die(&mut self, id: String) -> Result<()> {
+    async fn get_stderr(&mut self, id: String) -> Result<String> {
+        // TODO: Move this out of here, instead we should just return the log file.
         let session = self
             .sessions
             .get_mut(&id)
             .ok_or(NightfallError::SessionDoesntExist)?;
-        info!("Killing session {}", id);
-        session.join().await;
-        session.set_timeout();
+        session.stderr().ok_or(NightfallError::Aborted)
+    }
 
-        Ok(())
+    /// Returns why a reaped session died, if it has been recorded and not drained yet.
+    #[handler]
+    async fn exit_status(&self, id: String) -> Result<Option<String>> {
+        Ok(self.exit_statuses.get(&id).cloned())
     }
 
+    /// Returns and clears the exit statuses recorded for reaped sessions. Callers should do this
+    /// periodically as the statuses otherwise accumulate for the lifetime of the actor.
     #[handler]
-    async fn die_ignore_gc(&mut self, id: String) -> Result<()> {
+    async fn drain_exit_statuses(&mut self) -> Result<HashMap<String, String>> {
+        Ok(std::mem::take(&mut self.exit_statuses))
+    }
+
+    /// Returns how many "Non-monotonous DTS" warnings ffmpeg emitted for this session. A high
+    /// count usually means the source is broken and the output will stutter.
+    #[handler]
+    async fn dts_warnings(&self, id: String) -> Result<u64> {
         let session = self
             .sessions
-            .get_mut(&id)
+            .get(&id)
             .ok_or(NightfallError::SessionDoesntExist)?;
-        info!("Killing session {}", id);
-        session.join().await;
+        Ok(session.dts_warnings())
+    }
 
+    #[handler]
+    async fn set_dts_warning_threshold(&mut self, threshold: Option<u64>) -> Result<()> {
+        self.dts_warning_threshold = threshold;
         Ok(())
     }
 
+    /// Returns a future which resolves once the session has finished, failed or got killed.
     #[handler]
-    async fn get_sub(&mut self, id: String, name: String) -> Result<String> {
+    async fn completion(&mut self, id: String) -> Result<Completion> {
         let session = self
             .sessions
             .get_mut(&id)
             .ok_or(NightfallError::SessionDoesntExist)?;
-
-        if !session.has_started() {
-            let _ = session.start().await;
-        }
-
-        session.subtitle(name).ok_or(NightfallError::ChunkNotDone)
+        Ok(session.completion())
     }
 
+    /// Set an absolute deadline after which the session gets reaped by `garbage_collect`, no
+    /// matter whether it is still in use. Passing `None` removes the deadline.
     #[handler]
-    async fn get_thumbnail(&mut self, id: String, name: String) -> Result<String> {
+    async fn set_expiry(&mut self, id: String, expires_at: Option<Instant>) -> Result<()> {
         let session = self
             .sessions
             .get_mut(&id)
             .ok_or(NightfallError::SessionDoesntExist)?;
 
-        if !session.has_started() {
-            let _ = session.start().await;
-        }
-
-        session.thumbnail(name).ok_or(NightfallError::ChunkNotDone)
+        session.expires_at = expires_at;
+        Ok(())
     }
 
+    /// Overrides the `GcPolicy` of a single session, ex. to keep the segments of a session that
+    /// is being recorded. `None` reverts to `gc_policy`.
     #[handler]
-    async fn get_stderr(&mut self, id: String) -> Result<String> {
-        // TODO: Move this out of here, instead we should just return the log file.
+    async fn set_session_gc_policy(&mut self, id: String, policy: Option<GcPolicy>) -> Result<()> {
         let session = self
             .sessions
             .get_mut(&id)
             .ok_or(NightfallError::SessionDoesntExist)?;
-        session.stderr().ok_or(NightfallError::Aborted)
+
+        session.gc_policy = policy;
+        Ok(())
     }
 
     #[handler]
     async fn garbage_collect(&mut self) -> Result<()> {
-        #[allow(clippy::ptr_arg)]
-        fn collect((_, session): &(&String, &Session)) -> bool {
-            session.is_hard_timeout()
-        }
+        let default_policy = self.gc_policy;
+        let collect = |(_, session): &(&String, &Session)| session.should_reap(&default_policy);
 
         // we want to check whether any session's ffmpeg process has died unexpectedly.
+        let mut events = Vec::new();
         for session in self.sessions.values_mut() {
-            session.try_wait();
+            session.poll_relocation();
+
+            let was_running = session.exit_reason().is_none();
+
+            if session.try_wait() && was_running {
+                if let Some(ExitReason::Failed { stderr, .. }) = session.exit_reason() {
+                    events.push(SessionEvent::Errored {
+                        id: session.id.clone(),
+                        error: stderr,
+                    });
+                }
+            }
+
+            if session.has_started() {
+                for chunk in session.take_ready_chunks() {
+                    events.push(SessionEvent::ChunkReady {
+                        id: session.id.clone(),
+                        chunk,
+                    });
+                }
+            }
         }
 
         // FIXME: This can be a drain_filter once #59618 hits stable.
@@ -436,32 +2960,157 @@ impl StateManager {
 
         if !to_reap.is_empty() {
             info!("Reaping {} streams", to_reap.len());
+            self.counters.reaps += to_reap.len() as u64;
         }
 
         for (k, v) in to_reap.iter_mut() {
-            self.exit_statuses
-                .insert(k.to_string(), v.stderr().unwrap_or_default());
+            // expired sessions get a distinct exit status so that callers can tell them apart
+            // from sessions that died or timed out.
+            let status = if v.is_expired() {
+                info!(session = %k, "Session expired");
+                NightfallError::SessionExpired.to_string()
+            } else {
+                v.stderr().unwrap_or_default()
+            };
+
+            self.exit_statuses.insert(k.to_string(), status);
             v.join().await;
-            v.delete_tmp();
+            v.finish(v.reap_reason());
+            self.counters.ffmpeg_restarts += v.restarts();
+            events.push(SessionEvent::Reaped { id: k.clone() });
+            v.forget_persisted();
+
+            if !v.gc_policy.unwrap_or(default_policy).keep_segments {
+                v.delete_tmp();
+            }
+        }
+
+        if let Some(threshold) = self.dts_warning_threshold {
+            for (k, v) in self.sessions.iter_mut() {
+                if v.dts_warnings() < threshold || v.is_dead() || !v.enable_genpts() {
+                    continue;
+                }
+
+                warn!(
+                    session = %k,
+                    warnings = v.dts_warnings(),
+                    "Source has non-monotonic timestamps, restarting with regenerated timestamps"
+                );
+
+                let chunk = v.current_chunk();
+                v.join().await;
+                v.reset_to(chunk);
+                let _ = v.start().await;
+            }
+        }
+
+        for session in self.sessions.values_mut() {
+            if session.profile_ctx.output_ctx.live_playlist && session.has_started() {
+                session.update_playlist();
+            }
         }
 
-        let mut cnt = 0;
+        let mut paused = 0;
+        let mut resumed = 0;
         for (_, v) in self.sessions.iter_mut() {
-            if v.is_timeout() && !v.is_throttled && !v.try_wait() {
+            if !v.has_started() || v.is_preempted || v.try_wait() {
+                continue;
+            }
+
+            let buffered = v.buffered_chunks();
+            let idle = v.is_idle(&v.gc_policy.unwrap_or(default_policy));
+
+            // idle sessions stay paused until a chunk gets requested again.
+            if idle {
+                if !v.is_throttled {
+                    debug!(session = %v.id, "Pausing idle session");
+                    v.pause();
+                    paused += 1;
+                    events.push(SessionEvent::Paused { id: v.id.clone() });
+                }
+            } else if v.profile_ctx.output_ctx.readrate.is_some() {
+                // ffmpeg paces itself, only make sure it isnt left paused.
+                if v.is_throttled {
+                    v.cont();
+                    resumed += 1;
+                }
+            } else if buffered >= self.pacing.high_watermark && !v.is_throttled {
                 v.pause();
-                cnt += 1;
+                paused += 1;
+                events.push(SessionEvent::Paused { id: v.id.clone() });
+            } else if buffered <= self.pacing.low_watermark && v.is_throttled {
+                v.cont();
+                resumed += 1;
+            }
+        }
+
+        if paused != 0 || resumed != 0 {
+            info!("Paused {} streams, resumed {} streams", paused, resumed);
+        }
+
+        for event in events {
+            self.emit(event);
+        }
+
+        // measured in the background so that `list_sessions` doesnt have to walk the outdirs.
+        for session in self.sessions.values().filter(|x| x.has_started()) {
+            tokio::spawn(session.measure_outdir());
+        }
+
+        if self.persist_sessions {
+            for session in self.sessions.values_mut() {
+                if !session.has_started() {
+                    continue;
+                }
+
+                if let Err(e) = session.persist() {
+                    warn!(session = %session.id, error = %e, "Failed to persist session");
+                }
             }
         }
 
-        if cnt != 0 {
-            info!("Paused {} streams", cnt);
+        // screenshots arent owned by any session, they are kept for as long as an idle session.
+        prune_screenshots(
+            format!("{}/{}", self.outdir, SCREENSHOT_DIR),
+            default_policy.reap_after,
+        );
+
+        if self.is_overloaded() && self.preempt_background() {
+            info!("Cpu is overloaded, paused a background session");
         }
 
+        self.start_queued().await;
+
         Ok(())
     }
 
+    /// Starts a session created with `OutputCtx::progressive` and returns the MP4 ffmpeg writes
+    /// to stdout. ffmpeg only encodes as fast as the returned reader gets drained. The stream can
+    /// only be taken once, seeking is done by creating a new session with `InputCtx::seek`.
+    #[handler]
+    async fn progressive_stream(&mut self, id: String) -> Result<ProcessOutput> {
+        self.admit(&id)?;
+
+        let session = self
+            .sessions
+            .get_mut(&id)
+            .ok_or(NightfallError::SessionDoesntExist)?;
+
+        if !session.profile.is_progressive() {
+            return Err(NightfallError::ProfileNotSupported(
+                "Session doesnt stream a progressive MP4.".into(),
+            ));
+        }
+
+        if !session.has_started() {
+            session.start().await?;
+        }
+
+        session.take_stdout().ok_or(NightfallError::Aborted)
+    }
+
     #[handler]
-    async fn take_stdout(&mut self, id: String) -> Result<ChildStdout> {
+    async fn take_stdout(&mut self, id: String) -> Result<ProcessOutput> {
         let session = self
             .sessions
             .get_mut(&id)
@@ -472,6 +3121,8 @@ impl StateManager {
 
     #[handler]
     async fn start(&mut self, id: String) -> Result<()> {
+        self.admit(&id)?;
+
         let session = self
             .sessions
             .get_mut(&id)
@@ -489,6 +3140,15 @@ impl StateManager {
         Ok(session.is_dead())
     }
 
+    #[handler]
+    async fn selected_stream(&self, id: String) -> Result<usize> {
+        let session = self
+            .sessions
+            .get(&id)
+            .ok_or(NightfallError::SessionDoesntExist)?;
+        Ok(session.profile_ctx.input_ctx.stream)
+    }
+
     #[handler]
     async fn has_started(&self, id: String) -> Result<bool> {
         let session = self
@@ -497,4 +3157,101 @@ impl StateManager {
             .ok_or(NightfallError::SessionDoesntExist)?;
         Ok(session.has_started())
     }
+
+    /// Changes the directory layout used for sessions created from now on.
+    #[handler]
+    async fn set_outdir_layout(&mut self, layout: OutdirLayout) -> Result<()> {
+        self.layout = layout;
+        Ok(())
+    }
+
+    #[handler]
+    async fn set_pacing(&mut self, pacing: Pacing) -> Result<()> {
+        if pacing.low_watermark >= pacing.high_watermark {
+            return Err(NightfallError::InvalidConfig(
+                "Pacing low watermark must be below the high watermark.".into(),
+            ));
+        }
+
+        self.pacing = pacing;
+        Ok(())
+    }
+
+    /// Bound the amount of in-flight messages to `capacity`. Guards handed out before share the
+    /// new capacity, and messages already in flight count against it.
+    #[handler]
+    async fn set_mailbox_capacity(&mut self, capacity: Option<usize>) -> Result<MailboxGuard> {
+        self.mailbox.set_capacity(capacity);
+        Ok(self.mailbox.clone())
+    }
+
+    #[handler]
+    async fn mailbox(&self) -> Result<MailboxGuard> {
+        Ok(self.mailbox.clone())
+    }
+
+    #[handler]
+    async fn mailbox_depth(&self) -> Result<usize> {
+        Ok(self.mailbox.depth())
+    }
+
+    #[handler]
+    async fn metrics(&self) -> Result<Metrics> {
+        let mut metrics = Metrics {
+            counters: self.counters,
+            ..Default::default()
+        };
+
+        for session in self.sessions.values() {
+            // restarts of reaped sessions are already part of the counters.
+            metrics.counters.ffmpeg_restarts += session.restarts();
+
+            if !session.has_started() {
+                metrics.queued_sessions += 1;
+            } else if session.is_dead() {
+                metrics.dead_sessions += 1;
+            } else {
+                metrics.active_sessions += 1;
+            }
+        }
+
+        Ok(metrics)
+    }
+
+    /// Returns a receiver holding the latest `-progress` report of ffmpeg for the session, ex. to
+    /// show how far along a background transcode is. The receiver keeps working across seeks
+    /// and profile fallbacks, and holds `None` until ffmpeg reported for the first time.
+    #[handler]
+    async fn progress(&self, id: String) -> Result<watch::Receiver<Option<Progress>>> {
+        let session = self
+            .sessions
+            .get(&id)
+            .ok_or(NightfallError::SessionDoesntExist)?;
+
+        Ok(session.progress())
+    }
+
+    /// Returns an overview of every session, sorted by id, ex. for an admin dashboard.
+    #[handler]
+    async fn list_sessions(&self) -> Result<Vec<SessionSummary>> {
+        let mut sessions = self
+            .sessions
+            .values()
+            .map(|x| SessionSummary {
+                id: x.id.clone(),
+                profile: x.profile.tag().to_string(),
+                current_chunk: x.current_chunk(),
+                start_num: x.start_num(),
+                is_started: x.has_started(),
+                is_paused: x.is_throttled,
+                is_dead: x.has_started() && x.is_dead(),
+                is_direct_play: x.profile.profile_type() == ProfileType::Transmux,
+                outdir_size: x.outdir_size(),
+            })
+            .collect::<Vec<_>>();
+
+        sessions.sort_by(|a, b| a.id.cmp(&b.id));
+
+        Ok(sessions)
+    }
 }
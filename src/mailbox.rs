@@ -0,0 +1,150 @@
+use crate::error::NightfallError;
+use crate::error::Result;
+
+use std::future::Future;
+use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+
+/// Bounds how many messages are currently queued or being processed by the `StateManager`.
+///
+/// The xtra mailbox of the actor is unbounded, thus messages have to be sent through
+/// [`MailboxGuard::send`], which only hands a message to the actor once a slot is free and frees
+/// the slot once the reply arrives. Once `capacity` messages are in flight, further messages fail
+/// fast with `NightfallError::Overloaded` instead of piling onto the actor.
+///
+/// Every clone shares the same slots and capacity, thus changing the capacity applies to the
+/// messages already in flight too.
+#[derive(Clone, Debug)]
+pub struct MailboxGuard {
+    pending: Arc<AtomicUsize>,
+    capacity: Arc<AtomicUsize>,
+}
+
+impl Default for MailboxGuard {
+    fn default() -> Self {
+        Self::new(None)
+    }
+}
+
+impl MailboxGuard {
+    pub fn new(capacity: Option<usize>) -> Self {
+        Self {
+            pending: Arc::new(AtomicUsize::new(0)),
+            capacity: Arc::new(AtomicUsize::new(capacity.unwrap_or(usize::MAX))),
+        }
+    }
+
+    /// Returns how many messages are currently in flight.
+    pub fn depth(&self) -> usize {
+        self.pending.load(Ordering::SeqCst)
+    }
+
+    pub fn capacity(&self) -> Option<usize> {
+        Some(self.capacity.load(Ordering::SeqCst)).filter(|x| *x != usize::MAX)
+    }
+
+    /// Changes the capacity, `None` lifts the bound. Messages already in flight keep their slot
+    /// and count against the new capacity.
+    pub fn set_capacity(&self, capacity: Option<usize>) {
+        self.capacity
+            .store(capacity.unwrap_or(usize::MAX), Ordering::SeqCst);
+    }
+
+    /// Reserve a slot in the mailbox. The slot is released when the returned permit is dropped.
+    pub fn try_enter(&self) -> Result<MailboxPermit> {
+        let capacity = self.capacity.load(Ordering::SeqCst);
+
+        self.pending
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |x| {
+                (x < capacity).then(|| x + 1)
+            })
+            .map_err(|_| NightfallError::Overloaded)?;
+
+        Ok(MailboxPermit {
+            pending: self.pending.clone(),
+        })
+    }
+
+    /// Sends `message`, ex. `guard.send(state.chunk_request(id, 3))`, holding a slot until its
+    /// reply arrives. The message only reaches the actor if a slot is free, otherwise
+    /// `Overloaded` is returned right away.
+    pub async fn send<T>(&self, message: impl Future<Output = Result<T>>) -> Result<T> {
+        let _permit = self.try_enter()?;
+
+        message.await
+    }
+}
+
+/// A reserved slot in the `StateManager` mailbox.
+#[derive(Debug)]
+pub struct MailboxPermit {
+    pending: Arc<AtomicUsize>,
+}
+
+impl Drop for MailboxPermit {
+    fn drop(&mut self) {
+        self.pending.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::atomic::AtomicBool;
+    use tokio::sync::oneshot;
+
+    #[tokio::test]
+    async fn full_mailboxes_reject_messages() {
+        let guard = MailboxGuard::new(Some(1));
+        let (tx, rx) = oneshot::channel::<()>();
+
+        let in_flight = tokio::spawn({
+            let guard = guard.clone();
+            async move { guard.send(async { Ok(rx.await.is_ok()) }).await }
+        });
+
+        while guard.depth() == 0 {
+            tokio::task::yield_now().await;
+        }
+
+        let sent = AtomicBool::new(false);
+        let rejected = guard
+            .send(async {
+                sent.store(true, Ordering::SeqCst);
+                Ok(())
+            })
+            .await;
+
+        assert!(matches!(rejected, Err(NightfallError::Overloaded)));
+        // the message isnt even polled, thus never reaches the actor.
+        assert!(!sent.load(Ordering::SeqCst));
+
+        tx.send(()).unwrap();
+        assert!(in_flight.await.unwrap().unwrap());
+
+        assert_eq!(guard.depth(), 0);
+        assert_eq!(guard.send(async { Ok(1) }).await.unwrap(), 1);
+    }
+
+    #[test]
+    fn changing_the_capacity_keeps_messages_in_flight() {
+        let guard = MailboxGuard::new(Some(2));
+        let first = guard.try_enter().unwrap();
+        let _second = guard.try_enter().unwrap();
+
+        guard.clone().set_capacity(Some(1));
+        assert_eq!(guard.capacity(), Some(1));
+        assert!(guard.try_enter().is_err());
+
+        drop(first);
+        // the permit left still counts against the new capacity.
+        assert!(guard.try_enter().is_err());
+
+        guard.set_capacity(None);
+        assert_eq!(guard.capacity(), None);
+        assert!(guard.try_enter().is_ok());
+        assert_eq!(guard.depth(), 1);
+    }
+}
@@ -0,0 +1,161 @@
+use serde::Serialize;
+
+#[cfg(feature = "prometheus")]
+use std::fmt;
+use std::time::Duration;
+use std::time::SystemTime;
+
+/// Counters accumulated by the `StateManager` over its whole lifetime.
+#[derive(Clone, Copy, Debug, Default, Serialize)]
+pub struct Counters {
+    /// How many times we had to restart ffmpeg at a new chunk because a client seeked.
+    pub hard_seeks: u64,
+    /// Total size in bytes of the chunks handed out by `chunk_request`, counting a chunk every
+    /// time it is handed out.
+    pub bytes_served: u64,
+    /// How many sessions have been reaped by the garbage collector.
+    pub reaps: u64,
+    /// How many times a session had to move down its profile chain.
+    pub profile_fallbacks: u64,
+    /// How many chunks have been handed out by `chunk_request`.
+    pub chunks_served: u64,
+    /// How many times patching a segment failed, the segment gets handed out unpatched.
+    pub patch_failures: u64,
+    /// How many times ffmpeg got started again for an existing session, ex. after a seek.
+    pub ffmpeg_restarts: u64,
+}
+
+/// A point in time snapshot of the metrics tracked by the `StateManager`.
+#[derive(Clone, Copy, Debug, Default, Serialize)]
+pub struct Metrics {
+    /// Sessions that have a running ffmpeg process.
+    pub active_sessions: u64,
+    /// Sessions which have been created but haven't started ffmpeg yet.
+    pub queued_sessions: u64,
+    /// Sessions that have started but whose ffmpeg process has since died.
+    pub dead_sessions: u64,
+    #[serde(flatten)]
+    pub counters: Counters,
+}
+
+/// Progress and seeking stats of a single session.
+#[derive(Clone, Copy, Debug, Serialize)]
+pub struct StreamStats {
+    /// The chunk ffmpeg is currently encoding.
+    pub current_chunk: u32,
+    /// The chunk ffmpeg was last started at.
+    pub start_num: u32,
+    /// The speed last reported by ffmpeg.
+    pub raw_speed: f64,
+    /// Exponential moving average of the speeds reported by ffmpeg.
+    pub smoothed_speed: f64,
+    /// Time elapsed since the last hard seek, or since the session was first queried if it never
+    /// hard seeked.
+    pub since_last_hard_seek: Duration,
+    /// How many times this session hard seeked.
+    pub hard_seeks: u64,
+    /// How many chunks were returned since the init segment was last returned.
+    pub chunks_since_init: u32,
+}
+
+/// Encoding progress of a single session as last reported by ffmpeg, see
+/// `StateManager::session_stats`. Fields are `None` until ffmpeg reported them.
+#[derive(Clone, Copy, Debug, Default, Serialize)]
+pub struct SessionStats {
+    /// How many times faster than realtime ffmpeg is encoding, ex. `3.4`.
+    pub speed: Option<f64>,
+    /// Exponential moving average of `speed`.
+    pub smoothed_speed: Option<f64>,
+    /// Frames encoded per second.
+    pub fps: Option<f64>,
+    /// Frames encoded since ffmpeg was last started.
+    pub frames: Option<u64>,
+    /// Bytes written since ffmpeg was last started.
+    pub bytes: Option<u64>,
+    /// Timestamp of the last encoded frame.
+    pub out_time: Option<Duration>,
+    /// How many chunks are done and on disk, as of the last `garbage_collect`.
+    pub chunks_completed: u32,
+    /// When ffmpeg last reported its progress.
+    pub last_activity: Option<SystemTime>,
+}
+
+/// A single `-progress` report of ffmpeg, see `StateManager::progress`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize)]
+pub struct Progress {
+    /// Timestamp of the last encoded frame.
+    pub out_time: Option<Duration>,
+    /// How many times faster than realtime ffmpeg is encoding, ex. `3.4`.
+    pub speed: Option<f64>,
+    /// Frames encoded since ffmpeg was last started.
+    pub frame: Option<u64>,
+    /// Share of the input encoded so far, between `0.0` and `1.0`. `None` if the duration of the
+    /// input isnt known.
+    pub fraction: Option<f64>,
+    /// Whether ffmpeg reported that it reached the end of the input.
+    pub done: bool,
+}
+
+/// Overview of a single session, see `StateManager::list_sessions`.
+#[derive(Clone, Debug, Serialize)]
+pub struct SessionSummary {
+    pub id: String,
+    /// Tag of the profile the session is using, ex. `h264_copy`.
+    pub profile: String,
+    /// The chunk ffmpeg is currently encoding.
+    pub current_chunk: u32,
+    /// The chunk ffmpeg was last started at.
+    pub start_num: u32,
+    pub is_started: bool,
+    /// Whether ffmpeg got paused because the session is far ahead of the player.
+    pub is_paused: bool,
+    pub is_dead: bool,
+    /// Whether the stream is copied as is rather than transcoded.
+    pub is_direct_play: bool,
+    /// Size in bytes of everything the session has written to its outdir, as measured by the last
+    /// `garbage_collect`.
+    pub outdir_size: u64,
+}
+
+/// Renders the snapshot in the Prometheus text exposition format.
+#[cfg(feature = "prometheus")]
+impl fmt::Display for Metrics {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let gauges = [
+            ("nightfall_sessions_active", self.active_sessions),
+            ("nightfall_sessions_queued", self.queued_sessions),
+            ("nightfall_sessions_dead", self.dead_sessions),
+        ];
+
+        let counters = [
+            ("nightfall_hard_seeks_total", self.counters.hard_seeks),
+            ("nightfall_bytes_served_total", self.counters.bytes_served),
+            ("nightfall_reaps_total", self.counters.reaps),
+            (
+                "nightfall_profile_fallbacks_total",
+                self.counters.profile_fallbacks,
+            ),
+            ("nightfall_chunks_served_total", self.counters.chunks_served),
+            (
+                "nightfall_patch_failures_total",
+                self.counters.patch_failures,
+            ),
+            (
+                "nightfall_ffmpeg_restarts_total",
+                self.counters.ffmpeg_restarts,
+            ),
+        ];
+
+        for (name, value) in gauges {
+            writeln!(f, "# TYPE {} gauge", name)?;
+            writeln!(f, "{} {}", name, value)?;
+        }
+
+        for (name, value) in counters {
+            writeln!(f, "# TYPE {} counter", name)?;
+            writeln!(f, "{} {}", name, value)?;
+        }
+
+        Ok(())
+    }
+}
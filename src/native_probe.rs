@@ -0,0 +1,693 @@
+use std::convert::TryInto;
+use std::fs::File;
+use std::io::Read;
+use std::io::Seek;
+use std::io::SeekFrom;
+use std::path::Path;
+
+use crate::ffprobe::FFPWrapper;
+use crate::ffprobe::Format;
+use crate::ffprobe::Stream;
+use crate::ffprobe::Tags;
+use crate::patch::webm::read_uint;
+use crate::patch::webm::Cursor;
+
+const EBML_MAGIC: [u8; 4] = [0x1A, 0x45, 0xDF, 0xA3];
+/// Amount of bytes read from the start of a Matroska file, the header elements precede the
+/// clusters in any file written by a sane muxer.
+const MATROSKA_HEADER_LEN: u64 = 4 * 1024 * 1024;
+
+const SEGMENT_ID: u32 = 0x1853_8067;
+const INFO_ID: u32 = 0x1549_A966;
+const TIMESTAMP_SCALE_ID: u32 = 0x2A_D7B1;
+const DURATION_ID: u32 = 0x4489;
+const TRACKS_ID: u32 = 0x1654_AE6B;
+const TRACK_ENTRY_ID: u32 = 0xAE;
+const TRACK_TYPE_ID: u32 = 0x83;
+const CODEC_ID_ID: u32 = 0x86;
+const LANGUAGE_ID: u32 = 0x22_B59C;
+const NAME_ID: u32 = 0x536E;
+const VIDEO_ID: u32 = 0xE0;
+const PIXEL_WIDTH_ID: u32 = 0xB0;
+const PIXEL_HEIGHT_ID: u32 = 0xBA;
+const AUDIO_ID: u32 = 0xE1;
+const SAMPLING_FREQUENCY_ID: u32 = 0xB5;
+const CHANNELS_ID: u32 = 0x9F;
+const CLUSTER_ID: u32 = 0x1F43_B675;
+
+/// Largest `moov` box read into memory, sample tables of hours long files stay well below this.
+/// Files with a larger one are left to ffprobe.
+const MAX_MOOV_LEN: u64 = 64 * 1024 * 1024;
+
+/// Top level boxes an MP4 file can start with.
+const MP4_TOP_LEVEL: [&[u8; 4]; 6] = [b"ftyp", b"moov", b"mdat", b"free", b"skip", b"wide"];
+
+/// Function reads the headers of a MP4 or Matroska file and returns the codecs, the dimensions,
+/// the duration and the layout of its tracks without running ffprobe. Only a subset of what
+/// `FFProbeCtx::get_meta` reports is filled in, ex. there is no pixel format, color metadata nor
+/// bitrates per stream. Returns `None` for any other container or when the headers cant be
+/// parsed, in which case ffprobe should be used instead, see `FFProbeCtx::get_meta_fast`.
+pub fn probe(file: &Path) -> Option<FFPWrapper> {
+    let mut f = File::open(file).ok()?;
+    let size = f.metadata().ok()?.len();
+
+    let mut magic = [0; 8];
+    f.read_exact(&mut magic).ok()?;
+    f.seek(SeekFrom::Start(0)).ok()?;
+
+    let (format_name, streams, duration) = if magic[..4] == EBML_MAGIC {
+        let (streams, duration) = probe_matroska(&mut f)?;
+        ("matroska,webm", streams, duration)
+    } else if MP4_TOP_LEVEL.iter().any(|x| magic[4..] == x[..]) {
+        let (streams, duration) = probe_mp4(&mut f, size)?;
+        ("mov,mp4,m4a,3gp,3g2,mj2", streams, duration)
+    } else {
+        return None;
+    };
+
+    if streams.is_empty() {
+        return None;
+    }
+
+    let bit_rate = duration
+        .filter(|x| *x > 0.0)
+        .map(|x| ((size as f64 * 8.0 / x) as u64).to_string())
+        .unwrap_or_default();
+
+    let format = Format {
+        filename: file.to_string_lossy().into_owned(),
+        nb_streams: streams.len() as i64,
+        nb_programs: 0,
+        format_name: format_name.into(),
+        format_long_name: String::new(),
+        start_time: "0.000000".into(),
+        duration: duration.map(|x| format!("{:.6}", x)).unwrap_or_default(),
+        size: size.to_string(),
+        bit_rate,
+    };
+
+    Some(FFPWrapper::from_parts(streams, format))
+}
+
+fn stream(index: usize, codec_name: &str, codec_type: &str) -> Stream {
+    Stream {
+        index: index as i64,
+        codec_name: codec_name.into(),
+        codec_type: codec_type.into(),
+        ..Default::default()
+    }
+}
+
+fn tags(language: Option<String>, title: Option<String>) -> Option<Tags> {
+    if language.is_none() && title.is_none() {
+        return None;
+    }
+
+    Some(Tags {
+        language,
+        title,
+        ..Default::default()
+    })
+}
+
+/// Walks the top level boxes of the file and parses the `moov` box, wherever it is.
+fn probe_mp4(f: &mut File, size: u64) -> Option<(Vec<Stream>, Option<f64>)> {
+    let mut pos = 0;
+
+    while pos + 8 <= size {
+        f.seek(SeekFrom::Start(pos)).ok()?;
+
+        let mut header = [0; 16];
+        f.read_exact(&mut header[..8]).ok()?;
+
+        let (len, header_len) = match u32::from_be_bytes(header[..4].try_into().ok()?) {
+            0 => (size - pos, 8),
+            1 => {
+                f.read_exact(&mut header[8..]).ok()?;
+                (u64::from_be_bytes(header[8..].try_into().ok()?), 16)
+            }
+            x => (x as u64, 8),
+        };
+
+        // the length comes straight from the file, it must not reach past its end.
+        if len < header_len || len > size - pos {
+            return None;
+        }
+
+        if &header[4..8] == b"moov" {
+            if len - header_len > MAX_MOOV_LEN {
+                return None;
+            }
+
+            let mut moov = vec![0; (len - header_len) as usize];
+            f.read_exact(&mut moov).ok()?;
+
+            return parse_moov(&moov);
+        }
+
+        pos = pos.checked_add(len)?;
+    }
+
+    None
+}
+
+/// Iterates over the boxes contained in `data`, yielding their type and payload.
+pub(crate) fn boxes(data: &[u8]) -> impl Iterator<Item = (&[u8], &[u8])> {
+    let mut pos = 0;
+
+    std::iter::from_fn(move || {
+        let header = data.get(pos..pos + 8)?;
+        let len = u32::from_be_bytes(header[..4].try_into().ok()?) as usize;
+
+        let (len, header_len) = match len {
+            0 => (data.len() - pos, 8),
+            1 => {
+                let large = data.get(pos + 8..pos + 16)?;
+                (u64::from_be_bytes(large.try_into().ok()?) as usize, 16)
+            }
+            x => (x, 8),
+        };
+
+        let payload = data.get(pos + header_len..pos.checked_add(len)?)?;
+        let kind = &header[4..8];
+        pos += len;
+
+        Some((kind, payload))
+    })
+}
+
+pub(crate) fn child<'a>(data: &'a [u8], kind: &[u8]) -> Option<&'a [u8]> {
+    boxes(data).find(|(x, _)| *x == kind).map(|(_, x)| x)
+}
+
+fn be_u16(data: &[u8], at: usize) -> Option<u16> {
+    Some(u16::from_be_bytes(data.get(at..at + 2)?.try_into().ok()?))
+}
+
+pub(crate) fn be_u32(data: &[u8], at: usize) -> Option<u32> {
+    Some(u32::from_be_bytes(data.get(at..at + 4)?.try_into().ok()?))
+}
+
+fn be_u64(data: &[u8], at: usize) -> Option<u64> {
+    Some(u64::from_be_bytes(data.get(at..at + 8)?.try_into().ok()?))
+}
+
+/// Reads the timescale and duration of a `mvhd` or `mdhd` box, which share their layout.
+pub(crate) fn timescale_and_duration(data: &[u8]) -> Option<(u32, u64)> {
+    match data.first()? {
+        1 => Some((be_u32(data, 20)?, be_u64(data, 24)?)),
+        _ => Some((be_u32(data, 12)?, be_u32(data, 16)? as u64)),
+    }
+}
+
+fn parse_moov(moov: &[u8]) -> Option<(Vec<Stream>, Option<f64>)> {
+    let duration = child(moov, b"mvhd")
+        .and_then(timescale_and_duration)
+        .filter(|(timescale, _)| *timescale > 0)
+        .map(|(timescale, duration)| duration as f64 / timescale as f64);
+
+    // a track which cant be parsed would shift the indices of the streams ffmpeg sees.
+    let streams = boxes(moov)
+        .filter(|(kind, _)| *kind == b"trak")
+        .enumerate()
+        .map(|(index, (_, trak))| parse_trak(index, trak))
+        .collect::<Option<_>>()?;
+
+    Some((streams, duration))
+}
+
+fn parse_trak(index: usize, trak: &[u8]) -> Option<Stream> {
+    let mdia = child(trak, b"mdia")?;
+    let handler = child(mdia, b"hdlr")?.get(8..12)?;
+    let stsd = child(child(child(mdia, b"minf")?, b"stbl")?, b"stsd")?;
+    // version and flags followed by the entry count, only the first entry matters.
+    let (fourcc, entry) = boxes(stsd.get(8..)?).next()?;
+
+    let codec_type = match handler {
+        b"vide" => "video",
+        b"soun" => "audio",
+        b"subt" | b"sbtl" | b"text" => "subtitle",
+        _ => "data",
+    };
+
+    let codec_name = match fourcc {
+        b"avc1" | b"avc3" => "h264".to_string(),
+        b"hvc1" | b"hev1" | b"dvh1" | b"dvhe" => "hevc".into(),
+        b"av01" => "av1".into(),
+        b"vp09" => "vp9".into(),
+        b"mp4a" => mp4a_codec(entry)?.into(),
+        b"ac-3" => "ac3".into(),
+        b"ec-3" => "eac3".into(),
+        b"Opus" => "opus".into(),
+        b"fLaC" => "flac".into(),
+        b"alac" => "alac".into(),
+        b"wvtt" => "webvtt".into(),
+        b"tx3g" => "mov_text".into(),
+        b"stpp" => "ttml".into(),
+        x => String::from_utf8_lossy(x).trim().to_lowercase(),
+    };
+
+    let mut stream = stream(index, &codec_name, codec_type);
+
+    if let Some((timescale, duration)) = child(mdia, b"mdhd")
+        .and_then(timescale_and_duration)
+        .filter(|(timescale, _)| *timescale > 0)
+    {
+        stream.duration_ts = Some(duration as i64);
+        stream.duration = Some(format!("{:.6}", duration as f64 / timescale as f64));
+    }
+
+    // the language is packed as three 5 bit letters offset by 0x60.
+    let language = child(mdia, b"mdhd")
+        .and_then(|x| be_u16(x, if x.first() == Some(&1) { 32 } else { 20 }))
+        .map(|x| {
+            [10, 5, 0]
+                .iter()
+                .map(|shift| (((x >> shift) & 0x1F) as u8 + 0x60) as char)
+                .collect::<String>()
+        })
+        .filter(|x| x.chars().all(|x| x.is_ascii_lowercase()) && x != "und");
+    stream.tags = tags(language, None);
+
+    match codec_type {
+        // sample entries start with 6 reserved bytes and the data reference index.
+        "video" => {
+            stream.width = be_u16(entry, 24).map(|x| x as i64);
+            stream.height = be_u16(entry, 26).map(|x| x as i64);
+        }
+        "audio" => {
+            stream.channels = be_u16(entry, 16).map(|x| x as i64);
+            stream.sample_rate = be_u16(entry, 24).map(|x| x.to_string());
+        }
+        _ => {}
+    }
+
+    Some(stream)
+}
+
+/// Returns the codec of a `mp4a` sample entry, which is told by the object type of its `esds`
+/// box rather than by the fourcc, ex. MP3 is muxed as `mp4a` too.
+fn mp4a_codec(entry: &[u8]) -> Option<&'static str> {
+    // the child boxes follow the sound description, which QuickTime extends in versions 1 and 2.
+    let children = match be_u16(entry, 8)? {
+        0 => 28,
+        1 => 44,
+        2 => 64,
+        _ => return None,
+    };
+    let children = entry.get(children..)?;
+    let esds = child(children, b"esds").or_else(|| child(child(children, b"wave")?, b"esds"))?;
+
+    match object_type(esds.get(4..)?)? {
+        0x40 | 0x66..=0x68 => Some("aac"),
+        0x69 | 0x6B => Some("mp3"),
+        0xA5 => Some("ac3"),
+        0xA6 => Some("eac3"),
+        0xA9 | 0xAC => Some("dts"),
+        0xAD => Some("opus"),
+        0xDD => Some("vorbis"),
+        _ => None,
+    }
+}
+
+/// Reads the object type indication of the decoder config descriptor nested in the ES descriptor
+/// `data` starts with, see ISO/IEC 14496-1.
+fn object_type(data: &[u8]) -> Option<u8> {
+    // descriptors have a tag followed by a size of up to four 7 bit bytes.
+    let descriptor = |data: &[u8], tag: u8| -> Option<usize> {
+        if *data.first()? != tag {
+            return None;
+        }
+
+        (1..5)
+            .find(|x| data.get(*x).is_some_and(|x| x & 0x80 == 0))
+            .map(|x| x + 1)
+    };
+
+    let mut pos = descriptor(data, 0x03)?;
+    // ES_ID followed by the flags telling which optional fields are there.
+    let flags = *data.get(pos + 2)?;
+    pos += 3;
+
+    if flags & 0x80 != 0 {
+        pos += 2;
+    }
+    if flags & 0x40 != 0 {
+        pos += 1 + *data.get(pos)? as usize;
+    }
+    if flags & 0x20 != 0 {
+        pos += 2;
+    }
+
+    let data = data.get(pos..)?;
+    data.get(descriptor(data, 0x04)?).copied()
+}
+
+/// Reads the info and the tracks of the segment from the start of the file, stopping at the first
+/// cluster.
+fn probe_matroska(f: &mut File) -> Option<(Vec<Stream>, Option<f64>)> {
+    let mut data = Vec::new();
+    f.take(MATROSKA_HEADER_LEN).read_to_end(&mut data).ok()?;
+
+    let mut cursor = Cursor::new(&data);
+    let mut streams = Vec::new();
+    let mut timestamp_scale = 1_000_000u64;
+    let mut duration = None;
+
+    while !cursor.is_empty() {
+        let id = cursor.id().ok()?;
+        let size = cursor.size().ok()?;
+
+        match id {
+            // descend into the segment, its size is often unknown.
+            SEGMENT_ID => continue,
+            CLUSTER_ID => break,
+            INFO_ID => {
+                let info = cursor.take(size? as usize).ok()?;
+                for (id, value) in elements(info) {
+                    match id {
+                        TIMESTAMP_SCALE_ID => timestamp_scale = read_uint(value),
+                        DURATION_ID => duration = read_float(value),
+                        _ => {}
+                    }
+                }
+            }
+            TRACKS_ID => {
+                let tracks = cursor.take(size? as usize).ok()?;
+                streams = elements(tracks)
+                    .filter(|(id, _)| *id == TRACK_ENTRY_ID)
+                    .enumerate()
+                    .map(|(index, (_, entry))| parse_track_entry(index, entry))
+                    .collect();
+            }
+            _ => {
+                cursor.take(size? as usize).ok()?;
+            }
+        }
+    }
+
+    let duration = duration.map(|x| x * timestamp_scale as f64 / 1_000_000_000.0);
+
+    Some((streams, duration))
+}
+
+/// Iterates over the elements contained in `data`, yielding their id and payload.
+fn elements(data: &[u8]) -> impl Iterator<Item = (u32, &[u8])> {
+    let mut cursor = Cursor::new(data);
+
+    std::iter::from_fn(move || {
+        let id = cursor.id().ok()?;
+        let size = cursor.size().ok()??;
+        let value = cursor.take(size as usize).ok()?;
+
+        Some((id, value))
+    })
+}
+
+fn read_float(bytes: &[u8]) -> Option<f64> {
+    match bytes.len() {
+        4 => Some(f32::from_be_bytes(bytes.try_into().ok()?) as f64),
+        8 => Some(f64::from_be_bytes(bytes.try_into().ok()?)),
+        _ => None,
+    }
+}
+
+fn read_string(bytes: &[u8]) -> String {
+    String::from_utf8_lossy(bytes)
+        .trim_end_matches('\0')
+        .to_string()
+}
+
+fn parse_track_entry(index: usize, entry: &[u8]) -> Stream {
+    let mut track_type = 0;
+    let mut codec_id = String::new();
+    let mut language = None;
+    let mut name = None;
+    let mut width = None;
+    let mut height = None;
+    let mut sample_rate = None;
+    let mut channels = None;
+
+    for (id, value) in elements(entry) {
+        match id {
+            TRACK_TYPE_ID => track_type = read_uint(value),
+            CODEC_ID_ID => codec_id = read_string(value),
+            LANGUAGE_ID => language = Some(read_string(value)).filter(|x| x != "und"),
+            NAME_ID => name = Some(read_string(value)),
+            VIDEO_ID => {
+                for (id, value) in elements(value) {
+                    match id {
+                        PIXEL_WIDTH_ID => width = Some(read_uint(value) as i64),
+                        PIXEL_HEIGHT_ID => height = Some(read_uint(value) as i64),
+                        _ => {}
+                    }
+                }
+            }
+            AUDIO_ID => {
+                for (id, value) in elements(value) {
+                    match id {
+                        SAMPLING_FREQUENCY_ID => sample_rate = read_float(value),
+                        CHANNELS_ID => channels = Some(read_uint(value) as i64),
+                        _ => {}
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+
+    let codec_type = match track_type {
+        1 => "video",
+        2 => "audio",
+        0x11 => "subtitle",
+        _ => "data",
+    };
+
+    let codec_name = match codec_id.as_str() {
+        "V_MPEG4/ISO/AVC" => "h264",
+        "V_MPEGH/ISO/HEVC" => "hevc",
+        "V_AV1" => "av1",
+        "V_VP9" => "vp9",
+        "V_VP8" => "vp8",
+        "V_MPEG2" => "mpeg2video",
+        "A_AC3" => "ac3",
+        "A_EAC3" => "eac3",
+        "A_TRUEHD" => "truehd",
+        "A_OPUS" => "opus",
+        "A_FLAC" => "flac",
+        "A_VORBIS" => "vorbis",
+        "A_MPEG/L3" => "mp3",
+        "S_TEXT/UTF8" => "subrip",
+        "S_TEXT/ASS" | "S_TEXT/SSA" => "ass",
+        "S_TEXT/WEBVTT" => "webvtt",
+        "S_HDMV/PGS" => "hdmv_pgs_subtitle",
+        "S_VOBSUB" => "dvd_subtitle",
+        x if x.starts_with("A_AAC") => "aac",
+        x if x.starts_with("A_DTS") => "dts",
+        x => x,
+    };
+
+    let mut stream = stream(index, &codec_name.to_lowercase(), codec_type);
+    stream.width = width;
+    stream.height = height;
+    stream.sample_rate = sample_rate.map(|x| (x as u64).to_string());
+    // matroska defaults to mono when the channel count isnt written.
+    stream.channels = channels.or(Some(1).filter(|_| track_type == 2));
+    stream.tags = tags(language, name);
+
+    stream
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::io::Write;
+    use std::path::PathBuf;
+
+    fn mp4_box(kind: &[u8; 4], payload: &[u8]) -> Vec<u8> {
+        let mut out = ((8 + payload.len()) as u32).to_be_bytes().to_vec();
+        out.extend_from_slice(kind);
+        out.extend_from_slice(payload);
+        out
+    }
+
+    /// `esds` payload with an object type, `long_sizes` writes the descriptor sizes over four
+    /// bytes like some muxers do.
+    fn esds(object_type: u8, long_sizes: bool) -> Vec<u8> {
+        let size = |x: u8| {
+            if long_sizes {
+                vec![0x80, 0x80, 0x80, x]
+            } else {
+                vec![x]
+            }
+        };
+
+        let mut config = vec![object_type, 0x15];
+        config.extend_from_slice(&[0; 11]);
+
+        let mut es = vec![0x00, 0x01, 0x00, 0x04];
+        es.extend(size(config.len() as u8));
+        es.extend(config);
+
+        let mut out = vec![0; 4];
+        out.push(0x03);
+        out.extend(size(es.len() as u8));
+        out.extend(es);
+        out
+    }
+
+    fn mp4a(object_type: u8) -> Vec<u8> {
+        let mut entry = vec![0; 28];
+        entry[7] = 1;
+        entry[16..18].copy_from_slice(&2u16.to_be_bytes());
+        entry[24..28].copy_from_slice(&(48000u32 << 16).to_be_bytes());
+        entry.extend(mp4_box(b"esds", &esds(object_type, false)));
+        entry
+    }
+
+    fn avc1(width: u16, height: u16) -> Vec<u8> {
+        let mut entry = vec![0; 78];
+        entry[7] = 1;
+        entry[24..26].copy_from_slice(&width.to_be_bytes());
+        entry[26..28].copy_from_slice(&height.to_be_bytes());
+        entry
+    }
+
+    fn trak(handler: &[u8; 4], fourcc: &[u8; 4], entry: &[u8]) -> Vec<u8> {
+        let mut mdhd = vec![0; 24];
+        mdhd[12..16].copy_from_slice(&1000u32.to_be_bytes());
+        mdhd[16..20].copy_from_slice(&60_000u32.to_be_bytes());
+        // `eng`
+        mdhd[20..22].copy_from_slice(&0x15C7u16.to_be_bytes());
+
+        let mut hdlr = vec![0; 25];
+        hdlr[8..12].copy_from_slice(handler);
+
+        let mut stsd = vec![0, 0, 0, 0, 0, 0, 0, 1];
+        stsd.extend(mp4_box(fourcc, entry));
+
+        let stbl = mp4_box(b"stbl", &mp4_box(b"stsd", &stsd));
+        let minf = mp4_box(b"minf", &stbl);
+        let mdia = [mp4_box(b"mdhd", &mdhd), mp4_box(b"hdlr", &hdlr), minf].concat();
+
+        mp4_box(b"trak", &mp4_box(b"mdia", &mdia))
+    }
+
+    fn moov_payload(traks: &[Vec<u8>]) -> Vec<u8> {
+        let mut mvhd = vec![0; 100];
+        mvhd[12..16].copy_from_slice(&1000u32.to_be_bytes());
+        mvhd[16..20].copy_from_slice(&60_000u32.to_be_bytes());
+
+        [vec![mp4_box(b"mvhd", &mvhd)], traks.to_vec()]
+            .concat()
+            .concat()
+    }
+
+    fn ftyp() -> Vec<u8> {
+        mp4_box(b"ftyp", b"isom\0\0\x02\0isomiso2")
+    }
+
+    fn temp_file(data: &[u8]) -> PathBuf {
+        let path = std::env::temp_dir().join(format!(
+            "nightfall-probe-{}.mp4",
+            uuid::Uuid::new_v4().hyphenated()
+        ));
+        std::fs::write(&path, data).unwrap();
+        path
+    }
+
+    fn probe_moov(traks: &[Vec<u8>]) -> Option<FFPWrapper> {
+        let path = temp_file(&[ftyp(), mp4_box(b"moov", &moov_payload(traks))].concat());
+        let probe = probe(&path);
+
+        let _ = std::fs::remove_file(path);
+        probe
+    }
+
+    #[test]
+    fn mp4_headers_are_probed() {
+        let probe = probe_moov(&[
+            trak(b"vide", b"avc1", &avc1(1920, 1080)),
+            trak(b"soun", b"mp4a", &mp4a(0x40)),
+        ])
+        .unwrap();
+
+        assert_eq!(probe.duration(), Some(60.0));
+
+        let streams = probe.streams();
+        assert_eq!(streams.len(), 2);
+
+        assert_eq!(streams[0].codec_name, "h264");
+        assert_eq!(streams[0].codec_type, "video");
+        assert_eq!(
+            (streams[0].width, streams[0].height),
+            (Some(1920), Some(1080))
+        );
+        // left to ffprobe, see `FFProbeCtx::get_meta_fast`.
+        assert_eq!(streams[0].pix_fmt, None);
+
+        assert_eq!(streams[1].index, 1);
+        assert_eq!(streams[1].codec_name, "aac");
+        assert_eq!(streams[1].channels, Some(2));
+        assert_eq!(streams[1].sample_rate.as_deref(), Some("48000"));
+        assert_eq!(
+            streams[1].tags.as_ref().unwrap().language.as_deref(),
+            Some("eng")
+        );
+    }
+
+    #[test]
+    fn mp4a_codec_comes_from_the_object_type() {
+        for (object_type, codec) in [(0x40, "aac"), (0x67, "aac"), (0x6B, "mp3"), (0xA5, "ac3")] {
+            let probe = probe_moov(&[trak(b"soun", b"mp4a", &mp4a(object_type))]).unwrap();
+            assert_eq!(probe.streams()[0].codec_name, codec);
+        }
+
+        let mut entry = mp4a(0x69);
+        entry.truncate(28);
+        entry.extend(mp4_box(b"esds", &esds(0x69, true)));
+        let probe = probe_moov(&[trak(b"soun", b"mp4a", &entry)]).unwrap();
+        assert_eq!(probe.streams()[0].codec_name, "mp3");
+
+        // a codec we cant name is left to ffprobe rather than guessed.
+        assert!(probe_moov(&[trak(b"soun", b"mp4a", &mp4a(0x01))]).is_none());
+
+        let mut entry = mp4a(0x40);
+        entry.truncate(28);
+        assert!(probe_moov(&[trak(b"soun", b"mp4a", &entry)]).is_none());
+    }
+
+    #[test]
+    fn oversized_moov_is_left_to_ffprobe() {
+        let moov = moov_payload(&[trak(b"soun", b"mp4a", &mp4a(0x40))]);
+
+        let probe_padded = |padding: u64| {
+            let free_len = 8 + padding;
+            let moov_len = 8 + moov.len() as u64 + free_len;
+
+            let mut data = ftyp();
+            data.extend_from_slice(&(moov_len as u32).to_be_bytes());
+            data.extend_from_slice(b"moov");
+            data.extend_from_slice(&moov);
+            data.extend_from_slice(&(free_len as u32).to_be_bytes());
+            data.extend_from_slice(b"free");
+
+            let path = temp_file(&data);
+            // the padding is a hole, thus the file doesnt take up the space on disk.
+            let mut file = std::fs::OpenOptions::new()
+                .append(true)
+                .open(&path)
+                .unwrap();
+            file.set_len(data.len() as u64 + padding).unwrap();
+            file.flush().unwrap();
+
+            let probe = probe(&path);
+            let _ = std::fs::remove_file(path);
+            probe
+        };
+
+        assert!(probe_padded(1024).is_some());
+        assert!(probe_padded(MAX_MOOV_LEN).is_none());
+    }
+}
@@ -0,0 +1,1030 @@
+use std::convert::TryInto;
+use std::fmt;
+use std::fs;
+
+use crate::utils::is_newer;
+use crate::NightfallError;
+use crate::Result;
+
+use aes::cipher::generic_array::GenericArray;
+use aes::cipher::BlockEncrypt;
+use aes::cipher::KeyInit;
+use aes::Aes128;
+use serde_derive::{Deserialize, Serialize};
+use tokio::task::spawn_blocking;
+use tracing::debug;
+
+/// Suffix appended to the path of a segment to get the path of its protected copy.
+pub const PROTECTED_SUFFIX: &str = "cenc";
+
+/// Default amount of bytes at the start of every video NAL unit which stay in the clear, so that
+/// the NAL header and the slice header can be parsed without the key. Same as HLS SAMPLE-AES.
+///
+/// NOTE: Slice headers arent parsed, which would take the SPS and PPS of the stream. Slice headers
+/// running past the leader, ex. with long reference list modifications or weighted prediction
+/// tables, end up partly encrypted, which `cbcs` decoders like FairPlay reject. Streams like these
+/// need a larger `CencConfig::clear_leader`.
+pub const DEFAULT_CLEAR_LEADER: usize = 32;
+
+fn default_clear_leader() -> usize {
+    DEFAULT_CLEAR_LEADER
+}
+
+/// Encryption pattern of `cbcs` video, one block is encrypted out of every ten.
+const CBCS_CRYPT_BLOCKS: usize = 1;
+const CBCS_SKIP_BLOCKS: usize = 9;
+
+/// Common Encryption schemes, see ISO/IEC 23001-7.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum EncryptionScheme {
+    /// AES-CTR with random per-sample IVs, used by Widevine and PlayReady.
+    Cenc,
+    /// AES-CBC with a 1:9 pattern and a constant IV, used by FairPlay and supported by Widevine.
+    Cbcs,
+}
+
+impl EncryptionScheme {
+    fn fourcc(&self) -> &'static [u8; 4] {
+        match self {
+            Self::Cenc => b"cenc",
+            Self::Cbcs => b"cbcs",
+        }
+    }
+}
+
+/// Keys fMP4 segments get protected with, see `OutputCtx::cenc`. Provided by the caller, usually
+/// out of the key server the license server is backed by.
+///
+/// Only the copies handed out are protected, the segments ffmpeg writes stay in the clear next to
+/// them. The outdir must never be served directly, or the protection is trivially bypassed.
+#[derive(Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CencConfig {
+    pub scheme: EncryptionScheme,
+    /// Id of `key`, written into the init segment so that players can request a license for it.
+    pub key_id: [u8; 16],
+    /// Content key, never serialized so that it doesnt end up in the persisted state of the
+    /// session. Sessions brought back by `StateManager::restore_sessions` have none until it is
+    /// supplied again with `StateManager::set_cenc_key`.
+    #[serde(skip)]
+    pub key: Option<[u8; 16]>,
+    /// Constant IV of `cbcs`, ignored by `cenc` which uses random per-sample IVs.
+    pub iv: [u8; 16],
+    /// `pssh` boxes to append to the init segment, complete with their header. Can be left empty
+    /// when the manifest carries the DRM system data instead.
+    pub pssh: Vec<Vec<u8>>,
+    /// Bytes at the start of every video NAL unit which stay in the clear, see
+    /// `DEFAULT_CLEAR_LEADER`.
+    #[serde(default = "default_clear_leader")]
+    pub clear_leader: usize,
+}
+
+impl CencConfig {
+    fn key(&self) -> Result<&[u8; 16]> {
+        self.key
+            .as_ref()
+            .ok_or_else(|| error("Content key missing, see `StateManager::set_cenc_key`."))
+    }
+}
+
+impl fmt::Debug for CencConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // keep the key out of logs.
+        f.debug_struct("CencConfig")
+            .field("scheme", &self.scheme)
+            .field("key_id", &self.key_id)
+            .finish_non_exhaustive()
+    }
+}
+
+/// How the samples of a track are laid out, which decides which of their bytes get encrypted.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SampleFormat {
+    /// Length prefixed h264 NAL units, only the slice data past `CencConfig::clear_leader` is
+    /// encrypted.
+    H264,
+    /// Length prefixed hevc NAL units, only the slice data past `CencConfig::clear_leader` is
+    /// encrypted.
+    Hevc,
+    /// Audio samples are encrypted whole.
+    Audio,
+}
+
+impl SampleFormat {
+    /// Returns the format of the samples of a stream encoded with `codec`, or `None` if they cant
+    /// be protected, which is the case for every video codec other than h264 and hevc.
+    pub fn for_codec(codec: &str, is_video: bool) -> Option<Self> {
+        match codec {
+            _ if !is_video => Some(Self::Audio),
+            "h264" => Some(Self::H264),
+            "hevc" => Some(Self::Hevc),
+            _ => None,
+        }
+    }
+
+    /// Returns whether the NAL unit starting with `header` carries slice data.
+    fn is_vcl(&self, header: u8) -> bool {
+        match self {
+            Self::H264 => (1..=5).contains(&(header & 0x1F)),
+            Self::Hevc => (header >> 1) & 0x3F < 32,
+            Self::Audio => false,
+        }
+    }
+}
+
+fn error(msg: &str) -> NightfallError {
+    NightfallError::SegmentPatchError(msg.into())
+}
+
+fn read_u16(data: &[u8], pos: usize) -> Result<u16> {
+    data.get(pos..pos + 2)
+        .map(|x| u16::from_be_bytes(x.try_into().unwrap()))
+        .ok_or_else(|| error("Truncated box."))
+}
+
+fn read_u32(data: &[u8], pos: usize) -> Result<u32> {
+    data.get(pos..pos + 4)
+        .map(|x| u32::from_be_bytes(x.try_into().unwrap()))
+        .ok_or_else(|| error("Truncated box."))
+}
+
+fn read_u64(data: &[u8], pos: usize) -> Result<u64> {
+    data.get(pos..pos + 8)
+        .map(|x| u64::from_be_bytes(x.try_into().unwrap()))
+        .ok_or_else(|| error("Truncated box."))
+}
+
+/// Returns the length of the header, the end and the type of the box starting at `pos`. Boxes
+/// with a size of 0 extend up to `end`.
+fn box_at(data: &[u8], pos: usize, end: usize) -> Result<(usize, usize, [u8; 4])> {
+    if pos + 8 > end {
+        return Err(error("Truncated box header."));
+    }
+
+    let kind: [u8; 4] = data[pos + 4..pos + 8].try_into().unwrap();
+    let (header_len, size) = match read_u32(data, pos)? {
+        0 => (8, end - pos),
+        1 => (16, read_u64(data, pos + 8)? as usize),
+        x => (8, x as usize),
+    };
+
+    if size < header_len || pos + size > end {
+        return Err(error("Box overflows its parent."));
+    }
+
+    Ok((header_len, pos + size, kind))
+}
+
+/// Returns the position of every child of the box at `parent` of the given type.
+fn children_of(data: &[u8], parent: usize, kind: &[u8; 4]) -> Result<Vec<usize>> {
+    let (header_len, end, _) = box_at(data, parent, data.len())?;
+    let mut found = Vec::new();
+    let mut pos = parent + header_len;
+
+    while pos < end {
+        let (_, child_end, child_kind) = box_at(data, pos, end)?;
+
+        if &child_kind == kind {
+            found.push(pos);
+        }
+
+        pos = child_end;
+    }
+
+    Ok(found)
+}
+
+fn child_of(data: &[u8], parent: usize, kind: &[u8; 4]) -> Result<usize> {
+    children_of(data, parent, kind)?
+        .first()
+        .copied()
+        .ok_or_else(|| error(&format!("Missing {} box.", String::from_utf8_lossy(kind))))
+}
+
+/// Adds `n` bytes to the size of the box at `pos`.
+fn grow_box(data: &mut [u8], pos: usize, n: usize) -> Result<()> {
+    match read_u32(data, pos)? {
+        0 => {}
+        1 => {
+            let size = read_u64(data, pos + 8)? + n as u64;
+            data[pos + 8..pos + 16].copy_from_slice(&size.to_be_bytes());
+        }
+        size => {
+            let size = (size as usize + n) as u32;
+            data[pos..pos + 4].copy_from_slice(&size.to_be_bytes());
+        }
+    }
+
+    Ok(())
+}
+
+fn plain_box(kind: &[u8; 4], payload: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(8 + payload.len());
+    out.extend_from_slice(&((8 + payload.len()) as u32).to_be_bytes());
+    out.extend_from_slice(kind);
+    out.extend_from_slice(payload);
+    out
+}
+
+fn full_box(kind: &[u8; 4], version: u8, flags: u32, payload: &[u8]) -> Vec<u8> {
+    let mut body = (((version as u32) << 24) | flags).to_be_bytes().to_vec();
+    body.extend_from_slice(payload);
+
+    plain_box(kind, &body)
+}
+
+/// Returns the `sinf` box declaring that the track, whose sample entry was of type `format`, is
+/// protected with `config`.
+fn sinf_box(format: &[u8; 4], is_video: bool, config: &CencConfig) -> Vec<u8> {
+    let frma = plain_box(b"frma", format);
+
+    let mut schm = config.scheme.fourcc().to_vec();
+    schm.extend_from_slice(&0x0001_0000u32.to_be_bytes());
+    let schm = full_box(b"schm", 0, 0, &schm);
+
+    let tenc = match config.scheme {
+        EncryptionScheme::Cenc => {
+            // default_isProtected and the size of the per-sample IVs.
+            let mut tenc = vec![0, 0, 1, 8];
+            tenc.extend_from_slice(&config.key_id);
+
+            full_box(b"tenc", 0, 0, &tenc)
+        }
+        EncryptionScheme::Cbcs => {
+            // audio is encrypted whole, which is signaled by a 0:0 pattern.
+            let pattern = if is_video {
+                ((CBCS_CRYPT_BLOCKS << 4) | CBCS_SKIP_BLOCKS) as u8
+            } else {
+                0
+            };
+
+            let mut tenc = vec![0, pattern, 1, 0];
+            tenc.extend_from_slice(&config.key_id);
+            tenc.push(16);
+            tenc.extend_from_slice(&config.iv);
+
+            full_box(b"tenc", 1, 0, &tenc)
+        }
+    };
+
+    let schi = plain_box(b"schi", &tenc);
+
+    plain_box(b"sinf", &[frma, schm, schi].concat())
+}
+
+/// Function marks every audio and video track of an init segment as protected with `config`.
+/// Their sample entries get renamed to `encv` and `enca` and get a `sinf` box describing the
+/// scheme and the key id. The `pssh` boxes of `config` are appended to the `moov` box.
+pub fn protect_init(data: &[u8], config: &CencConfig) -> Result<Vec<u8>> {
+    let mut data = data.to_vec();
+    let moov = children_of_top(&data, b"moov")?
+        .ok_or_else(|| error("Init segment doesnt have a moov box."))?;
+
+    for trak in children_of(&data, moov, b"trak")?.into_iter().rev() {
+        // tracks are handled back to front, so that growing one doesnt move the others.
+        let grown = protect_track(&mut data, trak, config)?;
+        grow_box(&mut data, moov, grown)?;
+    }
+
+    let (_, moov_end, _) = box_at(&data, moov, data.len())?;
+    let pssh = config.pssh.concat();
+
+    data.splice(moov_end..moov_end, pssh.iter().copied());
+    grow_box(&mut data, moov, pssh.len())?;
+
+    Ok(data)
+}
+
+/// Returns the position of the first top level box of the given type.
+fn children_of_top(data: &[u8], kind: &[u8; 4]) -> Result<Option<usize>> {
+    let mut pos = 0;
+
+    while pos < data.len() {
+        let (_, end, box_kind) = box_at(data, pos, data.len())?;
+
+        if &box_kind == kind {
+            return Ok(Some(pos));
+        }
+
+        pos = end;
+    }
+
+    Ok(None)
+}
+
+/// Protects the sample entry of the track at `trak`, returns by how much the track grew.
+fn protect_track(data: &mut Vec<u8>, trak: usize, config: &CencConfig) -> Result<usize> {
+    let mdia = child_of(data, trak, b"mdia")?;
+    let hdlr = child_of(data, mdia, b"hdlr")?;
+    let (hdlr_header, _, _) = box_at(data, hdlr, data.len())?;
+
+    // the handler type follows the version, flags and pre_defined fields.
+    let is_video = match data.get(hdlr + hdlr_header + 8..hdlr + hdlr_header + 12) {
+        Some(b"vide") => true,
+        Some(b"soun") => false,
+        _ => return Ok(0),
+    };
+
+    let minf = child_of(data, mdia, b"minf")?;
+    let stbl = child_of(data, minf, b"stbl")?;
+    let stsd = child_of(data, stbl, b"stsd")?;
+    let (stsd_header, stsd_end, _) = box_at(data, stsd, data.len())?;
+
+    // the first sample entry follows the version, flags and entry_count fields.
+    let entry = stsd + stsd_header + 8;
+    let (_, entry_end, format) = box_at(data, entry, stsd_end)?;
+
+    data[entry + 4..entry + 8].copy_from_slice(if is_video { b"encv" } else { b"enca" });
+
+    let sinf = sinf_box(&format, is_video, config);
+    data.splice(entry_end..entry_end, sinf.iter().copied());
+
+    for parent in [entry, stsd, stbl, minf, mdia, trak].iter() {
+        grow_box(data, *parent, sinf.len())?;
+    }
+
+    Ok(sinf.len())
+}
+
+/// AES-CTR keystream of `cenc`, running across every protected range of a sample.
+struct Ctr<'a> {
+    aes: &'a Aes128,
+    counter: [u8; 16],
+    keystream: [u8; 16],
+    used: usize,
+}
+
+impl<'a> Ctr<'a> {
+    fn new(aes: &'a Aes128, iv: [u8; 8]) -> Self {
+        let mut counter = [0; 16];
+        counter[..8].copy_from_slice(&iv);
+
+        Self {
+            aes,
+            counter,
+            keystream: [0; 16],
+            used: 16,
+        }
+    }
+
+    fn apply(&mut self, data: &mut [u8]) {
+        for x in data.iter_mut() {
+            if self.used == 16 {
+                self.keystream = self.counter;
+                self.aes
+                    .encrypt_block(GenericArray::from_mut_slice(&mut self.keystream));
+                self.used = 0;
+
+                // the block counter is the lower half of the counter block.
+                let block = u64::from_be_bytes(self.counter[8..].try_into().unwrap());
+                self.counter[8..].copy_from_slice(&block.wrapping_add(1).to_be_bytes());
+            }
+
+            *x ^= self.keystream[self.used];
+            self.used += 1;
+        }
+    }
+}
+
+/// Encrypts the full blocks of `data` with AES-CBC, following the `crypt`:`skip` pattern when
+/// there is one. Skipped blocks and the trailing partial block stay in the clear.
+fn encrypt_cbcs(aes: &Aes128, iv: &[u8; 16], data: &mut [u8], crypt: usize, skip: usize) {
+    let mut prev = *iv;
+
+    for (i, block) in data.chunks_exact_mut(16).enumerate() {
+        if crypt + skip != 0 && i % (crypt + skip) >= crypt {
+            continue;
+        }
+
+        for (x, p) in block.iter_mut().zip(prev.iter()) {
+            *x ^= p;
+        }
+
+        aes.encrypt_block(GenericArray::from_mut_slice(block));
+        prev.copy_from_slice(block);
+    }
+}
+
+/// Splits a video sample into subsamples of clear and protected bytes, one per NAL unit. Only the
+/// slice data past the first `leader` bytes is protected, rounded down to whole blocks. Subsamples
+/// without protected bytes get merged into the next one.
+fn subsamples(sample: &[u8], format: SampleFormat, leader: usize) -> Result<Vec<(u16, u32)>> {
+    let mut out: Vec<(u16, u32)> = Vec::new();
+    let mut pos = 0;
+
+    while pos < sample.len() {
+        let len = read_u32(sample, pos).map_err(|_| error("Truncated NAL unit."))? as usize;
+        let total = 4 + len;
+
+        if pos + total > sample.len() {
+            return Err(error("NAL unit overflows its sample."));
+        }
+
+        let is_vcl = sample.get(pos + 4).is_some_and(|x| format.is_vcl(*x));
+        let protected = if is_vcl && len > leader {
+            (len - leader) / 16 * 16
+        } else {
+            0
+        };
+        let mut clear = total - protected;
+
+        match out.last_mut() {
+            Some(last) if last.1 == 0 && last.0 as usize + clear <= u16::MAX as usize => {
+                last.0 += clear as u16;
+                last.1 = protected as u32;
+            }
+            _ => {
+                while clear > u16::MAX as usize {
+                    out.push((u16::MAX, 0));
+                    clear -= u16::MAX as usize;
+                }
+
+                out.push((clear as u16, protected as u32));
+            }
+        }
+
+        pos += total;
+    }
+
+    Ok(out)
+}
+
+/// Encrypts a sample in place and returns its sample auxiliary information, ie. its IV and
+/// subsamples as stored in the `senc` box.
+fn protect_sample(
+    sample: &mut [u8],
+    aes: &Aes128,
+    config: &CencConfig,
+    format: SampleFormat,
+    next_iv: &mut u64,
+) -> Result<Vec<u8>> {
+    let subsamples = match format {
+        SampleFormat::Audio => None,
+        _ => Some(subsamples(sample, format, config.clear_leader)?),
+    };
+
+    let ranges = match subsamples.as_ref() {
+        Some(subsamples) => {
+            let mut pos = 0;
+
+            subsamples
+                .iter()
+                .map(|(clear, protected)| {
+                    let start = pos + *clear as usize;
+                    pos = start + *protected as usize;
+                    (start, pos)
+                })
+                .collect()
+        }
+        None => vec![(0, sample.len())],
+    };
+
+    let mut aux = Vec::new();
+
+    match config.scheme {
+        EncryptionScheme::Cenc => {
+            let iv = next_iv.to_be_bytes();
+            *next_iv = next_iv.wrapping_add(1);
+
+            let mut ctr = Ctr::new(aes, iv);
+            for (start, end) in ranges {
+                ctr.apply(&mut sample[start..end]);
+            }
+
+            aux.extend_from_slice(&iv);
+        }
+        EncryptionScheme::Cbcs => {
+            let (crypt, skip) = match format {
+                SampleFormat::Audio => (0, 0),
+                _ => (CBCS_CRYPT_BLOCKS, CBCS_SKIP_BLOCKS),
+            };
+
+            // every subsample starts over with the constant IV.
+            for (start, end) in ranges {
+                encrypt_cbcs(aes, &config.iv, &mut sample[start..end], crypt, skip);
+            }
+        }
+    }
+
+    if let Some(subsamples) = subsamples {
+        aux.extend_from_slice(&(subsamples.len() as u16).to_be_bytes());
+
+        for (clear, protected) in subsamples {
+            aux.extend_from_slice(&clear.to_be_bytes());
+            aux.extend_from_slice(&protected.to_be_bytes());
+        }
+    }
+
+    Ok(aux)
+}
+
+/// Function encrypts the samples of every fragment of a segment with `config`, and adds the
+/// `senc`, `saiz` and `saio` boxes describing how to decrypt them to their `traf`. Fragments may
+/// only carry a single track, which is what nightfall writes. `sidx` references are grown along
+/// with the fragments they point to.
+pub fn protect_fragments(
+    data: &[u8],
+    config: &CencConfig,
+    format: SampleFormat,
+) -> Result<Vec<u8>> {
+    let aes = Aes128::new(GenericArray::from_slice(config.key()?));
+    let mut data = data.to_vec();
+
+    // `cenc` IVs have to be unique per key, random 64-bit IVs make collisions negligible.
+    let mut next_iv = [0; 8];
+    getrandom::getrandom(&mut next_iv).map_err(|_| error("Failed to generate IVs."))?;
+    let mut next_iv = u64::from_be_bytes(next_iv);
+
+    let mut grown = Vec::new();
+    let mut pos = 0;
+
+    while pos < data.len() {
+        let (_, end, kind) = box_at(&data, pos, data.len())?;
+
+        if &kind == b"moof" {
+            let shift = grown.iter().sum();
+            let n = protect_fragment(&mut data, pos, shift, &aes, config, format, &mut next_iv)?;
+
+            grown.push(n);
+            pos = end + n;
+        } else {
+            pos = end;
+        }
+    }
+
+    grow_sidx(&mut data, &grown)?;
+
+    Ok(data)
+}
+
+/// Protects the fragment whose `moof` box starts at `moof`, returns by how much it grew. `shift`
+/// is how much the fragments before it grew, which absolute offsets have to be moved by.
+fn protect_fragment(
+    data: &mut Vec<u8>,
+    moof: usize,
+    shift: usize,
+    aes: &Aes128,
+    config: &CencConfig,
+    format: SampleFormat,
+    next_iv: &mut u64,
+) -> Result<usize> {
+    let (_, moof_end, _) = box_at(data, moof, data.len())?;
+
+    let traf = match children_of(data, moof, b"traf")?.as_slice() {
+        [x] => *x,
+        _ => return Err(error("Only fragments of a single track can be protected.")),
+    };
+    let (_, traf_end, _) = box_at(data, traf, data.len())?;
+
+    let tfhd = child_of(data, traf, b"tfhd")?;
+    let (tfhd_header, _, _) = box_at(data, tfhd, data.len())?;
+    let tfhd_flags = read_u32(data, tfhd + tfhd_header)? & 0xFF_FFFF;
+
+    // skip the version, flags and track_ID fields.
+    let mut p = tfhd + tfhd_header + 8;
+    let base_offset_pos = if tfhd_flags & 0x1 != 0 {
+        p += 8;
+        Some(p - 8)
+    } else {
+        None
+    };
+
+    if tfhd_flags & 0x2 != 0 {
+        p += 4;
+    }
+
+    if tfhd_flags & 0x8 != 0 {
+        p += 4;
+    }
+
+    let default_size = if tfhd_flags & 0x10 != 0 {
+        Some(read_u32(data, p)?)
+    } else {
+        None
+    };
+
+    let trun = child_of(data, traf, b"trun")?;
+    let (trun_header, _, _) = box_at(data, trun, data.len())?;
+    let trun_flags = read_u32(data, trun + trun_header)? & 0xFF_FFFF;
+    let sample_count = read_u32(data, trun + trun_header + 4)?;
+
+    let mut p = trun + trun_header + 8;
+    let data_offset_pos = if trun_flags & 0x1 != 0 {
+        p += 4;
+        Some(p - 4)
+    } else {
+        None
+    };
+
+    if trun_flags & 0x4 != 0 {
+        p += 4;
+    }
+
+    let mut sizes = Vec::with_capacity(sample_count as usize);
+
+    for _ in 0..sample_count {
+        if trun_flags & 0x100 != 0 {
+            p += 4;
+        }
+
+        let size = if trun_flags & 0x200 != 0 {
+            p += 4;
+            read_u32(data, p - 4)?
+        } else {
+            default_size.ok_or_else(|| error("Fragment doesnt carry sample sizes."))?
+        };
+
+        if trun_flags & 0x400 != 0 {
+            p += 4;
+        }
+
+        if trun_flags & 0x800 != 0 {
+            p += 4;
+        }
+
+        sizes.push(size as usize);
+    }
+
+    let base = match base_offset_pos {
+        Some(x) => read_u64(data, x)? as usize + shift,
+        None => moof,
+    };
+
+    let mut pos = match data_offset_pos {
+        Some(x) => (base as i64 + read_u32(data, x)? as i32 as i64) as usize,
+        // without an offset the samples start right at the data of the following mdat.
+        None => {
+            let (mdat_header, _, kind) = box_at(data, moof_end, data.len())?;
+
+            if &kind != b"mdat" {
+                return Err(error("Fragment isnt followed by a mdat box."));
+            }
+
+            moof_end + mdat_header
+        }
+    };
+
+    let mut aux = Vec::with_capacity(sizes.len());
+
+    for size in sizes {
+        let sample = data
+            .get_mut(pos..pos + size)
+            .ok_or_else(|| error("Sample overflows the segment."))?;
+
+        aux.push(protect_sample(sample, aes, config, format, next_iv)?);
+        pos += size;
+    }
+
+    // `cbcs` audio has neither IVs nor subsamples, the `tenc` box says it all.
+    if aux.iter().all(|x| x.is_empty()) {
+        return Ok(0);
+    }
+
+    if aux.iter().any(|x| x.len() > u8::MAX as usize) {
+        return Err(error("Sample has too many NAL units to be protected."));
+    }
+
+    let mut senc = sample_count.to_be_bytes().to_vec();
+    senc.extend(aux.iter().flatten());
+    let senc = full_box(
+        b"senc",
+        0,
+        if format == SampleFormat::Audio {
+            0
+        } else {
+            0x2
+        },
+        &senc,
+    );
+
+    let default_size = match aux.first() {
+        Some(x) if aux.iter().all(|y| y.len() == x.len()) => x.len() as u8,
+        _ => 0,
+    };
+
+    let mut saiz = vec![default_size];
+    saiz.extend_from_slice(&sample_count.to_be_bytes());
+
+    if default_size == 0 {
+        saiz.extend(aux.iter().map(|x| x.len() as u8));
+    }
+
+    let saiz = full_box(b"saiz", 0, 0, &saiz);
+
+    // the auxiliary information starts after the header, version, flags and sample_count fields
+    // of the senc box, relative to the same base as the sample data.
+    let n = saiz.len() + 20 + senc.len();
+    let aux_offset = traf_end + saiz.len() + 20 + 16 - base;
+
+    let mut saio = 1u32.to_be_bytes().to_vec();
+    saio.extend_from_slice(&(aux_offset as u32).to_be_bytes());
+    let saio = full_box(b"saio", 0, 0, &saio);
+
+    if let Some(x) = data_offset_pos {
+        let offset = read_u32(data, x)? as i32 + n as i32;
+        data[x..x + 4].copy_from_slice(&offset.to_be_bytes());
+    }
+
+    if let Some(x) = base_offset_pos {
+        data[x..x + 8].copy_from_slice(&(base as u64).to_be_bytes());
+    }
+
+    data.splice(traf_end..traf_end, [saiz, saio, senc].concat());
+    grow_box(data, traf, n)?;
+    grow_box(data, moof, n)?;
+
+    Ok(n)
+}
+
+/// Grows the `sidx` references by how much the fragments they point to grew. References are
+/// matched with fragments in order, a single reference spanning every fragment is grown by their
+/// sum.
+fn grow_sidx(data: &mut [u8], grown: &[usize]) -> Result<()> {
+    if grown.iter().all(|x| *x == 0) {
+        return Ok(());
+    }
+
+    let mut references = Vec::new();
+    let mut pos = 0;
+
+    while pos < data.len() {
+        let (header_len, end, kind) = box_at(data, pos, data.len())?;
+
+        if &kind == b"sidx" {
+            let version = data[pos + header_len];
+            // skip the version, flags, reference_ID, timescale, earliest_presentation_time,
+            // first_offset and reserved fields.
+            let p = pos + header_len + 12 + if version == 0 { 8 } else { 16 } + 2;
+            let count = read_u16(data, p)? as usize;
+
+            references.extend((0..count).map(|i| p + 2 + i * 12));
+        }
+
+        pos = end;
+    }
+
+    let deltas = if references.len() == grown.len() {
+        grown.to_vec()
+    } else if references.len() == 1 {
+        vec![grown.iter().sum()]
+    } else {
+        debug!(
+            references = references.len(),
+            fragments = grown.len(),
+            "Cant match sidx references with fragments"
+        );
+
+        return Ok(());
+    };
+
+    for (pos, delta) in references.into_iter().zip(deltas) {
+        let reference = read_u32(data, pos)?;
+        // the top bit is the reference_type.
+        let size = (reference & 0x7FFF_FFFF) + delta as u32;
+        let reference = (reference & 0x8000_0000) | size;
+
+        data[pos..pos + 4].copy_from_slice(&reference.to_be_bytes());
+    }
+
+    Ok(())
+}
+
+/// Writes `data` next to `path` with `PROTECTED_SUFFIX` appended, through a temporary file so
+/// that a concurrent request never reads a partial copy.
+fn write_protected(path: &str, data: Vec<u8>) -> Result<String> {
+    let out = protected_path(path);
+    let tmp = format!("{}.tmp", out);
+
+    fs::write(&tmp, data)?;
+    fs::rename(&tmp, &out)?;
+
+    Ok(out)
+}
+
+/// Returns the path of the protected copy of `path`.
+fn protected_path(path: &str) -> String {
+    format!("{}.{}", path, PROTECTED_SUFFIX)
+}
+
+/// Function writes a protected copy of the init segment at `path`, see `protect_init`, and
+/// returns its path. A copy written after the init segment was last modified is reused as is.
+pub async fn protect_init_segment(path: String, config: CencConfig) -> Result<String> {
+    spawn_blocking(move || {
+        if is_newer(&protected_path(&path), &path) {
+            return Ok(protected_path(&path));
+        }
+
+        let data = protect_init(&fs::read(&path)?, &config)?;
+        write_protected(&path, data)
+    })
+    .await
+    .map_err(|_| NightfallError::Aborted)?
+}
+
+/// Function writes a protected copy of the chunk at `path`, see `protect_fragments`, and returns
+/// its path. The chunk has to be patched beforehand, as patching drops the boxes added here. A
+/// copy written after the chunk was last modified is reused as is.
+pub async fn protect_chunk(
+    path: String,
+    config: CencConfig,
+    format: SampleFormat,
+) -> Result<String> {
+    spawn_blocking(move || {
+        if is_newer(&protected_path(&path), &path) {
+            return Ok(protected_path(&path));
+        }
+
+        let data = protect_fragments(&fs::read(&path)?, &config, format)?;
+        write_protected(&path, data)
+    })
+    .await
+    .map_err(|_| NightfallError::Aborted)?
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use aes::cipher::BlockDecrypt;
+    use std::convert::TryInto;
+
+    const KEY: [u8; 16] = [
+        0x2b, 0x7e, 0x15, 0x16, 0x28, 0xae, 0xd2, 0xa6, 0xab, 0xf7, 0x15, 0x88, 0x09, 0xcf, 0x4f,
+        0x3c,
+    ];
+
+    fn config(scheme: EncryptionScheme) -> CencConfig {
+        CencConfig {
+            scheme,
+            key_id: [0x11; 16],
+            key: Some(KEY),
+            iv: [0x22; 16],
+            pssh: Vec::new(),
+            clear_leader: DEFAULT_CLEAR_LEADER,
+        }
+    }
+
+    /// Returns a length prefixed NAL unit of `len` bytes starting with `header`.
+    fn nal(header: u8, len: usize) -> Vec<u8> {
+        let mut out = (len as u32).to_be_bytes().to_vec();
+        out.push(header);
+        out.extend((1..len).map(|x| x as u8));
+        out
+    }
+
+    fn samples() -> Vec<Vec<u8>> {
+        vec![
+            // IDR slice.
+            nal(0x65, 100),
+            // SEI followed by a non-IDR slice.
+            [nal(0x06, 10), nal(0x41, 80)].concat(),
+        ]
+    }
+
+    /// Returns a fragment of a single track whose `trun` box points at `samples` in the `mdat`
+    /// box following the `moof` box.
+    fn fragment(samples: &[Vec<u8>]) -> Vec<u8> {
+        let moof = |data_offset: u32| {
+            let mfhd = full_box(b"mfhd", 0, 0, &1u32.to_be_bytes());
+            // default-base-is-moof
+            let tfhd = full_box(b"tfhd", 0, 0x2_0000, &1u32.to_be_bytes());
+
+            let mut trun = (samples.len() as u32).to_be_bytes().to_vec();
+            trun.extend_from_slice(&data_offset.to_be_bytes());
+            for sample in samples {
+                trun.extend_from_slice(&(sample.len() as u32).to_be_bytes());
+            }
+            // data-offset-present and sample-size-present
+            let trun = full_box(b"trun", 0, 0x201, &trun);
+
+            let traf = plain_box(b"traf", &[tfhd, trun].concat());
+            plain_box(b"moof", &[mfhd, traf].concat())
+        };
+
+        let data_offset = moof(0).len() as u32 + 8;
+
+        [moof(data_offset), plain_box(b"mdat", &samples.concat())].concat()
+    }
+
+    fn data_offset(data: &[u8]) -> u32 {
+        let traf = child_of(data, 0, b"traf").unwrap();
+        let trun = child_of(data, traf, b"trun").unwrap();
+
+        read_u32(data, trun + 16).unwrap()
+    }
+
+    fn decrypt_cbcs(aes: &Aes128, iv: &[u8; 16], data: &mut [u8]) {
+        let mut prev = *iv;
+
+        for (i, block) in data.chunks_exact_mut(16).enumerate() {
+            if i % (CBCS_CRYPT_BLOCKS + CBCS_SKIP_BLOCKS) >= CBCS_CRYPT_BLOCKS {
+                continue;
+            }
+
+            let cipher: [u8; 16] = (&*block).try_into().unwrap();
+            aes.decrypt_block(GenericArray::from_mut_slice(block));
+
+            for (x, p) in block.iter_mut().zip(prev.iter()) {
+                *x ^= p;
+            }
+
+            prev = cipher;
+        }
+    }
+
+    /// Decrypts the samples of the fragment at the start of `data`, finding the sample auxiliary
+    /// information through the `saiz` and `saio` boxes like a player would.
+    fn decrypt(data: &[u8], config: &CencConfig) -> Vec<Vec<u8>> {
+        let aes = Aes128::new(GenericArray::from_slice(&KEY));
+        let traf = child_of(data, 0, b"traf").unwrap();
+
+        let trun = child_of(data, traf, b"trun").unwrap();
+        let count = read_u32(data, trun + 12).unwrap() as usize;
+        let sizes = (0..count)
+            .map(|i| read_u32(data, trun + 20 + i * 4).unwrap() as usize)
+            .collect::<Vec<_>>();
+
+        let saiz = child_of(data, traf, b"saiz").unwrap();
+        assert_eq!(read_u32(data, saiz + 13).unwrap() as usize, count);
+        let info_sizes = match data[saiz + 12] as usize {
+            0 => data[saiz + 17..saiz + 17 + count]
+                .iter()
+                .map(|x| *x as usize)
+                .collect(),
+            x => vec![x; count],
+        };
+
+        let saio = child_of(data, traf, b"saio").unwrap();
+        assert_eq!(read_u32(data, saio + 12).unwrap(), 1);
+        // the fragment starts at 0, thus the offset relative to the moof box is absolute.
+        let mut info = read_u32(data, saio + 16).unwrap() as usize;
+
+        let senc = child_of(data, traf, b"senc").unwrap();
+        assert_eq!(info, senc + 16);
+
+        let iv_size = match config.scheme {
+            EncryptionScheme::Cenc => 8,
+            EncryptionScheme::Cbcs => 0,
+        };
+
+        let mut pos = data_offset(data) as usize;
+        let mut samples = Vec::new();
+
+        for (size, info_size) in sizes.into_iter().zip(info_sizes) {
+            let aux = &data[info..info + info_size];
+            let mut sample = data[pos..pos + size].to_vec();
+
+            let mut ranges = Vec::new();
+            let mut p = 0;
+            for i in 0..read_u16(aux, iv_size).unwrap() as usize {
+                let clear = read_u16(aux, iv_size + 2 + i * 6).unwrap() as usize;
+                let protected = read_u32(aux, iv_size + 4 + i * 6).unwrap() as usize;
+
+                ranges.push((p + clear, p + clear + protected));
+                p += clear + protected;
+            }
+            assert_eq!(p, size);
+
+            match config.scheme {
+                EncryptionScheme::Cenc => {
+                    let mut ctr = Ctr::new(&aes, aux[..8].try_into().unwrap());
+                    for (start, end) in ranges {
+                        ctr.apply(&mut sample[start..end]);
+                    }
+                }
+                EncryptionScheme::Cbcs => {
+                    for (start, end) in ranges {
+                        decrypt_cbcs(&aes, &config.iv, &mut sample[start..end]);
+                    }
+                }
+            }
+
+            samples.push(sample);
+            info += info_size;
+            pos += size;
+        }
+
+        samples
+    }
+
+    fn round_trip(scheme: EncryptionScheme) {
+        let config = config(scheme);
+        let plain = fragment(&samples());
+        let protected = protect_fragments(&plain, &config, SampleFormat::H264).unwrap();
+
+        assert!(!protected.ends_with(&samples().concat()));
+        assert_eq!(
+            data_offset(&protected) as usize,
+            data_offset(&plain) as usize + protected.len() - plain.len()
+        );
+        assert_eq!(decrypt(&protected, &config), samples());
+    }
+
+    #[test]
+    fn cenc_fragments_decrypt_with_their_sample_auxiliary_information() {
+        round_trip(EncryptionScheme::Cenc);
+    }
+
+    #[test]
+    fn cbcs_fragments_decrypt_with_their_sample_auxiliary_information() {
+        round_trip(EncryptionScheme::Cbcs);
+    }
+}
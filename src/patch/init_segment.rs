@@ -100,6 +100,31 @@ impl InitSegment {
     }
 }
 
+/// Function checks whether two init segments describe the same streams, ie. whether segments
+/// produced alongside `other` can be decoded using `init`. This is the case when the codec
+/// parameters (SPS/PPS etc.) are unchanged, which means the `moov` boxes are identical.
+pub fn init_segments_compatible(init: impl AsRef<Path>, other: impl AsRef<Path>) -> Result<bool> {
+    let read = |path: &Path| -> Result<InitSegment> {
+        let f = File::open(path)?;
+        let size = f.metadata()?.len();
+        InitSegment::from_reader(BufReader::new(f), size)
+    };
+
+    let init = read(init.as_ref())?;
+    let other = read(other.as_ref())?;
+
+    Ok(!init.moov.is_empty() && init.moov == other.moov)
+}
+
+/// Function returns the timescale of the first track described by the init segment.
+pub fn init_segment_timescale(init: impl AsRef<Path>) -> Result<Option<u32>> {
+    let f = File::open(init)?;
+    let size = f.metadata()?.len();
+    let init = InitSegment::from_reader(BufReader::new(f), size)?;
+
+    Ok(super::sidx::mdhd_timescale(&init.moov))
+}
+
 /// Function reads a init segment and moves audio-visual data over from the init segment into
 /// `segment`.
 ///
@@ -108,10 +133,12 @@ impl InitSegment {
 /// * `init` - Path to the initialization segment.
 /// * `segment` - Path to the segment
 /// * `seq` - starting sequence number
+/// * `timescale` - timescale of the track if it was pinned with `ProfileContext::target_timescale`
 pub async fn patch_init_segment(
     init: impl AsRef<Path> + Send + 'static,
     segment_path: impl AsRef<Path> + Send + 'static,
     mut seq: u32,
+    timescale: Option<u32>,
 ) -> Result<u32> {
     spawn_blocking(move || {
         let f = File::open(&init)?;
@@ -127,7 +154,7 @@ pub async fn patch_init_segment(
             segment
                 .gen_styp()
                 .set_styp()
-                .normalize_dts()
+                .normalize_dts(timescale)
                 .set_segno(seq)
                 .write(&mut f)?;
 
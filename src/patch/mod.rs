@@ -1,5 +1,9 @@
+pub mod cenc;
 pub mod init_segment;
+pub mod mpegts;
 pub mod segment;
+pub mod sidx;
+pub mod webm;
 
 use crate::Result;
 use mp4::mp4box::*;
@@ -0,0 +1,346 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+
+use crate::NightfallError;
+use crate::Result;
+
+use tokio::task::spawn_blocking;
+
+const PACKET_SIZE: usize = 188;
+const SYNC_BYTE: u8 = 0x47;
+/// PTS, DTS and the PCR base are 33 bit counters.
+const TIMESTAMP_MASK: u64 = (1 << 33) - 1;
+
+/// Clock of PTS, DTS and of the PCR base, in Hz.
+pub const TS_TIMESCALE: u64 = 90_000;
+/// ffmpeg starts MPEG-TS timestamps 1.4s in, which leaves room for the PCR to lead the PTS. Chunks
+/// are shifted onto the same offset.
+pub const TS_START_OFFSET: u64 = 126_000;
+
+/// Last continuity counter written for every PID, carried from one chunk to the next so that
+/// players dont see a discontinuity at every chunk boundary.
+pub type ContinuityCounters = HashMap<u16, u8>;
+
+fn pid(packet: &[u8]) -> u16 {
+    (((packet[1] & 0x1F) as u16) << 8) | packet[2] as u16
+}
+
+fn has_adaptation_field(packet: &[u8]) -> bool {
+    packet[3] & 0x20 != 0
+}
+
+fn has_payload(packet: &[u8]) -> bool {
+    packet[3] & 0x10 != 0
+}
+
+/// Returns the offset of the payload within the packet.
+fn payload_offset(packet: &[u8]) -> usize {
+    if has_adaptation_field(packet) {
+        5 + packet[4] as usize
+    } else {
+        4
+    }
+}
+
+/// Returns the offset of the PTS and, if present, of the DTS of the PES header starting in this
+/// packet.
+fn pes_timestamps(packet: &[u8]) -> Vec<usize> {
+    let start = payload_offset(packet);
+    let pes = match packet.get(start..) {
+        Some(x) if packet[1] & 0x40 != 0 && has_payload(packet) => x,
+        _ => return Vec::new(),
+    };
+
+    // packet_start_code_prefix followed by the stream id, and a header which has the optional
+    // fields, which some streams like padding dont have.
+    if pes.len() < 14 || pes[..3] != [0, 0, 1] || pes[6] & 0xC0 != 0x80 {
+        return Vec::new();
+    }
+
+    match pes[7] >> 6 {
+        0b10 => vec![start + 9],
+        0b11 if pes.len() >= 19 => vec![start + 9, start + 14],
+        _ => Vec::new(),
+    }
+}
+
+fn read_timestamp(bytes: &[u8]) -> u64 {
+    ((bytes[0] as u64 >> 1) & 0x07) << 30
+        | (bytes[1] as u64) << 22
+        | (bytes[2] as u64 >> 1) << 15
+        | (bytes[3] as u64) << 7
+        | bytes[4] as u64 >> 1
+}
+
+/// Writes a timestamp while keeping the prefix bits and marker bits in place.
+fn write_timestamp(bytes: &mut [u8], value: u64) {
+    bytes[0] = (bytes[0] & 0xF0) | (((value >> 30) as u8 & 0x07) << 1) | 1;
+    bytes[1] = (value >> 22) as u8;
+    bytes[2] = (((value >> 15) as u8) << 1) | 1;
+    bytes[3] = (value >> 7) as u8;
+    bytes[4] = ((value as u8) << 1) | 1;
+}
+
+/// Returns the offset of the PCR of this packet, if it carries one.
+fn pcr_offset(packet: &[u8]) -> Option<usize> {
+    if has_adaptation_field(packet) && packet[4] >= 7 && packet[5] & 0x10 != 0 {
+        return Some(6);
+    }
+
+    None
+}
+
+fn read_pcr_base(bytes: &[u8]) -> u64 {
+    (bytes[0] as u64) << 25
+        | (bytes[1] as u64) << 17
+        | (bytes[2] as u64) << 9
+        | (bytes[3] as u64) << 1
+        | bytes[4] as u64 >> 7
+}
+
+/// Writes the 33 bit PCR base, the reserved bits and the 9 bit extension are kept as is.
+fn write_pcr_base(bytes: &mut [u8], value: u64) {
+    bytes[0] = (value >> 25) as u8;
+    bytes[1] = (value >> 17) as u8;
+    bytes[2] = (value >> 9) as u8;
+    bytes[3] = (value >> 1) as u8;
+    bytes[4] = (bytes[4] & 0x7F) | (((value & 1) as u8) << 7);
+}
+
+/// Function patches a MPEG-TS chunk in memory, see `patch_ts_chunk`.
+pub fn patch_ts(data: &mut [u8], start: u64, continuity: &mut ContinuityCounters) -> Result<()> {
+    if data.is_empty() || data.len() % PACKET_SIZE != 0 {
+        return Err(NightfallError::SegmentPatchError(
+            "Chunk isnt made of whole TS packets".into(),
+        ));
+    }
+
+    // the earliest presentation time of the chunk decides how much everything gets shifted by.
+    let first = data
+        .chunks(PACKET_SIZE)
+        .flat_map(|packet| {
+            pes_timestamps(packet)
+                .first()
+                .map(|x| read_timestamp(&packet[*x..]))
+        })
+        .min()
+        .ok_or_else(|| {
+            NightfallError::SegmentPatchError("Chunk doesnt contain any timestamps".into())
+        })?;
+    let shift = start.wrapping_sub(first);
+
+    for packet in data.chunks_mut(PACKET_SIZE) {
+        if packet[0] != SYNC_BYTE {
+            return Err(NightfallError::SegmentPatchError(
+                "Lost sync with the TS packets".into(),
+            ));
+        }
+
+        for offset in pes_timestamps(packet) {
+            let value = read_timestamp(&packet[offset..]).wrapping_add(shift) & TIMESTAMP_MASK;
+            write_timestamp(&mut packet[offset..offset + 5], value);
+        }
+
+        if let Some(offset) = pcr_offset(packet) {
+            let value = read_pcr_base(&packet[offset..]).wrapping_add(shift) & TIMESTAMP_MASK;
+            write_pcr_base(&mut packet[offset..offset + 5], value);
+        }
+
+        // the counter only increments on packets carrying a payload.
+        let pid = pid(packet);
+        let counter = match continuity.get(&pid) {
+            Some(last) if has_payload(packet) => (last + 1) & 0x0F,
+            Some(last) => *last,
+            None => packet[3] & 0x0F,
+        };
+
+        packet[3] = (packet[3] & 0xF0) | counter;
+        continuity.insert(pid, counter);
+    }
+
+    Ok(())
+}
+
+/// Function rewrites the timestamps and continuity counters of a MPEG-TS chunk, so that chunks
+/// written by different ffmpeg processes, ex. after a hard seek, play back as a continuous stream
+/// the same way `patch_segment` does for fMP4 segments. PTS, DTS and PCR are shifted so that the
+/// earliest PTS of the chunk equals `start`, and continuity counters pick up where the previous
+/// chunk left off.
+///
+/// # Arguments
+/// * `file` - target input/output file.
+/// * `start` - presentation time the chunk should start at, in `TS_TIMESCALE`.
+/// * `continuity` - counters left by the previous chunk, or `None` if the chunk doesnt follow
+///   the previously patched one.
+///
+/// # Returns
+/// The counters left by this chunk, to be passed along when patching the next chunk.
+pub async fn patch_ts_chunk(
+    file: impl AsRef<Path> + Send + 'static,
+    start: u64,
+    continuity: Option<ContinuityCounters>,
+) -> Result<ContinuityCounters> {
+    spawn_blocking(move || {
+        let mut data = fs::read(&file)?;
+        let mut continuity = continuity.unwrap_or_default();
+
+        patch_ts(&mut data, start, &mut continuity)?;
+        fs::write(&file, data)?;
+
+        Ok(continuity)
+    })
+    .await
+    .unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PID: u16 = 0x100;
+
+    /// Builds a TS packet of `pid`, starting a PES with `pts` and `dts` if given, with the
+    /// adaptation field carrying `pcr` if given.
+    fn packet(pid: u16, counter: u8, pcr: Option<u64>, pts: Option<(u64, Option<u64>)>) -> Vec<u8> {
+        let mut out = vec![SYNC_BYTE, (pid >> 8) as u8, pid as u8, 0x10 | counter];
+
+        if let Some(pcr) = pcr {
+            out[3] |= 0x20;
+            out.extend_from_slice(&[7, 0x10, 0, 0, 0, 0, 0x7E, 0]);
+            write_pcr_base(&mut out[6..11], pcr);
+        }
+
+        if let Some((pts, dts)) = pts {
+            out[1] |= 0x40;
+            let flags = if dts.is_some() { 0xC0 } else { 0x80 };
+            out.extend_from_slice(&[0, 0, 1, 0xE0, 0, 0, 0x80, flags, 10]);
+
+            let start = out.len();
+            out.extend_from_slice(&[0x31, 0, 1, 0, 1, 0x11, 0, 1, 0, 1]);
+            write_timestamp(&mut out[start..start + 5], pts);
+            write_timestamp(&mut out[start + 5..start + 10], dts.unwrap_or(pts));
+        }
+
+        out.resize(PACKET_SIZE, 0xFF);
+        out
+    }
+
+    fn timestamps(packet: &[u8]) -> Vec<u64> {
+        pes_timestamps(packet)
+            .into_iter()
+            .map(|x| read_timestamp(&packet[x..]))
+            .collect()
+    }
+
+    fn pcr(packet: &[u8]) -> Option<u64> {
+        pcr_offset(packet).map(|x| read_pcr_base(&packet[x..]))
+    }
+
+    #[test]
+    fn timestamps_are_shifted_onto_start() {
+        let mut data = [
+            packet(PID, 0, Some(190_000), Some((203_000, Some(199_000)))),
+            packet(PID, 1, None, Some((200_000, None))),
+        ]
+        .concat();
+
+        patch_ts(&mut data, TS_START_OFFSET, &mut ContinuityCounters::new()).unwrap();
+
+        let packets = data.chunks(PACKET_SIZE).collect::<Vec<_>>();
+        assert_eq!(timestamps(packets[0]), vec![129_000, 125_000]);
+        assert_eq!(pcr(packets[0]), Some(116_000));
+        assert_eq!(timestamps(packets[1]), vec![126_000]);
+        assert_eq!(pcr(packets[1]), None);
+
+        // marker bits and the PTS/DTS prefixes are kept.
+        let offsets = pes_timestamps(packets[0]);
+        assert_eq!(packets[0][offsets[0]] & 0xF1, 0x31);
+        assert_eq!(packets[0][offsets[1]] & 0xF1, 0x11);
+    }
+
+    #[test]
+    fn timestamps_wrap_around_33_bits() {
+        let mut data = packet(PID, 0, Some(500), Some((1_000, None)));
+
+        patch_ts(&mut data, 0, &mut ContinuityCounters::new()).unwrap();
+
+        assert_eq!(timestamps(&data), vec![0]);
+        assert_eq!(pcr(&data), Some(TIMESTAMP_MASK + 1 - 500));
+    }
+
+    #[test]
+    fn continuity_counters_carry_over() {
+        let mut adaptation_only = packet(PID, 7, Some(0), None);
+        adaptation_only[3] &= !0x10;
+
+        let mut data = [
+            packet(PID, 5, None, Some((1_000, None))),
+            adaptation_only,
+            packet(PID, 6, None, None),
+            packet(0x101, 9, None, None),
+        ]
+        .concat();
+
+        let mut continuity = ContinuityCounters::new();
+        continuity.insert(PID, 15);
+
+        patch_ts(&mut data, 0, &mut continuity).unwrap();
+
+        let counters = data
+            .chunks(PACKET_SIZE)
+            .map(|x| x[3] & 0x0F)
+            .collect::<Vec<_>>();
+        // packets without a payload dont increment the counter, unseen PIDs keep their own.
+        assert_eq!(counters, vec![0, 0, 1, 9]);
+        assert_eq!(continuity.get(&PID), Some(&1));
+        assert_eq!(continuity.get(&0x101), Some(&9));
+    }
+
+    #[test]
+    fn malformed_chunks_are_rejected() {
+        let mut continuity = ContinuityCounters::new();
+
+        assert!(patch_ts(&mut [], 0, &mut continuity).is_err());
+
+        let mut truncated = packet(PID, 0, None, Some((1_000, None)));
+        truncated.pop();
+        assert!(patch_ts(&mut truncated, 0, &mut continuity).is_err());
+
+        let mut no_timestamps = packet(PID, 0, None, None);
+        assert!(patch_ts(&mut no_timestamps, 0, &mut continuity).is_err());
+
+        let mut lost_sync = [
+            packet(PID, 0, None, Some((1_000, None))),
+            packet(PID, 1, None, None),
+        ]
+        .concat();
+        lost_sync[PACKET_SIZE] = 0;
+        assert!(patch_ts(&mut lost_sync, 0, &mut continuity).is_err());
+    }
+
+    #[tokio::test]
+    async fn chunks_are_patched_in_place() {
+        let path = std::env::temp_dir().join(format!(
+            "nightfall-mpegts-{}.ts",
+            uuid::Uuid::new_v4().hyphenated()
+        ));
+        let data = [
+            packet(PID, 3, None, Some((500_000, None))),
+            packet(PID, 4, None, None),
+        ]
+        .concat();
+        fs::write(&path, &data).unwrap();
+
+        let continuity = patch_ts_chunk(path.clone(), TS_START_OFFSET, None)
+            .await
+            .unwrap();
+
+        let patched = fs::read(&path).unwrap();
+        assert_eq!(timestamps(&patched[..PACKET_SIZE]), vec![TS_START_OFFSET]);
+        // without counters to pick up from, the ones of the chunk are kept.
+        assert_eq!(continuity.get(&PID), Some(&4));
+
+        let _ = fs::remove_file(path);
+    }
+}
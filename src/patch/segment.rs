@@ -1,7 +1,9 @@
 use std::collections::VecDeque;
+use std::convert::TryInto;
 use std::fs::File;
 use std::io::prelude::*;
 use std::io::BufReader;
+use std::io::Cursor;
 use std::io::Seek;
 use std::io::SeekFrom;
 use std::path::Path;
@@ -12,6 +14,7 @@ use crate::Result;
 use tokio::task::spawn_blocking;
 
 use mp4::mp4box::*;
+use mp4::FourCC;
 use tracing::debug;
 
 /// Struct represents an individual segment from a stream.
@@ -89,11 +92,17 @@ impl Segment {
         self.styp.is_some() && self.sidx.is_none() && self.moof.is_none() && self.mdat.is_none()
     }
 
-    /// Method will create a styp box for this segment if it doesnt exist
+    /// Method will create a styp box for this segment if it doesnt exist. The box carries the
+    /// `msdh` and `msix` brands DASH-IF expects on indexed media segments.
     pub fn gen_styp(mut self) -> Self {
         if self.styp.is_none() {
             let mut styp = FtypBox::default();
             styp.box_type = BoxType::StypBox;
+            styp.major_brand = FourCC::from(u32::from_be_bytes(*b"msdh"));
+            styp.compatible_brands = vec![
+                FourCC::from(u32::from_be_bytes(*b"msdh")),
+                FourCC::from(u32::from_be_bytes(*b"msix")),
+            ];
 
             self.styp = Some(styp);
         }
@@ -116,7 +125,10 @@ impl Segment {
         self
     }
 
-    pub fn normalize_dts(mut self) -> Self {
+    /// Sets the base decode time of the segment to its earliest presentation time. When
+    /// `timescale` is supplied the presentation time is rescaled from the timescale of the `sidx`
+    /// box into it, as the decode time is expressed in the timescale of the track.
+    pub fn normalize_dts(mut self, timescale: Option<u32>) -> Self {
         // NOTE: Sometimes the first segment after init.mp4 can be blank, in cases like that we
         // just ignore that moof is empty.
         if let Some(tfdt) = self
@@ -125,32 +137,52 @@ impl Segment {
             .and_then(|x| x.trafs.get_mut(0).and_then(|x| x.tfdt.as_mut()))
         {
             if let Some(sidx) = self.sidx.as_ref() {
-                tfdt.base_media_decode_time = sidx.earliest_presentation_time;
+                tfdt.base_media_decode_time = match timescale {
+                    Some(timescale) if sidx.timescale != 0 && sidx.timescale != timescale => {
+                        (sidx.earliest_presentation_time as u128 * timescale as u128
+                            / sidx.timescale as u128) as u64
+                    }
+                    _ => sidx.earliest_presentation_time,
+                };
             }
         }
 
         self
     }
 
-    pub fn write(self, file: &mut File) -> Result<()> {
+    pub fn write<W: Write>(self, writer: &mut W) -> Result<()> {
         if let Some(styp) = self.styp {
-            styp.write_box(file)?;
+            styp.write_box(writer)?;
         }
 
         if let Some(sidx) = self.sidx {
-            sidx.write_box(file)?;
+            sidx.write_box(writer)?;
         }
 
         if let Some(moof) = self.moof {
-            moof.write_box(file)?;
+            moof.write_box(writer)?;
         }
 
         if let Some(mdat) = self.mdat {
-            mdat.write_box(file)?;
+            mdat.write_box(writer)?;
         }
 
         Ok(())
     }
+
+    /// Applies what `patch_segment` does to every fragment of a segment, `seq` being the
+    /// sequence number of this fragment.
+    fn patched(self, seq: u32, normalize: bool, timescale: Option<u32>) -> Self {
+        // Here we normalize the DTS to be equal to the EPT/PTS and we also set the corrent
+        // segment number.
+        let segment = if normalize {
+            self.normalize_dts(timescale)
+        } else {
+            self
+        };
+
+        segment.gen_styp().set_segno(seq)
+    }
 }
 
 /// Function reads a segment file and patches it so that it is consistent
@@ -159,10 +191,46 @@ impl Segment {
 /// * `log` - logger instance for debugging
 /// * `file` - target input/output file.
 /// * `seq` - starting sequence number.
+/// * `timescale` - timescale of the track if it was pinned with `ProfileContext::target_timescale`,
+///   the decode time then gets reset to the earliest presentation time in that timescale.
 ///
 /// # Returns
 /// This function will return the index of the current segment.
-pub async fn patch_segment(file: impl AsRef<Path> + Send + 'static, mut seq: u32) -> Result<u32> {
+pub async fn patch_segment(
+    file: impl AsRef<Path> + Send + 'static,
+    seq: u32,
+    timescale: Option<u32>,
+) -> Result<u32> {
+    patch(file, seq, timescale.is_some(), timescale).await
+}
+
+/// Function patches a segment produced by a one-off direct play process, see
+/// `StateManager::direct_play_seek`. On top of what `patch_segment` does, the decode time is reset
+/// to the earliest presentation time, as the one-off process starts with its own timeline.
+pub async fn patch_direct_play_segment(
+    file: impl AsRef<Path> + Send + 'static,
+    seq: u32,
+) -> Result<u32> {
+    patch(file, seq, true, None).await
+}
+
+/// Function patches a segment that has already been patched before, see `StateManager::repatch`.
+/// The decode time is reset to the earliest presentation time, rescaled into `timescale`, which
+/// should be the timescale of the track as found in the init segment.
+pub async fn repatch_segment(
+    file: impl AsRef<Path> + Send + 'static,
+    seq: u32,
+    timescale: Option<u32>,
+) -> Result<u32> {
+    patch(file, seq, true, timescale).await
+}
+
+async fn patch(
+    file: impl AsRef<Path> + Send + 'static,
+    mut seq: u32,
+    normalize: bool,
+    timescale: Option<u32>,
+) -> Result<u32> {
     spawn_blocking(move || {
         let f = File::open(&file)?;
         let size = f.metadata()?.len();
@@ -188,12 +256,7 @@ pub async fn patch_segment(file: impl AsRef<Path> + Send + 'static, mut seq: u32
 
         let mut f = File::create(&file)?;
         while let Some(segment) = segments.pop_front() {
-            // Here we normalize the DTS to be equal to the EPT/PTS and we also set the corrent
-            // segment number.
-            segment
-                .gen_styp()
-                .set_segno(seq)
-                .write(&mut f)?;
+            segment.patched(seq, normalize, timescale).write(&mut f)?;
 
             seq += 1;
         }
@@ -203,3 +266,221 @@ pub async fn patch_segment(file: impl AsRef<Path> + Send + 'static, mut seq: u32
     .await
     .unwrap()
 }
+
+/// Function patches a single fragment of a segment ffmpeg is still writing, as found by
+/// `fragment_ranges`, the same way `patch_segment` patches it once the segment is complete.
+/// `seq` is the sequence number of the fragment, ie. the one the segment is patched with plus
+/// the index of the fragment.
+pub fn patch_fragment(data: &[u8], seq: u32, timescale: Option<u32>) -> Result<Vec<u8>> {
+    let (segment, _) = Segment::from_reader(Cursor::new(data), data.len() as u64)?;
+    let mut out = Vec::with_capacity(data.len() + 32);
+
+    segment
+        .patched(seq, timescale.is_some(), timescale)
+        .write(&mut out)?;
+
+    Ok(out)
+}
+
+/// Function returns the byte ranges of the complete fragments of a segment, as pairs of offset
+/// and length. Every range ends with a `mdat` box and starts right after the previous one, thus
+/// the first range also contains the `styp` and `sidx` boxes. Used to hand out the parts of a
+/// segment ffmpeg is still writing, the trailing incomplete fragment is ignored.
+pub fn fragment_ranges(data: &[u8]) -> Vec<(u64, u64)> {
+    walk_fragments(data.len() as u64, |pos, header| {
+        match data.get(pos as usize..pos as usize + header.len()) {
+            Some(x) => {
+                header.copy_from_slice(x);
+                true
+            }
+            None => false,
+        }
+    })
+}
+
+/// Same as `fragment_ranges` but only reads the box headers of `file`, rather than the whole
+/// segment.
+pub fn file_fragment_ranges(file: &mut File) -> Vec<(u64, u64)> {
+    let len = match file.metadata() {
+        Ok(x) => x.len(),
+        Err(_) => return Vec::new(),
+    };
+
+    walk_fragments(len, |pos, header| {
+        file.seek(SeekFrom::Start(pos)).is_ok() && file.read_exact(header).is_ok()
+    })
+}
+
+/// Walks the top level boxes of a segment of `len` bytes, `read` fills the buffer with the bytes
+/// found at the given offset or returns `false` if there arent enough.
+fn walk_fragments(len: u64, mut read: impl FnMut(u64, &mut [u8]) -> bool) -> Vec<(u64, u64)> {
+    let mut ranges = Vec::new();
+    let mut start = 0;
+    let mut pos = 0;
+    let mut header = [0; 16];
+
+    while pos + 8 <= len && read(pos, &mut header[..8]) {
+        let size = match u32::from_be_bytes(header[..4].try_into().unwrap()) {
+            // the box extends to the end of the file, thus isnt complete yet.
+            0 => break,
+            1 if pos + 16 <= len && read(pos + 8, &mut header[8..16]) => {
+                u64::from_be_bytes(header[8..16].try_into().unwrap())
+            }
+            1 => break,
+            x => x as u64,
+        };
+
+        if size < 8 || pos + size > len {
+            break;
+        }
+
+        pos += size;
+
+        if &header[4..8] == b"mdat" {
+            ranges.push((start, pos - start));
+            start = pos;
+        }
+    }
+
+    ranges
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn segment(sidx_timescale: u32, earliest_presentation_time: u64) -> Segment {
+        Segment {
+            sidx: Some(SidxBox {
+                timescale: sidx_timescale,
+                earliest_presentation_time,
+                ..Default::default()
+            }),
+            moof: Some(MoofBox {
+                trafs: vec![TrafBox {
+                    tfdt: Some(TfdtBox::default()),
+                    ..Default::default()
+                }],
+                ..Default::default()
+            }),
+            ..Default::default()
+        }
+    }
+
+    fn decode_time(segment: &Segment) -> u64 {
+        segment.moof.as_ref().unwrap().trafs[0]
+            .tfdt
+            .as_ref()
+            .unwrap()
+            .base_media_decode_time
+    }
+
+    fn plain_box(kind: &[u8; 4], len: usize) -> Vec<u8> {
+        let mut out = ((8 + len) as u32).to_be_bytes().to_vec();
+        out.extend_from_slice(kind);
+        out.resize(8 + len, 0xAB);
+        out
+    }
+
+    #[test]
+    fn fragment_ranges_end_with_complete_mdat_boxes() {
+        let data = [
+            plain_box(b"styp", 16),
+            plain_box(b"sidx", 32),
+            plain_box(b"moof", 100),
+            plain_box(b"mdat", 400),
+            plain_box(b"moof", 80),
+            plain_box(b"mdat", 200),
+        ]
+        .concat();
+
+        assert_eq!(fragment_ranges(&data), vec![(0, 580), (580, 296)]);
+
+        // the fragment ffmpeg is still writing is left out.
+        let partial = [
+            &data[..],
+            &plain_box(b"moof", 80),
+            &plain_box(b"mdat", 200)[..50],
+        ]
+        .concat();
+        assert_eq!(fragment_ranges(&partial), fragment_ranges(&data));
+
+        // so is a box of unknown size.
+        let mut unsized_box = plain_box(b"mdat", 20);
+        unsized_box[..4].copy_from_slice(&0u32.to_be_bytes());
+        assert_eq!(
+            fragment_ranges(&[&data[..], &unsized_box].concat()),
+            fragment_ranges(&data)
+        );
+
+        assert!(fragment_ranges(&data[..10]).is_empty());
+    }
+
+    #[test]
+    fn fragment_ranges_read_large_sizes() {
+        let mut mdat = plain_box(b"mdat", 8 + 40);
+        mdat[..4].copy_from_slice(&1u32.to_be_bytes());
+        mdat[8..16].copy_from_slice(&56u64.to_be_bytes());
+
+        let data = [plain_box(b"moof", 24), mdat].concat();
+
+        assert_eq!(fragment_ranges(&data), vec![(0, 88)]);
+    }
+
+    #[test]
+    fn file_fragment_ranges_match_fragment_ranges() {
+        let data = [
+            plain_box(b"moof", 100),
+            plain_box(b"mdat", 400),
+            plain_box(b"moof", 100),
+            plain_box(b"mdat", 400)[..100].to_vec(),
+        ]
+        .concat();
+
+        let path = std::env::temp_dir().join(format!(
+            "nightfall-fragments-{}.m4s",
+            uuid::Uuid::new_v4().hyphenated()
+        ));
+        std::fs::write(&path, &data).unwrap();
+
+        let mut file = File::open(&path).unwrap();
+        assert_eq!(file_fragment_ranges(&mut file), fragment_ranges(&data));
+        assert_eq!(file_fragment_ranges(&mut file), vec![(0, 516)]);
+
+        let _ = std::fs::remove_file(path);
+    }
+
+    #[test]
+    fn patch_fragment_patches_like_patch_segment() {
+        let mut fragment = segment(1000, 5000);
+        fragment.moof.as_mut().unwrap().mfhd.sequence_number = 3;
+        fragment.mdat = Some(MdatBox {
+            data: vec![1, 2, 3, 4],
+            ..Default::default()
+        });
+
+        let mut data = Vec::new();
+        fragment.write(&mut data).unwrap();
+
+        let patched = patch_fragment(&data, 42, Some(90000)).unwrap();
+        let (patched, _) =
+            Segment::from_reader(Cursor::new(&patched[..]), patched.len() as u64).unwrap();
+
+        assert!(patched.styp.is_some());
+        assert_eq!(patched.moof.as_ref().unwrap().mfhd.sequence_number, 42);
+        assert_eq!(decode_time(&patched), 450_000);
+        assert_eq!(patched.mdat.unwrap().data, vec![1, 2, 3, 4]);
+    }
+
+    #[test]
+    fn normalize_dts_rescales_into_pinned_timescale() {
+        let patched = segment(1000, 5000).normalize_dts(Some(90000));
+        assert_eq!(decode_time(&patched), 450_000);
+
+        let patched = segment(90000, 450_000).normalize_dts(Some(90000));
+        assert_eq!(decode_time(&patched), 450_000);
+
+        let patched = segment(1000, 5000).normalize_dts(None);
+        assert_eq!(decode_time(&patched), 5000);
+    }
+}
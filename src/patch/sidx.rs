@@ -0,0 +1,457 @@
+use std::convert::TryFrom;
+use std::fs;
+use std::fs::File;
+use std::io;
+use std::io::prelude::*;
+use std::io::BufReader;
+use std::io::BufWriter;
+use std::io::Seek;
+use std::io::SeekFrom;
+use std::path::Path;
+
+use crate::native_probe::be_u32;
+use crate::native_probe::boxes;
+use crate::native_probe::child;
+use crate::native_probe::timescale_and_duration;
+use crate::NightfallError;
+use crate::Result;
+
+use tokio::task::spawn_blocking;
+
+use mp4::mp4box::*;
+
+/// Byte-range index of a single fragmented mp4 file, used to build a `<SegmentBase>` manifest.
+#[derive(Clone, Debug, Default)]
+pub struct SegmentIndex {
+    /// Path of the indexed file.
+    pub path: String,
+    /// Timescale all the times in this index are expressed in.
+    pub timescale: u32,
+    /// Inclusive byte range of the `ftyp` and `moov` boxes.
+    pub init_range: (u64, u64),
+    /// Inclusive byte range of the `sidx` box.
+    pub index_range: (u64, u64),
+    pub subsegments: Vec<Subsegment>,
+}
+
+/// A single fragment (`moof` + `mdat`) of the file.
+#[derive(Clone, Debug, Default)]
+pub struct Subsegment {
+    pub offset: u64,
+    pub size: u64,
+    pub start: u64,
+    pub duration: u64,
+}
+
+/// A fragment as found in the file before we insert the `sidx` box.
+struct Fragment {
+    offset: u64,
+    decode_time: u64,
+    duration: Option<u64>,
+}
+
+/// A track as described by the `moov` box.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(super) struct Track {
+    pub id: u32,
+    pub timescale: u32,
+    pub is_video: bool,
+}
+
+/// Function indexes a single fragmented mp4 file and inserts a `sidx` box describing all of its
+/// fragments right after the `moov` box. Any top-level `sidx` box already present is replaced.
+///
+/// # Arguments
+/// * `file` - target input/output file.
+///
+/// # Returns
+/// The byte ranges of the init segment, the index and of every fragment in the patched file.
+pub async fn index_single_file(file: impl AsRef<Path> + Send + 'static) -> Result<SegmentIndex> {
+    spawn_blocking(move || {
+        let path = file.as_ref();
+        let f = File::open(path)?;
+        let size = f.metadata()?.len();
+        let mut reader = BufReader::new(f);
+
+        let mut moov_end = None;
+        let mut reference = None;
+        let mut first_moof = None;
+        let mut fragments = Vec::new();
+        let mut current = reader.stream_position()?;
+
+        while current < size {
+            let BoxHeader { name, size: s } = BoxHeader::read(&mut reader)?;
+
+            match name {
+                BoxType::MoovBox => {
+                    let mut moov = vec![0; (s - 8) as usize];
+                    reader.read_exact(moov.as_mut_slice())?;
+
+                    // the index describes the video track, or the first track of audio only
+                    // files.
+                    let tracks = moov_tracks(&moov);
+                    reference = tracks
+                        .iter()
+                        .find(|x| x.is_video)
+                        .or_else(|| tracks.first())
+                        .copied();
+                    moov_end = Some(current + s);
+                }
+                BoxType::MoofBox => {
+                    let moof = MoofBox::read_box(&mut reader, s)?;
+                    let track = reference.ok_or(NightfallError::MissingSegmentBox)?;
+                    first_moof.get_or_insert(current);
+
+                    // fragments without samples of the indexed track belong to the previous
+                    // subsegment.
+                    let mut trafs = moof
+                        .trafs
+                        .iter()
+                        .filter(|x| x.tfhd.track_id == track.id)
+                        .peekable();
+
+                    let decode_time = match trafs.peek() {
+                        Some(traf) => traf.tfdt.as_ref().map_or(0, |x| x.base_media_decode_time),
+                        None => {
+                            current = reader.stream_position()?;
+                            continue;
+                        }
+                    };
+
+                    let duration = trafs
+                        .filter_map(|x| x.trun.as_ref())
+                        .flat_map(|x| x.sample_durations.iter())
+                        .map(|x| *x as u64)
+                        .sum::<u64>();
+
+                    fragments.push(Fragment {
+                        offset: current,
+                        decode_time,
+                        duration: Some(duration).filter(|x| *x > 0),
+                    });
+                }
+                _ => {
+                    skip_box(&mut reader, s)?;
+                }
+            }
+
+            current = reader.stream_position()?;
+        }
+
+        let moov_end = moov_end.ok_or(NightfallError::MissingSegmentBox)?;
+        let track = reference.ok_or(NightfallError::MissingSegmentBox)?;
+        let first_offset = first_moof.ok_or(NightfallError::MissingSegmentBox)?;
+        let first = fragments.first().ok_or(NightfallError::MissingSegmentBox)?;
+
+        let mut subsegments = Vec::new();
+        for (idx, fragment) in fragments.iter().enumerate() {
+            let next = fragments.get(idx + 1);
+            let end = next.map_or(size, |x| x.offset);
+            // leading fragments of the other tracks go along with the first subsegment.
+            let offset = if idx == 0 {
+                first_offset
+            } else {
+                fragment.offset
+            };
+
+            // trun durations are only present when the samples dont all share the default
+            // duration, so we fall back to the distance between the decode times.
+            let duration = fragment
+                .duration
+                .or_else(|| next.map(|x| x.decode_time.saturating_sub(fragment.decode_time)))
+                .or_else(|| subsegments.last().map(|x: &Subsegment| x.duration))
+                .unwrap_or(0);
+
+            subsegments.push(Subsegment {
+                offset,
+                size: end - offset,
+                start: fragment.decode_time,
+                duration,
+            });
+        }
+
+        let sidx = build_sidx(track.id, track.timescale, first.decode_time, &subsegments)?;
+        let sidx_len = sidx.len() as u64;
+
+        // Everything between the end of the `moov` box and the first fragment (old `sidx` boxes,
+        // `free` boxes) gets dropped, thus fragments start right after our `sidx` box.
+        let shift = moov_end + sidx_len;
+
+        let tmp = path.with_extension("mp4.tmp");
+        {
+            let mut reader = BufReader::new(File::open(path)?);
+            let mut writer = BufWriter::new(File::create(&tmp)?);
+
+            io::copy(&mut (&mut reader).take(moov_end), &mut writer)?;
+            writer.write_all(&sidx)?;
+            reader.seek(SeekFrom::Start(first_offset))?;
+            io::copy(&mut reader, &mut writer)?;
+            writer.flush()?;
+        }
+        fs::rename(&tmp, path)?;
+
+        for subsegment in subsegments.iter_mut() {
+            subsegment.offset = subsegment.offset - first_offset + shift;
+        }
+
+        Ok(SegmentIndex {
+            path: path.to_string_lossy().to_string(),
+            timescale: track.timescale,
+            init_range: (0, moov_end - 1),
+            index_range: (moov_end, moov_end + sidx_len - 1),
+            subsegments,
+        })
+    })
+    .await
+    .unwrap()
+}
+
+/// Function returns the tracks described by the payload of a `moov` box, in order. Tracks
+/// missing their `tkhd`, `mdhd` or `hdlr` box are left out.
+pub(super) fn moov_tracks(moov: &[u8]) -> Vec<Track> {
+    boxes(moov)
+        .filter(|(kind, _)| *kind == b"trak")
+        .filter_map(|(_, trak)| {
+            let tkhd = child(trak, b"tkhd")?;
+            let mdia = child(trak, b"mdia")?;
+            // the creation and modification times in front of the id are 64 bit in version 1.
+            let id = match tkhd.first()? {
+                1 => be_u32(tkhd, 20)?,
+                _ => be_u32(tkhd, 12)?,
+            };
+            let (timescale, _) = timescale_and_duration(child(mdia, b"mdhd")?)?;
+
+            Some(Track {
+                id,
+                timescale,
+                is_video: child(mdia, b"hdlr")?.get(8..12)? == b"vide",
+            })
+        })
+        .collect()
+}
+
+/// Function returns the timescale of the first track described by the payload of a `moov` box.
+pub(super) fn mdhd_timescale(moov: &[u8]) -> Option<u32> {
+    moov_tracks(moov).first().map(|x| x.timescale)
+}
+
+/// Function serializes a version 1 `sidx` box referencing `subsegments`, all of which are assumed
+/// to start with a keyframe.
+fn build_sidx(
+    track_id: u32,
+    timescale: u32,
+    earliest_presentation_time: u64,
+    subsegments: &[Subsegment],
+) -> Result<Vec<u8>> {
+    let size = 8 + 4 + 4 + 4 + 8 + 8 + 2 + 2 + 12 * subsegments.len();
+    let mut sidx = Vec::with_capacity(size);
+
+    sidx.extend_from_slice(&(size as u32).to_be_bytes());
+    sidx.extend_from_slice(b"sidx");
+    // version 1, no flags
+    sidx.extend_from_slice(&(1u32 << 24).to_be_bytes());
+    sidx.extend_from_slice(&track_id.to_be_bytes());
+    sidx.extend_from_slice(&timescale.to_be_bytes());
+    sidx.extend_from_slice(&earliest_presentation_time.to_be_bytes());
+    // first_offset, fragments directly follow this box.
+    sidx.extend_from_slice(&0u64.to_be_bytes());
+    // reserved
+    sidx.extend_from_slice(&0u16.to_be_bytes());
+    sidx.extend_from_slice(&(subsegments.len() as u16).to_be_bytes());
+
+    for subsegment in subsegments {
+        let (size, duration) = match (
+            u32::try_from(subsegment.size),
+            u32::try_from(subsegment.duration),
+        ) {
+            (Ok(size), Ok(duration)) if size < 1 << 31 => (size, duration),
+            _ => {
+                return Err(NightfallError::SegmentPatchError(
+                    "Fragment too large to be referenced by a sidx box.".into(),
+                ))
+            }
+        };
+
+        // reference_type = 0 (media)
+        sidx.extend_from_slice(&size.to_be_bytes());
+        sidx.extend_from_slice(&duration.to_be_bytes());
+        // starts_with_SAP = 1, SAP_type = 1, SAP_delta_time = 0
+        sidx.extend_from_slice(&0x9000_0000u32.to_be_bytes());
+    }
+
+    Ok(sidx)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::convert::TryInto;
+
+    fn plain_box(kind: &[u8; 4], payload: &[u8]) -> Vec<u8> {
+        let mut out = ((8 + payload.len()) as u32).to_be_bytes().to_vec();
+        out.extend_from_slice(kind);
+        out.extend_from_slice(payload);
+        out
+    }
+
+    fn trak(id: u32, timescale: u32, handler: &[u8; 4]) -> Vec<u8> {
+        let mut tkhd = vec![0; 84];
+        tkhd[12..16].copy_from_slice(&id.to_be_bytes());
+        let mut mdhd = vec![0; 24];
+        mdhd[12..16].copy_from_slice(&timescale.to_be_bytes());
+        let mut hdlr = vec![0; 25];
+        hdlr[8..12].copy_from_slice(handler);
+
+        let mdia = [plain_box(b"mdhd", &mdhd), plain_box(b"hdlr", &hdlr)].concat();
+        plain_box(
+            b"trak",
+            &[plain_box(b"tkhd", &tkhd), plain_box(b"mdia", &mdia)].concat(),
+        )
+    }
+
+    fn traf(track_id: u32, decode_time: u64, durations: Vec<u32>) -> TrafBox {
+        TrafBox {
+            tfhd: TfhdBox {
+                track_id,
+                ..Default::default()
+            },
+            tfdt: Some(TfdtBox {
+                base_media_decode_time: decode_time,
+                ..Default::default()
+            }),
+            trun: Some(TrunBox {
+                // sample-duration-present
+                flags: 0x100,
+                sample_count: durations.len() as u32,
+                sample_durations: durations,
+                ..Default::default()
+            }),
+            ..Default::default()
+        }
+    }
+
+    fn fragment(trafs: Vec<TrafBox>, len: usize) -> Vec<u8> {
+        let mut out = Vec::new();
+
+        MoofBox {
+            trafs,
+            ..Default::default()
+        }
+        .write_box(&mut out)
+        .unwrap();
+        MdatBox {
+            data: vec![0xAB; len],
+            ..Default::default()
+        }
+        .write_box(&mut out)
+        .unwrap();
+
+        out
+    }
+
+    fn be_u32_at(data: &[u8], at: usize) -> u32 {
+        u32::from_be_bytes(data[at..at + 4].try_into().unwrap())
+    }
+
+    #[test]
+    fn tracks_are_read_from_the_box_tree() {
+        let moov = [trak(1, 48000, b"soun"), trak(2, 90000, b"vide")].concat();
+        // a `mdhd` tag inside of an unrelated box doesnt count.
+        let moov = [plain_box(b"udta", b"mdhd\x00\x00\x00\x00"), moov].concat();
+
+        assert_eq!(
+            moov_tracks(&moov),
+            vec![
+                Track {
+                    id: 1,
+                    timescale: 48000,
+                    is_video: false,
+                },
+                Track {
+                    id: 2,
+                    timescale: 90000,
+                    is_video: true,
+                },
+            ]
+        );
+        assert_eq!(mdhd_timescale(&moov), Some(48000));
+    }
+
+    #[tokio::test]
+    async fn index_describes_the_video_track() {
+        let ftyp = plain_box(b"ftyp", b"isom\x00\x00\x02\x00isomiso6");
+        // the audio track comes first, as do its samples in every fragment.
+        let moov = plain_box(
+            b"moov",
+            &[trak(1, 48000, b"soun"), trak(2, 90000, b"vide")].concat(),
+        );
+        let first = fragment(
+            vec![traf(1, 0, vec![1024; 94]), traf(2, 0, vec![3750; 48])],
+            300,
+        );
+        let second = fragment(
+            vec![
+                traf(1, 96256, vec![1024; 94]),
+                traf(2, 180_000, vec![3750; 24]),
+            ],
+            200,
+        );
+        let audio_only = fragment(vec![traf(1, 192_512, vec![1024; 10])], 50);
+        let fragments = [first.clone(), second.clone(), audio_only.clone()].concat();
+
+        let path = std::env::temp_dir().join(format!(
+            "nightfall-sidx-{}.mp4",
+            uuid::Uuid::new_v4().hyphenated()
+        ));
+        let data = [
+            ftyp.clone(),
+            moov.clone(),
+            plain_box(b"free", &[0; 16]),
+            fragments.clone(),
+        ]
+        .concat();
+        fs::write(&path, data).unwrap();
+
+        let index = index_single_file(path.clone()).await.unwrap();
+        let init_len = (ftyp.len() + moov.len()) as u64;
+        let base = init_len + 40 + 2 * 12;
+
+        assert_eq!(index.timescale, 90000);
+        assert_eq!(index.init_range, (0, init_len - 1));
+        assert_eq!(index.index_range, (init_len, base - 1));
+        assert_eq!(
+            index
+                .subsegments
+                .iter()
+                .map(|x| (x.offset, x.size, x.start, x.duration))
+                .collect::<Vec<_>>(),
+            vec![
+                (base, first.len() as u64, 0, 180_000),
+                (
+                    base + first.len() as u64,
+                    (second.len() + audio_only.len()) as u64,
+                    180_000,
+                    90000
+                ),
+            ]
+        );
+
+        // the `free` box is dropped and the `sidx` box references the same ranges.
+        let patched = fs::read(&path).unwrap();
+        let sidx = &patched[init_len as usize..base as usize];
+        assert_eq!(&sidx[4..8], b"sidx");
+        assert_eq!(be_u32_at(sidx, 12), 2);
+        assert_eq!(be_u32_at(sidx, 16), 90000);
+        assert_eq!(be_u32_at(sidx, 40), first.len() as u32);
+        assert_eq!(be_u32_at(sidx, 44), 180_000);
+        assert_eq!(
+            be_u32_at(sidx, 52),
+            (second.len() + audio_only.len()) as u32
+        );
+        assert_eq!(be_u32_at(sidx, 56), 90000);
+        assert_eq!(&patched[base as usize..], &fragments[..]);
+
+        let _ = fs::remove_file(path);
+    }
+}
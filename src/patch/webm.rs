@@ -0,0 +1,223 @@
+use std::fs;
+use std::path::Path;
+
+use crate::NightfallError;
+use crate::Result;
+
+use tokio::task::spawn_blocking;
+
+const SEGMENT_ID: u32 = 0x1853_8067;
+const INFO_ID: u32 = 0x1549_A966;
+const TIMESTAMP_SCALE_ID: u32 = 0x2A_D7B1;
+const CLUSTER_ID: u32 = 0x1F43_B675;
+const TIMESTAMP_ID: u32 = 0xE7;
+
+/// Timestamp scale matroska assumes when the header doesnt specify one, in nanoseconds.
+pub const DEFAULT_TIMESTAMP_SCALE: u64 = 1_000_000;
+
+/// Minimal cursor over EBML encoded data, just enough to walk the elements of a WebM chunk.
+pub(crate) struct Cursor<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Cursor<'a> {
+    pub(crate) fn new(data: &'a [u8]) -> Self {
+        Self { data, pos: 0 }
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.pos >= self.data.len()
+    }
+
+    pub(crate) fn take(&mut self, len: usize) -> Result<&'a [u8]> {
+        let end = self.pos.checked_add(len);
+        let bytes = end
+            .and_then(|end| self.data.get(self.pos..end))
+            .ok_or_else(|| NightfallError::SegmentPatchError("Truncated EBML element".into()))?;
+        self.pos += len;
+
+        Ok(bytes)
+    }
+
+    /// Reads a variable length integer, returning its length in bytes and its value with the
+    /// length marker stripped.
+    pub(crate) fn vint(&mut self) -> Result<(usize, u64)> {
+        let first = self.take(1)?[0];
+        let len = first.leading_zeros() as usize + 1;
+
+        if len > 8 {
+            return Err(NightfallError::SegmentPatchError(
+                "Invalid EBML variable length integer".into(),
+            ));
+        }
+
+        let mut value = (first as u64) & (0xFF >> len);
+        for byte in self.take(len - 1)? {
+            value = (value << 8) | *byte as u64;
+        }
+
+        Ok((len, value))
+    }
+
+    /// Reads an element id, ids keep their length marker.
+    pub(crate) fn id(&mut self) -> Result<u32> {
+        let start = self.pos;
+        let (len, _) = self.vint()?;
+
+        Ok(self.data[start..start + len]
+            .iter()
+            .fold(0, |acc, x| (acc << 8) | *x as u32))
+    }
+
+    /// Peeks at the id of the next element without consuming it.
+    pub(crate) fn peek_id(&mut self) -> Result<u32> {
+        let start = self.pos;
+        let id = self.id();
+        self.pos = start;
+
+        id
+    }
+
+    /// Reads the size of an element, `None` stands for an unknown size.
+    pub(crate) fn size(&mut self) -> Result<Option<u64>> {
+        let (len, value) = self.vint()?;
+
+        if value == (1 << (7 * len)) - 1 {
+            return Ok(None);
+        }
+
+        Ok(Some(value))
+    }
+}
+
+pub(crate) fn read_uint(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0, |acc, x| (acc << 8) | *x as u64)
+}
+
+fn write_id(out: &mut Vec<u8>, id: u32) {
+    let bytes = id.to_be_bytes();
+    let skip = bytes.iter().take_while(|x| **x == 0).count();
+    out.extend_from_slice(&bytes[skip..]);
+}
+
+/// Sizes are always written on 8 bytes so that we dont have to care about their length.
+fn write_size(out: &mut Vec<u8>, size: u64) {
+    out.push(0x01);
+    out.extend_from_slice(&size.to_be_bytes()[1..]);
+}
+
+fn write_uint_element(out: &mut Vec<u8>, id: u32, value: u64) {
+    let bytes = value.to_be_bytes();
+    let skip = bytes.iter().take_while(|x| **x == 0).count().min(7);
+
+    write_id(out, id);
+    write_size(out, (8 - skip) as u64);
+    out.extend_from_slice(&bytes[skip..]);
+}
+
+/// Function returns the timestamp scale in nanoseconds of a WebM header, the timestamps of the
+/// clusters are expressed in this unit.
+pub fn header_timestamp_scale(header: impl AsRef<Path>) -> Result<u64> {
+    let data = fs::read(header)?;
+    let mut cursor = Cursor::new(&data);
+
+    while !cursor.is_empty() {
+        let id = cursor.id()?;
+        let size = cursor.size()?;
+
+        match (id, size) {
+            // we only have to descend into the segment and its info element.
+            (SEGMENT_ID, _) | (INFO_ID, _) => continue,
+            (TIMESTAMP_SCALE_ID, Some(size)) => return Ok(read_uint(cursor.take(size as usize)?)),
+            (CLUSTER_ID, _) => break,
+            (_, Some(size)) => {
+                cursor.take(size as usize)?;
+            }
+            (_, None) => break,
+        }
+    }
+
+    Ok(DEFAULT_TIMESTAMP_SCALE)
+}
+
+/// Function rewrites the cluster timestamps of a WebM chunk so that the chunk starts at `start`.
+/// Chunks written by different ffmpeg processes, ex. after a hard seek, dont necessarily share a
+/// timeline, shifting every chunk onto its expected position makes them continuous the same way
+/// `patch_segment` does for fMP4 segments. Blocks are timed relative to their cluster, thus only
+/// the clusters have to be touched.
+///
+/// # Arguments
+/// * `file` - target input/output file.
+/// * `start` - timestamp the first cluster should have, in the timestamp scale of the header, see
+///   `header_timestamp_scale`.
+pub async fn patch_webm_chunk(file: impl AsRef<Path> + Send + 'static, start: u64) -> Result<()> {
+    spawn_blocking(move || {
+        let data = fs::read(&file)?;
+        let mut cursor = Cursor::new(&data);
+        let mut out = Vec::with_capacity(data.len());
+        let mut shift = None;
+
+        while !cursor.is_empty() {
+            let element_start = cursor.pos;
+            let id = cursor.id()?;
+            let size = cursor.size()?;
+
+            if id != CLUSTER_ID {
+                let size = size.ok_or_else(|| {
+                    NightfallError::SegmentPatchError("Unknown sized element in chunk".into())
+                })?;
+
+                cursor.take(size as usize)?;
+                out.extend_from_slice(&data[element_start..cursor.pos]);
+                continue;
+            }
+
+            // clusters of unknown size last until the next cluster or the end of the chunk.
+            let end = size.map(|x| cursor.pos + x as usize);
+            let mut children = Vec::new();
+
+            while !cursor.is_empty() && end.is_none_or(|x| cursor.pos < x) {
+                if end.is_none() && cursor.peek_id()? == CLUSTER_ID {
+                    break;
+                }
+
+                let child_start = cursor.pos;
+                let child_id = cursor.id()?;
+                let child_size = cursor.size()?.ok_or_else(|| {
+                    NightfallError::SegmentPatchError("Unknown sized element in cluster".into())
+                })?;
+                let value = cursor.take(child_size as usize)?;
+
+                if child_id == TIMESTAMP_ID {
+                    let timestamp = read_uint(value) as i128;
+                    let shift = *shift.get_or_insert(start as i128 - timestamp);
+
+                    write_uint_element(
+                        &mut children,
+                        TIMESTAMP_ID,
+                        (timestamp + shift).max(0) as u64,
+                    );
+                } else {
+                    children.extend_from_slice(&data[child_start..cursor.pos]);
+                }
+            }
+
+            write_id(&mut out, CLUSTER_ID);
+            write_size(&mut out, children.len() as u64);
+            out.append(&mut children);
+        }
+
+        if shift.is_none() {
+            return Err(NightfallError::SegmentPatchError(
+                "Chunk doesnt contain any clusters".into(),
+            ));
+        }
+
+        fs::write(&file, out)?;
+
+        Ok(())
+    })
+    .await
+    .unwrap()
+}
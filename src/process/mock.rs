@@ -0,0 +1,945 @@
+//! A `ProcessSpawner` which simulates ffmpeg without spawning anything, so that the session
+//! logic can be exercised deterministically.
+use super::Output;
+use super::Process;
+use super::ProcessOutput;
+use super::ProcessSpawner;
+use super::SpawnCommand;
+
+use std::collections::VecDeque;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::process::ExitStatus;
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use async_trait::async_trait;
+use tokio::io::AsyncWriteExt;
+use tokio::io::DuplexStream;
+use tokio::sync::watch;
+
+/// Scripted behaviour of a single simulated ffmpeg run.
+#[derive(Clone, Debug)]
+pub struct MockRun {
+    /// How many segments get written before the process exits.
+    pub segments: u32,
+    /// Wall clock time it takes to produce a single segment.
+    pub segment_time: Duration,
+    /// Frames reported on stdout for every segment written.
+    pub frames_per_segment: u64,
+    /// Exit code reported once every segment has been written.
+    pub exit_code: i32,
+    /// Fail the spawn itself, as if the binary couldnt be executed.
+    pub spawn_error: bool,
+    /// Bytes logged on stderr for every segment written. Just like with a real pipe, the process
+    /// stalls once a few KiB havent been read.
+    pub stderr_per_segment: usize,
+    /// Duration in seconds listed for every segment in the playlist of the hls muxer.
+    pub segment_duration: f64,
+}
+
+impl Default for MockRun {
+    fn default() -> Self {
+        Self {
+            segments: 10,
+            segment_time: Duration::from_millis(10),
+            frames_per_segment: 120,
+            exit_code: 0,
+            spawn_error: false,
+            stderr_per_segment: 0,
+            segment_duration: 5.0,
+        }
+    }
+}
+
+/// Simulates ffmpeg by writing empty init and media segments where the hls muxer would have put
+/// them, and reporting progress on stdout. Runs pushed with `push_run` are used in spawn order,
+/// once they run out every process behaves like `fallback`.
+#[derive(Clone, Debug, Default)]
+pub struct MockSpawner {
+    pub fallback: MockRun,
+    runs: Arc<Mutex<VecDeque<MockRun>>>,
+    spawned: Arc<Mutex<Vec<Vec<String>>>>,
+}
+
+impl MockSpawner {
+    pub fn new(fallback: MockRun) -> Self {
+        Self {
+            fallback,
+            ..Default::default()
+        }
+    }
+
+    /// Queues the behaviour of the next spawned process.
+    pub fn push_run(&self, run: MockRun) {
+        self.runs.lock().unwrap().push_back(run);
+    }
+
+    /// Returns the arguments of every process spawned so far.
+    pub fn spawned(&self) -> Vec<Vec<String>> {
+        self.spawned.lock().unwrap().clone()
+    }
+}
+
+impl ProcessSpawner for MockSpawner {
+    fn spawn(&self, command: SpawnCommand) -> io::Result<Box<dyn Process>> {
+        let run = self
+            .runs
+            .lock()
+            .unwrap()
+            .pop_front()
+            .unwrap_or_else(|| self.fallback.clone());
+
+        self.spawned.lock().unwrap().push(command.args.clone());
+
+        if run.spawn_error {
+            return Err(io::Error::from(io::ErrorKind::NotFound));
+        }
+
+        let state = Arc::new(MockState {
+            status: watch::channel(None).0,
+            paused: AtomicBool::new(false),
+            killed: AtomicBool::new(false),
+        });
+
+        let (stdout, writer) = match command.stdout {
+            Output::Piped => {
+                let (reader, writer) = tokio::io::duplex(4096);
+                (Some(Box::new(reader) as ProcessOutput), Some(writer))
+            }
+            _ => (None, None),
+        };
+
+        let (stderr, stderr_writer) = match command.stderr {
+            Output::Piped => {
+                let (reader, writer) = tokio::io::duplex(4096);
+                (Some(Box::new(reader) as ProcessOutput), Some(writer))
+            }
+            _ => (None, None),
+        };
+
+        tokio::spawn(simulate(
+            run,
+            command.args,
+            writer,
+            stderr_writer,
+            state.clone(),
+        ));
+
+        Ok(Box::new(MockProcess {
+            state,
+            stdout,
+            stderr,
+            kill_on_drop: command.kill_on_drop,
+        }))
+    }
+}
+
+struct MockState {
+    status: watch::Sender<Option<ExitStatus>>,
+    paused: AtomicBool,
+    killed: AtomicBool,
+}
+
+struct MockProcess {
+    state: Arc<MockState>,
+    stdout: Option<ProcessOutput>,
+    stderr: Option<ProcessOutput>,
+    kill_on_drop: bool,
+}
+
+impl Drop for MockProcess {
+    fn drop(&mut self) {
+        if self.kill_on_drop {
+            self.state.killed.store(true, Ordering::SeqCst);
+        }
+    }
+}
+
+#[async_trait]
+impl Process for MockProcess {
+    fn id(&self) -> Option<u32> {
+        None
+    }
+
+    fn take_stdout(&mut self) -> Option<ProcessOutput> {
+        self.stdout.take()
+    }
+
+    fn take_stderr(&mut self) -> Option<ProcessOutput> {
+        self.stderr.take()
+    }
+
+    fn try_wait(&mut self) -> io::Result<Option<ExitStatus>> {
+        Ok(*self.state.status.borrow())
+    }
+
+    fn start_kill(&mut self) -> io::Result<()> {
+        self.state.killed.store(true, Ordering::SeqCst);
+        Ok(())
+    }
+
+    async fn kill(&mut self) -> io::Result<()> {
+        self.start_kill()?;
+        self.wait().await.map(|_| ())
+    }
+
+    async fn wait(&mut self) -> io::Result<ExitStatus> {
+        let mut rx = self.state.status.subscribe();
+
+        loop {
+            if let Some(status) = *rx.borrow() {
+                return Ok(status);
+            }
+
+            rx.changed()
+                .await
+                .map_err(|_| io::Error::from(io::ErrorKind::BrokenPipe))?;
+        }
+    }
+
+    fn is_dead(&self) -> bool {
+        self.state.status.borrow().is_some()
+    }
+
+    fn pause(&self) {
+        self.state.paused.store(true, Ordering::SeqCst);
+    }
+
+    fn cont(&self) {
+        self.state.paused.store(false, Ordering::SeqCst);
+    }
+}
+
+/// Drives a simulated process until it either writes all of its segments or gets killed.
+async fn simulate(
+    run: MockRun,
+    args: Vec<String>,
+    mut stdout: Option<DuplexStream>,
+    mut stderr: Option<DuplexStream>,
+    state: Arc<MockState>,
+) {
+    let arg = |name: &str| {
+        args.iter()
+            .position(|x| x == name)
+            .and_then(|x| args.get(x + 1))
+            .cloned()
+    };
+
+    let start_num = arg("-start_number")
+        .and_then(|x| x.parse::<u32>().ok())
+        .unwrap_or(0);
+    let output = args.last().cloned().unwrap_or_default();
+    let outdir = Path::new(&output)
+        .parent()
+        .map(|x| x.to_path_buf())
+        .unwrap_or_default();
+
+    if let Some(init) = arg("-hls_fmp4_init_filename") {
+        let _ = fs::write(outdir.join(init), b"");
+    }
+
+    // the hls muxer lists every segment once it has been written.
+    let mut playlist = format!(
+        "#EXTM3U\n#EXT-X-VERSION:7\n#EXT-X-MEDIA-SEQUENCE:{}\n#EXT-X-PLAYLIST-TYPE:EVENT\n",
+        start_num
+    );
+    let mut killed = false;
+
+    for written in 1..=run.segments {
+        tokio::time::sleep(run.segment_time).await;
+
+        while state.paused.load(Ordering::SeqCst) && !state.killed.load(Ordering::SeqCst) {
+            tokio::time::sleep(Duration::from_millis(5)).await;
+        }
+
+        if state.killed.load(Ordering::SeqCst) {
+            killed = true;
+            break;
+        }
+
+        if let Some(stderr) = stderr.as_mut() {
+            let line = b"[hls @ 0x0] Opening segment for writing\n";
+            let log = line.iter().cycle().take(run.stderr_per_segment);
+
+            let _ = stderr.write_all(&log.copied().collect::<Vec<_>>()).await;
+        }
+
+        if let Some(pattern) = arg("-hls_segment_filename") {
+            let segment = pattern.replace("%d", &(start_num + written - 1).to_string());
+            let _ = fs::write(&segment, b"");
+
+            if output.ends_with(".m3u8") {
+                let name = Path::new(&segment).file_name().unwrap_or_default();
+                playlist += &format!(
+                    "#EXTINF:{:.6},\n{}\n",
+                    run.segment_duration,
+                    name.to_string_lossy()
+                );
+                let _ = fs::write(&output, &playlist);
+            }
+        }
+
+        if let Some(stdout) = stdout.as_mut() {
+            let frame = written as u64 * run.frames_per_segment;
+            let progress = format!(
+                "frame={}\nout_time_us={}\nspeed=1.0x\nprogress=continue\n",
+                frame,
+                frame * 1_000_000 / 24
+            );
+            let _ = stdout.write_all(progress.as_bytes()).await;
+        }
+    }
+
+    if !killed && !output.ends_with(".m3u8") {
+        let _ = fs::write(&output, b"");
+    }
+
+    let status = if killed {
+        exit_status_killed()
+    } else {
+        exit_status(run.exit_code)
+    };
+
+    // dropping stdout closes the pipe, just like a real process exiting.
+    drop(stdout);
+    drop(stderr);
+    state.status.send_replace(Some(status));
+}
+
+cfg_if::cfg_if! {
+    if #[cfg(not(target_os = "windows"))] {
+        use std::os::unix::process::ExitStatusExt;
+
+        fn exit_status(code: i32) -> ExitStatus {
+            ExitStatus::from_raw(code << 8)
+        }
+
+        fn exit_status_killed() -> ExitStatus {
+            // terminated by SIGKILL
+            ExitStatus::from_raw(9)
+        }
+    } else {
+        use std::os::windows::process::ExitStatusExt;
+
+        fn exit_status(code: i32) -> ExitStatus {
+            ExitStatus::from_raw(code as u32)
+        }
+
+        fn exit_status_killed() -> ExitStatus {
+            ExitStatus::from_raw(1)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::NightfallError;
+    use crate::profiles::Priority;
+    use crate::profiles::ProfileContext;
+    use crate::profiles::ProfileType;
+    use crate::profiles::StreamType;
+    use crate::profiles::TranscodingProfile;
+    use crate::session::Session;
+    use crate::session::STATE_FILE;
+    use crate::GcPolicy;
+
+    use std::time::Instant;
+
+    /// Profile writing hls segments into the outdir, just like the real ones do.
+    #[derive(Debug)]
+    struct TestProfile(&'static str);
+
+    impl TranscodingProfile for TestProfile {
+        fn profile_type(&self) -> ProfileType {
+            ProfileType::Transmux
+        }
+
+        fn stream_type(&self) -> StreamType {
+            StreamType::Video
+        }
+
+        fn build(&self, ctx: ProfileContext) -> Option<Vec<String>> {
+            let output_ctx = ctx.output_ctx;
+
+            Some(vec![
+                "-start_number".into(),
+                output_ctx.start_num.to_string(),
+                "-hls_fmp4_init_filename".into(),
+                format!("{}_init.mp4", output_ctx.start_num),
+                "-hls_segment_filename".into(),
+                format!("{}/%d.m4s", output_ctx.outdir),
+                format!("{}/playlist.m3u8", output_ctx.outdir),
+            ])
+        }
+
+        fn supports(&self, _: &ProfileContext) -> Result<(), NightfallError> {
+            Ok(())
+        }
+
+        /// Profiles tagged `stdio` stream over stdout, like progressive ones.
+        fn is_stdio_stream(&self) -> bool {
+            self.0 == "stdio"
+        }
+
+        fn tag(&self) -> &str {
+            self.0
+        }
+
+        fn name(&self) -> &str {
+            self.0
+        }
+    }
+
+    /// Returns a session whose chain is made of `tags`, the first one being the active profile.
+    fn session(spawner: &MockSpawner, tags: &[&'static str]) -> Session {
+        let id = uuid::Uuid::new_v4().hyphenated().to_string();
+        let mut ctx = ProfileContext::default();
+        ctx.output_ctx.outdir = std::env::temp_dir()
+            .join(format!("nightfall-mock-{}", id))
+            .to_string_lossy()
+            .into_owned();
+
+        let chain = tags
+            .iter()
+            .rev()
+            .map(|x| Arc::new(TestProfile(x)) as Arc<dyn TranscodingProfile>)
+            .collect();
+
+        Session::new(id, chain, ctx, Arc::new(spawner.clone()))
+    }
+
+    async fn wait_until(mut f: impl FnMut() -> bool) {
+        for _ in 0..1000 {
+            if f() {
+                return;
+            }
+
+            tokio::time::sleep(Duration::from_millis(5)).await;
+        }
+
+        panic!("Condition wasnt met in time.");
+    }
+
+    /// A run which doesnt produce anything for as long as a test runs.
+    fn stalled() -> MockRun {
+        MockRun {
+            segment_time: Duration::from_secs(60),
+            ..Default::default()
+        }
+    }
+
+    fn failing() -> MockRun {
+        MockRun {
+            segments: 0,
+            exit_code: 1,
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn failed_runs_fall_back_to_the_next_profile() {
+        let spawner = MockSpawner::default();
+        spawner.push_run(failing());
+
+        let mut session = session(&spawner, &["primary", "fallback"]);
+        session.start().await.unwrap();
+        wait_until(|| session.try_wait()).await;
+
+        // the session recovers, thus it isnt done.
+        assert_eq!(session.exit_reason(), None);
+        assert_eq!(
+            session.fall_back().unwrap(),
+            Some(("primary".to_string(), "fallback".to_string()))
+        );
+        assert!(!session.has_started());
+
+        session.start().await.unwrap();
+        wait_until(|| session.is_chunk_done(0)).await;
+
+        assert_eq!(session.profile.tag(), "fallback");
+        assert_eq!(spawner.spawned().len(), 2);
+        // the fallback run is still going, thus nothing to fall back from.
+        assert_eq!(session.fall_back().unwrap(), None);
+
+        session.join().await;
+        session.delete_tmp();
+    }
+
+    #[tokio::test]
+    async fn falling_back_fails_once_the_chain_is_exhausted() {
+        let spawner = MockSpawner::new(failing());
+        let mut session = session(&spawner, &["primary", "fallback"]);
+
+        for _ in 0..2 {
+            session.start().await.unwrap();
+            wait_until(|| session.try_wait()).await;
+
+            if session.profile.tag() == "primary" {
+                assert!(session.fall_back().unwrap().is_some());
+            }
+        }
+
+        assert!(matches!(
+            session.fall_back(),
+            Err(NightfallError::ProfileChainExhausted)
+        ));
+        assert!(matches!(
+            session.exit_reason(),
+            Some(crate::ExitReason::Failed { code: Some(1), .. })
+        ));
+
+        session.delete_tmp();
+    }
+
+    #[tokio::test]
+    async fn hard_seeks_only_far_or_backward_targets() {
+        let spawner = MockSpawner::new(stalled());
+        let mut session = session(&spawner, &["primary"]);
+        let long_ago = Instant::now() - Duration::from_secs(60);
+
+        // nothing to seek in before ffmpeg runs.
+        assert!(!session.should_hard_seek(100, long_ago, 0));
+
+        session.start().await.unwrap();
+
+        // ffmpeg is assumed to make at least 4 chunks per second, close targets are waited for.
+        assert!(!session.should_hard_seek(10, long_ago, 0));
+        assert!(session.should_hard_seek(100, long_ago, 0));
+        // right after a hard seek, anything over 15 chunks ahead is seeked to.
+        assert!(!session.should_hard_seek(30, long_ago, 0));
+        assert!(session.should_hard_seek(30, Instant::now(), 0));
+
+        session.reset_to(20);
+        session.start().await.unwrap();
+
+        assert!(session.should_hard_seek(5, long_ago, 0));
+        assert!(!session.should_hard_seek(25, long_ago, 0));
+
+        // a preserved init segment doesnt bring the current run back to earlier chunks.
+        session.reset_preserving_init(20).await;
+        session.start().await.unwrap();
+        assert!(session.preserves_init());
+        assert!(session.should_hard_seek(5, long_ago, 0));
+
+        session.delete_tmp();
+    }
+
+    #[tokio::test]
+    async fn chunk_times_come_from_the_written_segments() {
+        let spawner = MockSpawner::new(MockRun {
+            segments: 3,
+            segment_duration: 4.5,
+            ..Default::default()
+        });
+        let mut session = session(&spawner, &["primary"]);
+        session.start().await.unwrap();
+        wait_until(|| session.try_wait()).await;
+
+        // nothing has been read yet, thus the chunks are assumed to be `target_gop` long.
+        assert_eq!(session.chunk_start(2), 10.0);
+
+        session.refresh_segment_times();
+        assert_eq!(session.chunk_duration(1), 4.5);
+        assert_eq!(session.chunk_start(2), 9.0);
+        // not written yet.
+        assert_eq!(session.chunk_start(3), 15.0);
+
+        // the muxer measures segments from where the new run starts rather than on a grid.
+        session.reset_to(20);
+        session.start().await.unwrap();
+        wait_until(|| session.try_wait()).await;
+        session.refresh_segment_times();
+
+        assert_eq!(session.chunk_start(20), 100.0);
+        assert_eq!(session.chunk_start(22), 109.0);
+        // chunks of the earlier run keep their times.
+        assert_eq!(session.chunk_start(2), 9.0);
+
+        session.delete_tmp();
+    }
+
+    #[tokio::test]
+    async fn playlist_chunks_are_patched_once_in_the_background() {
+        let spawner = MockSpawner::new(MockRun {
+            segments: 3,
+            segment_duration: 4.5,
+            ..Default::default()
+        });
+        let mut session = session(&spawner, &["primary"]);
+        session.start().await.unwrap();
+        wait_until(|| session.try_wait()).await;
+
+        session.update_playlist();
+        assert!(session.is_patching(0));
+
+        wait_until(|| {
+            session.update_playlist();
+            !session.is_patching(0)
+        })
+        .await;
+        assert_eq!(
+            session.patched_chunks.iter().copied().collect::<Vec<_>>(),
+            vec![0, 1, 2]
+        );
+
+        // everything is listed already, thus nothing gets patched again.
+        session.update_playlist();
+        assert!(!session.is_patching(0));
+
+        let path = session.playlist_path();
+        wait_until(|| fs::read_to_string(&path).is_ok_and(|x| x.contains("2.m4s"))).await;
+
+        let playlist = fs::read_to_string(&path).unwrap();
+        assert_eq!(playlist.matches("#EXTINF:4.500,").count(), 3);
+
+        session.delete_tmp();
+    }
+
+    #[tokio::test]
+    async fn deleting_a_session_keeps_files_it_didnt_write() {
+        let spawner = MockSpawner::new(MockRun {
+            segments: 2,
+            ..Default::default()
+        });
+        let mut session = session(&spawner, &["primary"]);
+        let outdir = Path::new(&session.profile_ctx.output_ctx.outdir).to_path_buf();
+        fs::create_dir_all(&outdir).unwrap();
+        fs::write(outdir.join("poster.png"), b"").unwrap();
+
+        session.start().await.unwrap();
+        wait_until(|| session.try_wait()).await;
+        assert!(outdir.join("1.m4s").is_file());
+
+        session.delete_tmp();
+        let left = fs::read_dir(&outdir)
+            .unwrap()
+            .map(|x| x.unwrap().file_name().into_string().unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(left, vec!["poster.png".to_string()]);
+
+        fs::remove_file(outdir.join("poster.png")).unwrap();
+        session.delete_tmp();
+        assert!(!outdir.exists());
+    }
+
+    #[tokio::test]
+    async fn playlists_split_chunks_where_ffmpeg_restarted() {
+        let spawner = MockSpawner::new(MockRun {
+            segments: 4,
+            ..Default::default()
+        });
+        let mut session = session(&spawner, &["primary"]);
+        session.start().await.unwrap();
+        wait_until(|| session.try_wait()).await;
+
+        // the second run picks up right where the first one stopped.
+        session.reset_to(4);
+        session.start().await.unwrap();
+        wait_until(|| session.try_wait()).await;
+        session.refresh_segment_times();
+
+        assert_eq!(session.run_start(3), 0);
+        assert_eq!(session.run_start(4), 4);
+        assert_eq!(session.run_start(7), 4);
+
+        let playlist = session.event_playlist();
+        assert!(playlist.contains("#EXT-X-MAP:URI=\"0_init.mp4\""));
+        assert!(playlist.contains("3.m4s\n#EXT-X-DISCONTINUITY\n#EXT-X-MAP:URI=\"4_init.mp4\""));
+
+        session.delete_tmp();
+    }
+
+    #[tokio::test]
+    async fn preview_chunks_are_patched_once() {
+        let spawner = MockSpawner::new(MockRun {
+            segments: 3,
+            ..Default::default()
+        });
+        let mut session = session(&spawner, &["primary"]);
+        session.spawn_preview(5).unwrap();
+
+        let path = session.preview_chunk_to_path(6);
+        wait_until(|| Path::new(&path).is_file()).await;
+        session.patch_preview_chunk(5).await.unwrap();
+
+        // a chunk patched already isnt read again, thus not even a truncated file fails.
+        fs::write(session.preview_chunk_to_path(5), b"abc").unwrap();
+        session.patch_preview_chunk(5).await.unwrap();
+
+        fs::write(&path, b"abc").unwrap();
+        assert!(session.patch_preview_chunk(6).await.is_err());
+
+        session.delete_tmp();
+    }
+
+    #[tokio::test]
+    async fn state_is_only_persisted_once_it_changes() {
+        let spawner = MockSpawner::new(MockRun {
+            segments: 2,
+            ..Default::default()
+        });
+        let mut session = session(&spawner, &["primary"]);
+        session.start().await.unwrap();
+        wait_until(|| session.try_wait()).await;
+
+        let state = Path::new(&session.profile_ctx.output_ctx.outdir).join(STATE_FILE);
+        session.persist().unwrap();
+        fs::remove_file(&state).unwrap();
+
+        session.persist().unwrap();
+        assert!(!state.exists());
+
+        assert_eq!(session.take_ready_chunks(), vec![0, 1]);
+        session.persist().unwrap();
+
+        let persisted: crate::session::PersistedSession =
+            serde_json::from_str(&fs::read_to_string(&state).unwrap()).unwrap();
+        assert_eq!(persisted.last_chunk, Some(1));
+
+        session.delete_tmp();
+    }
+
+    #[tokio::test]
+    async fn outdir_sizes_are_cached_once_measured() {
+        let spawner = MockSpawner::new(MockRun::default());
+        let mut session = session(&spawner, &["primary"]);
+        session.start().await.unwrap();
+        wait_until(|| session.try_wait()).await;
+
+        let outdir = Path::new(&session.profile_ctx.output_ctx.outdir).to_path_buf();
+        fs::write(outdir.join("0.m4s"), [0; 100]).unwrap();
+        assert_eq!(session.outdir_size(), 0);
+
+        let measured = session.measure_outdir().await;
+        assert!(measured >= 100);
+        assert_eq!(session.outdir_size(), measured);
+
+        session.delete_tmp();
+    }
+
+    #[tokio::test]
+    async fn stats_are_kept_on_the_session() {
+        let spawner = MockSpawner::new(MockRun {
+            segments: 2,
+            ..Default::default()
+        });
+        let mut session = session(&spawner, &["primary"]);
+        assert_eq!(session.stats().last_activity, None);
+
+        session.start().await.unwrap();
+        wait_until(|| session.try_wait() && session.stats().last_activity.is_some()).await;
+
+        // chunks count once reported.
+        assert_eq!(session.stats().chunks_completed, 0);
+        assert_eq!(session.take_ready_chunks(), vec![0, 1]);
+        assert_eq!(session.stats().chunks_completed, 2);
+
+        session.delete_tmp();
+    }
+
+    #[tokio::test]
+    async fn bandwidth_is_the_peak_of_the_written_chunks() {
+        let spawner = MockSpawner::new(MockRun {
+            segments: 2,
+            segment_duration: 4.0,
+            ..Default::default()
+        });
+        let mut session = session(&spawner, &["primary"]);
+        session.profile_ctx.input_ctx.bitrate = 1_000;
+        // nothing written yet.
+        assert_eq!(session.bandwidth(), 1_000);
+
+        session.start().await.unwrap();
+        wait_until(|| session.try_wait()).await;
+        session.refresh_segment_times();
+
+        let outdir = Path::new(&session.profile_ctx.output_ctx.outdir).to_path_buf();
+        fs::write(outdir.join("0.m4s"), [0; 1_000]).unwrap();
+        fs::write(outdir.join("1.m4s"), [0; 5_000]).unwrap();
+        assert_eq!(session.bandwidth(), 10_000);
+
+        // the requested bitrate is an average, the peak can only be above it.
+        session.profile_ctx.output_ctx.bitrate = Some(20_000);
+        assert_eq!(session.bandwidth(), 20_000);
+
+        session.delete_tmp();
+    }
+
+    #[tokio::test]
+    async fn renditions_are_patched_once_into_their_own_directories() {
+        let spawner = MockSpawner::new(MockRun::default());
+        let mut session = session(&spawner, &["primary"]);
+        session.profile_ctx.output_ctx.audio_renditions = vec!["aac".into(), "ac3".into()];
+
+        let outdir = session.profile_ctx.output_ctx.outdir.clone();
+        assert_eq!(
+            session.rendition_dir(None),
+            format!("{}/rendition_0", outdir)
+        );
+        assert_eq!(
+            session.rendition_dir(Some("ac3")),
+            format!("{}/rendition_1", outdir)
+        );
+
+        for rendition in ["aac", "ac3"] {
+            fs::create_dir_all(session.rendition_dir(Some(rendition))).unwrap();
+            let path = session.rendition_chunk_to_path(rendition, 0);
+            fs::write(&path, b"").unwrap();
+            session.patch_rendition_chunk(rendition, 0).await.unwrap();
+
+            // patching the chunk again would fail now that it is gone.
+            fs::remove_file(&path).unwrap();
+            session.patch_rendition_chunk(rendition, 0).await.unwrap();
+        }
+
+        // the first rendition is the one `chunk_request` hands out.
+        assert!(session.patched_chunks.contains(&0));
+
+        session.delete_tmp();
+        assert!(!Path::new(&outdir).exists());
+    }
+
+    #[tokio::test]
+    async fn expired_sessions_complete_as_expired() {
+        let spawner = MockSpawner::new(stalled());
+        let mut session = session(&spawner, &["primary"]);
+        let completion = session.completion();
+        assert_eq!(session.reap_reason(), crate::ExitReason::Killed);
+
+        session.expires_at = Some(Instant::now());
+        tokio::time::sleep(Duration::from_millis(5)).await;
+
+        session.join().await;
+        session.finish(session.reap_reason());
+        assert_eq!(completion.await, crate::ExitReason::Expired);
+
+        session.delete_tmp();
+    }
+
+    #[tokio::test]
+    async fn verbose_ffmpeg_keeps_going_while_stdout_is_read_slowly() {
+        let spawner = MockSpawner::new(MockRun {
+            // way more than a pipe buffers.
+            stderr_per_segment: 64 * 1024,
+            ..Default::default()
+        });
+        let mut session = session(&spawner, &["stdio"]);
+        session.start().await.unwrap();
+
+        let mut stdout = session.take_stdout().unwrap();
+        let reader = tokio::spawn(async move {
+            let mut buf = [0; 16];
+
+            while tokio::io::AsyncReadExt::read(&mut stdout, &mut buf)
+                .await
+                .is_ok_and(|x| x != 0)
+            {
+                tokio::time::sleep(Duration::from_millis(5)).await;
+            }
+        });
+
+        wait_until(|| session.try_wait()).await;
+
+        assert_eq!(session.exit_reason(), Some(crate::ExitReason::Success));
+        assert!(session.is_chunk_done(9));
+
+        reader.await.unwrap();
+        session.delete_tmp();
+    }
+
+    #[tokio::test]
+    async fn idle_sessions_get_reaped() {
+        let spawner = MockSpawner::new(stalled());
+        let policy = GcPolicy {
+            reap_after: Duration::from_millis(20),
+            ..Default::default()
+        };
+
+        let mut session = session(&spawner, &["primary"]);
+        assert!(!session.should_reap(&policy));
+
+        tokio::time::sleep(Duration::from_millis(30)).await;
+        assert!(session.should_reap(&policy));
+
+        // requesting a chunk keeps it alive.
+        session.reset_timeout(0);
+        assert!(!session.should_reap(&policy));
+
+        // the policy of the session wins over the default one.
+        session.gc_policy = Some(GcPolicy::default());
+        tokio::time::sleep(Duration::from_millis(30)).await;
+        assert!(!session.should_reap(&policy));
+
+        session.expires_at = Some(Instant::now());
+        tokio::time::sleep(Duration::from_millis(5)).await;
+        assert!(session.should_reap(&policy));
+
+        session.expires_at = None;
+        session.set_timeout();
+        assert!(session.should_reap(&policy));
+    }
+
+    #[tokio::test]
+    async fn background_sessions_get_reaped_once_done() {
+        let spawner = MockSpawner::new(MockRun {
+            segments: 2,
+            ..Default::default()
+        });
+        let policy = GcPolicy {
+            reap_after: Duration::from_millis(20),
+            ..Default::default()
+        };
+
+        let mut session = session(&spawner, &["primary"]);
+        session.profile_ctx.output_ctx.priority = Priority::Background;
+
+        // nobody requests the chunks of a background session, be it queued or running.
+        tokio::time::sleep(Duration::from_millis(30)).await;
+        assert!(!session.should_reap(&policy));
+
+        session.start().await.unwrap();
+        wait_until(|| session.try_wait()).await;
+        assert!(!session.should_reap(&policy));
+
+        tokio::time::sleep(Duration::from_millis(30)).await;
+        assert!(session.should_reap(&policy));
+
+        session.delete_tmp();
+    }
+
+    #[tokio::test]
+    async fn killed_sessions_are_neither_restored_nor_reaped_once_restarted() {
+        let spawner = MockSpawner::new(stalled());
+        let policy = GcPolicy::default();
+        let mut session = session(&spawner, &["primary"]);
+        session.start().await.unwrap();
+
+        let state = Path::new(&session.profile_ctx.output_ctx.outdir).join(STATE_FILE);
+        session.persist().unwrap();
+        assert!(state.exists());
+
+        session.set_timeout();
+        session.forget_persisted();
+        assert!(!state.exists());
+        // nor does it get written again before the session is reaped.
+        session.persist().unwrap();
+        assert!(!state.exists());
+        assert!(session.should_reap(&policy));
+
+        session.join().await;
+        session.start().await.unwrap();
+        assert!(!session.should_reap(&policy));
+
+        session.persist().unwrap();
+        assert!(state.exists());
+
+        session.join().await;
+        session.delete_tmp();
+    }
+}
@@ -0,0 +1,141 @@
+#[cfg(any(test, feature = "mock"))]
+pub mod mock;
+
+use std::fmt;
+use std::fs::File;
+use std::io;
+use std::process::ExitStatus;
+use std::process::Stdio;
+
+use async_trait::async_trait;
+use tokio::io::AsyncRead;
+use tokio::process::Child;
+use tokio::process::Command;
+
+/// Readable end of a piped stdout or stderr.
+pub type ProcessOutput = Box<dyn AsyncRead + Send + Unpin>;
+
+/// Where a stdio stream of a spawned process goes.
+#[derive(Debug)]
+pub enum Output {
+    /// Piped back to us, see `Process::take_stdout` and `Process::take_stderr`.
+    Piped,
+    /// Written straight into a file.
+    File(File),
+    /// Discarded.
+    Null,
+}
+
+impl From<Output> for Stdio {
+    fn from(output: Output) -> Self {
+        match output {
+            Output::Piped => Stdio::piped(),
+            Output::File(file) => file.into(),
+            Output::Null => Stdio::null(),
+        }
+    }
+}
+
+/// Describes a process a session wants to spawn.
+#[derive(Debug)]
+pub struct SpawnCommand {
+    /// Path to the binary, usually `ProfileContext::ffmpeg_bin`.
+    pub program: String,
+    pub args: Vec<String>,
+    pub stdout: Output,
+    pub stderr: Output,
+    /// Whether the process should get killed once its handle is dropped.
+    pub kill_on_drop: bool,
+}
+
+/// Spawns the processes sessions use to transcode. `FfmpegSpawner` is used by default, another
+/// implementation can be set with `StateManager::with_spawner`, for example to simulate ffmpeg.
+pub trait ProcessSpawner: Send + Sync + fmt::Debug {
+    fn spawn(&self, command: SpawnCommand) -> io::Result<Box<dyn Process>>;
+}
+
+/// Handle to a process spawned by a `ProcessSpawner`.
+#[async_trait]
+pub trait Process: Send {
+    /// OS assigned id of the process, `None` once the process has been reaped.
+    fn id(&self) -> Option<u32>;
+    fn take_stdout(&mut self) -> Option<ProcessOutput>;
+    fn take_stderr(&mut self) -> Option<ProcessOutput>;
+    /// Returns the exit status if the process has exited, without blocking.
+    fn try_wait(&mut self) -> io::Result<Option<ExitStatus>>;
+    /// Sends the kill signal to the process without waiting for it to exit.
+    fn start_kill(&mut self) -> io::Result<()>;
+    /// Kills the process and waits for it to exit.
+    async fn kill(&mut self) -> io::Result<()>;
+    async fn wait(&mut self) -> io::Result<ExitStatus>;
+    /// Returns whether the process has exited or is a zombie.
+    fn is_dead(&self) -> bool;
+    /// Suspends the process until `cont` is called.
+    fn pause(&self);
+    fn cont(&self);
+}
+
+/// Spawns real processes.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct FfmpegSpawner;
+
+impl ProcessSpawner for FfmpegSpawner {
+    fn spawn(&self, command: SpawnCommand) -> io::Result<Box<dyn Process>> {
+        let child = Command::new(command.program)
+            .stdout(command.stdout)
+            .stderr(command.stderr)
+            .stdin(Stdio::null())
+            .args(command.args.as_slice())
+            .kill_on_drop(command.kill_on_drop)
+            .spawn()?;
+
+        Ok(Box::new(child))
+    }
+}
+
+#[async_trait]
+impl Process for Child {
+    fn id(&self) -> Option<u32> {
+        Child::id(self)
+    }
+
+    fn take_stdout(&mut self) -> Option<ProcessOutput> {
+        self.stdout.take().map(|x| Box::new(x) as ProcessOutput)
+    }
+
+    fn take_stderr(&mut self) -> Option<ProcessOutput> {
+        self.stderr.take().map(|x| Box::new(x) as ProcessOutput)
+    }
+
+    fn try_wait(&mut self) -> io::Result<Option<ExitStatus>> {
+        Child::try_wait(self)
+    }
+
+    fn start_kill(&mut self) -> io::Result<()> {
+        Child::start_kill(self)
+    }
+
+    async fn kill(&mut self) -> io::Result<()> {
+        Child::kill(self).await
+    }
+
+    async fn wait(&mut self) -> io::Result<ExitStatus> {
+        Child::wait(self).await
+    }
+
+    fn is_dead(&self) -> bool {
+        Child::id(self).is_none_or(crate::utils::is_process_effectively_dead)
+    }
+
+    fn pause(&self) {
+        if let Some(pid) = Child::id(self) {
+            crate::utils::pause_proc(pid);
+        }
+    }
+
+    fn cont(&self) {
+        if let Some(pid) = Child::id(self) {
+            crate::utils::cont_proc(pid);
+        }
+    }
+}
@@ -1,3 +1,5 @@
+use super::hwaccel::probe_encoder;
+use super::video::get_scale_filter;
 use super::ProfileContext;
 use super::ProfileType;
 use super::StreamType;
@@ -5,12 +7,53 @@ use super::TranscodingProfile;
 
 use crate::NightfallError;
 
+/// Codecs the AMF encoders can produce.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AmfCodec {
+    H264,
+    Hevc,
+}
+
+impl AmfCodec {
+    fn encoder(&self) -> &'static str {
+        match self {
+            Self::H264 => "h264_amf",
+            Self::Hevc => "hevc_amf",
+        }
+    }
+
+    /// Returns the value of `OutputCtx::codec` this encoder produces.
+    fn codec(&self) -> &'static str {
+        match self {
+            Self::H264 => "h264",
+            Self::Hevc => "hevc",
+        }
+    }
+}
+
+/// AMD AMF transcoding profiles.
+/// One profile exists per `AmfCodec`, each is only enabled if ffmpeg manages to encode a test
+/// frame with its encoder, which requires both a ffmpeg build with AMF support and an AMD GPU.
+/// Disabled profiles are dropped by `profiles_init` and thus never end up in a profile chain.
 #[derive(Debug)]
-pub struct AmfTranscodeProfile;
+pub struct AmfTranscodeProfile {
+    codec: AmfCodec,
+    /// Outcome of the test encode ran when the profile was created.
+    available: Result<(), NightfallError>,
+}
+
+impl AmfTranscodeProfile {
+    pub fn new(ffmpeg_bin: &str, codec: AmfCodec) -> Self {
+        Self {
+            codec,
+            available: probe_encoder(ffmpeg_bin, codec.encoder(), &[], None),
+        }
+    }
+}
 
 impl TranscodingProfile for AmfTranscodeProfile {
     fn profile_type(&self) -> ProfileType {
-        ProfileType::Transcode
+        ProfileType::HardwareTranscode
     }
 
     fn stream_type(&self) -> StreamType {
@@ -18,7 +61,14 @@ impl TranscodingProfile for AmfTranscodeProfile {
     }
 
     fn name(&self) -> &str {
-        "AmfTranscodeProfile"
+        match self.codec {
+            AmfCodec::H264 => "AmfTranscodeProfile",
+            AmfCodec::Hevc => "AmfHevcTranscodeProfile",
+        }
+    }
+
+    fn is_enabled(&self) -> Result<(), NightfallError> {
+        self.available.clone()
     }
 
     fn build(&self, ctx: ProfileContext) -> Option<Vec<String>> {
@@ -31,26 +81,51 @@ impl TranscodingProfile for AmfTranscodeProfile {
         let mut args = vec![
             "-y".into(),
             "-ss".into(),
-            (ctx.output_ctx.start_num * ctx.output_ctx.target_gop).to_string(),
+            ctx.input_seek().to_string(),
             "-i".into(),
             ctx.file.clone(),
             "-copyts".into(),
             "-map".into(),
             stream,
             "-c:0".into(),
-            "h264_amf".into(),
+            self.codec.encoder().into(),
+            "-bf".into(),
+            "0".into(),
         ];
 
+        args.append(&mut super::video::get_output_seek_flags(&ctx));
+
+        // Apple players only accept hevc tagged as `hvc1`.
+        if self.codec == AmfCodec::Hevc {
+            args.append(&mut vec!["-tag:0".into(), "hvc1".into()]);
+        }
+
+        if let Some(scale) = get_scale_filter("scale", &ctx) {
+            args.append(&mut vec!["-vf".into(), scale]);
+        }
+
+        if let Some(bitrate) = ctx.output_ctx.bitrate {
+            args.push("-b:v".into());
+            args.push(bitrate.to_string());
+        }
+
+        args.append(&mut super::video::get_fps_flags(&ctx));
+
         args.append(&mut vec![
             "-start_at_zero".into(),
             "-vsync".into(),
-            "passthrough".into(),
+            super::video::get_fps_mode(&ctx),
             "-avoid_negative_ts".into(),
             "disabled".into(),
             "-max_muxing_queue_size".into(),
             "2048".into(),
         ]);
 
+        args.append(&mut super::video::get_metadata_flags(
+            &ctx,
+            self.profile_type(),
+        ));
+
         args.append(&mut vec![
             "-f".into(),
             "hls".into(),
@@ -99,20 +174,26 @@ impl TranscodingProfile for AmfTranscodeProfile {
         Some(args)
     }
 
-    /// This profile technically could work on any codec since the codec is just `copy` here, but
-    /// the container doesnt support it, so we will be constricting it down.
     fn supports(&self, ctx: &ProfileContext) -> Result<(), NightfallError> {
-        if ctx.output_ctx.codec == "h264" {
+        if ctx.output_ctx.codec == self.codec.codec() {
             return Ok(());
         }
 
         Err(NightfallError::ProfileNotSupported(format!(
-            "Got output codec {} but profile only supports `h264`.",
-            ctx.output_ctx.codec
+            "Got output codec {} but profile only supports `{}`.",
+            ctx.output_ctx.codec,
+            self.codec.codec()
         )))
     }
 
+    fn encoder(&self) -> Option<&str> {
+        Some(self.codec.encoder())
+    }
+
     fn tag(&self) -> &str {
-        "h264_amf"
+        match self.codec {
+            AmfCodec::H264 => "h264_amf",
+            AmfCodec::Hevc => "hevc_amf",
+        }
     }
 }
@@ -1,3 +1,4 @@
+use super::Container;
 use super::ProfileContext;
 use super::ProfileType;
 use super::StreamType;
@@ -5,6 +6,125 @@ use super::TranscodingProfile;
 
 use crate::error::NightfallError;
 
+use serde_derive::{Deserialize, Serialize};
+
+/// Describes a secondary audio stream, such as an audio description track, to mix into the main
+/// audio stream.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct AudioMix {
+    /// Absolute index of the secondary audio stream.
+    pub stream: usize,
+    /// Volume multiplier applied to the main audio stream.
+    pub main_volume: f32,
+    /// Volume multiplier applied to the secondary audio stream.
+    pub secondary_volume: f32,
+}
+
+impl Default for AudioMix {
+    fn default() -> Self {
+        Self {
+            stream: 0,
+            main_volume: 1.0,
+            secondary_volume: 1.0,
+        }
+    }
+}
+
+/// Channel layouts audio can be downmixed to.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ChannelLayout {
+    Mono,
+    Stereo,
+    Surround51,
+}
+
+impl ChannelLayout {
+    /// Returns the layout ffmpeg uses by default for `channels` channels, if we support it.
+    pub fn from_channels(channels: u64) -> Option<Self> {
+        match channels {
+            1 => Some(Self::Mono),
+            2 => Some(Self::Stereo),
+            6 => Some(Self::Surround51),
+            _ => None,
+        }
+    }
+
+    pub fn channels(&self) -> u64 {
+        match self {
+            Self::Mono => 1,
+            Self::Stereo => 2,
+            Self::Surround51 => 6,
+        }
+    }
+
+    fn ffmpeg_name(&self) -> &'static str {
+        match self {
+            Self::Mono => "mono",
+            Self::Stereo => "stereo",
+            Self::Surround51 => "5.1",
+        }
+    }
+}
+
+/// Builds the filter downmixing the input to the target layout, `None` if the input doesnt have
+/// more channels than the target. The target is `OutputCtx::channel_layout`, or the default
+/// layout for `OutputCtx::audio_channels`.
+///
+/// The plain downmix of ffmpeg attenuates the center channel, which makes dialogue hard to hear
+/// on stereo outputs. 5.1 and 7.1 sources thus get mixed to stereo with a `pan` filter keeping
+/// the center at full level and the other channels below it. The gains get renormalized to avoid
+/// clipping. Channels are addressed by index so that both the back and side variants of the
+/// layouts are handled. Every other case is left to `aresample`.
+pub(super) fn downmix_filter(ctx: &ProfileContext) -> Option<String> {
+    let input = ctx.input_ctx.audio_channels;
+    let target = ctx
+        .output_ctx
+        .channel_layout
+        .or_else(|| ChannelLayout::from_channels(ctx.output_ctx.audio_channels));
+
+    let target = match target {
+        Some(x) if input > x.channels() => x,
+        Some(_) => return None,
+        None if input > ctx.output_ctx.audio_channels => {
+            return Some(format!(
+                "aresample=out_chlayout={}c",
+                ctx.output_ctx.audio_channels
+            ))
+        }
+        None => return None,
+    };
+
+    let filter = match (input, target) {
+        (6, ChannelLayout::Stereo) => {
+            "pan=stereo|FL<c2+0.707*c0+0.5*c4+0.3*c3|FR<c2+0.707*c1+0.5*c5+0.3*c3".to_string()
+        }
+        (8, ChannelLayout::Stereo) => {
+            "pan=stereo|FL<c2+0.707*c0+0.5*c4+0.5*c6+0.3*c3|FR<c2+0.707*c1+0.5*c5+0.5*c7+0.3*c3"
+                .to_string()
+        }
+        (_, layout) => format!(
+            "aresample=out_chlayout={}:center_mix_level=1.0",
+            layout.ffmpeg_name()
+        ),
+    };
+
+    Some(filter)
+}
+
+/// Builds the filtergraph mixing the main stream with the secondary stream into the `[aout]`
+/// label. The main stream is downmixed first if the channel count has to change, and the output
+/// keeps the duration of the main stream.
+fn audio_mix_filter(ctx: &ProfileContext, mix: &AudioMix) -> String {
+    let downmix = downmix_filter(ctx)
+        .map(|x| format!(",{}", x))
+        .unwrap_or_default();
+
+    format!(
+        "[0:{}]volume={}{}[main];[0:{}]volume={}[sec];[main][sec]amix=inputs=2:duration=first:normalize=0[aout]",
+        ctx.input_ctx.stream, mix.main_volume, downmix, mix.stream, mix.secondary_volume
+    )
+}
+
 #[derive(Debug)]
 pub struct Eac3TransmuxProfile;
 
@@ -34,7 +154,7 @@ impl TranscodingProfile for Eac3TransmuxProfile {
             "-ss".into(),
             (ctx.output_ctx.start_num * ctx.output_ctx.target_gop).to_string(),
             "-i".into(),
-            ctx.file,
+            ctx.file.clone(),
             "-copyts".into(),
             "-map".into(),
             stream,
@@ -50,6 +170,11 @@ impl TranscodingProfile for Eac3TransmuxProfile {
             "make_non_negative".into(),
         ]);
 
+        args.append(&mut super::video::get_metadata_flags(
+            &ctx,
+            self.profile_type(),
+        ));
+
         args.append(&mut vec![
             "-f".into(),
             "hls".into(),
@@ -70,20 +195,7 @@ impl TranscodingProfile for Eac3TransmuxProfile {
             "5000000".into(),
         ]);
 
-        // these args are needed if we start a new stream in the middle of a old one, such as when
-        // seeking. These args will reset the base decode ts to equal the earliest presentation
-        // timestamp.
-        if ctx.output_ctx.start_num > 0 {
-            args.append(&mut vec![
-                "-hls_segment_options".into(),
-                "movflags=frag_custom+dash+delay_moov+frag_discont".into(),
-            ]);
-        } else {
-            args.append(&mut vec![
-                "-hls_segment_options".into(),
-                "movflags=frag_custom+dash+delay_moov".into(),
-            ]);
-        }
+        args.append(&mut super::video::get_discont_flags(&ctx));
 
         // args needed so we can distinguish between init fragments for new streams.
         // Basically on the web seeking works by reloading the entire video because of
@@ -113,12 +225,15 @@ impl TranscodingProfile for Eac3TransmuxProfile {
     /// This profile technically could work on any codec since the codec is just `copy` here, but
     /// the container doesnt support it, so we will be constricting it down.
     fn supports(&self, ctx: &ProfileContext) -> Result<(), NightfallError> {
-        if ctx.input_ctx.codec == ctx.output_ctx.codec && ctx.input_ctx.codec == "eac3" {
+        if ctx.input_ctx.codec == "eac3"
+            && ctx.output_ctx.passes_through(&ctx.input_ctx.codec)
+            && ctx.output_ctx.audio_mix.is_none()
+        {
             return Ok(());
         }
 
         Err(NightfallError::ProfileNotSupported(
-            "Profile only supports eac3 input and output codecs.".into(),
+            "Profile only supports eac3 input, with a eac3 output or bitstream support.".into(),
         ))
     }
 
@@ -156,7 +271,7 @@ impl TranscodingProfile for Ac3TransmuxProfile {
             "-ss".into(),
             (ctx.output_ctx.start_num * ctx.output_ctx.target_gop).to_string(),
             "-i".into(),
-            ctx.file,
+            ctx.file.clone(),
             "-copyts".into(),
             "-map".into(),
             stream,
@@ -172,6 +287,11 @@ impl TranscodingProfile for Ac3TransmuxProfile {
             "make_non_negative".into(),
         ]);
 
+        args.append(&mut super::video::get_metadata_flags(
+            &ctx,
+            self.profile_type(),
+        ));
+
         args.append(&mut vec![
             "-f".into(),
             "hls".into(),
@@ -192,20 +312,7 @@ impl TranscodingProfile for Ac3TransmuxProfile {
             "5000000".into(),
         ]);
 
-        // these args are needed if we start a new stream in the middle of a old one, such as when
-        // seeking. These args will reset the base decode ts to equal the earliest presentation
-        // timestamp.
-        if ctx.output_ctx.start_num > 0 {
-            args.append(&mut vec![
-                "-hls_segment_options".into(),
-                "movflags=frag_custom+dash+delay_moov+frag_discont".into(),
-            ]);
-        } else {
-            args.append(&mut vec![
-                "-hls_segment_options".into(),
-                "movflags=frag_custom+dash+delay_moov".into(),
-            ]);
-        }
+        args.append(&mut super::video::get_discont_flags(&ctx));
 
         // args needed so we can distinguish between init fragments for new streams.
         // Basically on the web seeking works by reloading the entire video because of
@@ -235,12 +342,15 @@ impl TranscodingProfile for Ac3TransmuxProfile {
     /// This profile technically could work on any codec since the codec is just `copy` here, but
     /// the container doesnt support it, so we will be constricting it down.
     fn supports(&self, ctx: &ProfileContext) -> Result<(), NightfallError> {
-        if ctx.input_ctx.codec == ctx.output_ctx.codec && ctx.input_ctx.codec == "ac3" {
+        if ctx.input_ctx.codec == "ac3"
+            && ctx.output_ctx.passes_through(&ctx.input_ctx.codec)
+            && ctx.output_ctx.audio_mix.is_none()
+        {
             return Ok(());
         }
 
         Err(NightfallError::ProfileNotSupported(
-            "Profile only supports ac3 input and output codecs.".into(),
+            "Profile only supports ac3 input, with a ac3 output or bitstream support.".into(),
         ))
     }
 
@@ -276,21 +386,34 @@ impl TranscodingProfile for AacTranscodeProfile {
         let mut args = vec![
             "-y".into(),
             "-ss".into(),
-            (ctx.output_ctx.start_num * ctx.output_ctx.target_gop).to_string(),
+            ctx.input_seek().to_string(),
             "-i".into(),
-            ctx.file,
+            ctx.file.clone(),
             "-copyts".into(),
-            "-map".into(),
-            stream,
-            "-c:0".into(),
-            "aac".into(),
         ];
 
-        if ctx.input_ctx.audio_channels != ctx.output_ctx.audio_channels {
+        args.append(&mut super::video::get_output_seek_flags(&ctx));
+
+        if let Some(mix) = ctx.output_ctx.audio_mix.as_ref() {
             args.append(&mut vec![
-                "-af".into(),
-                "pan=stereo|FL=0.5*FC+0.707*FL+0.707*BL+0.5*LFE|FR=0.5*FC+0.707*FR+0.707*BR+0.5*LFE".into(),
+                "-filter_complex".into(),
+                audio_mix_filter(&ctx, mix),
+                "-map".into(),
+                "[aout]".into(),
+                "-c:0".into(),
+                "aac".into(),
             ]);
+        } else {
+            args.append(&mut vec![
+                "-map".into(),
+                stream,
+                "-c:0".into(),
+                "aac".into(),
+            ]);
+
+            if let Some(filter) = downmix_filter(&ctx) {
+                args.append(&mut vec!["-af".into(), filter]);
+            }
         }
 
         let ab = ctx.output_ctx.bitrate.unwrap_or(120_000).to_string();
@@ -305,6 +428,11 @@ impl TranscodingProfile for AacTranscodeProfile {
             "make_non_negative".into(),
         ]);
 
+        args.append(&mut super::video::get_metadata_flags(
+            &ctx,
+            self.profile_type(),
+        ));
+
         args.append(&mut vec![
             "-f".into(),
             "hls".into(),
@@ -325,20 +453,7 @@ impl TranscodingProfile for AacTranscodeProfile {
             "5000000".into(),
         ]);
 
-        // these args are needed if we start a new stream in the middle of a old one, such as when
-        // seeking. These args will reset the base decode ts to equal the earliest presentation
-        // timestamp.
-        if ctx.output_ctx.start_num > 0 {
-            args.append(&mut vec![
-                "-hls_segment_options".into(),
-                "movflags=frag_custom+dash+delay_moov+frag_discont".into(),
-            ]);
-        } else {
-            args.append(&mut vec![
-                "-hls_segment_options".into(),
-                "movflags=frag_custom+dash+delay_moov".into(),
-            ]);
-        }
+        args.append(&mut super::video::get_discont_flags(&ctx));
 
         // args needed so we can distinguish between init fragments for new streams.
         // Basically on the web seeking works by reloading the entire video because of
@@ -375,7 +490,391 @@ impl TranscodingProfile for AacTranscodeProfile {
         ))
     }
 
+    fn encoder(&self) -> Option<&str> {
+        Some("aac")
+    }
+
     fn tag(&self) -> &str {
         "aac"
     }
 }
+
+/// Profile producing several audio renditions of the same stream in parallel, ex. a AAC transcode
+/// for compatibility alongside a AC-3 passthrough. Renditions whose codec matches the input codec
+/// are copied, the others are transcoded. The renditions are exposed as variants of a master
+/// playlist.
+#[derive(Debug)]
+pub struct MultiAudioTranscodeProfile;
+
+impl TranscodingProfile for MultiAudioTranscodeProfile {
+    fn profile_type(&self) -> ProfileType {
+        ProfileType::Transcode
+    }
+
+    fn stream_type(&self) -> StreamType {
+        StreamType::Audio
+    }
+
+    fn name(&self) -> &str {
+        "MultiAudioTranscodeProfile"
+    }
+
+    fn build(&self, ctx: ProfileContext) -> Option<Vec<String>> {
+        let start_num = ctx.output_ctx.start_num.to_string();
+        let stream = format!("0:{}", ctx.input_ctx.stream);
+        let init_seg = format!("{}_init.mp4", &start_num);
+        let seg_name = format!("{}/%v/%d.m4s", ctx.output_ctx.outdir);
+        let outdir = format!("{}/%v/playlist.m3u8", ctx.output_ctx.outdir);
+
+        let mut args = vec![
+            "-y".into(),
+            "-ss".into(),
+            ctx.input_seek().to_string(),
+            "-i".into(),
+            ctx.file.clone(),
+            "-copyts".into(),
+        ];
+
+        args.append(&mut super::video::get_output_seek_flags(&ctx));
+
+        for _ in ctx.output_ctx.audio_renditions.iter() {
+            args.append(&mut vec!["-map".into(), stream.clone()]);
+        }
+
+        for (idx, codec) in ctx.output_ctx.audio_renditions.iter().enumerate() {
+            if *codec == ctx.input_ctx.codec {
+                args.append(&mut vec![format!("-c:a:{}", idx), "copy".into()]);
+                continue;
+            }
+
+            args.append(&mut vec![format!("-c:a:{}", idx), codec.clone()]);
+
+            if let Some(filter) = downmix_filter(&ctx) {
+                args.append(&mut vec![format!("-filter:a:{}", idx), filter]);
+            }
+
+            let ab = ctx.output_ctx.bitrate.unwrap_or(120_000).to_string();
+            args.append(&mut vec![format!("-b:a:{}", idx), ab]);
+        }
+
+        args.append(&mut vec![
+            "-start_at_zero".into(),
+            "-fps_mode".into(),
+            "auto".into(),
+            "-avoid_negative_ts".into(),
+            "make_non_negative".into(),
+        ]);
+
+        args.append(&mut super::video::get_metadata_flags(
+            &ctx,
+            self.profile_type(),
+        ));
+
+        args.append(&mut vec![
+            "-f".into(),
+            "hls".into(),
+            "-hls_playlist_type".into(),
+            "event".into(),
+            "-start_number".into(),
+            start_num,
+        ]);
+
+        // needed so that in progress segments are named `tmp` and then renamed after the data is
+        // on disk.
+        // This in theory practically prevents the web server from returning a segment that is
+        // in progress.
+        args.append(&mut vec![
+            "-hls_flags".into(),
+            "temp_file+append_list".into(),
+            "-max_delay".into(),
+            "5000000".into(),
+        ]);
+
+        args.append(&mut super::video::get_discont_flags(&ctx));
+
+        args.append(&mut vec!["-hls_fmp4_init_filename".into(), init_seg]);
+
+        args.append(&mut vec![
+            "-hls_time".into(),
+            ctx.output_ctx.target_gop.to_string(),
+            "-force_key_frames".into(),
+            format!("expr:gte(t,n_forced*{})", ctx.output_ctx.target_gop),
+        ]);
+
+        args.append(&mut vec!["-hls_segment_type".into(), "fmp4".into()]);
+
+        // every rendition becomes its own variant named after its directory, the master playlist
+        // referencing all of them ends up in the root of `outdir`.
+        let var_stream_map = ctx
+            .output_ctx
+            .rendition_dirs()
+            .iter()
+            .enumerate()
+            .map(|(idx, dir)| format!("a:{},agroup:audio,name:{}", idx, dir))
+            .collect::<Vec<_>>()
+            .join(" ");
+
+        args.append(&mut vec![
+            "-var_stream_map".into(),
+            var_stream_map,
+            "-master_pl_name".into(),
+            "playlist.m3u8".into(),
+        ]);
+
+        args.append(&mut vec![
+            "-loglevel".into(),
+            "info".into(),
+            "-progress".into(),
+            "pipe:1".into(),
+        ]);
+        args.append(&mut vec!["-hls_segment_filename".into(), seg_name]);
+        args.push(outdir);
+
+        Some(args)
+    }
+
+    fn supports(&self, ctx: &ProfileContext) -> Result<(), NightfallError> {
+        if ctx.output_ctx.codec != "multi" {
+            return Err(NightfallError::ProfileNotSupported(
+                "Profile only supports the `multi` output codec.".into(),
+            ));
+        }
+
+        if ctx.output_ctx.audio_renditions.is_empty() {
+            return Err(NightfallError::ProfileNotSupported(
+                "No audio renditions requested.".into(),
+            ));
+        }
+
+        if ctx.output_ctx.audio_mix.is_some() {
+            return Err(NightfallError::ProfileNotSupported(
+                "Audio mixing is not supported with multiple renditions.".into(),
+            ));
+        }
+
+        for (idx, codec) in ctx.output_ctx.audio_renditions.iter().enumerate() {
+            // renditions are requested by their codec.
+            if ctx.output_ctx.audio_renditions[..idx].contains(codec) {
+                return Err(NightfallError::ProfileNotSupported(format!(
+                    "Rendition {} is requested more than once.",
+                    codec
+                )));
+            }
+
+            if codec != "aac" && *codec != ctx.input_ctx.codec {
+                return Err(NightfallError::ProfileNotSupported(format!(
+                    "Rendition {} can neither be transcoded nor copied from {}.",
+                    codec, ctx.input_ctx.codec
+                )));
+            }
+        }
+
+        Ok(())
+    }
+
+    fn tag(&self) -> &str {
+        "multi_audio"
+    }
+}
+
+/// Profile transcoding audio to Opus in WebM chunks, meant to be paired with
+/// `Vp9TranscodeProfile`.
+#[derive(Debug)]
+pub struct OpusTranscodeProfile;
+
+impl TranscodingProfile for OpusTranscodeProfile {
+    fn profile_type(&self) -> ProfileType {
+        ProfileType::Transcode
+    }
+
+    fn stream_type(&self) -> StreamType {
+        StreamType::Audio
+    }
+
+    fn name(&self) -> &str {
+        "OpusTranscodeProfile"
+    }
+
+    fn build(&self, ctx: ProfileContext) -> Option<Vec<String>> {
+        let mut args = opus_encode_args(&ctx);
+
+        args.append(&mut vec![
+            "-avoid_negative_ts".into(),
+            "make_non_negative".into(),
+        ]);
+
+        args.append(&mut super::video::get_metadata_flags(
+            &ctx,
+            self.profile_type(),
+        ));
+
+        args.append(&mut super::video::get_webm_chunk_flags(&ctx));
+
+        Some(args)
+    }
+
+    fn supports(&self, ctx: &ProfileContext) -> Result<(), NightfallError> {
+        if ctx.output_ctx.codec == "opus"
+            && ctx.output_ctx.audio_mix.is_none()
+            && ctx
+                .output_ctx
+                .container
+                .is_none_or(|x| x == Container::WebM)
+        {
+            return Ok(());
+        }
+
+        Err(NightfallError::ProfileNotSupported(
+            "Profile not supported.".into(),
+        ))
+    }
+
+    fn encoder(&self) -> Option<&str> {
+        Some("libopus")
+    }
+
+    fn tag(&self) -> &str {
+        "opus"
+    }
+
+    fn container(&self) -> Container {
+        Container::WebM
+    }
+}
+
+/// Profile transcoding audio to Opus in fMP4 segments, for browsers that play Opus but want the
+/// same container as the video. Only used when `OutputCtx::container` asks for `Container::Fmp4`,
+/// otherwise `OpusTranscodeProfile` is picked.
+#[derive(Debug)]
+pub struct OpusFmp4TranscodeProfile;
+
+impl TranscodingProfile for OpusFmp4TranscodeProfile {
+    fn profile_type(&self) -> ProfileType {
+        ProfileType::Transcode
+    }
+
+    fn stream_type(&self) -> StreamType {
+        StreamType::Audio
+    }
+
+    fn name(&self) -> &str {
+        "OpusFmp4TranscodeProfile"
+    }
+
+    fn build(&self, ctx: ProfileContext) -> Option<Vec<String>> {
+        let start_num = ctx.output_ctx.start_num.to_string();
+        let init_seg = format!("{}_init.mp4", &start_num);
+        let seg_name = format!("{}/%d.m4s", ctx.output_ctx.outdir);
+        let outdir = format!("{}/playlist.m3u8", ctx.output_ctx.outdir);
+
+        let mut args = opus_encode_args(&ctx);
+
+        args.append(&mut vec![
+            "-start_at_zero".into(),
+            "-avoid_negative_ts".into(),
+            "make_non_negative".into(),
+        ]);
+
+        args.append(&mut super::video::get_metadata_flags(
+            &ctx,
+            self.profile_type(),
+        ));
+
+        args.append(&mut vec![
+            "-f".into(),
+            "hls".into(),
+            "-hls_playlist_type".into(),
+            "event".into(),
+            "-start_number".into(),
+            start_num,
+        ]);
+
+        // needed so that in progress segments are named `tmp` and then renamed after the data is
+        // on disk.
+        // This in theory practically prevents the web server from returning a segment that is
+        // in progress.
+        args.append(&mut vec![
+            "-hls_flags".into(),
+            "temp_file+append_list".into(),
+            "-max_delay".into(),
+            "5000000".into(),
+        ]);
+
+        args.append(&mut super::video::get_discont_flags(&ctx));
+
+        // args needed so we can distinguish between init fragments for new streams.
+        // Basically on the web seeking works by reloading the entire video because of
+        // discontinuity issues that browsers seem to not ignore like mpv.
+        args.append(&mut vec!["-hls_fmp4_init_filename".into(), init_seg]);
+
+        args.append(&mut vec![
+            "-hls_time".into(),
+            ctx.output_ctx.target_gop.to_string(),
+        ]);
+
+        args.append(&mut vec!["-hls_segment_type".into(), "fmp4".into()]);
+        args.append(&mut vec![
+            "-loglevel".into(),
+            "info".into(),
+            "-progress".into(),
+            "pipe:1".into(),
+        ]);
+        args.append(&mut vec!["-hls_segment_filename".into(), seg_name]);
+        args.append(&mut vec![outdir]);
+
+        Some(args)
+    }
+
+    fn supports(&self, ctx: &ProfileContext) -> Result<(), NightfallError> {
+        if ctx.output_ctx.codec == "opus"
+            && ctx.output_ctx.audio_mix.is_none()
+            && ctx.output_ctx.container == Some(Container::Fmp4)
+        {
+            return Ok(());
+        }
+
+        Err(NightfallError::ProfileNotSupported(
+            "Profile not supported.".into(),
+        ))
+    }
+
+    fn encoder(&self) -> Option<&str> {
+        Some("libopus")
+    }
+
+    fn tag(&self) -> &str {
+        "opus_mp4"
+    }
+}
+
+/// Function returns the input and encoder args shared by the Opus profiles. Without an explicit
+/// bitrate 128kbps are used.
+fn opus_encode_args(ctx: &ProfileContext) -> Vec<String> {
+    let mut args = vec![
+        "-y".into(),
+        "-ss".into(),
+        ctx.input_seek().to_string(),
+        "-i".into(),
+        ctx.file.clone(),
+        "-copyts".into(),
+        "-map".into(),
+        format!("0:{}", ctx.input_ctx.stream),
+        "-c:0".into(),
+        "libopus".into(),
+        // libopus refuses some of the channel layouts ffmpeg reports for 5.1 sources.
+        "-mapping_family".into(),
+        "1".into(),
+    ];
+
+    args.append(&mut super::video::get_output_seek_flags(ctx));
+
+    if let Some(filter) = downmix_filter(ctx) {
+        args.append(&mut vec!["-af".into(), filter]);
+    }
+
+    let ab = ctx.output_ctx.bitrate.unwrap_or(128_000).to_string();
+    args.push("-b:a".into());
+    args.push(ab);
+
+    args
+}
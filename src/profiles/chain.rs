@@ -0,0 +1,186 @@
+use super::get_profile_for;
+use super::Container;
+use super::ProfileContext;
+use super::ProfileType;
+use super::StreamType;
+use super::TonemapAlgorithm;
+use super::TranscodingProfile;
+
+use crate::ffprobe::FFPWrapper;
+
+use serde_derive::{Deserialize, Serialize};
+use std::sync::Arc;
+
+/// What a client can play, usually reported by the player itself. Used by `build_chain` to pick
+/// the profiles of a session.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ClientCapabilities {
+    /// Video codecs the client can decode in order of preference, ex. `["hevc", "h264"]`.
+    pub video_codecs: Vec<String>,
+    /// Audio codecs the client can decode in order of preference, ex. `["aac", "opus"]`.
+    pub audio_codecs: Vec<String>,
+    /// Containers the client can play segments in.
+    pub containers: Vec<Container>,
+    /// Largest frame the client can display, video is downscaled to fit when it is larger.
+    pub max_width: Option<i64>,
+    pub max_height: Option<i64>,
+    /// Highest bitrate the client can take, in bits per second, see `OutputCtx::max_bitrate`.
+    pub max_bitrate: Option<u64>,
+    /// Whether the client can display HDR10 and HLG, HDR input gets tone mapped otherwise.
+    pub hdr: bool,
+}
+
+impl Default for ClientCapabilities {
+    fn default() -> Self {
+        Self {
+            video_codecs: vec!["h264".into()],
+            audio_codecs: vec!["aac".into()],
+            containers: vec![Container::Fmp4],
+            max_width: None,
+            max_height: None,
+            max_bitrate: None,
+            hdr: false,
+        }
+    }
+}
+
+impl ClientCapabilities {
+    fn codecs(&self, stream_type: StreamType) -> &[String] {
+        match stream_type {
+            StreamType::Video => &self.video_codecs,
+            StreamType::Audio => &self.audio_codecs,
+            _ => &[],
+        }
+    }
+
+    /// Returns the frame size to downscale a `width`x`height` video to so that it fits
+    /// `max_width` and `max_height`, or `None` if it already fits.
+    fn fit(&self, width: i64, height: i64) -> Option<(i64, i64)> {
+        if width <= 0 || height <= 0 {
+            return None;
+        }
+
+        let scale = [
+            self.max_width.map(|x| x as f64 / width as f64),
+            self.max_height.map(|x| x as f64 / height as f64),
+        ]
+        .iter()
+        .flatten()
+        .fold(1.0f64, |acc, x| acc.min(*x));
+
+        if scale >= 1.0 {
+            return None;
+        }
+
+        // most encoders only take even dimensions.
+        let even = |x: f64| ((x / 2.0).floor() as i64 * 2).max(2);
+
+        Some((even(width as f64 * scale), even(height as f64 * scale)))
+    }
+}
+
+/// Function builds the profile chain of the stream `ctx.input_ctx.stream` of `probe` for a client.
+/// The input fields of `ctx` are filled in from `probe` and its output fields are set up for the
+/// client, thus `ctx` should be passed to `StateManager::create` along with the chain.
+///
+/// Output codecs are tried in the order the client prefers them, except that the codec of the
+/// input comes first when the client can play it, so that the stream can be copied. The first
+/// codec any profile supports is picked. Within the chain the stream is preferably copied, then
+/// transcoded on the GPU and lastly transcoded in software. As `Session` pops profiles off the
+/// end of the chain, the chain is returned in the reverse order.
+///
+/// Returns an empty chain if the client cant play the stream with any of the active profiles.
+pub fn build_chain(
+    client: &ClientCapabilities,
+    probe: &FFPWrapper,
+    stream_type: StreamType,
+    ctx: &mut ProfileContext,
+) -> Vec<Arc<dyn TranscodingProfile>> {
+    let index = ctx.input_ctx.stream as i64;
+
+    if let Some(stream) = probe.streams().iter().find(|x| x.index == index) {
+        let input_ctx = &mut ctx.input_ctx;
+
+        input_ctx.codec = stream.codec_name.clone();
+        input_ctx.profile = stream.profile.clone().unwrap_or_default();
+        input_ctx.bitrate = probe.stream_bitrate(index).unwrap_or_default();
+        input_ctx.duration = probe.duration();
+
+        if let Some(channels) = stream.channels {
+            input_ctx.audio_channels = channels as u64;
+        }
+
+        if stream_type == StreamType::Video {
+            input_ctx.set_color_metadata(stream);
+
+            let size = stream.width.zip(stream.height);
+
+            if let Some((width, height)) = size.and_then(|(w, h)| client.fit(w, h)) {
+                ctx.output_ctx.width = Some(width);
+                ctx.output_ctx.height = Some(height);
+            }
+        }
+    }
+
+    ctx.output_ctx.max_bitrate = client.max_bitrate;
+
+    if stream_type == StreamType::Video {
+        if client.hdr {
+            ctx.output_ctx.hdr_passthrough = true;
+        } else {
+            ctx.output_ctx.tonemap = ctx.output_ctx.tonemap.or(Some(TonemapAlgorithm::default()));
+        }
+
+        if ctx.exceeds_bitrate_cap() {
+            ctx.output_ctx.bitrate = client.max_bitrate;
+        }
+    }
+
+    // MPEG-TS is only ever written when asked for, see `OutputCtx::container`.
+    if !client.containers.contains(&Container::Fmp4)
+        && !client.containers.contains(&Container::WebM)
+        && client.containers.contains(&Container::MpegTs)
+    {
+        ctx.output_ctx.container = Some(Container::MpegTs);
+    }
+
+    // subtitles and thumbnails dont have a codec the client picks.
+    if !matches!(stream_type, StreamType::Video | StreamType::Audio) {
+        return get_profile_for(stream_type, ctx);
+    }
+
+    let codecs = client.codecs(stream_type);
+
+    let input_codec = ctx.input_ctx.codec.clone();
+    let candidates = codecs
+        .iter()
+        .filter(|x| **x == input_codec)
+        .chain(codecs.iter().filter(|x| **x != input_codec));
+
+    for codec in candidates {
+        ctx.output_ctx.codec = codec.clone();
+
+        let mut chain = get_profile_for(stream_type, ctx)
+            .into_iter()
+            .filter(|x| client.containers.contains(&x.container()))
+            .collect::<Vec<_>>();
+
+        if chain.is_empty() {
+            continue;
+        }
+
+        chain.sort_by_key(|x| {
+            let rank = match x.profile_type() {
+                ProfileType::Transcode => 0,
+                ProfileType::HardwareTranscode => 1,
+                ProfileType::Transmux => 2,
+            };
+
+            (rank, x.priority())
+        });
+
+        return chain;
+    }
+
+    Vec::new()
+}
@@ -3,16 +3,56 @@ use super::ProfileType;
 use super::StreamType;
 use super::TranscodingProfile;
 
+use super::hwaccel::probe_encoder;
+use super::video::get_scale_filter;
+
 use crate::NightfallError;
 
+/// Codecs the NVENC encoders can produce.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum NvencCodec {
+    H264,
+    Hevc,
+}
+
+impl NvencCodec {
+    fn encoder(&self) -> &'static str {
+        match self {
+            Self::H264 => "h264_nvenc",
+            Self::Hevc => "hevc_nvenc",
+        }
+    }
+
+    /// Returns the value of `OutputCtx::codec` this encoder produces.
+    fn codec(&self) -> &'static str {
+        match self {
+            Self::H264 => "h264",
+            Self::Hevc => "hevc",
+        }
+    }
+}
+
 /// Cuda(NVENC/NVDEC) transcoding profiles.
-/// This is a nvidia exclusive transcoding profile that leverages cuda. This profile will
-/// automatically be enabled if any of your GPUs support encoding and decoding h264 with the
-/// profiles `Main`, `High` and `ConstrainedBaseline`. This profile will only transcode h264 input
-/// streams.
+/// This is a nvidia exclusive transcoding profile that leverages cuda. One profile exists per
+/// `NvencCodec`, each is only enabled if ffmpeg manages to encode a test frame with its encoder,
+/// which requires both a ffmpeg build with NVENC support and a capable GPU.
 #[cfg(unix)]
 #[derive(Debug)]
-pub struct CudaTranscodeProfile;
+pub struct CudaTranscodeProfile {
+    codec: NvencCodec,
+    /// Outcome of the test encode ran when the profile was created.
+    available: Result<(), NightfallError>,
+}
+
+#[cfg(unix)]
+impl CudaTranscodeProfile {
+    pub fn new(ffmpeg_bin: &str, codec: NvencCodec) -> Self {
+        Self {
+            codec,
+            available: probe_encoder(ffmpeg_bin, codec.encoder(), &[], None),
+        }
+    }
+}
 
 #[cfg(unix)]
 impl TranscodingProfile for CudaTranscodeProfile {
@@ -25,12 +65,14 @@ impl TranscodingProfile for CudaTranscodeProfile {
     }
 
     fn name(&self) -> &str {
-        "CudaTranscodeProfile"
+        match self.codec {
+            NvencCodec::H264 => "CudaTranscodeProfile",
+            NvencCodec::Hevc => "CudaHevcTranscodeProfile",
+        }
     }
 
     fn is_enabled(&self) -> Result<(), NightfallError> {
-        // TODO: Add runtime profile support detection.
-        return Ok(());
+        self.available.clone()
     }
 
     fn build(&self, ctx: ProfileContext) -> Option<Vec<String>> {
@@ -48,22 +90,27 @@ impl TranscodingProfile for CudaTranscodeProfile {
             "cuda".into(),
             "-y".into(),
             "-ss".into(),
-            (ctx.output_ctx.start_num * ctx.output_ctx.target_gop).to_string(),
+            ctx.input_seek().to_string(),
             "-i".into(),
             ctx.file.clone(),
             "-copyts".into(),
             "-map".into(),
             stream,
             "-c:0".into(),
-            "h264_nvenc".into(),
+            self.codec.encoder().into(),
             "-bf".into(),
             "0".into(),
         ];
 
-        if let Some(height) = ctx.output_ctx.height {
-            let width = ctx.output_ctx.width.unwrap_or(-2); // defaults to scaling by 2
-            args.push("-vf".into());
-            args.push(format!("scale_cuda={}:{}", height, width));
+        args.append(&mut super::video::get_output_seek_flags(&ctx));
+
+        // Apple players only accept hevc tagged as `hvc1`.
+        if self.codec == NvencCodec::Hevc {
+            args.append(&mut vec!["-tag:0".into(), "hvc1".into()]);
+        }
+
+        if let Some(scale) = get_scale_filter("scale_cuda", &ctx) {
+            args.append(&mut vec!["-vf".into(), scale]);
         }
 
         if let Some(bitrate) = ctx.output_ctx.bitrate {
@@ -71,24 +118,31 @@ impl TranscodingProfile for CudaTranscodeProfile {
             args.push(bitrate.to_string());
         }
 
+        args.append(&mut super::video::get_fps_flags(&ctx));
+
         args.append(&mut vec![
             "-start_at_zero".into(),
             "-vsync".into(),
-            "passthrough".into(),
+            super::video::get_fps_mode(&ctx),
             "-avoid_negative_ts".into(),
             "disabled".into(),
             "-max_muxing_queue_size".into(),
             "2048".into(),
             "-keyint_min".into(),
-            "120".into(),
+            super::video::get_gop_size(&ctx),
             "-g".into(),
-            "120".into(),
+            super::video::get_gop_size(&ctx),
             "-frag_duration".into(),
             "5000000".into(),
         ]);
 
         args.append(&mut super::video::get_discont_flags(&ctx));
 
+        args.append(&mut super::video::get_metadata_flags(
+            &ctx,
+            self.profile_type(),
+        ));
+
         args.append(&mut vec![
             "-f".into(),
             "hls".into(),
@@ -137,14 +191,30 @@ impl TranscodingProfile for CudaTranscodeProfile {
         Some(args)
     }
 
-    /// This profile technically could work on any codec since the codec is just `copy` here, but
-    /// the container doesnt support it, so we will be constricting it down.
-    fn supports(&self, _ctx: &ProfileContext) -> Result<(), NightfallError> {
-        // TODO: At runtime check which file formats are supported by the current gpu for enc/dec.
-        Ok(())
+    fn supports(&self, ctx: &ProfileContext) -> Result<(), NightfallError> {
+        if ctx.output_ctx.codec == self.codec.codec() {
+            return Ok(());
+        }
+
+        Err(NightfallError::ProfileNotSupported(format!(
+            "Got output codec {} but profile only supports `{}`.",
+            ctx.output_ctx.codec,
+            self.codec.codec()
+        )))
+    }
+
+    fn encoder(&self) -> Option<&str> {
+        Some(self.codec.encoder())
+    }
+
+    fn hwaccel(&self) -> Option<&str> {
+        Some("cuda")
     }
 
     fn tag(&self) -> &str {
-        "h264_cuda"
+        match self.codec {
+            NvencCodec::H264 => "h264_cuda",
+            NvencCodec::Hevc => "hevc_cuda",
+        }
     }
 }
@@ -0,0 +1,346 @@
+use super::video::get_discont_flags;
+use super::video::get_gop_size;
+use super::video::get_metadata_flags;
+use super::video::get_output_seek_flags;
+use super::video::get_thread_flags;
+use super::ProfileContext;
+use super::ProfileType;
+use super::StreamType;
+use super::TranscodingProfile;
+
+use crate::NightfallError;
+
+use serde_derive::{Deserialize, Serialize};
+use std::fs;
+use std::path::Path;
+
+/// Placeholders which can be used in `CustomProfile::args`.
+const PLACEHOLDERS: &[&str] = &[
+    "stream",
+    "bitrate",
+    "width",
+    "height",
+    "fps",
+    "gop_size",
+    "target_gop",
+    "audio_channels",
+    "threads",
+];
+
+/// A profile described by a config file instead of code, so that the ffmpeg flags can be tuned
+/// without recompiling. Loaded with `CustomProfile::load` and made available with
+/// `register_profile`.
+///
+/// The profile only supplies the encoding args, the input, seeking, metadata and fMP4 HLS output
+/// flags are added the same way the built-in profiles add them. In JSON:
+///
+/// ```json
+/// {
+///   "profiles": [{
+///     "tag": "h264_nvenc_p7",
+///     "name": "NvencSlowProfile",
+///     "stream_type": "Video",
+///     "codec": "h264",
+///     "encoder": "h264_nvenc",
+///     "args": ["-c:0", "h264_nvenc", "-preset", "p7", "-g", "{gop_size}"],
+///     "priority": 10
+///   }]
+/// }
+/// ```
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct CustomProfile {
+    /// Tag of the profile, must not clash with any other registered profile.
+    pub tag: String,
+    /// Name of the profile, used in logs.
+    pub name: String,
+    pub stream_type: StreamType,
+    /// Whether the profile copies or encodes the stream.
+    #[serde(default)]
+    pub profile_type: ProfileType,
+    /// Output codec the profile produces, ex. `h264`, matched against `OutputCtx::codec`.
+    pub codec: String,
+    /// Input codecs the profile accepts, any codec when empty.
+    #[serde(default)]
+    pub input_codecs: Vec<String>,
+    /// ffmpeg encoder used by `args`, ex. `h264_nvenc`. Used to check whether ffmpeg ships it,
+    /// see `Capabilities::check`, and to pass `ProfileContext::threads` on.
+    #[serde(default)]
+    pub encoder: Option<String>,
+    /// Encoding args put between the input and the output flags. The placeholders `{stream}`,
+    /// `{bitrate}`, `{width}`, `{height}`, `{fps}`, `{gop_size}`, `{target_gop}`,
+    /// `{audio_channels}` and `{threads}` get replaced with the values of the session, ex.
+    /// `{bitrate}` with `OutputCtx::bitrate`. The profile doesnt support sessions lacking a value
+    /// it uses.
+    pub args: Vec<String>,
+    /// Higher priority profiles are tried before the other profiles of the same type.
+    #[serde(default)]
+    pub priority: i32,
+}
+
+#[derive(Deserialize)]
+struct CustomProfiles {
+    profiles: Vec<CustomProfile>,
+}
+
+impl CustomProfile {
+    /// Function parses the profiles listed under `profiles` in a JSON document.
+    pub fn from_json(config: &str) -> Result<Vec<Self>, NightfallError> {
+        serde_json::from_str::<CustomProfiles>(config)
+            .map(|x| x.profiles)
+            .map_err(|e| NightfallError::InvalidConfig(format!("Invalid profiles: {}", e)))
+    }
+
+    /// Function parses the `[[profiles]]` tables of a TOML document.
+    #[cfg(feature = "toml")]
+    pub fn from_toml(config: &str) -> Result<Vec<Self>, NightfallError> {
+        toml::from_str::<CustomProfiles>(config)
+            .map(|x| x.profiles)
+            .map_err(|e| NightfallError::InvalidConfig(format!("Invalid profiles: {}", e)))
+    }
+
+    /// Function reads the profiles out of a config file, which is parsed as TOML if its extension
+    /// is `toml` and as JSON otherwise. Every profile gets validated, see `validate`.
+    pub fn load(path: impl AsRef<Path>) -> Result<Vec<Self>, NightfallError> {
+        let path = path.as_ref();
+        let config = fs::read_to_string(path).map_err(|e| {
+            NightfallError::InvalidConfig(format!("Failed to read {}: {}", path.display(), e))
+        })?;
+
+        let profiles = match path.extension().and_then(|x| x.to_str()) {
+            #[cfg(feature = "toml")]
+            Some("toml") => Self::from_toml(&config)?,
+            #[cfg(not(feature = "toml"))]
+            Some("toml") => {
+                return Err(NightfallError::InvalidConfig(
+                    "TOML profiles need the `toml` feature.".into(),
+                ))
+            }
+            _ => Self::from_json(&config)?,
+        };
+
+        for profile in profiles.iter() {
+            profile.validate()?;
+        }
+
+        Ok(profiles)
+    }
+
+    /// Function checks that the profile describes something nightfall can run. Only audio and
+    /// video streams can be described, and `args` may only use known placeholders.
+    pub fn validate(&self) -> Result<(), NightfallError> {
+        if self.tag.is_empty() || self.name.is_empty() {
+            return Err(NightfallError::InvalidConfig(
+                "Custom profiles need a tag and a name.".into(),
+            ));
+        }
+
+        if !matches!(self.stream_type, StreamType::Video | StreamType::Audio) {
+            return Err(NightfallError::InvalidConfig(format!(
+                "Custom profile {} must be for a video or audio stream.",
+                self.tag
+            )));
+        }
+
+        for arg in self.args.iter() {
+            for name in placeholders(arg) {
+                if !PLACEHOLDERS.contains(&name) {
+                    return Err(NightfallError::InvalidConfig(format!(
+                        "Custom profile {} uses unknown placeholder {{{}}}.",
+                        self.tag, name
+                    )));
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Returns `args` with the placeholders replaced, or the name of the first placeholder the
+    /// session doesnt have a value for.
+    fn render_args(&self, ctx: &ProfileContext) -> Result<Vec<String>, &'static str> {
+        let output_ctx = &ctx.output_ctx;
+        let value = |name: &str| -> Option<String> {
+            match name {
+                "stream" => Some(ctx.input_ctx.stream.to_string()),
+                "bitrate" => output_ctx.bitrate.map(|x| x.to_string()),
+                "width" => output_ctx.width.map(|x| x.to_string()),
+                "height" => output_ctx.height.map(|x| x.to_string()),
+                "fps" => output_ctx.fps.map(|x| x.to_string()),
+                "gop_size" => Some(get_gop_size(ctx)),
+                "target_gop" => Some(output_ctx.target_gop.to_string()),
+                "audio_channels" => Some(output_ctx.audio_channels.to_string()),
+                "threads" => ctx.threads.map(|x| x.to_string()),
+                _ => None,
+            }
+        };
+
+        self.args
+            .iter()
+            .map(|arg| {
+                let mut arg = arg.clone();
+
+                for name in PLACEHOLDERS {
+                    let placeholder = format!("{{{}}}", name);
+
+                    if arg.contains(&placeholder) {
+                        arg = arg.replace(&placeholder, &value(name).ok_or(*name)?);
+                    }
+                }
+
+                Ok(arg)
+            })
+            .collect()
+    }
+}
+
+/// Returns the names of the `{name}` placeholders in `arg`.
+fn placeholders(arg: &str) -> Vec<&str> {
+    arg.split('{')
+        .skip(1)
+        .filter_map(|x| x.split_once('}'))
+        .map(|(name, _)| name)
+        .collect()
+}
+
+impl TranscodingProfile for CustomProfile {
+    fn profile_type(&self) -> ProfileType {
+        self.profile_type
+    }
+
+    fn stream_type(&self) -> StreamType {
+        self.stream_type
+    }
+
+    fn is_enabled(&self) -> Result<(), NightfallError> {
+        self.validate()
+    }
+
+    fn build(&self, ctx: ProfileContext) -> Option<Vec<String>> {
+        let start_num = ctx.output_ctx.start_num.to_string();
+        let is_transmux = self.profile_type == ProfileType::Transmux;
+
+        // copied streams can only start on a keyframe.
+        let seek = if is_transmux {
+            ctx.chunk_start(ctx.output_ctx.start_num)
+        } else {
+            ctx.input_seek()
+        };
+
+        let mut args = vec![
+            "-y".into(),
+            "-ss".into(),
+            seek.to_string(),
+            "-i".into(),
+            ctx.file.clone(),
+            "-copyts".into(),
+            "-map".into(),
+            format!("0:{}", ctx.input_ctx.stream),
+        ];
+
+        if !is_transmux {
+            args.append(&mut get_output_seek_flags(&ctx));
+        }
+
+        args.append(&mut self.render_args(&ctx).ok()?);
+
+        if let Some(encoder) = self.encoder.as_deref() {
+            args.append(&mut get_thread_flags(&ctx, encoder));
+        }
+
+        if !is_transmux && self.stream_type == StreamType::Video {
+            args.append(&mut vec![
+                "-force_key_frames".into(),
+                format!("expr:gte(t,n_forced*{})", ctx.output_ctx.target_gop),
+            ]);
+        }
+
+        args.append(&mut vec![
+            "-avoid_negative_ts".into(),
+            "make_non_negative".into(),
+            "-max_muxing_queue_size".into(),
+            "2048".into(),
+        ]);
+
+        args.append(&mut get_metadata_flags(&ctx, self.profile_type));
+        args.append(&mut vec![
+            "-f".into(),
+            "hls".into(),
+            "-start_number".into(),
+            start_num.clone(),
+        ]);
+        args.append(&mut get_discont_flags(&ctx));
+        args.append(&mut vec![
+            "-hls_flags".into(),
+            "temp_file+append_list".into(),
+            "-max_delay".into(),
+            "5000000".into(),
+            "-hls_fmp4_init_filename".into(),
+            format!("{}_init.mp4", start_num),
+            "-hls_time".into(),
+            ctx.output_ctx.target_gop.to_string(),
+            "-hls_segment_type".into(),
+            "fmp4".into(),
+            "-loglevel".into(),
+            "info".into(),
+            "-progress".into(),
+            "pipe:1".into(),
+            "-hls_segment_filename".into(),
+            format!("{}/%d.m4s", ctx.output_ctx.outdir),
+            format!("{}/playlist.m3u8", ctx.output_ctx.outdir),
+        ]);
+
+        Some(args)
+    }
+
+    fn supports(&self, ctx: &ProfileContext) -> Result<(), NightfallError> {
+        if ctx.output_ctx.single_file || ctx.output_ctx.audio_mix.is_some() {
+            return Err(NightfallError::ProfileNotSupported(
+                "Custom profiles only write HLS segments of a single stream.".into(),
+            ));
+        }
+
+        if self.profile_type == ProfileType::Transmux
+            && (ctx.output_ctx.height.is_some()
+                || ctx.output_ctx.width.is_some()
+                || ctx.output_ctx.bitrate.is_some())
+        {
+            return Err(NightfallError::ProfileNotSupported(
+                "Transmuxed streams cannot be resized.".into(),
+            ));
+        }
+
+        if !self.input_codecs.is_empty() && !self.input_codecs.contains(&ctx.input_ctx.codec) {
+            return Err(NightfallError::ProfileNotSupported(format!(
+                "Got input codec {} but profile only supports {:?}.",
+                ctx.input_ctx.codec, self.input_codecs
+            )));
+        }
+
+        if ctx.output_ctx.codec != self.codec {
+            return Err(NightfallError::ProfileNotSupported(format!(
+                "Got output codec {} but profile only supports `{}`.",
+                ctx.output_ctx.codec, self.codec
+            )));
+        }
+
+        self.render_args(ctx).map(|_| ()).map_err(|name| {
+            NightfallError::ProfileNotSupported(format!("Profile needs a value for {{{}}}.", name))
+        })
+    }
+
+    fn encoder(&self) -> Option<&str> {
+        self.encoder.as_deref()
+    }
+
+    fn priority(&self) -> i32 {
+        self.priority
+    }
+
+    fn tag(&self) -> &str {
+        &self.tag
+    }
+
+    fn name(&self) -> &str {
+        &self.name
+    }
+}
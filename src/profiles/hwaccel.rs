@@ -0,0 +1,57 @@
+use crate::NightfallError;
+
+use std::process::Command;
+use std::process::Stdio;
+
+/// Function checks whether `encoder` actually works with the given ffmpeg build and the hardware
+/// of this host by encoding a single blank frame with it. Listing the encoders ffmpeg was built
+/// with isnt enough, as hardware encoders only fail once a device is opened.
+///
+/// # Arguments
+/// * `ffmpeg_bin` - path to the ffmpeg binary sessions will use.
+/// * `encoder` - name of the encoder, ex. `h264_nvenc`.
+/// * `pre_args` - args placed before the input, ex. to initialize a hardware device.
+/// * `filter` - filter uploading the frame to the device, if the encoder requires it.
+pub fn probe_encoder(
+    ffmpeg_bin: &str,
+    encoder: &str,
+    pre_args: &[&str],
+    filter: Option<&str>,
+) -> Result<(), NightfallError> {
+    let mut args = vec!["-hide_banner", "-loglevel", "error"];
+    args.extend_from_slice(pre_args);
+    args.extend_from_slice(&[
+        "-f",
+        "lavfi",
+        "-i",
+        "color=black:size=256x256:duration=0.1",
+        "-frames:v",
+        "1",
+    ]);
+
+    if let Some(filter) = filter {
+        args.extend_from_slice(&["-vf", filter]);
+    }
+
+    args.extend_from_slice(&["-c:v", encoder, "-f", "null", "-"]);
+
+    let output = Command::new(ffmpeg_bin)
+        .args(args.as_slice())
+        .stdin(Stdio::null())
+        .output()
+        .map_err(|e| {
+            NightfallError::ProfileNotSupported(format!("Failed to run {}: {}", ffmpeg_bin, e))
+        })?;
+
+    if output.status.success() {
+        return Ok(());
+    }
+
+    let stderr = String::from_utf8_lossy(&output.stderr);
+
+    Err(NightfallError::ProfileNotSupported(format!(
+        "Encoder {} is unavailable: {}",
+        encoder,
+        stderr.lines().last().unwrap_or("unknown error")
+    )))
+}
@@ -1,69 +1,179 @@
 #[cfg(windows)]
 pub mod amf;
 pub mod audio;
+pub mod chain;
 #[cfg(all(unix, feature = "cuda"))]
 pub mod cuda;
+pub mod custom;
+pub mod hwaccel;
+pub mod mpegts;
+pub mod overlay;
+pub mod progressive;
+#[cfg(feature = "qsv")]
+pub mod qsv;
 pub mod subtitle;
 pub mod thumbnail;
 #[cfg(all(unix, feature = "vaapi"))]
 pub mod vaapi;
 pub mod video;
+#[cfg(target_os = "macos")]
+pub mod videotoolbox;
 
+#[cfg(windows)]
+pub use amf::AmfCodec;
 #[cfg(windows)]
 pub use amf::AmfTranscodeProfile;
 pub use audio::AacTranscodeProfile;
 pub use audio::Ac3TransmuxProfile;
+pub use audio::AudioMix;
+pub use audio::ChannelLayout;
 pub use audio::Eac3TransmuxProfile;
+pub use audio::MultiAudioTranscodeProfile;
+pub use audio::OpusFmp4TranscodeProfile;
+pub use audio::OpusTranscodeProfile;
+pub use chain::build_chain;
+pub use chain::ClientCapabilities;
 #[cfg(all(unix, feature = "cuda"))]
 pub use cuda::CudaTranscodeProfile;
+#[cfg(all(unix, feature = "cuda"))]
+pub use cuda::NvencCodec;
+pub use custom::CustomProfile;
+pub use mpegts::AacTsTranscodeProfile;
+pub use mpegts::H264TsTranscodeProfile;
+pub use mpegts::H264TsTransmuxProfile;
+pub use overlay::BurnInTranscodeProfile;
+pub use overlay::OverlayLayer;
+pub use progressive::ProgressiveMp4Profile;
+#[cfg(feature = "qsv")]
+pub use qsv::QsvCodec;
+#[cfg(feature = "qsv")]
+pub use qsv::QsvTranscodeProfile;
 use serde_derive::{Deserialize, Serialize};
 #[cfg(feature = "ssa_transmux")]
 pub use subtitle::AssExtractProfile;
+pub use subtitle::SegmentedWebvttTranscodeProfile;
+pub use subtitle::TimedTextTranscodeProfile;
 pub use subtitle::WebvttTranscodeProfile;
 pub use thumbnail::ThumbnailProfile;
 use tracing::debug;
 use tracing::info;
 use tracing::warn;
 #[cfg(all(unix, feature = "vaapi"))]
+pub use vaapi::VaapiCodec;
+#[cfg(all(unix, feature = "vaapi"))]
 pub use vaapi::VaapiTranscodeProfile;
+pub use video::AV1TranscodeProfile;
 pub use video::AV1TransmuxProfile;
 pub use video::H264TranscodeProfile;
 pub use video::H264TransmuxProfile;
+pub use video::HevcTranscodeProfile;
 pub use video::HevcTransmuxProfile;
 pub use video::RawVideoTranscodeProfile;
+pub use video::TonemapAlgorithm;
+pub use video::TrickplayTranscodeProfile;
+pub use video::Vp9TranscodeProfile;
+#[cfg(target_os = "macos")]
+pub use videotoolbox::VideoToolboxCodec;
+#[cfg(target_os = "macos")]
+pub use videotoolbox::VideoToolboxTranscodeProfile;
 
+use crate::ffprobe::FieldOrder;
+use crate::ffprobe::Stream;
+use crate::patch::cenc::CencConfig;
+use crate::thumbnails::ThumbnailConfig;
 use crate::NightfallError;
+use std::collections::HashMap;
 use std::fmt::Debug;
+use std::sync::Arc;
+use std::sync::RwLock;
 
 use once_cell::sync::OnceCell;
 
-static PROFILES: OnceCell<Vec<Box<dyn TranscodingProfile>>> = OnceCell::new();
+static PROFILES: OnceCell<RwLock<Vec<Arc<dyn TranscodingProfile>>>> = OnceCell::new();
+
+fn registry() -> &'static RwLock<Vec<Arc<dyn TranscodingProfile>>> {
+    PROFILES
+        .get()
+        .expect("nightfall::PROFILES not initialized.")
+}
 
-pub fn profiles_init(_ffmpeg_bin: String) {
+pub fn profiles_init(ffmpeg_bin: String) {
     let profiles: Vec<Option<Box<dyn TranscodingProfile>>> = vec![
         Some(Box::new(AacTranscodeProfile)),
         Some(Box::new(Ac3TransmuxProfile)),
         Some(Box::new(Eac3TransmuxProfile)),
+        Some(Box::new(MultiAudioTranscodeProfile)),
+        Some(Box::new(OpusTranscodeProfile)),
+        Some(Box::new(OpusFmp4TranscodeProfile)),
         Some(Box::new(AV1TransmuxProfile)),
+        Some(Box::new(AV1TranscodeProfile::new(&ffmpeg_bin))),
         Some(Box::new(H264TranscodeProfile)),
         Some(Box::new(H264TransmuxProfile)),
         Some(Box::new(HevcTransmuxProfile)),
+        Some(Box::new(HevcTranscodeProfile::new(&ffmpeg_bin))),
         Some(Box::new(RawVideoTranscodeProfile)),
+        Some(Box::new(TrickplayTranscodeProfile)),
+        Some(Box::new(Vp9TranscodeProfile)),
+        Some(Box::new(H264TsTransmuxProfile)),
+        Some(Box::new(H264TsTranscodeProfile)),
+        Some(Box::new(AacTsTranscodeProfile)),
+        Some(Box::new(ProgressiveMp4Profile)),
         Some(Box::new(WebvttTranscodeProfile)),
+        Some(Box::new(SegmentedWebvttTranscodeProfile)),
+        Some(Box::new(TimedTextTranscodeProfile)),
+        Some(Box::new(BurnInTranscodeProfile)),
         Some(Box::new(ThumbnailProfile)),
         #[cfg(feature = "ssa_transmux")]
         Some(Box::new(AssExtractProfile)),
         #[cfg(all(unix, feature = "cuda"))]
-        Some(Box::new(CudaTranscodeProfile)),
+        Some(Box::new(CudaTranscodeProfile::new(
+            &ffmpeg_bin,
+            NvencCodec::H264,
+        ))),
+        #[cfg(all(unix, feature = "cuda"))]
+        Some(Box::new(CudaTranscodeProfile::new(
+            &ffmpeg_bin,
+            NvencCodec::Hevc,
+        ))),
         #[cfg(all(unix, feature = "vaapi"))]
-        VaapiTranscodeProfile::new().map(|x| Box::new(x) as _),
+        VaapiTranscodeProfile::new(VaapiCodec::H264).map(|x| Box::new(x) as _),
+        #[cfg(all(unix, feature = "vaapi"))]
+        VaapiTranscodeProfile::new(VaapiCodec::Hevc).map(|x| Box::new(x) as _),
+        #[cfg(feature = "qsv")]
+        Some(Box::new(QsvTranscodeProfile::new(
+            &ffmpeg_bin,
+            QsvCodec::H264,
+        ))),
+        #[cfg(feature = "qsv")]
+        Some(Box::new(QsvTranscodeProfile::new(
+            &ffmpeg_bin,
+            QsvCodec::Hevc,
+        ))),
+        #[cfg(target_os = "macos")]
+        Some(Box::new(VideoToolboxTranscodeProfile::new(
+            &ffmpeg_bin,
+            VideoToolboxCodec::H264,
+        ))),
+        #[cfg(target_os = "macos")]
+        Some(Box::new(VideoToolboxTranscodeProfile::new(
+            &ffmpeg_bin,
+            VideoToolboxCodec::Hevc,
+        ))),
+        #[cfg(windows)]
+        Some(Box::new(AmfTranscodeProfile::new(
+            &ffmpeg_bin,
+            AmfCodec::H264,
+        ))),
         #[cfg(windows)]
-        Some(Box::new(AmfTranscodeProfile)),
+        Some(Box::new(AmfTranscodeProfile::new(
+            &ffmpeg_bin,
+            AmfCodec::Hevc,
+        ))),
     ];
 
     let profiles = profiles.into_iter().filter_map(|x| x).collect::<Vec<_>>();
 
-    let _ = PROFILES.set(
+    let _ = PROFILES.set(RwLock::new(
         profiles
             .into_iter()
             .filter(|x| {
@@ -81,29 +191,92 @@ pub fn profiles_init(_ffmpeg_bin: String) {
                     true
                 }
             })
+            .map(Arc::from)
             .collect(),
-    );
+    ));
 }
 
-pub fn get_active_profiles() -> Vec<&'static dyn TranscodingProfile> {
-    PROFILES
-        .get()
-        .expect("nightfall::PROFILES not initialized.")
-        .iter()
-        .map(AsRef::as_ref)
-        .collect()
+/// Function registers a profile at runtime, ex. a profile configured by the application, so that
+/// it gets picked by `get_profile_for` like the built-in ones. Sessions hold on to their profiles,
+/// thus a profile can be replaced or unregistered while sessions still use it.
+///
+/// # Errors
+/// Returns `ProfileNotSupported` if the profile isnt enabled on this host, see
+/// `TranscodingProfile::is_enabled`, and `InvalidConfig` if a profile with the same tag is
+/// already registered.
+pub fn register_profile(profile: Arc<dyn TranscodingProfile>) -> Result<(), NightfallError> {
+    profile.is_enabled()?;
+
+    let mut profiles = registry().write().unwrap();
+
+    if profiles.iter().any(|x| x.tag() == profile.tag()) {
+        return Err(NightfallError::InvalidConfig(format!(
+            "A profile tagged {} is already registered.",
+            profile.tag()
+        )));
+    }
+
+    info!(profile = profile.name(), "Registering profile");
+    profiles.push(profile);
+
+    Ok(())
+}
+
+/// Function unregisters the profile tagged `tag`, returning it if it was registered.
+pub fn unregister_profile(tag: &str) -> Option<Arc<dyn TranscodingProfile>> {
+    let mut profiles = registry().write().unwrap();
+    let idx = profiles.iter().position(|x| x.tag() == tag)?;
+
+    Some(profiles.remove(idx))
+}
+
+pub fn get_active_profiles() -> Vec<Arc<dyn TranscodingProfile>> {
+    registry().read().unwrap().clone()
+}
+
+/// Returns whether `profile` would decode a Dolby Vision stream whose base layer cant be decoded
+/// on its own, see `SideDataList::has_compatible_base_layer`. Only copying such streams works.
+fn breaks_dolby_vision(profile: &dyn TranscodingProfile, ctx: &ProfileContext) -> bool {
+    profile.stream_type() == StreamType::Video
+        && profile.profile_type() != ProfileType::Transmux
+        && ctx
+            .input_ctx
+            .dolby_vision()
+            .is_some_and(|x| !x.has_compatible_base_layer())
+}
+
+/// Returns whether `profile` would copy a stream whose bitrate is above what the client can take,
+/// see `OutputCtx::max_bitrate`.
+fn exceeds_bitrate_cap(profile: &dyn TranscodingProfile, ctx: &ProfileContext) -> bool {
+    profile.profile_type() == ProfileType::Transmux && ctx.exceeds_bitrate_cap()
+}
+
+/// Returns whether `profile` writes MPEG-TS chunks while the context doesnt ask for them, or the
+/// other way around. MPEG-TS chunks dont have init segments thus are only written when asked for
+/// with `OutputCtx::container`.
+fn mismatches_ts(profile: &dyn TranscodingProfile, ctx: &ProfileContext) -> bool {
+    matches!(profile.stream_type(), StreamType::Video | StreamType::Audio)
+        && (profile.container() == Container::MpegTs)
+            != (ctx.output_ctx.container == Some(Container::MpegTs))
 }
 
 pub fn get_profile_for(
     stream_type: StreamType,
     ctx: &ProfileContext,
-) -> Vec<&'static dyn TranscodingProfile> {
-    let mut profiles: Vec<_> = PROFILES
-        .get()
-        .expect("nightfall::PROFILES not initialized.")
+) -> Vec<Arc<dyn TranscodingProfile>> {
+    let mut profiles: Vec<_> = registry()
+        .read()
+        .unwrap()
         .iter()
         .filter(|x| {
             x.stream_type() == stream_type
+                && x.burns_in() == ctx.output_ctx.needs_burn_in()
+                && x.is_progressive() == ctx.output_ctx.progressive
+                && (x.stream_type() != StreamType::Video || x.tonemaps() || !ctx.needs_tonemap())
+                && (x.stream_type() != StreamType::Video || x.keeps_hdr() || !ctx.keeps_hdr())
+                && !breaks_dolby_vision(x.as_ref(), ctx)
+                && !mismatches_ts(x.as_ref(), ctx)
+                && !exceeds_bitrate_cap(x.as_ref(), ctx)
                 && if let Err(e) = x.supports(ctx) {
                     debug!(
                         profile = x.name(),
@@ -116,10 +289,10 @@ pub fn get_profile_for(
                     true
                 }
         })
-        .map(AsRef::as_ref)
+        .cloned()
         .collect();
 
-    profiles.sort_by_key(|x| x.profile_type());
+    profiles.sort_by_key(|x| (x.profile_type(), x.priority()));
 
     profiles
 }
@@ -128,14 +301,21 @@ pub fn get_profile_for_with_type(
     stream_type: StreamType,
     profile_type: ProfileType,
     ctx: &ProfileContext,
-) -> Vec<&'static dyn TranscodingProfile> {
-    let mut profiles: Vec<_> = PROFILES
-        .get()
-        .expect("nightfall::PROFILES not initialized.")
+) -> Vec<Arc<dyn TranscodingProfile>> {
+    let mut profiles: Vec<_> = registry()
+        .read()
+        .unwrap()
         .iter()
         .filter(|x| {
             x.profile_type() == profile_type
                 && x.stream_type() == stream_type
+                && x.burns_in() == ctx.output_ctx.needs_burn_in()
+                && x.is_progressive() == ctx.output_ctx.progressive
+                && (x.stream_type() != StreamType::Video || x.tonemaps() || !ctx.needs_tonemap())
+                && (x.stream_type() != StreamType::Video || x.keeps_hdr() || !ctx.keeps_hdr())
+                && !breaks_dolby_vision(x.as_ref(), ctx)
+                && !mismatches_ts(x.as_ref(), ctx)
+                && !exceeds_bitrate_cap(x.as_ref(), ctx)
                 && if let Err(e) = x.supports(ctx) {
                     debug!(
                         profile = x.name(),
@@ -148,14 +328,49 @@ pub fn get_profile_for_with_type(
                     true
                 }
         })
-        .map(AsRef::as_ref)
+        .cloned()
         .collect();
 
-    profiles.sort_by_key(|x| x.profile_type());
+    profiles.sort_by_key(|x| (x.profile_type(), x.priority()));
 
     profiles
 }
 
+/// Function explains why none of the transmux profiles for `stream_type` can handle `ctx`.
+pub fn direct_play_unavailable_reason(stream_type: StreamType, ctx: &ProfileContext) -> String {
+    let profiles = PROFILES
+        .get()
+        .map(|x| x.read().unwrap().clone())
+        .unwrap_or_default();
+
+    let reasons = profiles
+        .iter()
+        .filter(|x| x.profile_type() == ProfileType::Transmux && x.stream_type() == stream_type)
+        .filter_map(|x| {
+            x.supports(ctx)
+                .err()
+                .map(|e| format!("{}: {}", x.name(), e))
+        })
+        .collect::<Vec<_>>();
+
+    if ctx.exceeds_bitrate_cap() {
+        return format!(
+            "Input bitrate {} exceeds the cap of {}.",
+            ctx.input_ctx.bitrate,
+            ctx.output_ctx.max_bitrate.unwrap_or_default()
+        );
+    }
+
+    if reasons.is_empty() {
+        return format!(
+            "No transmux profile for {} -> {}.",
+            ctx.input_ctx.codec, ctx.output_ctx.codec
+        );
+    }
+
+    reasons.join("; ")
+}
+
 pub trait TranscodingProfile: Debug + Send + Sync + 'static {
     /// Function must return what kind of profile it is.
     fn profile_type(&self) -> ProfileType;
@@ -191,19 +406,179 @@ pub trait TranscodingProfile: Debug + Send + Sync + 'static {
     fn is_stdio_stream(&self) -> bool {
         false
     }
+
+    /// Function will return the frame rate of the output if the profile forces one regardless of
+    /// the input. This is used to figure out which chunk ffmpeg is currently encoding.
+    fn output_fps(&self) -> Option<f64> {
+        None
+    }
+
+    /// Function will return the container the segments produced by this profile are stored in.
+    fn container(&self) -> Container {
+        Container::Fmp4
+    }
+
+    /// Function will return whether this profile burns `OutputCtx::burn_layers` into the video.
+    /// When anything is to be burned in, only such profiles are picked.
+    fn burns_in(&self) -> bool {
+        false
+    }
+
+    /// Function will return whether this profile tone maps HDR input to SDR when asked to with
+    /// `OutputCtx::tonemap`. When tone mapping is needed, only such video profiles are picked.
+    fn tonemaps(&self) -> bool {
+        false
+    }
+
+    /// Function will return whether this profile keeps the HDR metadata of the input. When
+    /// `ProfileContext::keeps_hdr` is true, only such video profiles are picked.
+    fn keeps_hdr(&self) -> bool {
+        false
+    }
+
+    /// Function will return whether the WebVTT subtitle written by this profile has to be split
+    /// into chunks once ffmpeg exits, see `webvtt::split_file`.
+    fn splits_subtitles(&self) -> bool {
+        false
+    }
+
+    /// Function will return whether this profile streams the whole input as a single progressive
+    /// MP4 over stdout. Such profiles are only picked when `OutputCtx::progressive` is set.
+    fn is_progressive(&self) -> bool {
+        false
+    }
+
+    /// Function will return the ffmpeg encoder this profile encodes with, if any. Profiles whose
+    /// encoder isnt shipped by ffmpeg are dropped from a chain, see `Capabilities::check`.
+    fn encoder(&self) -> Option<&str> {
+        None
+    }
+
+    /// Function will return the `-hwaccel` method this profile decodes with, if any.
+    fn hwaccel(&self) -> Option<&str> {
+        None
+    }
+
+    /// Function will return the priority of this profile. Among the profiles of the same type,
+    /// those with a higher priority are tried first.
+    fn priority(&self) -> i32 {
+        0
+    }
 }
 
 /// A context which contains information we may need when building the ffmpeg arguments.
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct ProfileContext {
     pub file: String,
     pub pre_args: Vec<String>,
     pub input_ctx: InputCtx,
     pub output_ctx: OutputCtx,
     pub ffmpeg_bin: String,
+    /// Overrides the `movflags` fMP4 segments are muxed with, ex. `cmaf+dash+delay_moov`.
+    /// `frag_discont` is still appended when seeking. See `validate_movflags`.
+    pub movflags: Option<String>,
+    /// Pins the timescale of video tracks in the init segment (`mdhd`), so that segments of
+    /// renditions produced by different sessions share the same time base. The decode times of
+    /// video segments get normalized against it when patching.
+    pub target_timescale: Option<u32>,
+    /// Arbitrary labels attached by the caller, ex. to group sessions with `OutdirLayout::Grouped`.
+    pub labels: HashMap<String, String>,
+    /// Render node vaapi profiles use for this session, ex. `/dev/dri/renderD129`. Defaults to the
+    /// render node the profile was created with.
+    pub vaapi_device: Option<String>,
+    /// Container level metadata written into the output, ex. `title`. Passed to ffmpeg as
+    /// `-metadata key=value`, see `validate_metadata`.
+    pub metadata: HashMap<String, String>,
+    /// Maximum amount of threads software encoders may use, so that a single heavy transcode
+    /// cant starve the other sessions. Hardware encoders ignore it.
+    pub threads: Option<u32>,
+    /// Niceness ffmpeg runs with, from `-20` to `19`. Unix only.
+    pub nice: Option<i32>,
+    /// cgroup v2 directory ffmpeg gets moved into once started, ex.
+    /// `/sys/fs/cgroup/nightfall/background`, to cap its cpu and memory. Linux only.
+    pub cgroup: Option<String>,
+    /// Opaque key of the client owning the session, ex. a user or device id. Sessions of the same
+    /// owner can be listed and killed together, see `StateManager::owner_sessions`.
+    pub owner: Option<String>,
 }
 
-#[derive(Clone, Debug)]
+/// Function checks that the keys and values of `ProfileContext::metadata` can be passed to
+/// ffmpeg verbatim. Keys may only contain ascii alphanumerics, `_`, `-` and `.`, as ffmpeg splits
+/// `key=value` on the first `=` and muxers silently drop anything else.
+pub fn validate_metadata(metadata: &HashMap<String, String>) -> Result<(), NightfallError> {
+    for (key, value) in metadata.iter() {
+        let valid_key = !key.is_empty()
+            && key
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '-' | '.'));
+
+        if !valid_key {
+            return Err(NightfallError::InvalidProfileContext(format!(
+                "Invalid metadata key `{}`.",
+                key
+            )));
+        }
+
+        if value.contains('\0') {
+            return Err(NightfallError::InvalidProfileContext(format!(
+                "Value of metadata key `{}` contains a nul byte.",
+                key
+            )));
+        }
+    }
+
+    Ok(())
+}
+
+/// How many chunks get read at full speed before `OutputCtx::readrate` kicks in.
+pub const READRATE_BURST_CHUNKS: u32 = 3;
+
+/// How far ahead of the chunk boundary to seek the input with `OutputCtx::accurate_seek`, in
+/// seconds, when the keyframe interval of the source isnt known.
+const ACCURATE_SEEK_PREROLL: f64 = 10.0;
+
+/// Flags of which at least one must be present for ffmpeg to produce fragmented output, which the
+/// segment patching relies on.
+const FRAGMENT_MOVFLAGS: [&str; 4] = ["frag_custom", "frag_keyframe", "frag_every_frame", "cmaf"];
+
+/// Flags of which one must accompany `cmaf`, so that segments carry the `sidx` box their decode
+/// time gets normalized against.
+const SIDX_MOVFLAGS: [&str; 2] = ["dash", "global_sidx"];
+
+/// Function checks that custom `movflags` still produce segments nightfall can patch.
+pub fn validate_movflags(movflags: &str) -> Result<(), NightfallError> {
+    let flags = movflags.split('+').collect::<Vec<_>>();
+
+    if flags
+        .iter()
+        .any(|x| x.is_empty() || x.contains(char::is_whitespace))
+    {
+        return Err(NightfallError::InvalidProfileContext(format!(
+            "Malformed movflags `{}`.",
+            movflags
+        )));
+    }
+
+    if !flags.iter().any(|x| FRAGMENT_MOVFLAGS.contains(x)) {
+        return Err(NightfallError::InvalidProfileContext(format!(
+            "movflags `{}` must contain one of {}.",
+            movflags,
+            FRAGMENT_MOVFLAGS.join(", ")
+        )));
+    }
+
+    if flags.contains(&"cmaf") && !flags.iter().any(|x| SIDX_MOVFLAGS.contains(x)) {
+        return Err(NightfallError::InvalidProfileContext(format!(
+            "movflags `{}` must contain one of {} along with cmaf.",
+            movflags,
+            SIDX_MOVFLAGS.join(", ")
+        )));
+    }
+
+    Ok(())
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct InputCtx {
     pub stream: usize,
     pub audio_channels: u64,
@@ -215,6 +590,39 @@ pub struct InputCtx {
     pub bitrate: u64,
     pub seek: Option<i64>,
     pub side_data_list: Option<Vec<SideDataList>>,
+    /// Transfer characteristics of the video stream as reported by ffprobe, ex. `smpte2084` for
+    /// HDR10 or `arib-std-b67` for HLG.
+    pub color_transfer: Option<String>,
+    /// Color primaries of the video stream as reported by ffprobe, ex. `bt2020`.
+    pub color_primaries: Option<String>,
+    /// Matrix coefficients of the video stream as reported by ffprobe, ex. `bt2020nc`.
+    pub color_space: Option<String>,
+    /// How the frames of the video stream are scanned, as returned by `Stream::field_order` or
+    /// `detect_field_order`. Software transcodes deinterlace interlaced and telecined input.
+    pub field_order: Option<FieldOrder>,
+    /// Average interval in seconds between keyframes of the source stream, as returned by
+    /// `FFProbeCtx::get_keyframe_interval`.
+    pub keyframe_interval: Option<f64>,
+    /// Sorted timestamps in seconds of every keyframe of the source stream, as returned by
+    /// `FFProbeCtx::get_keyframes`. When set, transmuxed chunks start exactly on the source
+    /// keyframes, see `ProfileContext::chunk_start`.
+    pub keyframes: Option<Vec<f64>>,
+    /// Duration of the input in seconds, as returned by `FFPWrapper::duration`. Chunks past the
+    /// end of the input are rejected with `ChunkOutOfRange` when set.
+    pub duration: Option<f64>,
+    /// Ordered list of preferred audio languages. When non-empty, `create` will pick the audio
+    /// stream out of `audio_streams` that best matches these languages.
+    pub audio_languages: Vec<String>,
+    /// Probed audio streams available for language based selection.
+    pub audio_streams: Vec<Stream>,
+    /// Path to an external subtitle file (`.srt`, `.ass`, ...) that should be used instead of an
+    /// embedded subtitle stream.
+    pub subtitle_file: Option<String>,
+    /// Probed subtitle streams of the input, used to validate `OutputCtx::burn_subtitle`.
+    pub subtitle_streams: Vec<Stream>,
+    /// Font attachments of the input, as returned by `FFPWrapper::font_attachments`. These get
+    /// extracted when burning in subtitles so that libass renders them with the right fonts.
+    pub font_attachments: Vec<Stream>,
 }
 
 impl Default for InputCtx {
@@ -230,11 +638,50 @@ impl Default for InputCtx {
             bitrate: 0,
             seek: None,
             side_data_list: None,
+            color_transfer: None,
+            color_primaries: None,
+            color_space: None,
+            field_order: None,
+            keyframe_interval: None,
+            keyframes: None,
+            duration: None,
+            audio_languages: Vec::new(),
+            audio_streams: Vec::new(),
+            subtitle_file: None,
+            subtitle_streams: Vec::new(),
+            font_attachments: Vec::new(),
         }
     }
 }
 
-#[derive(Clone, Debug)]
+impl InputCtx {
+    /// Returns the Dolby Vision configuration of the video stream, if it is Dolby Vision.
+    pub fn dolby_vision(&self) -> Option<&SideDataList> {
+        self.side_data_list
+            .as_deref()?
+            .iter()
+            .find(|x| x.is_dolby_vision())
+    }
+
+    /// Copies the color metadata and the side data of a probed video stream, so that HDR input
+    /// is detected without probing the file again.
+    pub fn set_color_metadata(&mut self, stream: &Stream) {
+        self.color_transfer = stream.color_transfer.clone();
+        self.color_primaries = stream.color_primaries.clone();
+        self.color_space = stream.color_space.clone();
+        self.side_data_list = stream.side_data_list.clone();
+    }
+
+    /// Returns whether the video stream is HDR10 or HLG, based on `color_transfer`.
+    pub fn is_hdr(&self) -> bool {
+        matches!(
+            self.color_transfer.as_deref(),
+            Some("smpte2084") | Some("arib-std-b67")
+        )
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct OutputCtx {
     pub codec: String,
     pub start_num: u32,
@@ -245,6 +692,103 @@ pub struct OutputCtx {
     pub width: Option<i64>,
     pub audio_channels: u64,
     pub target_gop: u32,
+    /// Pins the exact output frame rate. Renditions of the same input should share this value so
+    /// that players can switch between them cleanly. Ignored by transmux profiles.
+    pub fps: Option<f32>,
+    /// Codecs of the audio renditions to produce in parallel out of a single audio stream, ex.
+    /// `["aac", "ac3"]`. Only used by `MultiAudioTranscodeProfile`, each rendition gets written
+    /// into its own sub-directory of `outdir`, see `rendition_dirs`.
+    pub audio_renditions: Vec<String>,
+    /// What to do when transmuxing a source whose keyframes dont line up with `target_gop`.
+    pub gop_mismatch: GopMismatchPolicy,
+    /// Index of the subtitle stream, counted among the subtitle streams only, to burn into the
+    /// video. When `InputCtx::subtitle_file` is set that file is burned in instead of the stream.
+    pub burn_subtitle: Option<usize>,
+    /// Only allow transmuxing, `create` fails with `DirectPlayUnavailable` instead of falling back
+    /// to a transcode.
+    pub direct_play_only: bool,
+    /// Maintain an `index.m3u8` in the outdir listing the patched segments as they complete, so
+    /// that the outdir can be served by a plain web server. Updated by `garbage_collect`.
+    pub live_playlist: bool,
+    /// Write the whole stream into a single fragmented mp4 instead of one file per segment, for
+    /// byte-range based packaging. Only supported by the h264 and transmux profiles and meant to
+    /// be used starting at chunk 0.
+    pub single_file: bool,
+    /// Layers to draw on top of the video, in order. The subtitle is drawn first unless it is
+    /// placed explicitly with `OverlayLayer::Subtitle`.
+    pub overlays: Vec<OverlayLayer>,
+    /// Secondary audio stream to mix into the audio stream, ex. an audio description track. Only
+    /// supported by `AacTranscodeProfile`.
+    pub audio_mix: Option<AudioMix>,
+    /// Container the segments should be written in. Only a few profiles look at it, every other
+    /// profile writes its own container regardless. Opus is written into WebM unless
+    /// `Container::Fmp4` is asked for, and `Container::MpegTs` is only ever used when asked for.
+    /// Subtitles split into WebVTT chunks map their cues onto the timestamps of MPEG-TS chunks
+    /// when it is set to `Container::MpegTs`.
+    pub container: Option<Container>,
+    /// Audio codecs the client can pass through to a receiver as a bitstream, ex. `["ac3",
+    /// "eac3"]`. When the input audio is one of them, the matching transmux profile supports the
+    /// session whatever `codec` is, so that the audio is passed through untouched and the
+    /// transcode to `codec` is only used as a fallback.
+    pub bitstream_codecs: Vec<String>,
+    /// Channel layout to downmix audio to, ex. `ChannelLayout::Stereo` for clients with stereo
+    /// only output. Defaults to the usual layout for `audio_channels`.
+    pub channel_layout: Option<ChannelLayout>,
+    /// Tone map HDR input to SDR with the given algorithm, for clients that cant display HDR.
+    /// Ignored if the input isnt HDR.
+    pub tonemap: Option<TonemapAlgorithm>,
+    /// Keep HDR input HDR when encoding to hevc, for clients that can display it. The mastering
+    /// display and content light level metadata get copied into the encode and tone mapping is
+    /// skipped.
+    pub hdr_passthrough: bool,
+    /// The client cant play Dolby Vision. Copied Dolby Vision streams get stripped down to their
+    /// HDR10/HLG/SDR base layer, and are refused when they dont have one (profile 5). When unset
+    /// they are tagged as Dolby Vision.
+    pub strip_dolby_vision: bool,
+    /// Write WebVTT subtitles as chunks of `target_gop` seconds along with a playlist, instead of
+    /// a single file.
+    pub segment_subtitles: bool,
+    /// Duration of the parts of a segment in seconds, for Low-Latency HLS. When set ffmpeg
+    /// flushes a fragment every `part_duration`, which can be handed out before the segment is
+    /// complete, see `StateManager::part_request`.
+    pub part_duration: Option<f32>,
+    /// Remux the whole input into a single MP4 streamed over stdout instead of writing segments,
+    /// for clients that cant do segmented playback, see `StateManager::progressive_stream`.
+    pub progressive: bool,
+    /// Start transcodes exactly on the chunk boundary after a hard seek, by seeking on both sides
+    /// of `-i`. The input seeks to the keyframe before the boundary and the frames decoded up to
+    /// it get dropped on output, which makes restarts slower. Ignored by transmux profiles, which
+    /// can only start on a keyframe.
+    pub accurate_seek: bool,
+    /// Highest bitrate the client can take, in bits per second. Transmux profiles are skipped when
+    /// `InputCtx::bitrate` is above it, so that the stream gets transcoded instead.
+    pub max_bitrate: Option<u64>,
+    /// How urgently the session is needed. Background sessions are the first to be paused when
+    /// the host is under pressure, see `StateManager::set_max_running` and
+    /// `StateManager::set_max_load`.
+    pub priority: Priority,
+    /// Read the input at most this many times faster than realtime, ex. `2.0`, with ffmpeg's
+    /// `-readrate`. The first `READRATE_BURST_CHUNKS` chunks are read at full speed so that
+    /// playback can start right away. Sessions with a readrate encode at a steady pace instead of
+    /// being paused and resumed by `StateManager::pacing`.
+    pub readrate: Option<f32>,
+    /// Encrypt chunks with a per-session AES-128 key before handing them out, see
+    /// `StateManager::encryption_key`. Chunks stay in the clear on disk, `chunk_request` returns
+    /// the path of an encrypted copy. Init segments arent encrypted. Cant be combined with
+    /// `part_duration`, `single_file`, `progressive` or `live_playlist`.
+    pub encrypt: bool,
+    /// Protect fMP4 segments with Common Encryption using the caller's keys, so that the output
+    /// can be fronted by a Widevine, PlayReady or FairPlay license server. `chunk_init_request`
+    /// and `chunk_request` return the paths of protected copies. Only h264, hevc and audio streams
+    /// can be protected, and every profile of the chain has to write `Container::Fmp4`. Cant be
+    /// combined with `encrypt`, `part_duration`, `single_file`, `progressive` or `live_playlist`.
+    ///
+    /// WARNING: init segments and chunks stay in the clear on disk, next to their protected
+    /// copies. Only ever serve the paths returned by the state manager, never the outdir itself.
+    /// The content key isnt persisted, see `CencConfig::key`.
+    pub cenc: Option<CencConfig>,
+    /// How `ThumbnailProfile` generates trickplay thumbnails, ignored by every other profile.
+    pub thumbnails: ThumbnailConfig,
 }
 
 impl Default for OutputCtx {
@@ -259,7 +803,112 @@ impl Default for OutputCtx {
             width: None,
             audio_channels: 2,
             target_gop: 5,
+            fps: None,
+            audio_renditions: Vec::new(),
+            gop_mismatch: GopMismatchPolicy::default(),
+            burn_subtitle: None,
+            single_file: false,
+            live_playlist: false,
+            direct_play_only: false,
+            overlays: Vec::new(),
+            audio_mix: None,
+            container: None,
+            bitstream_codecs: Vec::new(),
+            channel_layout: None,
+            tonemap: None,
+            hdr_passthrough: false,
+            strip_dolby_vision: false,
+            segment_subtitles: false,
+            part_duration: None,
+            progressive: false,
+            accurate_seek: false,
+            max_bitrate: None,
+            priority: Priority::default(),
+            readrate: None,
+            encrypt: false,
+            cenc: None,
+            thumbnails: ThumbnailConfig::default(),
+        }
+    }
+}
+
+impl OutputCtx {
+    /// Returns the input flags implementing `readrate`, these have to come before `-i`.
+    pub fn readrate_flags(&self) -> Vec<String> {
+        match self.readrate {
+            Some(readrate) => vec![
+                "-readrate".into(),
+                readrate.to_string(),
+                "-readrate_initial_burst".into(),
+                (READRATE_BURST_CHUNKS * self.target_gop).to_string(),
+            ],
+            None => Vec::new(),
+        }
+    }
+
+    /// Returns whether a stream in `codec` can be copied to the output as is, either because it
+    /// is the requested codec or because the client reported bitstream support for it.
+    pub fn passes_through(&self, codec: &str) -> bool {
+        self.codec == codec || self.bitstream_codecs.iter().any(|x| x == codec)
+    }
+
+    /// Returns whether anything has to be burned into the video.
+    pub fn needs_burn_in(&self) -> bool {
+        self.burn_subtitle.is_some() || !self.overlays.is_empty()
+    }
+
+    /// Returns the ordered list of layers to burn into the video.
+    pub fn burn_layers(&self) -> Vec<OverlayLayer> {
+        let mut layers = self.overlays.clone();
+
+        if self.burn_subtitle.is_some() && !layers.contains(&OverlayLayer::Subtitle) {
+            layers.insert(0, OverlayLayer::Subtitle);
+        }
+
+        layers
+    }
+
+    /// Returns the path of the file written in `single_file` mode.
+    pub fn single_file_path(&self) -> String {
+        format!("{}/media.mp4", self.outdir)
+    }
+
+    /// Returns the directory font attachments get extracted to.
+    pub fn fonts_dir(&self) -> String {
+        format!("{}/fonts", self.outdir)
+    }
+
+    /// Returns the names of the sub-directories of `outdir` the `audio_renditions` get written
+    /// into, in order. They are named after their position rather than their codec, so that they
+    /// cant collide with the other files of the session.
+    pub fn rendition_dirs(&self) -> Vec<String> {
+        (0..self.audio_renditions.len())
+            .map(|x| format!("rendition_{}", x))
+            .collect()
+    }
+
+    /// Returns whether segments of `target_gop` seconds would start on a keyframe of a source with
+    /// the given keyframe interval.
+    pub fn is_gop_aligned(&self, keyframe_interval: f64) -> bool {
+        if keyframe_interval <= 0.0 {
+            return true;
+        }
+
+        let ratio = self.target_gop as f64 / keyframe_interval;
+
+        ratio >= 1.0 && (ratio - ratio.round()).abs() < 0.05
+    }
+
+    /// Returns the smallest segment duration that is at least `target_gop` seconds long and is a
+    /// multiple of the keyframe interval.
+    pub fn aligned_gop(&self, keyframe_interval: f64) -> u32 {
+        if keyframe_interval <= 0.0 {
+            return self.target_gop;
         }
+
+        let keyframes = (self.target_gop as f64 / keyframe_interval).ceil().max(1.0);
+
+        ((keyframes * keyframe_interval).round() as u32).max(1)
     }
 }
 
@@ -271,19 +920,185 @@ impl Default for ProfileContext {
             input_ctx: Default::default(),
             output_ctx: Default::default(),
             ffmpeg_bin: "ffmpeg".into(),
+            movflags: None,
+            target_timescale: None,
+            labels: HashMap::new(),
+            metadata: HashMap::new(),
+            vaapi_device: None,
+            threads: None,
+            nice: None,
+            cgroup: None,
+            owner: None,
         }
     }
 }
 
+impl ProfileContext {
+    /// Returns a copy of the context safe to write to disk, with every secret a session can carry
+    /// removed. Secrets added to the context must be cleared here.
+    pub fn without_secrets(&self) -> Self {
+        let mut ctx = self.clone();
+
+        if let Some(cenc) = ctx.output_ctx.cenc.as_mut() {
+            cenc.key = None;
+        }
+
+        ctx
+    }
+
+    /// Returns whether the video has to be tone mapped from HDR to SDR.
+    pub fn needs_tonemap(&self) -> bool {
+        self.output_ctx.tonemap.is_some() && self.input_ctx.is_hdr() && !self.keeps_hdr()
+    }
+
+    /// Returns the time in seconds `chunk` starts at. Without a keyframe index chunks are
+    /// `target_gop` seconds long, with one every chunk starts on the first source keyframe at or
+    /// after that time, which is where ffmpeg cuts segments when copying the stream.
+    pub fn chunk_start(&self, chunk: u32) -> f64 {
+        let start = chunk as f64 * self.output_ctx.target_gop as f64;
+
+        self.input_ctx
+            .keyframes
+            .as_ref()
+            .and_then(|x| {
+                // timestamps reported by ffprobe are rounded to the microsecond.
+                let idx = x.partition_point(|kf| *kf < start - 0.000_001);
+                x.get(idx).copied()
+            })
+            .unwrap_or(start)
+    }
+
+    /// Returns whether the input is known to exceed `OutputCtx::max_bitrate`.
+    pub fn exceeds_bitrate_cap(&self) -> bool {
+        self.output_ctx
+            .max_bitrate
+            .is_some_and(|cap| self.input_ctx.bitrate > cap)
+    }
+
+    /// Returns where transcodes should seek the input to. With `OutputCtx::accurate_seek` this is
+    /// the keyframe before the chunk boundary, or a keyframe interval before it when there is no
+    /// keyframe index, and the boundary itself otherwise.
+    pub fn input_seek(&self) -> f64 {
+        let start = self.output_ctx.start_num as f64 * self.output_ctx.target_gop as f64;
+
+        if !self.output_ctx.accurate_seek || start == 0.0 {
+            return start;
+        }
+
+        let keyframe = self.input_ctx.keyframes.as_ref().and_then(|x| {
+            let idx = x.partition_point(|kf| *kf <= start);
+            idx.checked_sub(1).map(|idx| x[idx])
+        });
+
+        keyframe
+            .unwrap_or_else(|| {
+                start
+                    - self
+                        .input_ctx
+                        .keyframe_interval
+                        .unwrap_or(ACCURATE_SEEK_PREROLL)
+            })
+            .max(0.0)
+    }
+
+    /// Returns the duration of `chunk` in seconds, see `chunk_start`.
+    pub fn chunk_duration(&self, chunk: u32) -> f64 {
+        let end = self.chunk_start(chunk + 1);
+        let end = self.input_ctx.duration.map_or(end, |x| end.min(x));
+
+        (end - self.chunk_start(chunk)).max(0.0)
+    }
+
+    /// Returns whether HDR input has to stay HDR, see `OutputCtx::hdr_passthrough`.
+    pub fn keeps_hdr(&self) -> bool {
+        self.output_ctx.hdr_passthrough
+            && self.output_ctx.codec == "hevc"
+            && self.input_ctx.is_hdr()
+    }
+}
+
 #[repr(u32)]
-#[derive(Clone, Copy, Debug, PartialEq, Eq, Ord, PartialOrd)]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Ord, PartialOrd, Serialize, Deserialize)]
 pub enum ProfileType {
+    #[default]
     Transcode,
     Transmux,
     HardwareTranscode,
 }
 
-#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+/// Containers segments can be written in.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Container {
+    /// Fragmented mp4 segments (`N.m4s`) with a `N_init.mp4` init segment, patched to be
+    /// continuous before being handed out.
+    Fmp4,
+    /// WebM chunks (`N.webm`) made of matroska clusters with a `N_init.webm` header, whose
+    /// cluster timestamps are shifted to be continuous before being handed out.
+    WebM,
+    /// MPEG-TS chunks (`N.ts`) for clients which only play HLS with TS segments. They dont have
+    /// init segments, their timestamps and continuity counters are patched to be continuous
+    /// before being handed out.
+    MpegTs,
+}
+
+impl Container {
+    pub fn segment_extension(&self) -> &'static str {
+        match self {
+            Self::Fmp4 => "m4s",
+            Self::WebM => "webm",
+            Self::MpegTs => "ts",
+        }
+    }
+
+    /// Extension of the init segments, MPEG-TS chunks dont have any thus the segment extension is
+    /// returned for them.
+    pub fn init_extension(&self) -> &'static str {
+        match self {
+            Self::Fmp4 => "mp4",
+            Self::WebM => "webm",
+            Self::MpegTs => "ts",
+        }
+    }
+
+    pub fn has_init_segment(&self) -> bool {
+        *self != Self::MpegTs
+    }
+
+    /// Subtype of the mime type of the segments, ex. `mp4` for `video/mp4`.
+    pub fn mime_subtype(&self) -> &'static str {
+        match self {
+            Self::Fmp4 => "mp4",
+            Self::WebM => "webm",
+            Self::MpegTs => "mp2t",
+        }
+    }
+}
+
+/// Policy applied at session creation when the keyframes of a transmuxed source dont line up with
+/// the requested segment duration.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub enum GopMismatchPolicy {
+    /// Log a warning and transmux anyway.
+    #[default]
+    Warn,
+    /// Round the segment duration up to a multiple of the source keyframe interval.
+    Adjust,
+    /// Drop transmux profiles from the chain so that the stream gets transcoded.
+    Refuse,
+}
+
+/// Priority of a session, used to decide which sessions get paused first when too many are
+/// running at once.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Default, Serialize, Deserialize)]
+pub enum Priority {
+    /// Work nobody is waiting on yet, ex. pre-transcoding the next episode.
+    Background,
+    /// A player is waiting on the session.
+    #[default]
+    Foreground,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
 pub enum StreamType {
     Video,
     Audio,
@@ -291,6 +1106,8 @@ pub enum StreamType {
     Thumbnail,
 }
 
+/// Side data of a stream as reported by ffprobe, only the fields of the Dolby Vision
+/// configuration record and of the HDR10 static metadata are parsed.
 #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub struct SideDataList {
     pub side_data_type: String,
@@ -304,4 +1121,71 @@ pub struct SideDataList {
     pub bl_present_flag: Option<i64>,
     pub dv_bl_signal_compatibility_id: Option<i64>,
     pub dv_md_compression: Option<String>,
+    /// Mastering display metadata, chromaticities and luminances are rationals, ex. `34000/50000`.
+    pub red_x: Option<String>,
+    pub red_y: Option<String>,
+    pub green_x: Option<String>,
+    pub green_y: Option<String>,
+    pub blue_x: Option<String>,
+    pub blue_y: Option<String>,
+    pub white_point_x: Option<String>,
+    pub white_point_y: Option<String>,
+    pub min_luminance: Option<String>,
+    pub max_luminance: Option<String>,
+    /// Content light level metadata, in cd/m2.
+    pub max_content: Option<i64>,
+    pub max_average: Option<i64>,
+}
+
+impl SideDataList {
+    pub fn is_dolby_vision(&self) -> bool {
+        self.side_data_type == "DOVI configuration record"
+    }
+
+    /// Returns whether this is the mastering display metadata of a HDR10 stream.
+    pub fn is_mastering_display(&self) -> bool {
+        self.side_data_type == "Mastering display metadata"
+    }
+
+    /// Returns whether this is the content light level metadata of a HDR10 stream.
+    pub fn is_content_light_level(&self) -> bool {
+        self.side_data_type == "Content light level metadata"
+    }
+
+    /// Returns whether the RPU of the stream carries an enhancement layer, ex. profile 7.
+    pub fn has_enhancement_layer(&self) -> bool {
+        self.el_present_flag.unwrap_or(0) == 1
+    }
+
+    /// Returns whether the base layer can be decoded without the RPU, as HDR10, SDR or HLG.
+    /// Profile 5 streams use a proprietary colorspace, decoding them without the RPU results in
+    /// purple and green video.
+    pub fn has_compatible_base_layer(&self) -> bool {
+        match self.dv_bl_signal_compatibility_id {
+            Some(id) => id != 0,
+            None => self.dv_profile != Some(5),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn movflags_must_fragment_and_index_the_output() {
+        assert!(validate_movflags("frag_custom+dash+delay_moov").is_ok());
+        assert!(validate_movflags("cmaf+dash").is_ok());
+        assert!(validate_movflags("cmaf+global_sidx").is_ok());
+
+        // no fragments at all.
+        assert!(validate_movflags("faststart").is_err());
+        // cmaf alone doesnt write a sidx box.
+        assert!(validate_movflags("cmaf").is_err());
+        assert!(validate_movflags("cmaf+delay_moov").is_err());
+        // malformed.
+        assert!(validate_movflags("frag_custom++dash").is_err());
+        assert!(validate_movflags("frag_custom+ dash").is_err());
+        assert!(validate_movflags("").is_err());
+    }
 }
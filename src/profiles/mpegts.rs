@@ -0,0 +1,319 @@
+use super::audio::downmix_filter;
+use super::video::get_deinterlace_filter;
+use super::video::get_fps_flags;
+use super::video::get_fps_mode;
+use super::video::get_metadata_flags;
+use super::video::get_output_seek_flags;
+use super::video::get_scale_filter;
+use super::video::get_thread_flags;
+use super::video::get_tonemap_filter;
+use super::Container;
+use super::ProfileContext;
+use super::ProfileType;
+use super::StreamType;
+use super::TranscodingProfile;
+
+use crate::NightfallError;
+
+/// Returns the flags needed to write `N.ts` chunks through the hls muxer. MPEG-TS doesnt have
+/// init segments, every chunk carries its own PAT/PMT.
+fn get_ts_flags(ctx: &ProfileContext) -> Vec<String> {
+    vec![
+        "-f".into(),
+        "hls".into(),
+        "-start_number".into(),
+        ctx.output_ctx.start_num.to_string(),
+        // needed so that in progress segments are named `tmp` and then renamed after the data is
+        // on disk.
+        "-hls_flags".into(),
+        "temp_file+append_list".into(),
+        "-max_delay".into(),
+        "5000000".into(),
+        "-hls_time".into(),
+        ctx.output_ctx.target_gop.to_string(),
+        "-hls_segment_type".into(),
+        "mpegts".into(),
+        "-loglevel".into(),
+        "info".into(),
+        "-progress".into(),
+        "pipe:1".into(),
+        "-hls_segment_filename".into(),
+        format!("{}/%d.ts", ctx.output_ctx.outdir),
+        format!("{}/playlist.m3u8", ctx.output_ctx.outdir),
+    ]
+}
+
+/// Returns an error unless the context asks for MPEG-TS chunks, see `OutputCtx::container`.
+fn supports_ts(ctx: &ProfileContext) -> Result<(), NightfallError> {
+    if ctx.output_ctx.container == Some(Container::MpegTs) {
+        return Ok(());
+    }
+
+    Err(NightfallError::ProfileNotSupported(
+        "Profile only outputs MPEG-TS chunks.".into(),
+    ))
+}
+
+/// Profile copying h264 into MPEG-TS chunks, for older TVs which only play HLS with `.ts`
+/// segments.
+#[derive(Debug)]
+pub struct H264TsTransmuxProfile;
+
+impl TranscodingProfile for H264TsTransmuxProfile {
+    fn profile_type(&self) -> ProfileType {
+        ProfileType::Transmux
+    }
+
+    fn stream_type(&self) -> StreamType {
+        StreamType::Video
+    }
+
+    fn name(&self) -> &str {
+        "H264TsTransmuxProfile"
+    }
+
+    fn container(&self) -> Container {
+        Container::MpegTs
+    }
+
+    fn build(&self, ctx: ProfileContext) -> Option<Vec<String>> {
+        let mut args = vec![
+            "-y".into(),
+            "-ss".into(),
+            ctx.chunk_start(ctx.output_ctx.start_num).to_string(),
+            "-i".into(),
+            ctx.file.clone(),
+            "-copyts".into(),
+            "-map".into(),
+            format!("0:{}", ctx.input_ctx.stream),
+            "-c:0".into(),
+            "copy".into(),
+            // mp4 and mkv sources store h264 in the avcc format, TS wants annex b.
+            "-bsf:v".into(),
+            "h264_mp4toannexb".into(),
+            "-start_at_zero".into(),
+            "-fps_mode".into(),
+            "passthrough".into(),
+            "-avoid_negative_ts".into(),
+            "disabled".into(),
+            "-max_muxing_queue_size".into(),
+            "2048".into(),
+        ];
+
+        args.append(&mut get_metadata_flags(&ctx, self.profile_type()));
+        args.append(&mut get_ts_flags(&ctx));
+
+        Some(args)
+    }
+
+    fn supports(&self, ctx: &ProfileContext) -> Result<(), NightfallError> {
+        supports_ts(ctx)?;
+
+        if ctx.output_ctx.height.is_some()
+            || ctx.output_ctx.width.is_some()
+            || ctx.output_ctx.bitrate.is_some()
+        {
+            return Err(NightfallError::ProfileNotSupported(
+                "Transmuxed streams cannot be resized.".into(),
+            ));
+        }
+
+        if ctx.input_ctx.codec == ctx.output_ctx.codec && ctx.input_ctx.codec == "h264" {
+            return Ok(());
+        }
+
+        Err(NightfallError::ProfileNotSupported(
+            "Profile only supports h264 input and output codecs.".into(),
+        ))
+    }
+
+    fn tag(&self) -> &str {
+        "h264_ts_copy"
+    }
+}
+
+/// Profile transcoding video to h264 in MPEG-TS chunks.
+#[derive(Debug)]
+pub struct H264TsTranscodeProfile;
+
+impl TranscodingProfile for H264TsTranscodeProfile {
+    fn profile_type(&self) -> ProfileType {
+        ProfileType::Transcode
+    }
+
+    fn stream_type(&self) -> StreamType {
+        StreamType::Video
+    }
+
+    fn name(&self) -> &str {
+        "H264TsTranscodeProfile"
+    }
+
+    fn container(&self) -> Container {
+        Container::MpegTs
+    }
+
+    fn tonemaps(&self) -> bool {
+        true
+    }
+
+    fn build(&self, ctx: ProfileContext) -> Option<Vec<String>> {
+        let mut args = vec![
+            "-y".into(),
+            "-ss".into(),
+            ctx.input_seek().to_string(),
+            "-i".into(),
+            ctx.file.clone(),
+            "-copyts".into(),
+            "-map".into(),
+            format!("0:{}", ctx.input_ctx.stream),
+            "-c:0".into(),
+            "libx264".into(),
+            "-preset".into(),
+            "veryfast".into(),
+        ];
+
+        args.append(&mut get_output_seek_flags(&ctx));
+        args.append(&mut get_thread_flags(&ctx, "libx264"));
+
+        let mut vfilter = get_deinterlace_filter(&ctx)
+            .into_iter()
+            .chain(get_tonemap_filter(&ctx))
+            .collect::<Vec<_>>();
+
+        vfilter.extend(get_scale_filter("scale", &ctx));
+
+        if !vfilter.is_empty() {
+            args.append(&mut vec!["-vf".into(), vfilter.join(",")]);
+        }
+
+        if let Some(bitrate) = ctx.output_ctx.bitrate {
+            args.push("-b:v".into());
+            args.push(bitrate.to_string());
+        }
+
+        args.append(&mut get_fps_flags(&ctx));
+
+        args.append(&mut vec![
+            "-fps_mode".into(),
+            get_fps_mode(&ctx),
+            "-avoid_negative_ts".into(),
+            "make_non_negative".into(),
+            "-max_muxing_queue_size".into(),
+            "2048".into(),
+            "-force_key_frames".into(),
+            format!("expr:gte(t,n_forced*{})", ctx.output_ctx.target_gop),
+        ]);
+
+        args.append(&mut get_metadata_flags(&ctx, self.profile_type()));
+        args.append(&mut get_ts_flags(&ctx));
+
+        Some(args)
+    }
+
+    fn supports(&self, ctx: &ProfileContext) -> Result<(), NightfallError> {
+        supports_ts(ctx)?;
+
+        if ctx.output_ctx.codec == "h264" {
+            return Ok(());
+        }
+
+        Err(NightfallError::ProfileNotSupported(format!(
+            "Got output codec {} but profile only supports `h264`.",
+            ctx.output_ctx.codec
+        )))
+    }
+
+    fn encoder(&self) -> Option<&str> {
+        Some("libx264")
+    }
+
+    fn tag(&self) -> &str {
+        "h264_ts"
+    }
+}
+
+/// Profile transcoding audio to AAC in MPEG-TS chunks.
+#[derive(Debug)]
+pub struct AacTsTranscodeProfile;
+
+impl TranscodingProfile for AacTsTranscodeProfile {
+    fn profile_type(&self) -> ProfileType {
+        ProfileType::Transcode
+    }
+
+    fn stream_type(&self) -> StreamType {
+        StreamType::Audio
+    }
+
+    fn name(&self) -> &str {
+        "AacTsTranscodeProfile"
+    }
+
+    fn container(&self) -> Container {
+        Container::MpegTs
+    }
+
+    fn build(&self, ctx: ProfileContext) -> Option<Vec<String>> {
+        let mut args = vec![
+            "-y".into(),
+            "-ss".into(),
+            ctx.input_seek().to_string(),
+            "-i".into(),
+            ctx.file.clone(),
+            "-copyts".into(),
+            "-map".into(),
+            format!("0:{}", ctx.input_ctx.stream),
+            "-c:0".into(),
+            "aac".into(),
+        ];
+
+        args.append(&mut get_output_seek_flags(&ctx));
+
+        if let Some(filter) = downmix_filter(&ctx) {
+            args.append(&mut vec!["-af".into(), filter]);
+        }
+
+        args.append(&mut vec![
+            "-ab".into(),
+            ctx.output_ctx.bitrate.unwrap_or(120_000).to_string(),
+            "-start_at_zero".into(),
+            "-fps_mode".into(),
+            "auto".into(),
+            "-avoid_negative_ts".into(),
+            "make_non_negative".into(),
+        ]);
+
+        args.append(&mut get_metadata_flags(&ctx, self.profile_type()));
+        args.append(&mut get_ts_flags(&ctx));
+
+        Some(args)
+    }
+
+    fn supports(&self, ctx: &ProfileContext) -> Result<(), NightfallError> {
+        supports_ts(ctx)?;
+
+        if ctx.output_ctx.audio_mix.is_some() {
+            return Err(NightfallError::ProfileNotSupported(
+                "Profile doesnt mix audio streams.".into(),
+            ));
+        }
+
+        if ctx.output_ctx.codec == "aac" {
+            return Ok(());
+        }
+
+        Err(NightfallError::ProfileNotSupported(format!(
+            "Got output codec {} but profile only supports `aac`.",
+            ctx.output_ctx.codec
+        )))
+    }
+
+    fn encoder(&self) -> Option<&str> {
+        Some("aac")
+    }
+
+    fn tag(&self) -> &str {
+        "aac_ts"
+    }
+}
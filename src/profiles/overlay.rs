@@ -0,0 +1,396 @@
+use crate::error::NightfallError;
+
+use super::ProfileContext;
+use super::ProfileType;
+use super::StreamType;
+use super::TranscodingProfile;
+
+use serde_derive::{Deserialize, Serialize};
+
+/// A layer drawn on top of the video by `BurnInTranscodeProfile`.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum OverlayLayer {
+    /// The subtitle selected with `OutputCtx::burn_subtitle` or `InputCtx::subtitle_file`. Text
+    /// subtitles are rendered with libass, image based ones (PGS, VOBSUB, ...) are overlaid as is.
+    Subtitle,
+    /// A still image, ex. a logo, placed at the `overlay` filter expressions `x` and `y`.
+    Image { path: String, x: String, y: String },
+    /// The presentation timestamp of each frame drawn as `hh:mm:ss.mmm` at `x` and `y`.
+    Timecode { x: String, y: String },
+}
+
+/// A `-filter_complex` graph which draws several layers on top of each other.
+#[derive(Clone, Debug, PartialEq)]
+pub struct FilterGraph {
+    /// Additional inputs the graph reads from, these must be passed to ffmpeg in order right
+    /// after the main input.
+    pub inputs: Vec<String>,
+    /// The filtergraph itself.
+    pub graph: String,
+    /// Label of the last pad of the graph, this is what has to be mapped.
+    pub output: String,
+}
+
+impl FilterGraph {
+    /// Function chains `layers` in order on top of the video stream of `ctx`, each layer reading
+    /// the output pad of the previous one. `leading` filters, ex. tone mapping, get applied before
+    /// the first layer is drawn and `trailing` filters, ex. `scale`, after all layers have been
+    /// drawn.
+    pub fn build(
+        ctx: &ProfileContext,
+        leading: Vec<String>,
+        layers: &[OverlayLayer],
+        trailing: Vec<String>,
+    ) -> Option<Self> {
+        let mut inputs = Vec::new();
+        let mut chains = Vec::new();
+        let mut last = format!("[0:{}]", ctx.input_ctx.stream);
+
+        if !leading.is_empty() {
+            chains.push(format!("{}{}[pre]", last, leading.join(",")));
+            last = "[pre]".into();
+        }
+
+        for (idx, layer) in layers.iter().enumerate() {
+            let next = format!("[l{}]", idx);
+
+            let chain = match layer {
+                // image based subtitles are decoded to frames which we can simply overlay.
+                OverlayLayer::Subtitle if super::subtitle::is_bitmap_subtitle(ctx) => format!(
+                    "{}[0:s:{}]overlay=eof_action=pass{}",
+                    last,
+                    ctx.output_ctx.burn_subtitle?,
+                    next
+                ),
+                OverlayLayer::Subtitle => format!(
+                    "{}{}{}",
+                    last,
+                    super::subtitle::subtitles_filter(ctx)?,
+                    next
+                ),
+                OverlayLayer::Image { path, x, y } => {
+                    inputs.push(path.clone());
+                    // the main input is always the first input, thus images start at 1.
+                    format!(
+                        "{}[{}:v]overlay=x={}:y={}:eof_action=repeat{}",
+                        last,
+                        inputs.len(),
+                        x,
+                        y,
+                        next
+                    )
+                }
+                OverlayLayer::Timecode { x, y } => format!(
+                    "{}drawtext=text='%{{pts\\:hms}}':x={}:y={}:fontcolor=white:box=1:boxcolor=black@0.5{}",
+                    last, x, y, next
+                ),
+            };
+
+            chains.push(chain);
+            last = next;
+        }
+
+        if !trailing.is_empty() {
+            let next = format!("[l{}]", layers.len());
+            chains.push(format!("{}{}{}", last, trailing.join(","), next));
+            last = next;
+        }
+
+        if chains.is_empty() {
+            return None;
+        }
+
+        Some(Self {
+            inputs,
+            graph: chains.join(";"),
+            output: last,
+        })
+    }
+}
+
+/// Profile which transcodes the video to h264 while burning in the layers of
+/// `OutputCtx::burn_layers`, ex. a subtitle (srt, ass, pgs, ...) and a logo.
+#[derive(Debug)]
+pub struct BurnInTranscodeProfile;
+
+impl TranscodingProfile for BurnInTranscodeProfile {
+    fn profile_type(&self) -> ProfileType {
+        ProfileType::Transcode
+    }
+
+    fn stream_type(&self) -> StreamType {
+        StreamType::Video
+    }
+
+    fn name(&self) -> &str {
+        "BurnInTranscodeProfile"
+    }
+
+    fn tonemaps(&self) -> bool {
+        true
+    }
+
+    fn build(&self, ctx: ProfileContext) -> Option<Vec<String>> {
+        let start_num = ctx.output_ctx.start_num.to_string();
+        let init_seg = format!("{}_init.mp4", &start_num);
+        let seg_name = format!("{}/%d.m4s", ctx.output_ctx.outdir);
+        let outdir = format!("{}/playlist.m3u8", ctx.output_ctx.outdir);
+
+        let trailing = super::video::get_scale_filter("scale", &ctx)
+            .into_iter()
+            .collect();
+        let leading = super::video::get_deinterlace_filter(&ctx)
+            .into_iter()
+            .chain(super::video::get_tonemap_filter(&ctx))
+            .collect();
+        let graph = FilterGraph::build(&ctx, leading, &ctx.output_ctx.burn_layers(), trailing)?;
+
+        let mut args = vec!["-y".into()];
+
+        args.append(&mut super::subtitle::dump_font_attachments(&ctx));
+
+        args.append(&mut vec![
+            "-ss".into(),
+            ctx.input_seek().to_string(),
+            "-i".into(),
+            ctx.file.clone(),
+        ]);
+
+        // overlay images are looped forever so that they stay on screen for the whole stream.
+        for input in graph.inputs.iter() {
+            args.append(&mut vec![
+                "-loop".into(),
+                "1".into(),
+                "-i".into(),
+                input.clone(),
+            ]);
+        }
+
+        args.append(&mut super::video::get_output_seek_flags(&ctx));
+
+        args.append(&mut vec![
+            "-copyts".into(),
+            "-filter_complex".into(),
+            graph.graph,
+            "-map".into(),
+            graph.output,
+            "-c:0".into(),
+            "libx264".into(),
+            "-preset".into(),
+            "veryfast".into(),
+        ]);
+
+        args.append(&mut super::video::get_thread_flags(&ctx, "libx264"));
+
+        if let Some(bitrate) = ctx.output_ctx.bitrate {
+            args.push("-b:v".into());
+            args.push(bitrate.to_string());
+        }
+
+        args.append(&mut super::video::get_fps_flags(&ctx));
+
+        args.append(&mut vec![
+            "-fps_mode".into(),
+            super::video::get_fps_mode(&ctx),
+            "-avoid_negative_ts".into(),
+            "make_non_negative".into(),
+            "-max_muxing_queue_size".into(),
+            "2048".into(),
+        ]);
+
+        args.append(&mut super::video::get_metadata_flags(
+            &ctx,
+            self.profile_type(),
+        ));
+
+        args.append(&mut vec![
+            "-f".into(),
+            "hls".into(),
+            "-start_number".into(),
+            start_num,
+        ]);
+
+        args.append(&mut super::video::get_discont_flags(&ctx));
+
+        // needed so that in progress segments are named `tmp` and then renamed after the data is
+        // on disk.
+        // This in theory practically prevents the web server from returning a segment that is
+        // in progress.
+        args.append(&mut vec![
+            "-hls_flags".into(),
+            "temp_file+append_list".into(),
+            "-max_delay".into(),
+            "5000000".into(),
+        ]);
+
+        // args needed so we can distinguish between init fragments for new streams.
+        // Basically on the web seeking works by reloading the entire video because of
+        // discontinuity issues that browsers seem to not ignore like mpv.
+        args.append(&mut vec!["-hls_fmp4_init_filename".into(), init_seg]);
+        args.append(&mut vec![
+            "-hls_time".into(),
+            ctx.output_ctx.target_gop.to_string(),
+        ]);
+        args.append(&mut vec![
+            "-force_key_frames".into(),
+            format!("expr:gte(t,n_forced*{})", ctx.output_ctx.target_gop),
+        ]);
+
+        args.append(&mut vec!["-hls_segment_type".into(), "fmp4".into()]);
+        args.append(&mut vec![
+            "-loglevel".into(),
+            "info".into(),
+            "-progress".into(),
+            "pipe:1".into(),
+        ]);
+        args.append(&mut vec!["-hls_segment_filename".into(), seg_name]);
+        args.push(outdir);
+
+        Some(args)
+    }
+
+    fn supports(&self, ctx: &ProfileContext) -> Result<(), NightfallError> {
+        if !ctx.output_ctx.needs_burn_in() {
+            return Err(NightfallError::ProfileNotSupported(
+                "Nothing to burn in.".into(),
+            ));
+        }
+
+        // `FilterGraph::build` cant draw a subtitle layer without a subtitle to draw.
+        let has_subtitle =
+            ctx.output_ctx.burn_subtitle.is_some() || ctx.input_ctx.subtitle_file.is_some();
+
+        if !has_subtitle
+            && ctx
+                .output_ctx
+                .burn_layers()
+                .contains(&OverlayLayer::Subtitle)
+        {
+            return Err(NightfallError::ProfileNotSupported(
+                "Subtitle layer requested but no subtitle selected.".into(),
+            ));
+        }
+
+        if ctx.output_ctx.codec == "h264" {
+            return Ok(());
+        }
+
+        Err(NightfallError::ProfileNotSupported(format!(
+            "Got output codec {} but profile only supports `h264`.",
+            ctx.output_ctx.codec
+        )))
+    }
+
+    fn encoder(&self) -> Option<&str> {
+        Some("libx264")
+    }
+
+    fn tag(&self) -> &str {
+        "h264_burn"
+    }
+
+    fn burns_in(&self) -> bool {
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn layers_are_chained_in_order() {
+        let mut ctx = ProfileContext {
+            file: "/media/movie.mkv".into(),
+            ..Default::default()
+        };
+        ctx.input_ctx.stream = 0;
+        ctx.output_ctx.burn_subtitle = Some(1);
+
+        let layers = vec![
+            OverlayLayer::Subtitle,
+            OverlayLayer::Image {
+                path: "/media/logo.png".into(),
+                x: "10".into(),
+                y: "20".into(),
+            },
+            OverlayLayer::Timecode {
+                x: "0".into(),
+                y: "0".into(),
+            },
+        ];
+
+        let graph = FilterGraph::build(&ctx, vec![], &layers, vec![]).unwrap();
+
+        assert_eq!(graph.inputs, vec!["/media/logo.png".to_string()]);
+        assert_eq!(
+            graph.graph,
+            "[0:0]subtitles=filename=/media/movie.mkv:si=1[l0];\
+             [l0][1:v]overlay=x=10:y=20:eof_action=repeat[l1];\
+             [l1]drawtext=text='%{pts\\:hms}':x=0:y=0:fontcolor=white:box=1:boxcolor=black@0.5[l2]"
+        );
+        assert_eq!(graph.output, "[l2]");
+    }
+
+    #[test]
+    fn filtergraph_specials_in_the_path_are_escaped() {
+        let mut ctx = ProfileContext {
+            file: "/media/Show [1080p], S01.mkv".into(),
+            ..Default::default()
+        };
+        ctx.output_ctx.burn_subtitle = Some(0);
+
+        let graph = FilterGraph::build(&ctx, vec![], &[OverlayLayer::Subtitle], vec![]).unwrap();
+
+        assert_eq!(
+            graph.graph,
+            "[0:0]subtitles=filename=/media/Show \\[1080p\\]\\, S01.mkv:si=0[l0]"
+        );
+    }
+
+    #[test]
+    fn font_attachments_are_dumped_under_unique_names() {
+        let font = |index, filename: Option<&str>, mimetype: &str| crate::ffprobe::Stream {
+            index,
+            tags: Some(crate::ffprobe::Tags {
+                filename: filename.map(Into::into),
+                mimetype: Some(mimetype.into()),
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+
+        let mut ctx = ProfileContext::default();
+        ctx.output_ctx.outdir = "/tmp/session".into();
+        ctx.input_ctx.font_attachments = vec![
+            font(3, Some("arial.ttf"), "font/ttf"),
+            font(4, Some("arial.ttf"), "font/ttf"),
+            font(5, None, "application/vnd.ms-opentype"),
+        ];
+
+        let fonts_dir = ctx.output_ctx.fonts_dir();
+        assert_eq!(
+            super::super::subtitle::dump_font_attachments(&ctx),
+            vec![
+                "-dump_attachment:3".to_string(),
+                format!("{}/3_arial.ttf", fonts_dir),
+                "-dump_attachment:4".to_string(),
+                format!("{}/4_arial.ttf", fonts_dir),
+                "-dump_attachment:5".to_string(),
+                format!("{}/5.otf", fonts_dir),
+            ]
+        );
+    }
+
+    #[test]
+    fn subtitle_layer_without_subtitle_is_rejected() {
+        let mut ctx = ProfileContext::default();
+        ctx.output_ctx.codec = "h264".into();
+        ctx.output_ctx.overlays = vec![OverlayLayer::Subtitle];
+
+        assert!(BurnInTranscodeProfile.supports(&ctx).is_err());
+
+        ctx.output_ctx.burn_subtitle = Some(0);
+        assert!(BurnInTranscodeProfile.supports(&ctx).is_ok());
+    }
+}
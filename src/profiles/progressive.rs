@@ -0,0 +1,138 @@
+use super::video::get_metadata_flags;
+use super::ProfileContext;
+use super::ProfileType;
+use super::StreamType;
+use super::TranscodingProfile;
+
+use crate::ffprobe::select_audio_stream;
+use crate::NightfallError;
+
+/// Video codecs which can be copied into MP4 as is.
+const VIDEO_CODECS: &[&str] = &["h264", "hevc", "av1", "vp9"];
+/// Audio codecs which can be copied into MP4 as is, anything else gets transcoded to AAC.
+const AUDIO_CODECS: &[&str] = &["aac", "mp3", "ac3", "eac3", "opus", "flac", "alac"];
+
+/// Profile remuxing the video stream along with the preferred audio stream into a single MP4
+/// which gets streamed over stdout, for clients that cant do segmented playback. Backpressure
+/// comes from the pipe, ffmpeg blocks whenever the reader falls behind.
+///
+/// NOTE: A pipe cant be seeked back into, thus ffmpeg cant write the `moov` at the end nor
+/// relocate it like `+faststart` does. Instead an empty `moov` is written upfront followed by a
+/// fragment per keyframe, which players treat the same as a faststart file.
+#[derive(Debug)]
+pub struct ProgressiveMp4Profile;
+
+impl TranscodingProfile for ProgressiveMp4Profile {
+    fn profile_type(&self) -> ProfileType {
+        ProfileType::Transmux
+    }
+
+    fn stream_type(&self) -> StreamType {
+        StreamType::Video
+    }
+
+    fn name(&self) -> &str {
+        "ProgressiveMp4Profile"
+    }
+
+    fn is_stdio_stream(&self) -> bool {
+        true
+    }
+
+    fn is_progressive(&self) -> bool {
+        true
+    }
+
+    fn keeps_hdr(&self) -> bool {
+        true
+    }
+
+    fn build(&self, ctx: ProfileContext) -> Option<Vec<String>> {
+        let mut args = vec!["-y".into()];
+
+        if let Some(seek) = ctx.input_ctx.seek {
+            args.append(&mut vec!["-ss".into(), seek.to_string()]);
+        }
+
+        args.append(&mut vec![
+            "-i".into(),
+            ctx.file.clone(),
+            "-map".into(),
+            format!("0:{}", ctx.input_ctx.stream),
+            "-c:v".into(),
+            "copy".into(),
+        ]);
+
+        if ctx.input_ctx.codec == "hevc" {
+            args.append(&mut vec!["-tag:v".into(), "hvc1".into()]);
+        }
+
+        let audio =
+            select_audio_stream(&ctx.input_ctx.audio_streams, &ctx.input_ctx.audio_languages);
+
+        if let Some(audio) = audio {
+            let codec = if AUDIO_CODECS.contains(&audio.codec_name.as_str()) {
+                "copy"
+            } else {
+                "aac"
+            };
+
+            args.append(&mut vec![
+                "-map".into(),
+                format!("0:{}", audio.index),
+                "-c:a".into(),
+                codec.into(),
+            ]);
+        }
+
+        if let Some(max_to_transcode) = ctx.output_ctx.max_to_transcode {
+            args.append(&mut vec!["-t".into(), max_to_transcode.to_string()]);
+        }
+
+        args.append(&mut get_metadata_flags(&ctx, self.profile_type()));
+
+        args.append(&mut vec![
+            "-movflags".into(),
+            "empty_moov+default_base_moof+frag_keyframe".into(),
+            "-max_muxing_queue_size".into(),
+            "2048".into(),
+            "-f".into(),
+            "mp4".into(),
+            "-".into(),
+        ]);
+
+        Some(args)
+    }
+
+    fn supports(&self, ctx: &ProfileContext) -> Result<(), NightfallError> {
+        if !ctx.output_ctx.progressive {
+            return Err(NightfallError::ProfileNotSupported(
+                "Profile only streams progressive MP4.".into(),
+            ));
+        }
+
+        if ctx.output_ctx.height.is_some()
+            || ctx.output_ctx.width.is_some()
+            || ctx.output_ctx.bitrate.is_some()
+        {
+            return Err(NightfallError::ProfileNotSupported(
+                "Transmuxed streams cannot be resized.".into(),
+            ));
+        }
+
+        if ctx.input_ctx.codec == ctx.output_ctx.codec
+            && VIDEO_CODECS.contains(&ctx.input_ctx.codec.as_str())
+        {
+            return Ok(());
+        }
+
+        Err(NightfallError::ProfileNotSupported(format!(
+            "Codec {} cant be copied into MP4.",
+            ctx.input_ctx.codec
+        )))
+    }
+
+    fn tag(&self) -> &str {
+        "progressive_mp4"
+    }
+}
@@ -0,0 +1,251 @@
+use super::hwaccel::probe_encoder;
+use super::video::get_scale_filter;
+use super::ProfileContext;
+use super::ProfileType;
+use super::StreamType;
+use super::TranscodingProfile;
+
+use crate::NightfallError;
+
+/// Codecs the QuickSync encoders can produce.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum QsvCodec {
+    H264,
+    Hevc,
+}
+
+impl QsvCodec {
+    fn encoder(&self) -> &'static str {
+        match self {
+            Self::H264 => "h264_qsv",
+            Self::Hevc => "hevc_qsv",
+        }
+    }
+
+    /// Returns the value of `OutputCtx::codec` this encoder produces.
+    fn codec(&self) -> &'static str {
+        match self {
+            Self::H264 => "h264",
+            Self::Hevc => "hevc",
+        }
+    }
+}
+
+/// Render node the QSV device is derived from when the session doesnt set
+/// `ProfileContext::vaapi_device`.
+#[cfg(unix)]
+const DEFAULT_RENDER_NODE: &str = "/dev/dri/renderD128";
+
+/// Intel QuickSync transcoding profiles.
+/// Frames are decoded in software and uploaded to a QSV device which is derived from a vaapi
+/// device on unix and from a d3d11va device on windows. One profile exists per `QsvCodec`, each
+/// is only enabled if ffmpeg manages to encode a test frame with its encoder. If ffmpeg fails to
+/// initialize the device at runtime, sessions move on to the next profile in their chain like
+/// with any other failing profile.
+#[derive(Debug)]
+pub struct QsvTranscodeProfile {
+    codec: QsvCodec,
+    /// Outcome of the test encode ran when the profile was created.
+    available: Result<(), NightfallError>,
+}
+
+impl QsvTranscodeProfile {
+    pub fn new(ffmpeg_bin: &str, codec: QsvCodec) -> Self {
+        let device_args = init_device_args(None);
+        let device_args = device_args.iter().map(String::as_str).collect::<Vec<_>>();
+
+        Self {
+            codec,
+            available: probe_encoder(
+                ffmpeg_bin,
+                codec.encoder(),
+                &device_args,
+                Some("hwupload=extra_hw_frames=64,format=qsv"),
+            ),
+        }
+    }
+}
+
+/// Function returns the args initializing the QSV device `hw` and selecting it for filters.
+fn init_device_args(render_node: Option<&str>) -> Vec<String> {
+    #[cfg(unix)]
+    let parent = format!("vaapi=va:{}", render_node.unwrap_or(DEFAULT_RENDER_NODE));
+    #[cfg(unix)]
+    let derived = "qsv=hw@va";
+
+    // windows has no render nodes, the adapter is picked by the d3d11va device.
+    #[cfg(windows)]
+    let parent = {
+        let _ = render_node;
+        "d3d11va=dx".to_string()
+    };
+    #[cfg(windows)]
+    let derived = "qsv=hw@dx";
+
+    vec![
+        "-init_hw_device".into(),
+        parent,
+        "-init_hw_device".into(),
+        derived.into(),
+        "-filter_hw_device".into(),
+        "hw".into(),
+    ]
+}
+
+impl TranscodingProfile for QsvTranscodeProfile {
+    fn profile_type(&self) -> ProfileType {
+        ProfileType::HardwareTranscode
+    }
+
+    fn stream_type(&self) -> StreamType {
+        StreamType::Video
+    }
+
+    fn name(&self) -> &str {
+        match self.codec {
+            QsvCodec::H264 => "QsvTranscodeProfile",
+            QsvCodec::Hevc => "QsvHevcTranscodeProfile",
+        }
+    }
+
+    fn is_enabled(&self) -> Result<(), NightfallError> {
+        self.available.clone()
+    }
+
+    fn build(&self, ctx: ProfileContext) -> Option<Vec<String>> {
+        let start_num = ctx.output_ctx.start_num.to_string();
+        let stream = format!("0:{}", ctx.input_ctx.stream);
+        let init_seg = format!("{}_init.mp4", &start_num);
+        let seg_name = format!("{}/%d.m4s", ctx.output_ctx.outdir);
+        let outdir = format!("{}/playlist.m3u8", ctx.output_ctx.outdir);
+
+        let mut args = init_device_args(ctx.vaapi_device.as_deref());
+
+        args.append(&mut vec![
+            "-y".into(),
+            "-ss".into(),
+            ctx.input_seek().to_string(),
+            "-i".into(),
+            ctx.file.clone(),
+            "-copyts".into(),
+            "-map".into(),
+            stream,
+            "-c:0".into(),
+            self.codec.encoder().into(),
+            "-bf".into(),
+            "0".into(),
+        ]);
+
+        args.append(&mut super::video::get_output_seek_flags(&ctx));
+
+        // Apple players only accept hevc tagged as `hvc1`.
+        if self.codec == QsvCodec::Hevc {
+            args.append(&mut vec!["-tag:0".into(), "hvc1".into()]);
+        }
+
+        let mut vfilter = vec![
+            "hwupload=extra_hw_frames=64".to_string(),
+            "format=qsv".into(),
+        ];
+
+        vfilter.extend(get_scale_filter("scale_qsv", &ctx));
+
+        args.append(&mut vec!["-vf".into(), vfilter.join(",")]);
+
+        if let Some(bitrate) = ctx.output_ctx.bitrate {
+            args.push("-b:v".into());
+            args.push(bitrate.to_string());
+        }
+
+        args.append(&mut super::video::get_fps_flags(&ctx));
+
+        args.append(&mut vec![
+            "-start_at_zero".into(),
+            "-vsync".into(),
+            super::video::get_fps_mode(&ctx),
+            "-avoid_negative_ts".into(),
+            "disabled".into(),
+            "-max_muxing_queue_size".into(),
+            "2048".into(),
+            "-keyint_min".into(),
+            super::video::get_gop_size(&ctx),
+            "-g".into(),
+            super::video::get_gop_size(&ctx),
+        ]);
+
+        args.append(&mut super::video::get_discont_flags(&ctx));
+
+        args.append(&mut super::video::get_metadata_flags(
+            &ctx,
+            self.profile_type(),
+        ));
+
+        args.append(&mut vec![
+            "-f".into(),
+            "hls".into(),
+            "-start_number".into(),
+            start_num,
+        ]);
+
+        // needed so that in progress segments are named `tmp` and then renamed after the data is
+        // on disk.
+        // This in theory practically prevents the web server from returning a segment that is
+        // in progress.
+        args.append(&mut vec![
+            "-hls_flags".into(),
+            "temp_file+append_list".into(),
+            "-max_delay".into(),
+            "5000000".into(),
+        ]);
+
+        // args needed so we can distinguish between init fragments for new streams.
+        // Basically on the web seeking works by reloading the entire video because of
+        // discontinuity issues that browsers seem to not ignore like mpv.
+        args.append(&mut vec!["-hls_fmp4_init_filename".into(), init_seg]);
+
+        args.append(&mut vec![
+            "-hls_time".into(),
+            ctx.output_ctx.target_gop.to_string(),
+        ]);
+
+        args.append(&mut vec![
+            "-force_key_frames".into(),
+            format!("expr:gte(t,n_forced*{})", ctx.output_ctx.target_gop),
+        ]);
+
+        args.append(&mut vec!["-hls_segment_type".into(), "fmp4".into()]);
+        args.append(&mut vec![
+            "-loglevel".into(),
+            "info".into(),
+            "-progress".into(),
+            "pipe:1".into(),
+        ]);
+        args.append(&mut vec!["-hls_segment_filename".into(), seg_name]);
+        args.push(outdir);
+
+        Some(args)
+    }
+
+    fn supports(&self, ctx: &ProfileContext) -> Result<(), NightfallError> {
+        if ctx.output_ctx.codec == self.codec.codec() {
+            return Ok(());
+        }
+
+        Err(NightfallError::ProfileNotSupported(format!(
+            "Got output codec {} but profile only supports `{}`.",
+            ctx.output_ctx.codec,
+            self.codec.codec()
+        )))
+    }
+
+    fn encoder(&self) -> Option<&str> {
+        Some(self.codec.encoder())
+    }
+
+    fn tag(&self) -> &str {
+        match self.codec {
+            QsvCodec::H264 => "h264_qsv",
+            QsvCodec::Hevc => "hevc_qsv",
+        }
+    }
+}
@@ -5,6 +5,268 @@ use super::ProfileType;
 use super::StreamType;
 use super::TranscodingProfile;
 
+use std::path::Path;
+
+/// Returns the input file and stream to extract subtitles from. Sidecar subtitle files only ever
+/// contain a single stream.
+fn subtitle_input(ctx: &ProfileContext) -> (String, String) {
+    match ctx.input_ctx.subtitle_file.as_ref() {
+        Some(file) => (file.clone(), "0:0".into()),
+        None => (ctx.file.clone(), format!("0:{}", ctx.input_ctx.stream)),
+    }
+}
+
+/// Returns the codec of the subtitle input. For sidecar files where the caller didnt supply a
+/// codec we guess it from the file extension.
+pub(super) fn subtitle_codec(ctx: &ProfileContext) -> String {
+    if !ctx.input_ctx.codec.is_empty() {
+        return ctx.input_ctx.codec.clone();
+    }
+
+    ctx.input_ctx
+        .subtitle_file
+        .as_ref()
+        .and_then(|x| crate::ffprobe::sidecar_codec(Path::new(x)))
+        .unwrap_or_default()
+        .to_string()
+}
+
+/// Escapes a path so that it can be used as an option value inside of a filtergraph. The value
+/// gets unescaped twice, once by the filtergraph parser and once by the filter option parser, so
+/// the characters splitting filters and links, ex. `,` and `[`, only need escaping once.
+fn escape_filter_path(path: &str) -> String {
+    path.replace('\\', "\\\\\\\\")
+        .replace('\'', "\\\\\\'")
+        .replace(':', "\\\\:")
+        .replace(',', "\\,")
+        .replace(';', "\\;")
+        .replace('[', "\\[")
+        .replace(']', "\\]")
+}
+
+/// Returns whether the subtitle stream to burn in is image based, ex. PGS or DVD subtitles. These
+/// cant be rendered by libass and have to be overlaid on top of the video instead.
+pub(super) fn is_bitmap_subtitle(ctx: &ProfileContext) -> bool {
+    if ctx.input_ctx.subtitle_file.is_some() {
+        return false;
+    }
+
+    ctx.output_ctx
+        .burn_subtitle
+        .and_then(|x| ctx.input_ctx.subtitle_streams.get(x))
+        .is_some_and(|x| {
+            ["hdmv_pgs_subtitle", "dvd_subtitle", "dvb_subtitle", "xsub"]
+                .contains(&x.codec_name.as_str())
+        })
+}
+
+/// Returns the filter used to burn the selected subtitle into the video. ASS/SSA sidecar files
+/// are rendered by the `ass` filter which feeds them to libass as is, everything else, including
+/// embedded ASS streams, goes through the `subtitles` filter.
+pub(super) fn subtitles_filter(ctx: &ProfileContext) -> Option<String> {
+    let mut filter = match ctx.input_ctx.subtitle_file.as_ref() {
+        // the input codec is the one of the video here, so the sidecar codec has to be guessed.
+        Some(file)
+            if matches!(
+                crate::ffprobe::sidecar_codec(Path::new(file)),
+                Some("ass") | Some("ssa")
+            ) =>
+        {
+            format!("ass=filename={}", escape_filter_path(file))
+        }
+        Some(file) => format!("subtitles=filename={}", escape_filter_path(file)),
+        None => format!(
+            "subtitles=filename={}:si={}",
+            escape_filter_path(&ctx.file),
+            ctx.output_ctx.burn_subtitle?
+        ),
+    };
+
+    if !ctx.input_ctx.font_attachments.is_empty() {
+        filter.push_str(&format!(
+            ":fontsdir={}",
+            escape_filter_path(&ctx.output_ctx.fonts_dir())
+        ));
+    }
+
+    Some(filter)
+}
+
+/// Returns the file extension of a font with the given mimetype, attachments without a filename
+/// are dumped under it. Matroska files mostly tag TrueType fonts so that is the fallback.
+fn font_extension(mimetype: Option<&str>) -> &'static str {
+    match mimetype.unwrap_or_default() {
+        "font/otf" | "application/vnd.ms-opentype" | "application/x-font-otf" => "otf",
+        "font/collection" => "ttc",
+        "font/woff" | "application/font-woff" => "woff",
+        "font/woff2" => "woff2",
+        _ => "ttf",
+    }
+}
+
+/// Returns the args needed to extract all font attachments into `OutputCtx::fonts_dir`. These
+/// have to precede the input as ffmpeg dumps attachments when opening the input file, before the
+/// `subtitles` filter gets initialized.
+pub(super) fn dump_font_attachments(ctx: &ProfileContext) -> Vec<String> {
+    let fonts_dir = ctx.output_ctx.fonts_dir();
+
+    ctx.input_ctx
+        .font_attachments
+        .iter()
+        .flat_map(|x| {
+            // attachments of different streams can share a filename, so the index keeps one
+            // from overwriting the other.
+            let filename = match x.attachment_filename() {
+                Some(filename) => format!("{}_{}", x.index, filename),
+                None => format!("{}.{}", x.index, font_extension(x.attachment_mimetype())),
+            };
+
+            vec![
+                format!("-dump_attachment:{}", x.index),
+                format!("{}/{}", fonts_dir, filename),
+            ]
+        })
+        .collect()
+}
+
+/// Profile which converts a text subtitle into WebVTT chunks of `OutputCtx::target_gop` seconds,
+/// lined up with the video chunks, so that HLS players can side-load them. ffmpeg writes the whole
+/// subtitle into `webvtt::SOURCE_FILE` which gets split into `N.vtt` chunks listed by
+/// `webvtt::PLAYLIST_FILE` once it exits. Only used when `OutputCtx::segment_subtitles` is set.
+#[derive(Debug)]
+pub struct SegmentedWebvttTranscodeProfile;
+
+impl TranscodingProfile for SegmentedWebvttTranscodeProfile {
+    fn profile_type(&self) -> ProfileType {
+        ProfileType::Transcode
+    }
+
+    fn stream_type(&self) -> StreamType {
+        StreamType::Subtitle
+    }
+
+    fn name(&self) -> &str {
+        "SegmentedWebvttTranscodeProfile"
+    }
+
+    fn build(&self, ctx: ProfileContext) -> Option<Vec<String>> {
+        let (file, stream) = subtitle_input(&ctx);
+
+        let args = vec![
+            "-y".into(),
+            "-i".into(),
+            file,
+            "-map".into(),
+            stream,
+            "-f".into(),
+            "webvtt".into(),
+            format!("{}/{}", ctx.output_ctx.outdir, crate::webvtt::SOURCE_FILE),
+        ];
+
+        Some(args)
+    }
+
+    fn supports(&self, ctx: &ProfileContext) -> Result<(), NightfallError> {
+        let codec = subtitle_codec(ctx);
+
+        if ["srt", "ass", "ssa", "subrip", "webvtt"].contains(&codec.as_str())
+            && ctx.output_ctx.codec == "webvtt"
+            && ctx.output_ctx.segment_subtitles
+        {
+            return Ok(());
+        }
+
+        Err(NightfallError::ProfileNotSupported(format!(
+            "Codec {} not supported.",
+            codec
+        )))
+    }
+
+    fn tag(&self) -> &str {
+        "webvtt_segmented"
+    }
+
+    fn splits_subtitles(&self) -> bool {
+        true
+    }
+}
+
+/// Profile which converts a text subtitle into a fragmented mp4 timed-text track, either `wvtt`
+/// (WebVTT) or `stpp` (TTML), for DASH clients that dont handle sidecar subtitles well. The track
+/// is written to `timed_text.mp4` with a global `sidx` so that it can be served by byte ranges.
+#[derive(Debug)]
+pub struct TimedTextTranscodeProfile;
+
+impl TranscodingProfile for TimedTextTranscodeProfile {
+    fn profile_type(&self) -> ProfileType {
+        ProfileType::Transcode
+    }
+
+    fn stream_type(&self) -> StreamType {
+        StreamType::Subtitle
+    }
+
+    fn name(&self) -> &str {
+        "TimedTextTranscodeProfile"
+    }
+
+    fn build(&self, ctx: ProfileContext) -> Option<Vec<String>> {
+        let (file, stream) = subtitle_input(&ctx);
+
+        let codec = match ctx.output_ctx.codec.as_str() {
+            "wvtt" => "webvtt",
+            "stpp" => "ttml",
+            _ => return None,
+        };
+
+        let mut args = vec![
+            "-y".into(),
+            "-i".into(),
+            file,
+            "-map".into(),
+            stream,
+            "-c:s".into(),
+            codec.into(),
+        ];
+
+        // ttml in mp4 has to be tagged explicitly, otherwise ffmpeg refuses to mux it.
+        if codec == "ttml" {
+            args.append(&mut vec!["-tag:s".into(), "stpp".into()]);
+        }
+
+        args.append(&mut vec![
+            "-f".into(),
+            "mp4".into(),
+            "-movflags".into(),
+            "frag_keyframe+empty_moov+default_base_moof+global_sidx".into(),
+            "-frag_duration".into(),
+            (ctx.output_ctx.target_gop as u64 * 1_000_000).to_string(),
+            format!("{}/timed_text.mp4", ctx.output_ctx.outdir),
+        ]);
+
+        Some(args)
+    }
+
+    fn supports(&self, ctx: &ProfileContext) -> Result<(), NightfallError> {
+        let codec = subtitle_codec(ctx);
+
+        if ["srt", "ass", "ssa", "subrip", "webvtt", "mov_text"].contains(&codec.as_str())
+            && ["wvtt", "stpp"].contains(&ctx.output_ctx.codec.as_str())
+        {
+            return Ok(());
+        }
+
+        Err(NightfallError::ProfileNotSupported(format!(
+            "Cant convert {} into a {} timed-text track.",
+            codec, ctx.output_ctx.codec
+        )))
+    }
+
+    fn tag(&self) -> &str {
+        "timed_text"
+    }
+}
+
 #[derive(Debug)]
 pub struct WebvttTranscodeProfile;
 
@@ -22,12 +284,14 @@ impl TranscodingProfile for WebvttTranscodeProfile {
     }
 
     fn build(&self, ctx: ProfileContext) -> Option<Vec<String>> {
+        let (file, stream) = subtitle_input(&ctx);
+
         let args = vec![
             "-y".into(),
             "-i".into(),
-            ctx.file,
+            file,
             "-map".into(),
-            format!("0:{}", ctx.input_ctx.stream),
+            stream,
             "-f".into(),
             "webvtt".into(),
             "-".into(),
@@ -37,15 +301,18 @@ impl TranscodingProfile for WebvttTranscodeProfile {
     }
 
     fn supports(&self, ctx: &ProfileContext) -> Result<(), NightfallError> {
-        if ["srt", "ass", "ssa", "subrip"].contains(&ctx.input_ctx.codec.as_str())
+        let codec = subtitle_codec(ctx);
+
+        if ["srt", "ass", "ssa", "subrip"].contains(&codec.as_str())
             && ctx.output_ctx.codec == "webvtt"
+            && !ctx.output_ctx.segment_subtitles
         {
             return Ok(());
         }
 
         Err(NightfallError::ProfileNotSupported(format!(
             "Codec {} not supported.",
-            ctx.input_ctx.codec.as_str()
+            codec
         )))
     }
 
@@ -77,12 +344,14 @@ impl TranscodingProfile for AssExtractProfile {
     }
 
     fn build(&self, ctx: ProfileContext) -> Option<Vec<String>> {
+        let (file, stream) = subtitle_input(&ctx);
+
         let args = vec![
             "-y".into(),
             "-i".into(),
-            ctx.file,
+            file,
             "-map".into(),
-            format!("0:{}", ctx.input_ctx.stream),
+            stream,
             "-f".into(),
             "ass".into(),
             "-".into(),
@@ -92,7 +361,9 @@ impl TranscodingProfile for AssExtractProfile {
     }
 
     fn supports(&self, ctx: &ProfileContext) -> Result<(), NightfallError> {
-        if ["ass", "ssa"].contains(&ctx.input_ctx.codec.as_str()) && ctx.output_ctx.codec.as_str() == "ass" {
+        if ["ass", "ssa"].contains(&subtitle_codec(ctx).as_str())
+            && ctx.output_ctx.codec.as_str() == "ass"
+        {
             return Ok(());
         }
 
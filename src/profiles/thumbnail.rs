@@ -29,8 +29,7 @@ impl TranscodingProfile for ThumbnailProfile {
             "-i".into(),
             ctx.file,
             "-vf".into(),
-            // Make a thumbnail every 2 seconds that is 320px in width
-            "fps=1/2,scale=min(320\\,iw):ow/dar,tile=8x6".into(),
+            ctx.output_ctx.thumbnails.filter(),
             "-c:v".into(),
             "mjpeg".into(),
             "-fps_mode".into(),
@@ -54,7 +53,7 @@ impl TranscodingProfile for ThumbnailProfile {
         }
 
         if ctx.output_ctx.codec == "jpg" {
-            return Ok(());
+            return ctx.output_ctx.thumbnails.validate();
         }
 
         Err(NightfallError::ProfileNotSupported(format!(
@@ -63,6 +62,10 @@ impl TranscodingProfile for ThumbnailProfile {
         )))
     }
 
+    fn encoder(&self) -> Option<&str> {
+        Some("mjpeg")
+    }
+
     fn tag(&self) -> &str {
         "jpg"
     }
@@ -3,26 +3,55 @@ use super::ProfileType;
 use super::StreamType;
 use super::TranscodingProfile;
 
+use super::video::get_scale_filter;
+
 use crate::NightfallError;
 
 use std::fs;
 use std::path::PathBuf;
 
+/// Codecs the vaapi encoders can produce.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum VaapiCodec {
+    H264,
+    Hevc,
+}
+
+impl VaapiCodec {
+    fn encoder(&self) -> &'static str {
+        match self {
+            Self::H264 => "h264_vaapi",
+            Self::Hevc => "hevc_vaapi",
+        }
+    }
+
+    /// Returns the value of `OutputCtx::codec` this encoder produces.
+    fn codec(&self) -> &'static str {
+        match self {
+            Self::H264 => "h264",
+            Self::Hevc => "hevc",
+        }
+    }
+}
+
 /// Vaapi transcoding profiles.
-/// This is a unix exclusive transcoding profile that leverages vaapi. This profile will
-/// automatically be enabled if any of your GPUs support encoding and decoding h264 with the
-/// profiles `Main`, `High` and `ConstrainedBaseline`. This profile will only transcode h264 input
-/// streams.
+/// This is a unix exclusive transcoding profile that leverages vaapi. One profile exists per
+/// `VaapiCodec`. The h264 profile will automatically be enabled if any of your GPUs support
+/// encoding and decoding h264 with the profiles `Main`, `High` and `ConstrainedBaseline`, the
+/// hevc profile requires the device to encode `HEVCMain`. Both profiles only transcode h264 and
+/// hevc input streams.
 #[cfg(unix)]
 #[derive(Debug)]
 pub struct VaapiTranscodeProfile {
+    codec: VaapiCodec,
     profiles: Vec<rusty_vainfo::Profile>,
     vendor: String,
     dri: PathBuf,
 }
 
 impl VaapiTranscodeProfile {
-    pub fn new() -> Option<Self> {
+    /// Creates the profile for the first render node under `/dev/dri` vaapi can open.
+    pub fn new(codec: VaapiCodec) -> Option<Self> {
         let hw_targets = fs::read_dir("/dev/dri")
             .ok()?
             .filter_map(Result::ok)
@@ -31,22 +60,32 @@ impl VaapiTranscodeProfile {
             .collect::<Vec<_>>();
 
         for target in hw_targets {
-            if let Ok(x) = rusty_vainfo::VaInstance::with_drm(&target) {
-                return Some(Self {
-                    profiles: x.profiles().unwrap_or_default(),
-                    vendor: x.vendor_string(),
-                    dri: target,
-                });
+            if let Some(x) = Self::with_device(target, codec) {
+                return Some(x);
             }
         }
 
         Some(Self {
+            codec,
             profiles: Vec::new(),
             vendor: "<null_device>".into(),
             dri: PathBuf::new(),
         })
     }
 
+    /// Creates the profile for the render node at `dri`, ex. `/dev/dri/renderD129`. Sessions can
+    /// still pick another render node with `ProfileContext::vaapi_device`.
+    pub fn with_device(dri: PathBuf, codec: VaapiCodec) -> Option<Self> {
+        let instance = rusty_vainfo::VaInstance::with_drm(&dri).ok()?;
+
+        Some(Self {
+            codec,
+            profiles: instance.profiles().unwrap_or_default(),
+            vendor: instance.vendor_string(),
+            dri,
+        })
+    }
+
     fn hw_scaling_supported(&self) -> bool {
         let required_profiles = ["VAProfileH264Main", "VAProfileH264High"];
 
@@ -79,10 +118,29 @@ impl TranscodingProfile for VaapiTranscodeProfile {
     }
 
     fn name(&self) -> &str {
-        "VaapiTranscodeProfile"
+        match self.codec {
+            VaapiCodec::H264 => "VaapiTranscodeProfile",
+            VaapiCodec::Hevc => "VaapiHevcTranscodeProfile",
+        }
     }
 
     fn is_enabled(&self) -> Result<(), NightfallError> {
+        if self.codec == VaapiCodec::Hevc {
+            let can_encode = self.profiles.iter().any(|x| {
+                x.name == "VAProfileHEVCMain"
+                    && x.entrypoints
+                        .iter()
+                        .any(|x| x == "VAEntrypointEncSlice" || x == "VAEntrypointEncSliceLP")
+            });
+
+            if !can_encode {
+                return Err(NightfallError::ProfileNotSupported(format!(
+                    "Device {} doesnt support encoding hevc.",
+                    self.vendor
+                )));
+            }
+        }
+
         // Currently this profile only supports HW Encoding + decoding.
         let required_features = [
             "VAEntrypointEncSlice".to_string(),
@@ -148,35 +206,46 @@ impl TranscodingProfile for VaapiTranscodeProfile {
         let seg_name = format!("{}/%d.m4s", ctx.output_ctx.outdir);
         let outdir = format!("{}/playlist.m3u8", ctx.output_ctx.outdir);
 
+        let device = ctx
+            .vaapi_device
+            .clone()
+            .unwrap_or_else(|| self.dri.to_string_lossy().into());
+
         let mut args = vec![
             "-hwaccel".into(),
             "vaapi".into(),
             "-vaapi_device".into(),
-            self.dri.to_string_lossy().into(),
+            device,
             "-hwaccel_output_format".into(),
             "vaapi".into(),
             "-y".into(),
             "-ss".into(),
-            (ctx.output_ctx.start_num * ctx.output_ctx.target_gop).to_string(),
+            ctx.input_seek().to_string(),
             "-i".into(),
             ctx.file.clone(),
             "-copyts".into(),
             "-map".into(),
             stream,
             "-c:0".into(),
-            "h264_vaapi".into(),
+            self.codec.encoder().into(),
             "-bf".into(),
             "0".into(),
         ];
 
+        args.append(&mut super::video::get_output_seek_flags(&ctx));
+
+        // Apple players only accept hevc tagged as `hvc1`.
+        if self.codec == VaapiCodec::Hevc {
+            args.append(&mut vec!["-tag:0".into(), "hvc1".into()]);
+        }
+
         args.push("-vf".into());
 
-        if let Some(height) = ctx.output_ctx.height {
+        if ctx.output_ctx.height.is_some() {
             let mut vfilter = Vec::new();
-            let width = ctx.output_ctx.width.unwrap_or(-2); // defaults to scaling by 2
 
             if self.hw_scaling_supported() {
-                vfilter.push(format!("scale_vaapi={}:{}", height, width));
+                vfilter.extend(get_scale_filter("scale_vaapi", &ctx));
             }
 
             vfilter.push("hwdownload".into());
@@ -189,7 +258,7 @@ impl TranscodingProfile for VaapiTranscodeProfile {
             vfilter.push("format=nv12".into());
 
             if !self.hw_scaling_supported() {
-                vfilter.push(format!("scale={}:{}", height, width));
+                vfilter.extend(get_scale_filter("scale", &ctx));
             }
 
             vfilter.push("hwupload".into());
@@ -212,23 +281,30 @@ impl TranscodingProfile for VaapiTranscodeProfile {
             }
         }
 
+        args.append(&mut super::video::get_fps_flags(&ctx));
+
         args.append(&mut vec![
             "-vsync".into(),
-            "passthrough".into(),
+            super::video::get_fps_mode(&ctx),
             "-avoid_negative_ts".into(),
             "disabled".into(),
             "-max_muxing_queue_size".into(),
             "2048".into(),
             "-keyint_min".into(),
-            "120".into(),
+            super::video::get_gop_size(&ctx),
             "-g".into(),
-            "120".into(),
+            super::video::get_gop_size(&ctx),
             "-frag_duration".into(),
             "5000000".into(),
         ]);
 
         args.append(&mut super::video::get_discont_flags(&ctx));
 
+        args.append(&mut super::video::get_metadata_flags(
+            &ctx,
+            self.profile_type(),
+        ));
+
         args.append(&mut vec![
             "-f".into(),
             "hls".into(),
@@ -306,10 +382,11 @@ impl TranscodingProfile for VaapiTranscodeProfile {
             ));
         }
 
-        if ctx.output_ctx.codec != "h264" {
-            return Err(NightfallError::ProfileNotSupported(
-                "Profile only supports h264 output streams.".into(),
-            ));
+        if ctx.output_ctx.codec != self.codec.codec() {
+            return Err(NightfallError::ProfileNotSupported(format!(
+                "Profile only supports {} output streams.",
+                self.codec.codec()
+            )));
         }
 
         let profile = match [ctx.input_ctx.codec.as_str(), ctx.input_ctx.profile.as_str()] {
@@ -336,7 +413,18 @@ impl TranscodingProfile for VaapiTranscodeProfile {
         Ok(())
     }
 
+    fn encoder(&self) -> Option<&str> {
+        Some(self.codec.encoder())
+    }
+
+    fn hwaccel(&self) -> Option<&str> {
+        Some("vaapi")
+    }
+
     fn tag(&self) -> &str {
-        "h264_vaapi"
+        match self.codec {
+            VaapiCodec::H264 => "h264_vaapi",
+            VaapiCodec::Hevc => "hevc_vaapi",
+        }
     }
 }
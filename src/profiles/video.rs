@@ -1,10 +1,37 @@
+use super::hwaccel::probe_encoder;
+use super::Container;
 use super::ProfileContext;
 use super::ProfileType;
 use super::StreamType;
 use super::TranscodingProfile;
 
+use crate::ffprobe::FieldOrder;
 use crate::NightfallError;
 
+use serde_derive::{Deserialize, Serialize};
+
+/// Tone mapping operators used to map HDR video to SDR, see the `tonemap` filter of ffmpeg.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum TonemapAlgorithm {
+    /// Preserves details in both dark and bright areas, a good default for films.
+    #[default]
+    Hable,
+    /// Keeps the colors of in range areas accurate and only compresses highlights.
+    Mobius,
+    /// Simple curve which tends to make the image look flat.
+    Reinhard,
+}
+
+impl TonemapAlgorithm {
+    fn name(&self) -> &'static str {
+        match self {
+            Self::Hable => "hable",
+            Self::Mobius => "mobius",
+            Self::Reinhard => "reinhard",
+        }
+    }
+}
+
 #[derive(Debug)]
 pub struct HevcTransmuxProfile;
 
@@ -21,6 +48,10 @@ impl TranscodingProfile for HevcTransmuxProfile {
         "HevcTransmuxProfile"
     }
 
+    fn keeps_hdr(&self) -> bool {
+        true
+    }
+
     fn build(&self, ctx: ProfileContext) -> Option<Vec<String>> {
         let start_num = ctx.output_ctx.start_num.to_string();
         let stream = format!("0:{}", ctx.input_ctx.stream);
@@ -31,7 +62,7 @@ impl TranscodingProfile for HevcTransmuxProfile {
         let mut args = vec![
             "-y".into(),
             "-ss".into(),
-            (ctx.output_ctx.start_num * ctx.output_ctx.target_gop).to_string(),
+            ctx.chunk_start(ctx.output_ctx.start_num).to_string(),
             "-i".into(),
             ctx.file.clone(),
             "-copyts".into(),
@@ -41,6 +72,21 @@ impl TranscodingProfile for HevcTransmuxProfile {
             "copy".into(),
         ];
 
+        // Dolby Vision is either tagged as such, or stripped down to its base layer for clients
+        // that cant play it, see `OutputCtx::strip_dolby_vision`.
+        if ctx.input_ctx.dolby_vision().is_some() {
+            if ctx.output_ctx.strip_dolby_vision {
+                args.append(&mut vec![
+                    "-bsf:v".into(),
+                    "dovi_rpu=strip=1".into(),
+                    "-tag:v:0".into(),
+                    "hvc1".into(),
+                ]);
+            } else {
+                args.append(&mut vec!["-tag:v:0".into(), "dvh1".into()]);
+            }
+        }
+
         args.append(&mut vec![
             "-start_at_zero".into(),
             "-fps_mode".into(),
@@ -51,6 +97,13 @@ impl TranscodingProfile for HevcTransmuxProfile {
             "2048".into(),
         ]);
 
+        args.append(&mut get_metadata_flags(&ctx, self.profile_type()));
+
+        if ctx.output_ctx.single_file {
+            args.append(&mut get_single_file_flags(&ctx));
+            return Some(args);
+        }
+
         args.append(&mut vec![
             "-f".into(),
             "hls".into(),
@@ -90,13 +143,6 @@ impl TranscodingProfile for HevcTransmuxProfile {
 
         args.append(&mut vec!["-strict".into(), "unofficial".into()]);
 
-        // If Dolby Vision side data is detected, tag it appropriately
-        if let Some(x) = ctx.input_ctx.side_data_list {
-            if x[0].side_data_type == "DOVI configuration record".to_string() {
-                args.append(&mut vec!["-tag:v:0".into(), "dvh1".into()]);
-            }
-        }
-
         args.append(&mut vec!["-hls_segment_type".into(), "fmp4".into()]);
         args.append(&mut vec![
             "-loglevel".into(),
@@ -122,6 +168,17 @@ impl TranscodingProfile for HevcTransmuxProfile {
             ));
         }
 
+        let dolby_vision = ctx.input_ctx.dolby_vision();
+
+        if ctx.output_ctx.strip_dolby_vision
+            && dolby_vision.is_some_and(|x| !x.has_compatible_base_layer())
+        {
+            return Err(NightfallError::ProfileNotSupported(format!(
+                "Dolby Vision profile {} has no base layer to fall back to.",
+                dolby_vision.and_then(|x| x.dv_profile).unwrap_or_default()
+            )));
+        }
+
         if ctx.input_ctx.codec == ctx.output_ctx.codec && ctx.input_ctx.codec == "hevc" {
             return Ok(());
         }
@@ -162,7 +219,7 @@ impl TranscodingProfile for H264TransmuxProfile {
         let mut args = vec![
             "-y".into(),
             "-ss".into(),
-            (ctx.output_ctx.start_num * ctx.output_ctx.target_gop).to_string(),
+            ctx.chunk_start(ctx.output_ctx.start_num).to_string(),
             "-i".into(),
             ctx.file.clone(),
             "-copyts".into(),
@@ -182,6 +239,13 @@ impl TranscodingProfile for H264TransmuxProfile {
             "2048".into(),
         ]);
 
+        args.append(&mut get_metadata_flags(&ctx, self.profile_type()));
+
+        if ctx.output_ctx.single_file {
+            args.append(&mut get_single_file_flags(&ctx));
+            return Some(args);
+        }
+
         args.append(&mut vec![
             "-f".into(),
             "hls".into(),
@@ -284,7 +348,7 @@ impl TranscodingProfile for AV1TransmuxProfile {
         let mut args = vec![
             "-y".into(),
             "-ss".into(),
-            (ctx.output_ctx.start_num * ctx.output_ctx.target_gop).to_string(),
+            ctx.chunk_start(ctx.output_ctx.start_num).to_string(),
             "-i".into(),
             ctx.file.clone(),
             "-copyts".into(),
@@ -304,6 +368,13 @@ impl TranscodingProfile for AV1TransmuxProfile {
             "2048".into(),
         ]);
 
+        args.append(&mut get_metadata_flags(&ctx, self.profile_type()));
+
+        if ctx.output_ctx.single_file {
+            args.append(&mut get_single_file_flags(&ctx));
+            return Some(args);
+        }
+
         args.append(&mut vec![
             "-f".into(),
             "hls".into(),
@@ -396,6 +467,10 @@ impl TranscodingProfile for H264TranscodeProfile {
         "H264TranscodeProfile"
     }
 
+    fn tonemaps(&self) -> bool {
+        true
+    }
+
     fn build(&self, ctx: ProfileContext) -> Option<Vec<String>> {
         let start_num = ctx.output_ctx.start_num.to_string();
         let stream = format!("0:{}", ctx.input_ctx.stream);
@@ -406,7 +481,7 @@ impl TranscodingProfile for H264TranscodeProfile {
         let mut args = vec![
             "-y".into(),
             "-ss".into(),
-            (ctx.output_ctx.start_num * ctx.output_ctx.target_gop).to_string(),
+            ctx.input_seek().to_string(),
             "-i".into(),
             ctx.file.clone(),
             "-copyts".into(),
@@ -418,10 +493,18 @@ impl TranscodingProfile for H264TranscodeProfile {
             "veryfast".into(),
         ];
 
-        if let Some(height) = ctx.output_ctx.height {
-            let width = ctx.output_ctx.width.unwrap_or(-2); // defaults to scaling by 2
-            args.push("-vf".into());
-            args.push(format!("scale={}:{}", height, width));
+        args.append(&mut get_output_seek_flags(&ctx));
+        args.append(&mut get_thread_flags(&ctx, "libx264"));
+
+        let mut vfilter = get_deinterlace_filter(&ctx)
+            .into_iter()
+            .chain(get_tonemap_filter(&ctx))
+            .collect::<Vec<_>>();
+
+        vfilter.extend(get_scale_filter("scale", &ctx));
+
+        if !vfilter.is_empty() {
+            args.append(&mut vec!["-vf".into(), vfilter.join(",")]);
         }
 
         if let Some(bitrate) = ctx.output_ctx.bitrate {
@@ -429,15 +512,24 @@ impl TranscodingProfile for H264TranscodeProfile {
             args.push(bitrate.to_string());
         }
 
+        args.append(&mut get_fps_flags(&ctx));
+
         args.append(&mut vec![
             "-fps_mode".into(),
-            "passthrough".into(),
+            get_fps_mode(&ctx),
             "-avoid_negative_ts".into(),
             "make_non_negative".into(),
             "-max_muxing_queue_size".into(),
             "2048".into(),
         ]);
 
+        args.append(&mut get_metadata_flags(&ctx, self.profile_type()));
+
+        if ctx.output_ctx.single_file {
+            args.append(&mut get_single_file_flags(&ctx));
+            return Some(args);
+        }
+
         args.append(&mut vec![
             "-f".into(),
             "hls".into(),
@@ -495,15 +587,33 @@ impl TranscodingProfile for H264TranscodeProfile {
         )))
     }
 
+    fn encoder(&self) -> Option<&str> {
+        Some("libx264")
+    }
+
     fn tag(&self) -> &str {
         "h264"
     }
 }
 
+/// Profile transcoding video to HEVC with x265, for clients such as Safari and TVs which prefer
+/// HEVC as it needs less bandwidth than h264 at the same quality. Only enabled if the ffmpeg
+/// build ships `libx265`.
 #[derive(Debug)]
-pub struct RawVideoTranscodeProfile;
+pub struct HevcTranscodeProfile {
+    /// Outcome of the test encode ran when the profile was created.
+    available: Result<(), NightfallError>,
+}
 
-impl TranscodingProfile for RawVideoTranscodeProfile {
+impl HevcTranscodeProfile {
+    pub fn new(ffmpeg_bin: &str) -> Self {
+        Self {
+            available: probe_encoder(ffmpeg_bin, "libx265", &[], None),
+        }
+    }
+}
+
+impl TranscodingProfile for HevcTranscodeProfile {
     fn profile_type(&self) -> ProfileType {
         ProfileType::Transcode
     }
@@ -513,87 +623,1018 @@ impl TranscodingProfile for RawVideoTranscodeProfile {
     }
 
     fn name(&self) -> &str {
-        "RawVideoTranscodeProfile"
+        "HevcTranscodeProfile"
+    }
+
+    fn keeps_hdr(&self) -> bool {
+        true
+    }
+
+    fn tonemaps(&self) -> bool {
+        true
+    }
+
+    fn is_enabled(&self) -> Result<(), NightfallError> {
+        self.available.clone()
     }
 
     fn build(&self, ctx: ProfileContext) -> Option<Vec<String>> {
-        let mut args = vec!["-y".into()];
+        let start_num = ctx.output_ctx.start_num.to_string();
+        let stream = format!("0:{}", ctx.input_ctx.stream);
+        let init_seg = format!("{}_init.mp4", &start_num);
+        let seg_name = format!("{}/%d.m4s", ctx.output_ctx.outdir);
+        let outdir = format!("{}/playlist.m3u8", ctx.output_ctx.outdir);
 
-        if let Some(seek) = ctx.input_ctx.seek {
-            let flag = if seek.is_positive() {
-                "-ss".into()
-            } else {
-                "-sseof".into()
-            };
+        let mut args = vec![
+            "-y".into(),
+            "-ss".into(),
+            ctx.input_seek().to_string(),
+            "-i".into(),
+            ctx.file.clone(),
+            "-copyts".into(),
+            "-map".into(),
+            stream,
+            "-c:0".into(),
+            "libx265".into(),
+            "-preset".into(),
+            "veryfast".into(),
+            // Apple players only accept hevc tagged as `hvc1`.
+            "-tag:0".into(),
+            "hvc1".into(),
+        ];
 
-            args.push(flag);
-            args.push(seek.to_string());
+        args.append(&mut get_output_seek_flags(&ctx));
+        args.append(&mut get_thread_flags(&ctx, "libx265"));
+
+        let mut vfilter = get_deinterlace_filter(&ctx)
+            .into_iter()
+            .chain(get_tonemap_filter(&ctx))
+            .collect::<Vec<_>>();
+
+        vfilter.extend(get_scale_filter("scale", &ctx));
+
+        if !vfilter.is_empty() {
+            args.append(&mut vec!["-vf".into(), vfilter.join(",")]);
         }
 
-        if let Some(max_to_transcode) = ctx.output_ctx.max_to_transcode {
-            args.push("-t".into());
-            args.push(max_to_transcode.to_string());
+        if let Some(bitrate) = ctx.output_ctx.bitrate {
+            args.push("-b:v".into());
+            args.push(bitrate.to_string());
         }
 
+        args.append(&mut get_fps_flags(&ctx));
+
+        // x265 defaults to open gops and scene cut keyframes, both of which break segments that
+        // have to be decodable on their own.
         args.append(&mut vec![
-            "-map".into(),
-            format!("0:{}", ctx.input_ctx.stream),
-        ]);
-        args.append(&mut vec!["-c:v".into(), "rawvideo".into()]);
-        args.append(&mut vec![
-            "-flags2".into(),
-            "-pix_fmt".into(),
-            "rgb24".into(),
+            "-fps_mode".into(),
+            get_fps_mode(&ctx),
+            "-avoid_negative_ts".into(),
+            "make_non_negative".into(),
+            "-max_muxing_queue_size".into(),
+            "2048".into(),
         ]);
-        args.append(&mut vec!["-preset".into(), "ultrafast".into()]);
 
-        if let Some(height) = ctx.output_ctx.height {
-            let width = ctx.output_ctx.width.unwrap_or(-2);
+        let mut x265_params = vec![format!(
+            "keyint={gop}:min-keyint={gop}:scenecut=0:open-gop=0:log-level=error",
+            gop = get_gop_size(&ctx)
+        )];
+
+        if let Some(threads) = ctx.threads {
+            x265_params.push(format!("pools={}", threads));
+        }
+
+        if ctx.keeps_hdr() {
+            x265_params.append(&mut get_hdr_x265_params(&ctx));
 
             args.append(&mut vec![
-                "-vf".into(),
-                format!("scale={}:{}", height, width),
+                "-pix_fmt".into(),
+                "yuv420p10le".into(),
+                "-color_primaries".into(),
+                "bt2020".into(),
+                "-color_trc".into(),
+                ctx.input_ctx.color_transfer.clone().unwrap_or_default(),
+                "-colorspace".into(),
+                "bt2020nc".into(),
             ]);
         }
 
-        args.append(&mut vec!["-f".into(), "data".into(), "-".into()]);
+        args.append(&mut vec!["-x265-params".into(), x265_params.join(":")]);
+
+        args.append(&mut get_metadata_flags(&ctx, self.profile_type()));
+
+        if ctx.output_ctx.single_file {
+            args.append(&mut get_single_file_flags(&ctx));
+            return Some(args);
+        }
+
+        args.append(&mut vec![
+            "-f".into(),
+            "hls".into(),
+            "-start_number".into(),
+            start_num,
+        ]);
+
+        args.append(&mut get_discont_flags(&ctx));
+
+        // needed so that in progress segments are named `tmp` and then renamed after the data is
+        // on disk.
+        // This in theory practically prevents the web server from returning a segment that is
+        // in progress.
+        args.append(&mut vec![
+            "-hls_flags".into(),
+            "temp_file+append_list".into(),
+            "-max_delay".into(),
+            "5000000".into(),
+        ]);
+
+        // args needed so we can distinguish between init fragments for new streams.
+        // Basically on the web seeking works by reloading the entire video because of
+        // discontinuity issues that browsers seem to not ignore like mpv.
+        args.append(&mut vec!["-hls_fmp4_init_filename".into(), init_seg]);
+        args.append(&mut vec![
+            "-hls_time".into(),
+            ctx.output_ctx.target_gop.to_string(),
+        ]);
+        args.append(&mut vec![
+            "-force_key_frames".into(),
+            format!("expr:gte(t,n_forced*{})", ctx.output_ctx.target_gop),
+        ]);
+
+        args.append(&mut vec!["-hls_segment_type".into(), "fmp4".into()]);
+        args.append(&mut vec![
+            "-loglevel".into(),
+            "info".into(),
+            "-progress".into(),
+            "pipe:1".into(),
+        ]);
+        args.append(&mut vec!["-hls_segment_filename".into(), seg_name]);
+        args.push(outdir);
 
         Some(args)
     }
 
     fn supports(&self, ctx: &ProfileContext) -> Result<(), NightfallError> {
-        if ctx.output_ctx.codec == "rawvideo" {
+        if ctx.output_ctx.codec == "hevc" {
             return Ok(());
         }
 
         Err(NightfallError::ProfileNotSupported(format!(
-            "Codec {} is not supported.",
+            "Got output codec {} but profile only supports `hevc`.",
             ctx.output_ctx.codec
         )))
     }
 
+    fn encoder(&self) -> Option<&str> {
+        Some("libx265")
+    }
+
     fn tag(&self) -> &str {
-        "rawvideo"
+        "hevc"
     }
+}
 
-    fn is_stdio_stream(&self) -> bool {
-        true
+/// Profile transcoding video to AV1 with SVT-AV1, meant for clients that can decode AV1 as it
+/// needs far less bandwidth than h264 at high resolutions. Only enabled if the ffmpeg build
+/// ships `libsvtav1`.
+///
+/// When `OutputCtx::bitrate` is set the encoder runs in VBR mode targeting it, otherwise a crf
+/// picked from the output height is used. The preset is picked from the output height as well so
+/// that encoding stays faster than realtime.
+#[derive(Debug)]
+pub struct AV1TranscodeProfile {
+    /// Outcome of the test encode ran when the profile was created.
+    available: Result<(), NightfallError>,
+}
+
+impl AV1TranscodeProfile {
+    pub fn new(ffmpeg_bin: &str) -> Self {
+        Self {
+            available: probe_encoder(ffmpeg_bin, "libsvtav1", &[], None),
+        }
+    }
+
+    /// Returns the height of the output, falling back to 1080p when the stream isnt scaled.
+    fn output_height(ctx: &ProfileContext) -> i64 {
+        ctx.output_ctx.height.filter(|x| *x > 0).unwrap_or(1080)
+    }
+
+    /// Maps the output height to a SVT-AV1 preset, higher is faster.
+    fn preset(ctx: &ProfileContext) -> u32 {
+        match Self::output_height(ctx) {
+            h if h > 1440 => 12,
+            h if h > 720 => 11,
+            _ => 10,
+        }
+    }
+
+    /// Maps the output height to a crf, higher resolutions tolerate a higher crf.
+    fn crf(ctx: &ProfileContext) -> u32 {
+        match Self::output_height(ctx) {
+            h if h > 1440 => 35,
+            h if h > 720 => 32,
+            _ => 30,
+        }
     }
 }
 
-pub(super) fn get_discont_flags(ctx: &ProfileContext) -> Vec<String> {
-    // these args are needed if we start a new stream in the middle of a old one, such as when
-    // seeking. These args will reset the base decode ts to equal the earliest presentation
-    // timestamp.
-    if ctx.output_ctx.start_num > 0 {
-        vec![
-            "-hls_segment_options".into(),
-            "movflags=frag_custom+dash+delay_moov+frag_discont".into(),
-        ]
-    } else {
-        vec![
-            "-hls_segment_options".into(),
-            "movflags=frag_custom+dash+delay_moov".into(),
-        ]
+impl TranscodingProfile for AV1TranscodeProfile {
+    fn profile_type(&self) -> ProfileType {
+        ProfileType::Transcode
+    }
+
+    fn stream_type(&self) -> StreamType {
+        StreamType::Video
+    }
+
+    fn name(&self) -> &str {
+        "AV1TranscodeProfile"
+    }
+
+    fn tonemaps(&self) -> bool {
+        true
+    }
+
+    fn is_enabled(&self) -> Result<(), NightfallError> {
+        self.available.clone()
+    }
+
+    fn build(&self, ctx: ProfileContext) -> Option<Vec<String>> {
+        let start_num = ctx.output_ctx.start_num.to_string();
+        let stream = format!("0:{}", ctx.input_ctx.stream);
+        let init_seg = format!("{}_init.mp4", &start_num);
+        let seg_name = format!("{}/%d.m4s", ctx.output_ctx.outdir);
+        let outdir = format!("{}/playlist.m3u8", ctx.output_ctx.outdir);
+
+        let mut args = vec![
+            "-y".into(),
+            "-ss".into(),
+            ctx.input_seek().to_string(),
+            "-i".into(),
+            ctx.file.clone(),
+            "-copyts".into(),
+            "-map".into(),
+            stream,
+            "-c:0".into(),
+            "libsvtav1".into(),
+            "-preset".into(),
+            Self::preset(&ctx).to_string(),
+        ];
+
+        args.append(&mut get_output_seek_flags(&ctx));
+        args.append(&mut get_thread_flags(&ctx, "libsvtav1"));
+
+        let mut vfilter = get_deinterlace_filter(&ctx)
+            .into_iter()
+            .chain(get_tonemap_filter(&ctx))
+            .collect::<Vec<_>>();
+
+        vfilter.extend(get_scale_filter("scale", &ctx));
+
+        if !vfilter.is_empty() {
+            args.append(&mut vec!["-vf".into(), vfilter.join(",")]);
+        }
+
+        if let Some(bitrate) = ctx.output_ctx.bitrate {
+            args.push("-b:v".into());
+            args.push(bitrate.to_string());
+        } else {
+            args.push("-crf".into());
+            args.push(Self::crf(&ctx).to_string());
+        }
+
+        args.append(&mut get_fps_flags(&ctx));
+
+        // SVT-AV1 ignores `-force_key_frames`, so keyframes are placed through a closed gop of a
+        // fixed size instead.
+        args.append(&mut vec![
+            "-fps_mode".into(),
+            get_fps_mode(&ctx),
+            "-avoid_negative_ts".into(),
+            "make_non_negative".into(),
+            "-max_muxing_queue_size".into(),
+            "2048".into(),
+            "-g".into(),
+            get_gop_size(&ctx),
+            "-svtav1-params".into(),
+            "scd=0:irefresh-type=2".into(),
+        ]);
+
+        args.append(&mut get_metadata_flags(&ctx, self.profile_type()));
+
+        if ctx.output_ctx.single_file {
+            args.append(&mut get_single_file_flags(&ctx));
+            return Some(args);
+        }
+
+        args.append(&mut vec![
+            "-f".into(),
+            "hls".into(),
+            "-start_number".into(),
+            start_num,
+        ]);
+
+        args.append(&mut get_discont_flags(&ctx));
+
+        // needed so that in progress segments are named `tmp` and then renamed after the data is
+        // on disk.
+        // This in theory practically prevents the web server from returning a segment that is
+        // in progress.
+        args.append(&mut vec![
+            "-hls_flags".into(),
+            "temp_file+append_list".into(),
+            "-max_delay".into(),
+            "5000000".into(),
+        ]);
+
+        // args needed so we can distinguish between init fragments for new streams.
+        // Basically on the web seeking works by reloading the entire video because of
+        // discontinuity issues that browsers seem to not ignore like mpv.
+        args.append(&mut vec!["-hls_fmp4_init_filename".into(), init_seg]);
+        args.append(&mut vec![
+            "-hls_time".into(),
+            ctx.output_ctx.target_gop.to_string(),
+        ]);
+
+        args.append(&mut vec!["-hls_segment_type".into(), "fmp4".into()]);
+        args.append(&mut vec![
+            "-loglevel".into(),
+            "info".into(),
+            "-progress".into(),
+            "pipe:1".into(),
+        ]);
+        args.append(&mut vec!["-hls_segment_filename".into(), seg_name]);
+        args.push(outdir);
+
+        Some(args)
+    }
+
+    fn supports(&self, ctx: &ProfileContext) -> Result<(), NightfallError> {
+        if ctx.output_ctx.codec == "av1" {
+            return Ok(());
+        }
+
+        Err(NightfallError::ProfileNotSupported(format!(
+            "Got output codec {} but profile only supports `av1`.",
+            ctx.output_ctx.codec
+        )))
+    }
+
+    fn encoder(&self) -> Option<&str> {
+        Some("libsvtav1")
+    }
+
+    fn tag(&self) -> &str {
+        "av1"
+    }
+}
+
+#[derive(Debug)]
+pub struct RawVideoTranscodeProfile;
+
+impl TranscodingProfile for RawVideoTranscodeProfile {
+    fn profile_type(&self) -> ProfileType {
+        ProfileType::Transcode
+    }
+
+    fn stream_type(&self) -> StreamType {
+        StreamType::Video
+    }
+
+    fn name(&self) -> &str {
+        "RawVideoTranscodeProfile"
+    }
+
+    fn build(&self, ctx: ProfileContext) -> Option<Vec<String>> {
+        let mut args = vec!["-y".into()];
+
+        if let Some(seek) = ctx.input_ctx.seek {
+            let flag = if seek.is_positive() {
+                "-ss".into()
+            } else {
+                "-sseof".into()
+            };
+
+            args.push(flag);
+            args.push(seek.to_string());
+        }
+
+        if let Some(max_to_transcode) = ctx.output_ctx.max_to_transcode {
+            args.push("-t".into());
+            args.push(max_to_transcode.to_string());
+        }
+
+        args.append(&mut vec![
+            "-map".into(),
+            format!("0:{}", ctx.input_ctx.stream),
+        ]);
+        args.append(&mut vec!["-c:v".into(), "rawvideo".into()]);
+        args.append(&mut vec![
+            "-flags2".into(),
+            "-pix_fmt".into(),
+            "rgb24".into(),
+        ]);
+        args.append(&mut vec!["-preset".into(), "ultrafast".into()]);
+
+        if let Some(scale) = get_scale_filter("scale", &ctx) {
+            args.append(&mut vec!["-vf".into(), scale]);
+        }
+
+        args.append(&mut vec!["-f".into(), "data".into(), "-".into()]);
+
+        Some(args)
+    }
+
+    fn supports(&self, ctx: &ProfileContext) -> Result<(), NightfallError> {
+        if ctx.output_ctx.codec == "rawvideo" {
+            return Ok(());
+        }
+
+        Err(NightfallError::ProfileNotSupported(format!(
+            "Codec {} is not supported.",
+            ctx.output_ctx.codec
+        )))
+    }
+
+    fn encoder(&self) -> Option<&str> {
+        Some("rawvideo")
+    }
+
+    fn tag(&self) -> &str {
+        "rawvideo"
+    }
+
+    fn is_stdio_stream(&self) -> bool {
+        true
+    }
+}
+
+/// Profile transcoding video to VP9 in WebM chunks for clients that prefer WebM over fMP4.
+#[derive(Debug)]
+pub struct Vp9TranscodeProfile;
+
+impl TranscodingProfile for Vp9TranscodeProfile {
+    fn profile_type(&self) -> ProfileType {
+        ProfileType::Transcode
+    }
+
+    fn stream_type(&self) -> StreamType {
+        StreamType::Video
+    }
+
+    fn name(&self) -> &str {
+        "Vp9TranscodeProfile"
+    }
+
+    fn tonemaps(&self) -> bool {
+        true
+    }
+
+    fn build(&self, ctx: ProfileContext) -> Option<Vec<String>> {
+        let stream = format!("0:{}", ctx.input_ctx.stream);
+
+        let mut args = vec![
+            "-y".into(),
+            "-ss".into(),
+            ctx.input_seek().to_string(),
+            "-i".into(),
+            ctx.file.clone(),
+            "-copyts".into(),
+            "-map".into(),
+            stream,
+            "-c:0".into(),
+            "libvpx-vp9".into(),
+            "-deadline".into(),
+            "realtime".into(),
+            "-cpu-used".into(),
+            "8".into(),
+            "-row-mt".into(),
+            "1".into(),
+        ];
+
+        args.append(&mut get_output_seek_flags(&ctx));
+        args.append(&mut get_thread_flags(&ctx, "libvpx-vp9"));
+
+        let mut vfilter = get_deinterlace_filter(&ctx)
+            .into_iter()
+            .chain(get_tonemap_filter(&ctx))
+            .collect::<Vec<_>>();
+
+        vfilter.extend(get_scale_filter("scale", &ctx));
+
+        if !vfilter.is_empty() {
+            args.append(&mut vec!["-vf".into(), vfilter.join(",")]);
+        }
+
+        if let Some(bitrate) = ctx.output_ctx.bitrate {
+            args.push("-b:v".into());
+            args.push(bitrate.to_string());
+        }
+
+        args.append(&mut get_fps_flags(&ctx));
+
+        args.append(&mut vec![
+            "-fps_mode".into(),
+            get_fps_mode(&ctx),
+            "-avoid_negative_ts".into(),
+            "make_non_negative".into(),
+            "-max_muxing_queue_size".into(),
+            "2048".into(),
+        ]);
+
+        args.append(&mut get_metadata_flags(&ctx, self.profile_type()));
+
+        args.append(&mut get_webm_chunk_flags(&ctx));
+
+        Some(args)
+    }
+
+    fn supports(&self, ctx: &ProfileContext) -> Result<(), NightfallError> {
+        if ctx.output_ctx.codec == "vp9" {
+            return Ok(());
+        }
+
+        Err(NightfallError::ProfileNotSupported(format!(
+            "Got output codec {} but profile only supports `vp9`.",
+            ctx.output_ctx.codec
+        )))
+    }
+
+    fn encoder(&self) -> Option<&str> {
+        Some("libvpx-vp9")
+    }
+
+    fn tag(&self) -> &str {
+        "vp9"
+    }
+
+    fn container(&self) -> Container {
+        Container::WebM
+    }
+}
+
+/// Profile producing an I-frame only, 1 fps representation of a video stream. This is meant to be
+/// used as a DASH trick-mode track, thus segments are numbered and timed exactly like the main
+/// representation so that both share a timeline.
+#[derive(Debug)]
+pub struct TrickplayTranscodeProfile;
+
+impl TranscodingProfile for TrickplayTranscodeProfile {
+    fn profile_type(&self) -> ProfileType {
+        ProfileType::Transcode
+    }
+
+    fn stream_type(&self) -> StreamType {
+        StreamType::Video
+    }
+
+    fn name(&self) -> &str {
+        "TrickplayTranscodeProfile"
+    }
+
+    fn build(&self, ctx: ProfileContext) -> Option<Vec<String>> {
+        let start_num = ctx.output_ctx.start_num.to_string();
+        let stream = format!("0:{}", ctx.input_ctx.stream);
+        let init_seg = format!("{}_init.mp4", &start_num);
+        let seg_name = format!("{}/%d.m4s", ctx.output_ctx.outdir);
+        let outdir = format!("{}/playlist.m3u8", ctx.output_ctx.outdir);
+
+        let mut args = vec![
+            "-y".into(),
+            "-skip_frame".into(),
+            "nokey".into(),
+            "-ss".into(),
+            (ctx.output_ctx.start_num * ctx.output_ctx.target_gop).to_string(),
+            "-i".into(),
+            ctx.file.clone(),
+            "-copyts".into(),
+            "-map".into(),
+            stream,
+            "-c:0".into(),
+            "libx264".into(),
+            "-preset".into(),
+            "veryfast".into(),
+        ];
+
+        args.append(&mut get_thread_flags(&ctx, "libx264"));
+
+        // Every output frame is a keyframe so that players can display any of them on their own.
+        let mut vfilter = vec!["fps=1".to_string()];
+        vfilter.extend(get_scale_filter("scale", &ctx));
+
+        args.append(&mut vec![
+            "-vf".into(),
+            vfilter.join(","),
+            "-g".into(),
+            "1".into(),
+            "-bf".into(),
+            "0".into(),
+        ]);
+
+        if let Some(bitrate) = ctx.output_ctx.bitrate {
+            args.push("-b:v".into());
+            args.push(bitrate.to_string());
+        }
+
+        args.append(&mut vec![
+            "-avoid_negative_ts".into(),
+            "make_non_negative".into(),
+            "-max_muxing_queue_size".into(),
+            "2048".into(),
+        ]);
+
+        args.append(&mut get_metadata_flags(&ctx, self.profile_type()));
+
+        args.append(&mut vec![
+            "-f".into(),
+            "hls".into(),
+            "-start_number".into(),
+            start_num,
+        ]);
+
+        args.append(&mut get_discont_flags(&ctx));
+
+        // needed so that in progress segments are named `tmp` and then renamed after the data is
+        // on disk.
+        // This in theory practically prevents the web server from returning a segment that is
+        // in progress.
+        args.append(&mut vec![
+            "-hls_flags".into(),
+            "temp_file+append_list".into(),
+            "-max_delay".into(),
+            "5000000".into(),
+        ]);
+
+        args.append(&mut vec!["-hls_fmp4_init_filename".into(), init_seg]);
+        args.append(&mut vec![
+            "-hls_time".into(),
+            ctx.output_ctx.target_gop.to_string(),
+        ]);
+
+        args.append(&mut vec!["-hls_segment_type".into(), "fmp4".into()]);
+        args.append(&mut vec![
+            "-loglevel".into(),
+            "info".into(),
+            "-progress".into(),
+            "pipe:1".into(),
+        ]);
+        args.append(&mut vec!["-hls_segment_filename".into(), seg_name]);
+        args.push(outdir);
+
+        Some(args)
+    }
+
+    fn supports(&self, ctx: &ProfileContext) -> Result<(), NightfallError> {
+        if ctx.output_ctx.codec == "trickplay" {
+            return Ok(());
+        }
+
+        Err(NightfallError::ProfileNotSupported(format!(
+            "Codec {} is not supported.",
+            ctx.output_ctx.codec
+        )))
+    }
+
+    fn encoder(&self) -> Option<&str> {
+        Some("libx264")
+    }
+
+    fn tag(&self) -> &str {
+        "trickplay"
+    }
+
+    fn output_fps(&self) -> Option<f64> {
+        Some(1.0)
+    }
+}
+
+/// Returns the flags needed to write WebM chunks through the `webm_chunk` muxer. Every cluster
+/// (started on the forced keyframes) ends up in its own chunk, numbered like our fMP4 segments.
+pub(super) fn get_webm_chunk_flags(ctx: &ProfileContext) -> Vec<String> {
+    let start_num = ctx.output_ctx.start_num.to_string();
+
+    vec![
+        "-force_key_frames".into(),
+        format!("expr:gte(t,n_forced*{})", ctx.output_ctx.target_gop),
+        "-cluster_time_limit".into(),
+        (ctx.output_ctx.target_gop * 1000).to_string(),
+        "-cluster_size_limit".into(),
+        "1073741824".into(),
+        "-f".into(),
+        "webm_chunk".into(),
+        "-header".into(),
+        format!("{}/{}_init.webm", ctx.output_ctx.outdir, start_num),
+        "-chunk_start_index".into(),
+        start_num,
+        "-loglevel".into(),
+        "info".into(),
+        "-progress".into(),
+        "pipe:1".into(),
+        format!("{}/%d.webm", ctx.output_ctx.outdir),
+    ]
+}
+
+/// Returns the flags limiting the threads of the software `encoder` to `ProfileContext::threads`.
+/// libx264 sizes its own thread pool, thus it gets the limit through `-x264-params` as well.
+/// libx265 takes it as `pools` in its `-x265-params`, see `HevcTranscodeProfile`.
+pub(super) fn get_thread_flags(ctx: &ProfileContext, encoder: &str) -> Vec<String> {
+    let threads = match ctx.threads {
+        Some(x) => x.to_string(),
+        None => return Vec::new(),
+    };
+
+    let mut args = vec!["-threads".into(), threads.clone()];
+
+    if encoder == "libx264" {
+        args.append(&mut vec![
+            "-x264-params".into(),
+            format!("threads={}", threads),
+        ]);
+    }
+
+    args
+}
+
+/// Returns the output side seek of `OutputCtx::accurate_seek`, placed after `-i` so that the
+/// frames decoded between the keyframe the input seeked to and the chunk boundary get dropped.
+pub(super) fn get_output_seek_flags(ctx: &ProfileContext) -> Vec<String> {
+    if !ctx.output_ctx.accurate_seek {
+        return Vec::new();
+    }
+
+    vec![
+        "-ss".into(),
+        (ctx.output_ctx.start_num * ctx.output_ctx.target_gop).to_string(),
+    ]
+}
+
+/// Returns the flags needed to pin the output frame rate, if the context asks for one.
+pub(super) fn get_fps_flags(ctx: &ProfileContext) -> Vec<String> {
+    if let Some(fps) = ctx.output_ctx.fps {
+        vec!["-r".into(), fps.to_string()]
+    } else {
+        Vec::new()
+    }
+}
+
+/// Returns the frame rate mode to use. Timestamps are passed through untouched unless the output
+/// frame rate is pinned, in which case frames get duplicated or dropped to hit it.
+pub(super) fn get_fps_mode(ctx: &ProfileContext) -> String {
+    if ctx.output_ctx.fps.is_some() {
+        "cfr".into()
+    } else {
+        "passthrough".into()
+    }
+}
+
+/// Returns the amount of frames in a GOP. When the output frame rate is pinned the GOP spans
+/// exactly one segment, otherwise we assume 24 fps and 5s segments.
+pub(super) fn get_gop_size(ctx: &ProfileContext) -> String {
+    ctx.output_ctx
+        .fps
+        .map(|fps| ((fps * ctx.output_ctx.target_gop as f32).round() as u32).max(1))
+        .unwrap_or(120)
+        .to_string()
+}
+
+/// Returns the args needed to write the whole stream into a single fragmented mp4, fragmented
+/// every `target_gop` seconds. The file gets indexed with `patch::sidx::index_single_file` once
+/// ffmpeg is done.
+pub(super) fn get_single_file_flags(ctx: &ProfileContext) -> Vec<String> {
+    vec![
+        "-force_key_frames".into(),
+        format!("expr:gte(t,n_forced*{})", ctx.output_ctx.target_gop),
+        "-f".into(),
+        "mp4".into(),
+        "-movflags".into(),
+        "frag_keyframe+empty_moov+default_base_moof".into(),
+        "-frag_duration".into(),
+        (ctx.output_ctx.target_gop as u64 * 1_000_000).to_string(),
+        "-loglevel".into(),
+        "info".into(),
+        "-progress".into(),
+        "pipe:1".into(),
+        ctx.output_ctx.single_file_path(),
+    ]
+}
+
+/// Function returns the flags setting the container level metadata of the output. Transmuxed
+/// outputs keep the metadata of the source, with the keys of `ProfileContext::metadata` taking
+/// precedence. Keys are emitted in order so that the args are stable.
+pub(super) fn get_metadata_flags(ctx: &ProfileContext, profile_type: ProfileType) -> Vec<String> {
+    let mut args = Vec::new();
+
+    if profile_type == ProfileType::Transmux {
+        args.append(&mut vec!["-map_metadata".into(), "0".into()]);
+    }
+
+    let mut metadata = ctx.metadata.iter().collect::<Vec<_>>();
+    metadata.sort();
+
+    for (key, value) in metadata {
+        args.push("-metadata".into());
+        args.push(format!("{}={}", key, value));
+    }
+
+    args
+}
+
+/// Function returns the x265 params signaling the HDR format of the input, along with its
+/// mastering display and content light level metadata when ffprobe found them.
+fn get_hdr_x265_params(ctx: &ProfileContext) -> Vec<String> {
+    let transfer = ctx.input_ctx.color_transfer.clone().unwrap_or_default();
+    let mut params = vec![
+        format!(
+            "colorprim={}",
+            ctx.input_ctx.color_primaries.as_deref().unwrap_or("bt2020")
+        ),
+        format!("transfer={}", transfer),
+        format!(
+            "colormatrix={}",
+            ctx.input_ctx.color_space.as_deref().unwrap_or("bt2020nc")
+        ),
+        "repeat-headers=1".into(),
+    ];
+
+    // HLG doesnt carry static metadata.
+    if transfer != "smpte2084" {
+        return params;
+    }
+
+    params.push("hdr10=1".into());
+
+    let side_data = ctx.input_ctx.side_data_list.as_deref().unwrap_or_default();
+
+    // x265 wants chromaticities in units of 0.00002 and luminances in units of 0.0001 cd/m2.
+    let master_display = side_data
+        .iter()
+        .find(|x| x.is_mastering_display())
+        .and_then(|x| {
+            let chroma =
+                |v: &Option<String>| Some((parse_rational(v.as_deref()?)? * 50000.0).round());
+            let luma =
+                |v: &Option<String>| Some((parse_rational(v.as_deref()?)? * 10000.0).round());
+
+            Some(format!(
+                "master-display=G({},{})B({},{})R({},{})WP({},{})L({},{})",
+                chroma(&x.green_x)?,
+                chroma(&x.green_y)?,
+                chroma(&x.blue_x)?,
+                chroma(&x.blue_y)?,
+                chroma(&x.red_x)?,
+                chroma(&x.red_y)?,
+                chroma(&x.white_point_x)?,
+                chroma(&x.white_point_y)?,
+                luma(&x.max_luminance)?,
+                luma(&x.min_luminance)?,
+            ))
+        });
+
+    params.extend(master_display);
+
+    let content_light = side_data
+        .iter()
+        .find(|x| x.is_content_light_level())
+        .and_then(|x| Some(format!("max-cll={},{}", x.max_content?, x.max_average?)));
+
+    params.extend(content_light);
+
+    params
+}
+
+/// Parses a rational as printed by ffprobe, ex. `34000/50000`.
+fn parse_rational(value: &str) -> Option<f64> {
+    match value.split_once('/') {
+        Some((num, den)) => {
+            let den = den.parse::<f64>().ok().filter(|x| *x != 0.0)?;
+            Some(num.parse::<f64>().ok()? / den)
+        }
+        None => value.parse().ok(),
+    }
+}
+
+/// Returns the filter deinterlacing the input, if its frames are interlaced or telecined. Telecined
+/// frames get their fields matched back into progressive frames first, leaving only the frames
+/// which dont match for `yadif`, then the duplicate frame of every cycle of 5 is dropped to get
+/// back to the film rate.
+pub(super) fn get_deinterlace_filter(ctx: &ProfileContext) -> Option<String> {
+    let filter = match ctx.input_ctx.field_order? {
+        FieldOrder::Progressive => return None,
+        FieldOrder::TopFirst => "yadif=parity=tff",
+        FieldOrder::BottomFirst => "yadif=parity=bff",
+        FieldOrder::Telecined => "fieldmatch,yadif=deint=interlaced,decimate",
+    };
+
+    Some(filter.into())
+}
+
+/// Function returns the filter chain tone mapping HDR10 and HLG video to bt709 SDR, if the session
+/// asks for it with `OutputCtx::tonemap` and the input is HDR. The chain has to run before any
+/// scaling or overlays so that those work on SDR frames.
+pub(super) fn get_tonemap_filter(ctx: &ProfileContext) -> Option<String> {
+    if !ctx.needs_tonemap() {
+        return None;
+    }
+
+    let algorithm = ctx.output_ctx.tonemap.unwrap_or_default();
+
+    Some(format!(
+        "zscale=t=linear:npl=100,format=gbrpf32le,zscale=p=bt709,tonemap=tonemap={}:desat=0,zscale=t=bt709:m=bt709:r=tv,format=yuv420p",
+        algorithm.name()
+    ))
+}
+
+/// Returns `filter` scaling the video down to the output size of the session, if it asks for one.
+/// Without an explicit width the aspect ratio is kept, rounded to an even number of pixels as the
+/// encoders require. The scale filters all take the width first, so the dimensions are passed by
+/// name to keep the hardware variants from getting them swapped.
+pub(super) fn get_scale_filter(filter: &str, ctx: &ProfileContext) -> Option<String> {
+    let height = ctx.output_ctx.height?;
+    let width = ctx.output_ctx.width.unwrap_or(-2);
+
+    Some(format!("{}=w={}:h={}", filter, width, height))
+}
+
+pub(super) fn get_discont_flags(ctx: &ProfileContext) -> Vec<String> {
+    let mut movflags = ctx
+        .movflags
+        .clone()
+        .unwrap_or_else(|| "frag_custom+dash+delay_moov".into());
+
+    // these args are needed if we start a new stream in the middle of a old one, such as when
+    // seeking. These args will reset the base decode ts to equal the earliest presentation
+    // timestamp.
+    if ctx.output_ctx.start_num > 0 && !movflags.split('+').any(|x| x == "frag_discont") {
+        movflags.push_str("+frag_discont");
+    }
+
+    let mut options = format!("movflags={}", movflags);
+
+    if let Some(timescale) = ctx.target_timescale {
+        options.push_str(&format!(":video_track_timescale={}", timescale));
+    }
+
+    // the mp4 muxer flushes a new fragment, ie. a part, every `frag_duration` microseconds.
+    if let Some(part) = ctx.output_ctx.part_duration {
+        options.push_str(&format!(":frag_duration={}", (part * 1_000_000.0) as u64));
+    }
+
+    vec!["-hls_segment_options".into(), options]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn telecined_input_is_decimated_back_to_the_film_rate() {
+        let mut ctx = ProfileContext::default();
+        assert_eq!(get_deinterlace_filter(&ctx), None);
+
+        ctx.input_ctx.field_order = Some(FieldOrder::TopFirst);
+        assert_eq!(get_deinterlace_filter(&ctx).unwrap(), "yadif=parity=tff");
+
+        ctx.input_ctx.field_order = Some(FieldOrder::Telecined);
+        assert_eq!(
+            get_deinterlace_filter(&ctx).unwrap(),
+            "fieldmatch,yadif=deint=interlaced,decimate"
+        );
+    }
+
+    #[test]
+    fn scale_filter_takes_the_width_first() {
+        let mut ctx = ProfileContext::default();
+        assert_eq!(get_scale_filter("scale", &ctx), None);
+
+        ctx.output_ctx.height = Some(720);
+        assert_eq!(get_scale_filter("scale", &ctx).unwrap(), "scale=w=-2:h=720");
+
+        ctx.output_ctx.width = Some(1280);
+        assert_eq!(
+            get_scale_filter("scale_cuda", &ctx).unwrap(),
+            "scale_cuda=w=1280:h=720"
+        );
+    }
+
+    #[test]
+    fn target_timescale_is_passed_to_the_muxer() {
+        let mut ctx = ProfileContext {
+            target_timescale: Some(90000),
+            ..Default::default()
+        };
+
+        assert_eq!(
+            get_discont_flags(&ctx),
+            vec![
+                "-hls_segment_options".to_string(),
+                "movflags=frag_custom+dash+delay_moov:video_track_timescale=90000".to_string(),
+            ]
+        );
+
+        ctx.target_timescale = None;
+        assert_eq!(
+            get_discont_flags(&ctx)[1],
+            "movflags=frag_custom+dash+delay_moov"
+        );
     }
 }
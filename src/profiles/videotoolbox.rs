@@ -0,0 +1,270 @@
+use super::hwaccel::probe_encoder;
+use super::video::get_scale_filter;
+use super::ProfileContext;
+use super::ProfileType;
+use super::StreamType;
+use super::TranscodingProfile;
+
+use crate::NightfallError;
+
+/// Codecs the VideoToolbox encoders can produce.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum VideoToolboxCodec {
+    H264,
+    Hevc,
+}
+
+impl VideoToolboxCodec {
+    fn encoder(&self) -> &'static str {
+        match self {
+            Self::H264 => "h264_videotoolbox",
+            Self::Hevc => "hevc_videotoolbox",
+        }
+    }
+
+    /// Returns the value of `OutputCtx::codec` this encoder produces.
+    fn codec(&self) -> &'static str {
+        match self {
+            Self::H264 => "h264",
+            Self::Hevc => "hevc",
+        }
+    }
+
+    /// Bits per pixel used to pick a bitrate when the session doesnt set one. These roughly
+    /// match the quality of x264 at its default crf.
+    fn bits_per_pixel(&self) -> f64 {
+        match self {
+            Self::H264 => 0.1,
+            Self::Hevc => 0.07,
+        }
+    }
+}
+
+/// Apple VideoToolbox transcoding profiles, only available on macOS.
+/// One profile exists per `VideoToolboxCodec`, each is only enabled if ffmpeg manages to encode a
+/// test frame with its encoder.
+///
+/// Unlike x264, VideoToolbox has no notion of a crf or of presets and without a target bitrate
+/// it picks a very low one. Because of that these profiles always pass `-b:v`, falling back to
+/// the bitrate of the input, or to an estimate based on the output resolution when the output is
+/// scaled. Constant quality (`-q:v`) is left out as it is only supported by Apple Silicon.
+#[derive(Debug)]
+pub struct VideoToolboxTranscodeProfile {
+    codec: VideoToolboxCodec,
+    /// Outcome of the test encode ran when the profile was created.
+    available: Result<(), NightfallError>,
+}
+
+impl VideoToolboxTranscodeProfile {
+    pub fn new(ffmpeg_bin: &str, codec: VideoToolboxCodec) -> Self {
+        Self {
+            codec,
+            available: probe_encoder(ffmpeg_bin, codec.encoder(), &[], Some("format=nv12")),
+        }
+    }
+
+    /// Returns the bitrate to encode at, see the docs of the struct.
+    fn bitrate(&self, ctx: &ProfileContext) -> u64 {
+        if let Some(bitrate) = ctx.output_ctx.bitrate {
+            return bitrate;
+        }
+
+        if ctx.output_ctx.height.is_none() && ctx.input_ctx.bitrate != 0 {
+            return ctx.input_ctx.bitrate;
+        }
+
+        let height = ctx.output_ctx.height.filter(|x| *x > 0).unwrap_or(1080) as f64;
+        let width = ctx
+            .output_ctx
+            .width
+            .filter(|x| *x > 0)
+            .map(|x| x as f64)
+            .unwrap_or(height * 16.0 / 9.0);
+        let fps = ctx
+            .output_ctx
+            .fps
+            .map(|x| x as f64)
+            .filter(|x| *x > 0.0)
+            .or(Some(ctx.input_ctx.fps).filter(|x| *x > 0.0))
+            .unwrap_or(24.0);
+
+        (width * height * fps * self.codec.bits_per_pixel()) as u64
+    }
+}
+
+impl TranscodingProfile for VideoToolboxTranscodeProfile {
+    fn profile_type(&self) -> ProfileType {
+        ProfileType::HardwareTranscode
+    }
+
+    fn stream_type(&self) -> StreamType {
+        StreamType::Video
+    }
+
+    fn name(&self) -> &str {
+        match self.codec {
+            VideoToolboxCodec::H264 => "VideoToolboxTranscodeProfile",
+            VideoToolboxCodec::Hevc => "VideoToolboxHevcTranscodeProfile",
+        }
+    }
+
+    fn is_enabled(&self) -> Result<(), NightfallError> {
+        self.available.clone()
+    }
+
+    fn build(&self, ctx: ProfileContext) -> Option<Vec<String>> {
+        let start_num = ctx.output_ctx.start_num.to_string();
+        let stream = format!("0:{}", ctx.input_ctx.stream);
+        let init_seg = format!("{}_init.mp4", &start_num);
+        let seg_name = format!("{}/%d.m4s", ctx.output_ctx.outdir);
+        let outdir = format!("{}/playlist.m3u8", ctx.output_ctx.outdir);
+        let bitrate = self.bitrate(&ctx);
+
+        let mut args = vec![
+            "-hwaccel".into(),
+            "videotoolbox".into(),
+            "-y".into(),
+            "-ss".into(),
+            ctx.input_seek().to_string(),
+            "-i".into(),
+            ctx.file.clone(),
+            "-copyts".into(),
+            "-map".into(),
+            stream,
+            "-c:0".into(),
+            self.codec.encoder().into(),
+            // never fall back to the software encoder of VideoToolbox, x264 does a better job.
+            "-allow_sw".into(),
+            "0".into(),
+            "-realtime".into(),
+            "1".into(),
+            "-bf".into(),
+            "0".into(),
+        ];
+
+        args.append(&mut super::video::get_output_seek_flags(&ctx));
+
+        // Apple players only accept hevc tagged as `hvc1`.
+        if self.codec == VideoToolboxCodec::Hevc {
+            args.append(&mut vec!["-tag:0".into(), "hvc1".into()]);
+        }
+
+        let mut vfilter = Vec::new();
+
+        vfilter.extend(get_scale_filter("scale", &ctx));
+
+        // The h264 encoder only takes 8 bit frames, 10 bit sources have to be converted first.
+        if self.codec == VideoToolboxCodec::H264 {
+            vfilter.push("format=nv12".into());
+        }
+
+        if !vfilter.is_empty() {
+            args.append(&mut vec!["-vf".into(), vfilter.join(",")]);
+        }
+
+        // VideoToolbox treats `-b:v` as an average, the peak has to be capped separately.
+        args.append(&mut vec![
+            "-b:v".into(),
+            bitrate.to_string(),
+            "-maxrate".into(),
+            (bitrate * 3 / 2).to_string(),
+            "-bufsize".into(),
+            (bitrate * 2).to_string(),
+        ]);
+
+        args.append(&mut super::video::get_fps_flags(&ctx));
+
+        args.append(&mut vec![
+            "-start_at_zero".into(),
+            "-vsync".into(),
+            super::video::get_fps_mode(&ctx),
+            "-avoid_negative_ts".into(),
+            "disabled".into(),
+            "-max_muxing_queue_size".into(),
+            "2048".into(),
+            "-keyint_min".into(),
+            super::video::get_gop_size(&ctx),
+            "-g".into(),
+            super::video::get_gop_size(&ctx),
+        ]);
+
+        args.append(&mut super::video::get_discont_flags(&ctx));
+
+        args.append(&mut super::video::get_metadata_flags(
+            &ctx,
+            self.profile_type(),
+        ));
+
+        args.append(&mut vec![
+            "-f".into(),
+            "hls".into(),
+            "-start_number".into(),
+            start_num,
+        ]);
+
+        // needed so that in progress segments are named `tmp` and then renamed after the data is
+        // on disk.
+        // This in theory practically prevents the web server from returning a segment that is
+        // in progress.
+        args.append(&mut vec![
+            "-hls_flags".into(),
+            "temp_file+append_list".into(),
+            "-max_delay".into(),
+            "5000000".into(),
+        ]);
+
+        // args needed so we can distinguish between init fragments for new streams.
+        // Basically on the web seeking works by reloading the entire video because of
+        // discontinuity issues that browsers seem to not ignore like mpv.
+        args.append(&mut vec!["-hls_fmp4_init_filename".into(), init_seg]);
+
+        args.append(&mut vec![
+            "-hls_time".into(),
+            ctx.output_ctx.target_gop.to_string(),
+        ]);
+
+        args.append(&mut vec![
+            "-force_key_frames".into(),
+            format!("expr:gte(t,n_forced*{})", ctx.output_ctx.target_gop),
+        ]);
+
+        args.append(&mut vec!["-hls_segment_type".into(), "fmp4".into()]);
+        args.append(&mut vec![
+            "-loglevel".into(),
+            "info".into(),
+            "-progress".into(),
+            "pipe:1".into(),
+        ]);
+        args.append(&mut vec!["-hls_segment_filename".into(), seg_name]);
+        args.push(outdir);
+
+        Some(args)
+    }
+
+    fn supports(&self, ctx: &ProfileContext) -> Result<(), NightfallError> {
+        if ctx.output_ctx.codec == self.codec.codec() {
+            return Ok(());
+        }
+
+        Err(NightfallError::ProfileNotSupported(format!(
+            "Got output codec {} but profile only supports `{}`.",
+            ctx.output_ctx.codec,
+            self.codec.codec()
+        )))
+    }
+
+    fn encoder(&self) -> Option<&str> {
+        Some(self.codec.encoder())
+    }
+
+    fn hwaccel(&self) -> Option<&str> {
+        Some("videotoolbox")
+    }
+
+    fn tag(&self) -> &str {
+        match self.codec {
+            VideoToolboxCodec::H264 => "h264_videotoolbox",
+            VideoToolboxCodec::Hevc => "hevc_videotoolbox",
+        }
+    }
+}
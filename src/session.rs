@@ -1,38 +1,82 @@
+use crate::encryption::encrypt_chunk;
+use crate::encryption::SegmentKey;
+use crate::encryption::KEY_URI;
+use crate::error::NightfallError;
+use crate::ffprobe::Tags;
+use crate::hls::codecs_string;
+use crate::metrics::Progress;
+use crate::metrics::SessionStats;
+use crate::patch::cenc::protect_chunk;
+use crate::patch::cenc::SampleFormat;
+use crate::patch::init_segment::init_segment_timescale;
+use crate::patch::init_segment::init_segments_compatible;
+use crate::patch::init_segment::patch_init_segment;
+use crate::patch::mpegts::ContinuityCounters;
+use crate::patch::mpegts::TS_START_OFFSET;
+use crate::patch::segment::file_fragment_ranges;
+use crate::patch::segment::patch_direct_play_segment;
+use crate::patch::segment::patch_fragment;
+use crate::patch::segment::patch_segment;
+use crate::patch::segment::repatch_segment;
+use crate::patch::sidx::SegmentIndex;
+use crate::process::Output;
+use crate::process::Process;
+use crate::process::ProcessOutput;
+use crate::process::ProcessSpawner;
+use crate::process::SpawnCommand;
+use crate::profiles::Container;
+use crate::profiles::Priority;
 use crate::profiles::ProfileContext;
+use crate::profiles::ProfileType;
 use crate::profiles::StreamType;
 use crate::profiles::TranscodingProfile;
+use crate::Completion;
+use crate::ExitReason;
+use crate::GcPolicy;
+use crate::SegmentHook;
 
+use std::collections::BTreeMap;
+use std::collections::BTreeSet;
 use std::collections::HashMap;
 use std::fmt;
+use std::fmt::Write as _;
 use std::fs;
 use std::fs::File;
 use std::io;
 use std::io::Read;
+use std::io::Seek;
+use std::io::SeekFrom;
 use std::io::Write;
 use std::path::Path;
+use std::path::PathBuf;
 use std::process::ExitStatus;
-use std::process::Stdio;
+use std::sync::atomic::AtomicU32;
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering;
 use std::sync::Arc;
 use std::sync::RwLock;
 use std::time::Duration;
 use std::time::Instant;
+use std::time::SystemTime;
+use std::time::UNIX_EPOCH;
+
+use serde_derive::{Deserialize, Serialize};
 
 use tokio::io::AsyncBufReadExt;
+use tokio::io::AsyncWriteExt;
 use tokio::io::BufReader;
-use tokio::process::Child;
-use tokio::process::ChildStdout;
-use tokio::process::Command;
+use tokio::sync::oneshot;
+use tokio::sync::watch;
 use tokio::task::JoinHandle;
 
 use tokio_stream::wrappers::LinesStream;
 use tokio_stream::StreamExt;
 
 use tracing::debug;
-
-/// Represents how many chunks we encode before we require a timeout reset.
-/// Basically if within MAX_CHUNKS_AHEAD we do not get a timeout reset we kill the stream.
-/// This can be tuned
-const MAX_CHUNKS_AHEAD: u32 = 15;
+use tracing::info_span;
+use tracing::warn;
+use tracing::Instrument;
+use tracing::Span;
 
 // FIXME: This lazy static should be removed in favour of adding a new stats field to a session and
 // sharing it between two threads at max rather than per whole lib.
@@ -43,16 +87,135 @@ lazy_static::lazy_static! {
         Arc::new(RwLock::new(HashMap::new()));
 }
 
+/// How many chunks a direct play preview process produces, see `spawn_preview`.
+const PREVIEW_CHUNKS: u32 = 3;
+
+/// Upper bound of the amount of fragments, ie. parts, a chunk is made of, see `fragment_seq`.
+const FRAGMENTS_PER_CHUNK: u32 = 256;
+
+/// Name of the file in the outdir of a session its state gets persisted to, see `persist`.
+pub const STATE_FILE: &str = "session.json";
+
+/// State of a session written to its outdir, enough to pick the session back up after the host
+/// process restarted, see `StateManager::restore_sessions`. Secrets are left out, see
+/// `ProfileContext::without_secrets`.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct PersistedSession {
+    pub id: String,
+    /// Tags of the profile chain in the order they get tried in, starting with the active profile.
+    pub chain: Vec<String>,
+    pub profile_ctx: ProfileContext,
+    /// Last chunk of the current run which is complete on disk along with every chunk before it.
+    pub last_chunk: Option<u32>,
+}
+
+/// What the state written by `Session::persist` depends on. The profile context only changes
+/// along with a restart of ffmpeg or a move of the outdir, thus comparing these is enough to tell
+/// whether the state has to be written again.
+#[derive(Clone, Debug, PartialEq, Eq)]
+struct PersistedKey {
+    runs: u64,
+    profiles: usize,
+    last_chunk: Option<u32>,
+    outdir: String,
+}
+
+/// Parses the playlist written by the hls muxer of ffmpeg, which lists the segments of a single
+/// run. Returns the index of every segment along with its offset from the start of the first one
+/// and its duration, in seconds.
+fn parse_segment_times(playlist: &str) -> Vec<(u32, f64, f64)> {
+    let mut segments = Vec::new();
+    let mut offset = 0.0;
+    let mut duration = None;
+
+    for line in playlist.lines().map(str::trim) {
+        if let Some(extinf) = line.strip_prefix("#EXTINF:") {
+            duration = extinf.split(',').next().and_then(|x| x.parse::<f64>().ok());
+            continue;
+        }
+
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let (duration, chunk) = match (duration.take(), Path::new(line).file_stem()) {
+            (Some(duration), Some(stem)) => match stem.to_str().and_then(|x| x.parse().ok()) {
+                Some(chunk) => (duration, chunk),
+                None => continue,
+            },
+            _ => continue,
+        };
+
+        segments.push((chunk, offset, duration));
+        offset += duration;
+    }
+
+    segments
+}
+
+/// Returns the paths of the `STATE_FILE`s found in `dir`, looking at most `depth` directories
+/// deep.
+pub fn find_persisted(dir: &Path, depth: usize) -> Vec<PathBuf> {
+    let entries = match fs::read_dir(dir) {
+        Ok(x) => x,
+        Err(_) => return Vec::new(),
+    };
+
+    let mut found = Vec::new();
+
+    for path in entries.filter_map(Result::ok).map(|x| x.path()) {
+        if path.is_dir() && depth > 0 {
+            found.append(&mut find_persisted(&path, depth - 1));
+        } else if path.file_name().is_some_and(|x| x == STATE_FILE) {
+            found.push(path);
+        }
+    }
+
+    found
+}
+
+/// Seek state shared by the variants of an ABR ladder, so that a variant the player switches to
+/// starts where the player is instead of at chunk 0.
+#[derive(Debug, Default)]
+pub struct LadderState {
+    /// Last chunk requested from any variant of the ladder.
+    pub position: AtomicU32,
+}
+
+/// Move of the outdir of a session running in the background, see `Session::relocate`.
+struct Relocating {
+    to: String,
+    done: watch::Receiver<Option<Result<(), NightfallError>>>,
+    /// Whether ffmpeg was running, and thus writes through a link at the old location.
+    linked: bool,
+    /// Whether ffmpeg has to be resumed once done, it was paused for the move.
+    resume: bool,
+}
+
+/// Chunks being patched on a background task before getting listed in the on-disk playlist,
+/// see `Session::update_playlist`.
+struct PlaylistPatch {
+    /// Chunks to list once done, in order. Only the ones not patched yet are patched.
+    chunks: Vec<u32>,
+    done: oneshot::Receiver<Vec<(u32, Result<u32, NightfallError>)>>,
+}
+
 pub struct Session {
     /// Id of a stream in the form of a UUID.
     pub id: String,
+    /// Span everything logged about this session is recorded under, including the requests made
+    /// to it and the tasks reading ffmpeg's output.
+    pub span: Span,
     /// Indicates whether this stream is currently being throttled or not.
     pub is_throttled: bool,
+    /// Indicates whether this stream got paused to free its slot for a higher priority one, see
+    /// `preempt`.
+    pub is_preempted: bool,
     /// A list of fallback transcoding profiles. Nightfall will start using profiles from here if
     /// the first profile fails.
-    pub profile_chain: Vec<&'static dyn TranscodingProfile>,
+    pub profile_chain: Vec<Arc<dyn TranscodingProfile>>,
     /// The current transcoding profile being used in this session.
-    pub profile: &'static dyn TranscodingProfile,
+    pub profile: Arc<dyn TranscodingProfile>,
     /// The profile context for this session. This struct contains important information like
     /// target bitrate and container.
     pub profile_ctx: ProfileContext,
@@ -62,25 +225,108 @@ pub struct Session {
     /// How many chunks have we returned so far since init.mp4 was returned.
     pub chunks_since_init: u32,
     pub chunk_size: u32,
-
+    /// The start number of the init segment we keep serving across resets, see
+    /// `reset_preserving_init`.
+    pub preserved_init: Option<u32>,
+    /// Absolute deadline after which the session gets reaped regardless of activity.
+    pub expires_at: Option<Instant>,
+    /// Chunks listed in the on-disk playlist, mapped to the start number of the ffmpeg run which
+    /// produced them, see `update_playlist`.
+    listed_chunks: BTreeMap<u32, u32>,
+    /// Chunks being patched for the on-disk playlist.
+    playlist_patch: Option<PlaylistPatch>,
+    /// The on-disk playlist as last written, it is only rewritten when it changes.
+    written_playlist: Option<String>,
+    /// Splitting of the subtitle into WebVTT chunks running in the background, see `subtitle`.
+    subtitle_split: Option<JoinHandle<()>>,
+    /// Start and duration in seconds of the chunks ffmpeg wrote, see `refresh_segment_times`.
+    segment_times: BTreeMap<u32, (f64, f64)>,
+    /// Start number of the ffmpeg run which wrote each chunk, as listed by the playlists of the
+    /// hls muxer, see `run_start`.
+    chunk_runs: BTreeMap<u32, u32>,
+    /// Modification time and length of the playlist of the hls muxer when it was last read.
+    segment_times_read: Option<(SystemTime, u64)>,
+    /// Chunks on disk which have been patched already, every chunk is patched once so that
+    /// handing it out again doesnt rewrite it, see `StateManager::chunk_request`.
+    pub patched_chunks: BTreeSet<u32>,
+    /// Chunks of the audio renditions past the first which have been patched already, see
+    /// `patch_rendition_chunk`.
+    patched_renditions: HashMap<String, BTreeSet<u32>>,
+    /// Next sequence number of the audio renditions past the first, see `patch_rendition_chunk`.
+    rendition_seqs: HashMap<String, u32>,
+    /// Overrides `StateManager::gc_policy` for this session.
+    pub gc_policy: Option<GcPolicy>,
+    /// Invoked for every chunk handed out by `chunk_request`.
+    pub on_segment: Option<Arc<SegmentHook>>,
+    /// Chunks `on_segment` has fired for.
+    pub hooked_chunks: BTreeSet<u32>,
+    /// Key chunks get encrypted with before being handed out, see `OutputCtx::encrypt`.
+    pub encryption: Option<SegmentKey>,
+    /// Index of the file written in `single_file` mode, computed once ffmpeg is done.
+    pub segment_index: Option<SegmentIndex>,
+    /// Last MPEG-TS chunk patched along with the continuity counters it left, see
+    /// `patch_ts_chunk`.
+    pub ts_continuity: Option<(u32, ContinuityCounters)>,
+    /// Seek state shared with the other variants of the ladder this session belongs to, see
+    /// `StateManager::create_ladder`.
+    pub ladder: Option<Arc<LadderState>>,
+    /// Move of the outdir in progress, see `relocate`.
+    relocating: Option<Relocating>,
+    /// Previous outdir, left as a link to the current one for ffmpeg to keep writing through
+    /// until it gets restarted, see `relocate`.
+    relocated_from: Option<String>,
+    /// Published to once the session is finished, see `completion`.
+    completion: watch::Sender<Option<ExitReason>>,
+    /// Published to on every progress report of ffmpeg, see `progress`.
+    progress: watch::Sender<Option<Progress>>,
+    /// How many "Non-monotonous DTS" warnings ffmpeg emitted over the lifetime of the session.
+    dts_warnings: Arc<AtomicU64>,
+    /// Size in bytes of the outdir, see `measure_outdir`.
+    outdir_size: Arc<AtomicU64>,
+    /// Milliseconds since the unix epoch at which ffmpeg last reported its progress, 0 if it
+    /// hasnt yet.
+    last_activity: Arc<AtomicU64>,
+    /// Chunks reported by `take_ready_chunks`, which are on disk.
+    completed_chunks: BTreeSet<u32>,
+
+    /// What the state last written by `persist` was derived from.
+    persisted: Option<PersistedKey>,
+    /// Last chunk of the current run returned by `take_ready_chunks`.
+    reported_chunk: Option<u32>,
+    /// How many times ffmpeg got started.
+    runs: u64,
     has_started: bool,
     last_chunk: u32,
-    hard_timeout: Instant,
+    /// When a chunk was last requested, idle sessions get paused and reaped according to the
+    /// `GcPolicy`.
+    last_request: Instant,
+    /// When the session finished, see `finish`. Background sessions get reaped relative to it.
+    finished_at: Option<Instant>,
+    /// Set by `set_timeout` to have the session reaped on the next `garbage_collect`.
+    timed_out: bool,
     child_pid: Option<u32>,
-    real_process: Option<Child>,
+    spawner: Arc<dyn ProcessSpawner>,
+    real_process: Option<Box<dyn Process>>,
+    /// One-off process producing a few chunks after a direct play seek, with its first chunk.
+    preview: Option<(u32, Box<dyn Process>)>,
+    /// Chunks of the current preview process which have been patched already.
+    patched_previews: BTreeSet<u32>,
 
     _process: Option<JoinHandle<()>>,
+    _stderr: Option<JoinHandle<()>>,
 }
 
 impl Session {
     pub fn new(
         id: String,
-        mut profile_chain: Vec<&'static dyn TranscodingProfile>,
+        mut profile_chain: Vec<Arc<dyn TranscodingProfile>>,
         profile_ctx: ProfileContext,
+        spawner: Arc<dyn ProcessSpawner>,
     ) -> Self {
         let profile = profile_chain.pop().expect("Profile chain is empty.");
 
         Self {
+            span: info_span!("session", id = %id),
             id,
             profile,
             profile_chain,
@@ -89,60 +335,165 @@ impl Session {
             profile_ctx,
             last_chunk: 0,
             _process: None,
+            _stderr: None,
             is_throttled: false,
+            is_preempted: false,
             has_started: false,
             child_pid: None,
+            spawner,
             real_process: None,
-            hard_timeout: Instant::now() + Duration::from_secs(30 * 60),
+            preview: None,
+            patched_previews: BTreeSet::new(),
+            last_request: Instant::now(),
+            finished_at: None,
+            timed_out: false,
+            gc_policy: None,
             chunks_since_init: 0,
             exit_status: None,
+            preserved_init: None,
+            expires_at: None,
+            dts_warnings: Arc::new(AtomicU64::new(0)),
+            outdir_size: Arc::new(AtomicU64::new(0)),
+            last_activity: Arc::new(AtomicU64::new(0)),
+            completed_chunks: BTreeSet::new(),
+            completion: watch::channel(None).0,
+            progress: watch::channel(None).0,
+            on_segment: None,
+            hooked_chunks: BTreeSet::new(),
+            encryption: None,
+            segment_index: None,
+            ts_continuity: None,
+            ladder: None,
+            listed_chunks: BTreeMap::new(),
+            playlist_patch: None,
+            written_playlist: None,
+            subtitle_split: None,
+            segment_times: BTreeMap::new(),
+            chunk_runs: BTreeMap::new(),
+            segment_times_read: None,
+            patched_chunks: BTreeSet::new(),
+            patched_renditions: HashMap::new(),
+            rendition_seqs: HashMap::new(),
+            relocating: None,
+            relocated_from: None,
+            persisted: None,
+            reported_chunk: None,
+            runs: 0,
         }
     }
 
     pub async fn start(&mut self) -> Result<(), io::Error> {
+        // the new run has to write into the outdir the files are being moved to.
+        if let Some(mut done) = self.relocating.as_ref().map(|x| x.done.clone()) {
+            loop {
+                if done.borrow().is_some() || done.changed().await.is_err() {
+                    break;
+                }
+            }
+        }
+        self.poll_relocation();
+
+        // nothing writes through the old location anymore.
+        if let Some(link) = self.relocated_from.take() {
+            let _ = crate::utils::unlink_dir(Path::new(&link));
+        }
+
         // make sure we actually have a path to write files to.
         self.has_started = true;
         self.is_throttled = false;
+        // a killed session which gets started again is in use once more.
+        self.timed_out = false;
+        self.runs += 1;
 
-        let args = self.profile.build(self.profile_ctx.clone()).unwrap();
+        let mut args = self.profile.build(self.profile_ctx.clone()).unwrap();
+        args.splice(0..0, self.profile_ctx.pre_args.iter().cloned());
+        args.splice(0..0, self.profile_ctx.output_ctx.readrate_flags());
 
         let _ = std::fs::create_dir_all(&self.profile_ctx.output_ctx.outdir);
+        for rendition in self.profile_ctx.output_ctx.audio_renditions.iter() {
+            let _ = std::fs::create_dir_all(self.rendition_dir(Some(rendition)));
+        }
+        if self.profile.burns_in() {
+            let _ = std::fs::create_dir_all(self.profile_ctx.output_ctx.fonts_dir());
+        }
         let log_file = format!(
             "{}/ffmpeg_{}.log",
             &self.profile_ctx.output_ctx.outdir,
             self.profile.tag()
         );
 
-        let mut stderr = File::create(log_file)?;
-        let _ = stderr.write(args.as_slice().join(" ").as_ref());
-        let _ = stderr.write(b"\n");
-        let _ = stderr.flush();
+        let mut log = File::create(log_file)?;
+        let _ = log.write(args.as_slice().join(" ").as_ref());
+        let _ = log.write(b"\n");
+        let _ = log.flush();
 
-        let stderr: Stdio = stderr.into();
-
-        let stdout: Stdio = if self.profile.stream_type() == StreamType::Subtitle {
-            File::create(format!("{}/stream", &self.profile_ctx.output_ctx.outdir))?.into()
+        let stdout = if self.profile.stream_type() == StreamType::Subtitle {
+            Output::File(File::create(format!(
+                "{}/stream",
+                &self.profile_ctx.output_ctx.outdir
+            ))?)
         } else {
-            Stdio::piped()
+            Output::Piped
         };
 
-        let mut process = Command::new(self.profile_ctx.ffmpeg_bin.clone())
-            .stdout(stdout)
-            .stderr(stderr)
-            .stdin(Stdio::null())
-            .args(args.as_slice())
-            .spawn()?;
+        let mut program = self.profile_ctx.ffmpeg_bin.clone();
+        let mut spawn_args = args.clone();
+
+        // `nice` execs ffmpeg, thus the pid we get back is the one of ffmpeg.
+        if let Some(nice) = self.profile_ctx.nice.filter(|_| cfg!(unix)) {
+            spawn_args.splice(0..0, ["-n".into(), nice.to_string(), program]);
+            program = "nice".into();
+        }
+
+        let mut process = self.spawner.spawn(SpawnCommand {
+            program,
+            args: spawn_args,
+            stdout,
+            stderr: Output::Piped,
+            kill_on_drop: false,
+        })?;
 
         self.child_pid = process.id();
 
-        debug!(pid = self.child_pid, ffmpeg = %self.profile_ctx.ffmpeg_bin, ?args, "Started ffmpeg");
+        if let (Some(cgroup), Some(pid)) = (self.profile_ctx.cgroup.as_ref(), self.child_pid) {
+            let procs = Path::new(cgroup).join("cgroup.procs");
 
-        if !self.profile.is_stdio_stream() {
-            if let Some(stdout) = process.stdout.take() {
-                let stdout_parser_thread =
-                    StdoutParser::new(self.id.clone(), stdout, self.child_pid.clone().unwrap());
+            if let Err(e) = fs::write(procs, pid.to_string()) {
+                warn!(error = %e, cgroup = %cgroup, "Failed to move ffmpeg into its cgroup");
+            }
+        }
+
+        self.span.in_scope(|| {
+            debug!(pid = self.child_pid, ffmpeg = %self.profile_ctx.ffmpeg_bin, ?args, "Started ffmpeg")
+        });
+
+        // stderr is always drained on its own task, so that a slow consumer of stdout can never
+        // wedge ffmpeg by letting the stderr pipe fill up.
+        if let Some(stderr) = process.take_stderr() {
+            self._stderr = Some(tokio::spawn(
+                StderrDrain::new(
+                    stderr,
+                    tokio::fs::File::from_std(log),
+                    self.dts_warnings.clone(),
+                )
+                .handle()
+                .instrument(self.span.clone()),
+            ));
+        }
 
-                self._process = Some(tokio::spawn(stdout_parser_thread.handle()));
+        if !self.profile.is_stdio_stream() {
+            if let Some(stdout) = process.take_stdout() {
+                let stdout_parser_thread = StdoutParser::new(
+                    self.id.clone(),
+                    stdout,
+                    self.progress.clone(),
+                    self.last_activity.clone(),
+                    self.profile_ctx.input_ctx.duration,
+                );
+
+                self._process = Some(tokio::spawn(
+                    stdout_parser_thread.handle().instrument(self.span.clone()),
+                ));
             }
         }
         self.real_process = Some(process);
@@ -150,9 +501,78 @@ impl Session {
         Ok(())
     }
 
-    // NOTE: This will only work for RawVideo streams.
-    pub fn take_stdout(&mut self) -> Option<ChildStdout> {
-        self.real_process.as_mut().and_then(|x| x.stdout.take())
+    /// Spawns a short lived ffmpeg process which transmuxes `PREVIEW_CHUNKS` chunks starting at
+    /// `chunk` into `preview_dir`, so that direct play sessions can serve a seek target without
+    /// restarting the main process. A previous preview process gets killed.
+    pub fn spawn_preview(&mut self, chunk: u32) -> Result<(), io::Error> {
+        let mut ctx = self.profile_ctx.clone();
+        ctx.output_ctx.start_num = chunk;
+        ctx.output_ctx.outdir = self.preview_dir();
+        ctx.output_ctx.single_file = false;
+
+        let mut args = self
+            .profile
+            .build(ctx)
+            .ok_or_else(|| io::Error::from(io::ErrorKind::InvalidInput))?;
+        args.splice(0..0, self.profile_ctx.pre_args.iter().cloned());
+
+        // limit the duration right before the output path.
+        let output = args.pop().unwrap_or_default();
+        args.append(&mut vec![
+            "-t".into(),
+            (PREVIEW_CHUNKS * self.profile_ctx.output_ctx.target_gop).to_string(),
+            output,
+        ]);
+
+        let _ = fs::create_dir_all(self.preview_dir());
+
+        let process = self.spawner.spawn(SpawnCommand {
+            program: self.profile_ctx.ffmpeg_bin.clone(),
+            args,
+            stdout: Output::Null,
+            stderr: Output::Null,
+            kill_on_drop: true,
+        })?;
+
+        debug!(pid = process.id(), chunk, "Started direct play preview");
+
+        self.preview = Some((chunk, process));
+        self.patched_previews.clear();
+
+        Ok(())
+    }
+
+    /// Patches `chunk` as written by the preview process, the first time it is handed out.
+    /// Chunks which failed to patch are patched again on the next call.
+    pub async fn patch_preview_chunk(&mut self, chunk: u32) -> Result<(), NightfallError> {
+        if self.patched_previews.contains(&chunk) {
+            return Ok(());
+        }
+
+        patch_direct_play_segment(self.preview_chunk_to_path(chunk), chunk).await?;
+        self.patched_previews.insert(chunk);
+
+        Ok(())
+    }
+
+    /// Returns whether the current preview process produces `chunk`.
+    pub fn preview_covers(&self, chunk: u32) -> bool {
+        self.preview
+            .as_ref()
+            .is_some_and(|(start, _)| chunk >= *start && chunk < start + PREVIEW_CHUNKS)
+    }
+
+    pub fn preview_dir(&self) -> String {
+        format!("{}/preview", self.profile_ctx.output_ctx.outdir)
+    }
+
+    pub fn preview_chunk_to_path(&self, chunk_num: u32) -> String {
+        format!("{}/{}.m4s", self.preview_dir(), chunk_num)
+    }
+
+    // NOTE: This will only work for profiles streaming over stdio, see `is_stdio_stream`.
+    pub fn take_stdout(&mut self) -> Option<ProcessOutput> {
+        self.real_process.as_mut().and_then(|x| x.take_stdout())
     }
 
     pub fn start_num(&self) -> u32 {
@@ -164,6 +584,26 @@ impl Session {
         Some(self.profile.tag())
     }
 
+    /// Moves down the profile chain if the last ffmpeg run failed, returns the tags of the failed
+    /// profile and of the one replacing it. Fails with `ProfileChainExhausted` once every profile
+    /// of the chain failed.
+    pub fn fall_back(&mut self) -> Result<Option<(String, String)>, NightfallError> {
+        match self.exit_status.take() {
+            Some(status) if !status.success() => {
+                let from = self.profile.tag().to_string();
+                let to = self
+                    .next_profile()
+                    .ok_or(NightfallError::ProfileChainExhausted)?
+                    .to_string();
+
+                self.reset_to(self.start_num());
+
+                Ok(Some((from, to)))
+            }
+            _ => Ok(None),
+        }
+    }
+
     pub async fn join(&mut self) {
         if let Some(ref mut x) = self.real_process {
             let _ = x.kill().await;
@@ -192,6 +632,25 @@ impl Session {
         if let Some(ref mut x) = self.real_process {
             if let Ok(Some(status)) = x.try_wait() {
                 self.exit_status = Some(status);
+
+                let reason = match status.code() {
+                    _ if status.success() => ExitReason::Success,
+                    Some(code) => ExitReason::Failed {
+                        code: Some(code),
+                        stderr: self.stderr().unwrap_or_default(),
+                    },
+                    None => ExitReason::Killed,
+                };
+
+                // a failed run is only final once there is no profile left to fall back to,
+                // see `fall_back`.
+                let is_final =
+                    !matches!(reason, ExitReason::Failed { .. }) || self.profile_chain.is_empty();
+
+                if is_final {
+                    self.finish(reason);
+                }
+
                 return true;
             }
             self.exit_status = None;
@@ -200,44 +659,228 @@ impl Session {
         false
     }
 
-    pub fn is_hard_timeout(&self) -> bool {
-        Instant::now() > self.hard_timeout
+    /// Resolves the completion futures of this session with `reason`, unless they already have
+    /// been resolved.
+    pub fn finish(&mut self, reason: ExitReason) {
+        if self.completion.borrow().is_none() {
+            self.completion.send_replace(Some(reason));
+            self.finished_at = Some(Instant::now());
+        }
+    }
+
+    /// Returns how many times ffmpeg got started again after the first run, ex. after seeking or
+    /// falling back to another profile.
+    pub fn restarts(&self) -> u64 {
+        self.runs.saturating_sub(1)
+    }
+
+    /// Returns why the session finished, `None` while it is still running.
+    pub fn exit_reason(&self) -> Option<ExitReason> {
+        self.completion.borrow().clone()
+    }
+
+    /// Returns a receiver of the progress reports of ffmpeg, holding the latest one. Restarting
+    /// ffmpeg, ex. when seeking, keeps publishing to the same receiver.
+    pub fn progress(&self) -> watch::Receiver<Option<Progress>> {
+        self.progress.subscribe()
+    }
+
+    pub fn completion(&self) -> Completion {
+        Completion {
+            rx: self.completion.subscribe(),
+        }
+    }
+
+    /// Returns whether the session should be reaped, either because it was killed or because no
+    /// chunk has been requested for `reap_after`. Background sessions arent driven by requests,
+    /// they are kept until `reap_after` passed since they finished.
+    pub fn is_hard_timeout(&self, policy: &GcPolicy) -> bool {
+        if self.timed_out {
+            return true;
+        }
+
+        if self.priority() == Priority::Background
+            && !self
+                .finished_at
+                .is_some_and(|x| x.elapsed() > policy.reap_after)
+        {
+            return false;
+        }
+
+        // progressive streams never request chunks, they are in use for as long as ffmpeg runs.
+        if self.profile.is_progressive() && self.has_started && !self.is_dead() {
+            return false;
+        }
+
+        self.last_request.elapsed() > policy.reap_after
+    }
+
+    /// Returns whether `garbage_collect` should reap the session, `policy` being the one used
+    /// unless the session overrides it.
+    pub fn should_reap(&self, policy: &GcPolicy) -> bool {
+        let policy = self.gc_policy.as_ref().unwrap_or(policy);
+
+        self.is_hard_timeout(policy) || self.is_expired()
+    }
+
+    /// Returns whether serving `chunk` warrants restarting ffmpeg at it rather than waiting for
+    /// the current run to get there. `last_hard_seek` is when the session was last restarted
+    /// because of a seek, and `hard_seeked_at` the chunk it was restarted at.
+    pub fn should_hard_seek(
+        &self,
+        chunk: u32,
+        last_hard_seek: Instant,
+        hard_seeked_at: u32,
+    ) -> bool {
+        if !self.has_started() {
+            return false;
+        }
+
+        // if we are seeking backwards we always want to restart the stream, the current run
+        // never gets back to the chunk. A preserved init segment only spares the client from
+        // fetching a new one.
+        if chunk < self.start_num() {
+            return true;
+        }
+
+        // FIXME: When we hard seek and start a new ffmpeg session for some reason ffmpeg
+        // reports invalid speed but then evens out. The problem is that causes seeking
+        // multiple times in a row to be very slow.
+        // thus for like the first 10s after a hard seek we exclusively hard seek if the
+        // target is over 10 chunks into the future.
+        if chunk > self.current_chunk() + 15
+            && Instant::now() < last_hard_seek + Duration::from_secs(15)
+            && chunk > hard_seeked_at
+        {
+            return true;
+        }
+
+        (self.eta_for(chunk).as_millis() as f64) > (10_000.0 / self.raw_speed()).max(8_000.0)
+    }
+
+    /// Returns whether no chunk has been requested for `pause_after`.
+    pub fn is_idle(&self, policy: &GcPolicy) -> bool {
+        if self.profile.is_progressive() {
+            return false;
+        }
+
+        policy
+            .pause_after
+            .is_some_and(|x| self.last_request.elapsed() > x)
+    }
+
+    pub fn is_expired(&self) -> bool {
+        self.expires_at.is_some_and(|x| Instant::now() > x)
+    }
+
+    /// Returns why the session finished once `garbage_collect` reaps it.
+    pub fn reap_reason(&self) -> ExitReason {
+        if self.is_expired() {
+            ExitReason::Expired
+        } else {
+            ExitReason::Killed
+        }
+    }
+
+    pub fn dts_warnings(&self) -> u64 {
+        self.dts_warnings.load(Ordering::Relaxed)
+    }
+
+    /// Makes ffmpeg regenerate presentation timestamps of the input on the next start. Returns
+    /// `false` if this was already enabled.
+    pub fn enable_genpts(&mut self) -> bool {
+        let pre_args = &mut self.profile_ctx.pre_args;
+
+        if pre_args.iter().any(|x| x == "+genpts") {
+            return false;
+        }
+
+        pre_args.append(&mut vec!["-fflags".into(), "+genpts".into()]);
+        true
     }
 
     pub fn set_timeout(&mut self) {
-        self.hard_timeout = Instant::now();
+        self.timed_out = true;
     }
 
+    /// Deletes everything the session wrote into its outdir. The outdir itself is only removed
+    /// once empty, as a layout can place the files of the caller or of other sessions alongside,
+    /// see `OutdirLayout`.
     pub fn delete_tmp(&self) {
-        let _ = fs::remove_dir_all(&self.profile_ctx.output_ctx.outdir);
+        let output_ctx = &self.profile_ctx.output_ctx;
+        let outdir = Path::new(&output_ctx.outdir);
+
+        for entry in fs::read_dir(outdir).into_iter().flatten().flatten() {
+            let name = entry.file_name();
+            let name = name.to_string_lossy();
+            let is_owned_dir = ["preview", "attachments", "fonts"].contains(&name.as_ref())
+                || output_ctx.rendition_dirs().iter().any(|x| *x == name);
+
+            if is_owned_dir {
+                let _ = fs::remove_dir_all(entry.path());
+            } else if is_artifact(&name) {
+                let _ = fs::remove_file(entry.path());
+            }
+        }
+
+        let _ = fs::remove_dir(outdir);
+
+        if let Some(link) = self.relocated_from.as_ref() {
+            let _ = crate::utils::unlink_dir(Path::new(link));
+        }
     }
 
-    pub fn is_dead(&self) -> bool {
-        if let Some(x) = self.child_pid {
-            return crate::utils::is_process_effectively_dead(x);
+    /// Returns the process of the current run, `None` if the session has been reset since.
+    fn running_process(&self) -> Option<&dyn Process> {
+        if self.has_started {
+            self.real_process.as_deref()
+        } else {
+            None
         }
+    }
 
-        true
+    pub fn is_dead(&self) -> bool {
+        self.running_process().is_none_or(|x| x.is_dead())
     }
 
     pub fn pause(&mut self) {
-        if let Some(x) = self.child_pid {
+        if let Some(x) = self.running_process() {
             if !self.is_throttled {
-                crate::utils::pause_proc(x);
+                x.pause();
                 self.is_throttled = true;
             }
         }
     }
 
     pub fn cont(&mut self) {
-        if let Some(x) = self.child_pid {
+        // ffmpeg stays paused until its files are moved, see `relocate`.
+        if self.is_relocating() {
+            return;
+        }
+
+        if let Some(x) = self.running_process() {
             if self.is_throttled {
-                crate::utils::cont_proc(x);
+                x.cont();
                 self.is_throttled = false;
             }
         }
     }
 
+    pub fn priority(&self) -> Priority {
+        self.profile_ctx.output_ctx.priority
+    }
+
+    /// Pauses the session until `resume` is called, pacing wont resume it in the meantime.
+    pub fn preempt(&mut self) {
+        self.pause();
+        self.is_preempted = true;
+    }
+
+    pub fn resume(&mut self) {
+        self.is_preempted = false;
+        self.cont();
+    }
+
     pub fn get_key(&self, k: &str) -> Option<String> {
         let session = STREAMING_SESSION.read().unwrap();
         session.get(&self.id)?.get(k).cloned()
@@ -263,7 +906,14 @@ impl Session {
         match self.profile.stream_type() {
             StreamType::Audio { .. } => (frame / (self.chunk_size * 24)).max(self.last_chunk),
             StreamType::Video { .. } => {
-                frame / (self.chunk_size * 24) + self.profile_ctx.output_ctx.start_num
+                let fps = self
+                    .profile
+                    .output_fps()
+                    .or_else(|| self.profile_ctx.output_ctx.fps.map(f64::from))
+                    .unwrap_or(24.0)
+                    .round() as u32;
+                // frame rates below 0.5 fps round down to 0.
+                frame / (self.chunk_size * fps).max(1) + self.profile_ctx.output_ctx.start_num
             }
             _ => 0,
         }
@@ -276,6 +926,36 @@ impl Session {
             .unwrap_or(1.0) // assume if key is missing that our speed is 2.0
     }
 
+    /// Returns a exponential moving average of the speed reported by ffmpeg, falls back to the
+    /// raw speed if we havent seen enough progress reports.
+    pub fn smoothed_speed(&self) -> f64 {
+        self.get_key("speed_ema")
+            .and_then(|x| x.parse::<f64>().ok())
+            .unwrap_or_else(|| self.raw_speed())
+    }
+
+    /// Returns the progress last reported by ffmpeg, see `StateManager::session_stats`.
+    pub fn stats(&self) -> SessionStats {
+        let parse = |k: &str| self.get_key(k).and_then(|x| x.trim().parse::<f64>().ok());
+
+        SessionStats {
+            speed: self
+                .get_key("speed")
+                .and_then(|x| x.trim_end_matches('x').parse().ok()),
+            smoothed_speed: self
+                .get_key("speed_ema")
+                .and_then(|x| x.parse::<f64>().ok()),
+            fps: parse("fps"),
+            frames: parse("frame").map(|x| x as u64),
+            bytes: parse("total_size").map(|x| x as u64),
+            out_time: parse("out_time_us").map(|x| Duration::from_micros(x.max(0.0) as u64)),
+            chunks_completed: self.completed_chunks.len() as u32,
+            last_activity: Some(self.last_activity.load(Ordering::Relaxed))
+                .filter(|x| *x > 0)
+                .map(|x| UNIX_EPOCH + Duration::from_millis(x)),
+        }
+    }
+
     // returns how many chunks per second
     pub fn speed(&self) -> f64 {
         self.raw_speed().floor().max(20.0) / self.chunk_size as f64
@@ -290,22 +970,305 @@ impl Session {
         Duration::from_secs((diff / cps).abs().ceil() as u64)
     }
 
+    /// Returns how many chunks the whole input gets split into, if we know its duration.
+    pub fn chunk_count(&self) -> Option<u32> {
+        let duration = self.profile_ctx.input_ctx.duration?;
+
+        Some((duration / self.chunk_size as f64).ceil() as u32)
+    }
+
     /// Method does some math magic to guess if a chunk has been fully written by ffmpeg yet
     /// only works when `ffmpeg` writes files to tmp then renames them.
     pub fn is_chunk_done(&self, chunk_num: u32) -> bool {
-        Path::new(&format!(
-            "{}/{}.m4s",
-            &self.profile_ctx.output_ctx.outdir, chunk_num
-        ))
-        .is_file()
+        // `webm_chunk` doesnt write to temporary files, thus a chunk is only complete once ffmpeg
+        // moved on to the next one or exited.
+        if self.profile.container() == Container::WebM {
+            return Path::new(&self.chunk_to_path(chunk_num)).is_file()
+                && (Path::new(&self.chunk_to_path(chunk_num + 1)).is_file() || self.is_dead());
+        }
+
+        Path::new(&self.chunk_to_path(chunk_num)).is_file()
+    }
+
+    /// Returns the path to hand out for the chunk at `path`, a copy encrypted with `encryption` or
+    /// protected with `OutputCtx::cenc`, or `path` itself when the session does neither. Copies
+    /// are only written again once the chunk changed. `is_video` tells whether the chunk holds
+    /// video samples, which isnt the case for audio renditions.
+    pub async fn protected_chunk(
+        &self,
+        path: String,
+        chunk: u32,
+        is_video: bool,
+    ) -> Result<String, NightfallError> {
+        if let Some(key) = self.encryption {
+            return encrypt_chunk(path, key, chunk).await;
+        }
+
+        let config = match self.profile_ctx.output_ctx.cenc.clone() {
+            Some(x) => x,
+            None => return Ok(path),
+        };
+
+        let format = SampleFormat::for_codec(&self.profile_ctx.output_ctx.codec, is_video)
+            .ok_or_else(|| {
+                NightfallError::SegmentPatchError("Samples of this codec cant be protected.".into())
+            })?;
+
+        protect_chunk(path, config, format).await
+    }
+
+    /// Returns the sorted indices of all completed chunks currently on disk. The list can have
+    /// gaps when the session got reset or chunks got pruned.
+    pub fn available_chunks(&self) -> Vec<u32> {
+        let extension = self.profile.container().segment_extension();
+        let entries = match fs::read_dir(self.rendition_dir(None)) {
+            Ok(x) => x,
+            Err(_) => return Vec::new(),
+        };
+
+        let mut chunks = entries
+            .filter_map(Result::ok)
+            .filter_map(|entry| {
+                let path = entry.path();
+                if path.extension()? != extension {
+                    return None;
+                }
+
+                path.file_stem()?.to_str()?.parse::<u32>().ok()
+            })
+            .filter(|&chunk| self.is_chunk_done(chunk))
+            .collect::<Vec<_>>();
+
+        chunks.sort_unstable();
+        chunks
+    }
+
+    /// Returns the chunks of the current run which completed since the last call, in order.
+    pub fn take_ready_chunks(&mut self) -> Vec<u32> {
+        let mut next = self.reported_chunk.map_or(self.start_num(), |x| x + 1);
+        let mut ready = Vec::new();
+
+        while self.is_chunk_done(next) {
+            ready.push(next);
+            next += 1;
+        }
+
+        if let Some(last) = ready.last() {
+            self.reported_chunk = Some(*last);
+        }
+
+        self.completed_chunks.extend(ready.iter().copied());
+
+        ready
+    }
+
+    /// Returns the last chunk of the current run which is on disk along with every chunk of the
+    /// run before it.
+    pub fn last_completed_chunk(&self) -> Option<u32> {
+        let mut last = None;
+
+        for chunk in self.available_chunks() {
+            if chunk < self.start_num() {
+                continue;
+            }
+
+            if chunk != last.map_or(self.start_num(), |x| x + 1) {
+                break;
+            }
+
+            last = Some(chunk);
+        }
+
+        last
+    }
+
+    /// Writes the state needed to resume the session after a restart to `STATE_FILE` in its
+    /// outdir. Nothing gets written, nor serialized, unless ffmpeg got restarted, fell back to
+    /// another profile or completed chunks, or the outdir moved since the last call.
+    pub fn persist(&mut self) -> io::Result<()> {
+        // killed sessions are about to be reaped, see `forget_persisted`.
+        if self.timed_out {
+            return Ok(());
+        }
+
+        let key = PersistedKey {
+            runs: self.runs,
+            profiles: self.profile_chain.len(),
+            last_chunk: self.reported_chunk,
+            outdir: self.profile_ctx.output_ctx.outdir.clone(),
+        };
+
+        if self.persisted.as_ref() == Some(&key) {
+            return Ok(());
+        }
+
+        let state = PersistedSession {
+            id: self.id.clone(),
+            chain: std::iter::once(&self.profile)
+                .chain(self.profile_chain.iter().rev())
+                .map(|x| x.tag().to_string())
+                .collect(),
+            // the outdir can be served, thus only the secret free context is written.
+            profile_ctx: self.profile_ctx.without_secrets(),
+            // reported by `take_ready_chunks` on every garbage collection pass.
+            last_chunk: self.reported_chunk,
+        };
+
+        let json = serde_json::to_string(&state)?;
+
+        // written to a temporary file first so that a crash mid write doesnt leave a truncated
+        // state behind.
+        let path = Path::new(&self.profile_ctx.output_ctx.outdir).join(STATE_FILE);
+        let tmp = path.with_extension("json.tmp");
+
+        let mut options = fs::OpenOptions::new();
+        options.write(true).create(true).truncate(true);
+        // only readable by us, even if the outdir gets served.
+        #[cfg(unix)]
+        std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+
+        options.open(&tmp)?.write_all(json.as_bytes())?;
+        fs::rename(&tmp, &path)?;
+
+        self.persisted = Some(key);
+
+        Ok(())
+    }
+
+    /// Removes the `STATE_FILE` written by `persist`, so that a session which was killed or
+    /// reaped doesnt get picked back up by `StateManager::restore_sessions` when its segments
+    /// are kept.
+    pub fn forget_persisted(&mut self) {
+        let path = Path::new(&self.profile_ctx.output_ctx.outdir).join(STATE_FILE);
+        let _ = fs::remove_file(path);
+
+        self.persisted = None;
+    }
+
+    /// Returns the directory segments of `rendition` get written to. Sessions producing several
+    /// audio renditions write each of them into a sub-directory, see `OutputCtx::rendition_dirs`,
+    /// for these the first rendition is used when no rendition is specified.
+    pub fn rendition_dir(&self, rendition: Option<&str>) -> String {
+        let output_ctx = &self.profile_ctx.output_ctx;
+        let idx = match rendition {
+            Some(rendition) => output_ctx
+                .audio_renditions
+                .iter()
+                .position(|x| x == rendition),
+            None => Some(0),
+        };
+
+        match idx.and_then(|x| output_ctx.rendition_dirs().into_iter().nth(x)) {
+            Some(dir) => format!("{}/{}", output_ctx.outdir, dir),
+            None => output_ctx.outdir.clone(),
+        }
+    }
+
+    /// Patches `chunk` of the audio rendition `rendition` unless it has been patched already.
+    /// Every rendition tracks its sequence numbers like `real_segment` does for the chunks
+    /// handed out by `StateManager::chunk_request`, which are the chunks of the first rendition.
+    pub async fn patch_rendition_chunk(
+        &mut self,
+        rendition: &str,
+        chunk: u32,
+    ) -> Result<(), NightfallError> {
+        let path = self.rendition_chunk_to_path(rendition, chunk);
+        let is_first = self
+            .profile_ctx
+            .output_ctx
+            .audio_renditions
+            .first()
+            .is_some_and(|x| x == rendition);
+
+        if is_first {
+            if self.patched_chunks.contains(&chunk) {
+                return Ok(());
+            }
+
+            self.real_segment = patch_segment(path, self.real_segment, None).await?;
+            self.patched_chunks.insert(chunk);
+
+            return Ok(());
+        }
+
+        let is_patched = self
+            .patched_renditions
+            .get(rendition)
+            .is_some_and(|x| x.contains(&chunk));
+
+        if is_patched {
+            return Ok(());
+        }
+
+        let seq = self
+            .rendition_seqs
+            .get(rendition)
+            .copied()
+            .unwrap_or_else(|| self.start_num());
+        let seq = patch_segment(path, seq, None).await?;
+
+        self.rendition_seqs.insert(rendition.into(), seq);
+        self.patched_renditions
+            .entry(rendition.into())
+            .or_default()
+            .insert(chunk);
+
+        Ok(())
+    }
+
+    pub fn rendition_chunk_to_path(&self, rendition: &str, chunk_num: u32) -> String {
+        format!(
+            "{}/{}.{}",
+            self.rendition_dir(Some(rendition)),
+            chunk_num,
+            self.profile.container().segment_extension()
+        )
     }
 
-    pub fn subtitle(&self, file: String) -> Option<String> {
+    /// Returns the path of `file` once the subtitle is done. Subtitles split into WebVTT chunks
+    /// are split in the background once ffmpeg exits, until then `None` is returned.
+    pub fn subtitle(&mut self, file: String) -> Option<String> {
         if !matches!(self.profile.stream_type(), StreamType::Subtitle) {
             return None;
         }
 
-        let file = format!("{}/{}", &self.profile_ctx.output_ctx.outdir, file);
+        // Profiles which write the subtitle into a file instead of stdout are only done once
+        // ffmpeg exits.
+        if !self.profile.is_stdio_stream() && !self.is_dead() {
+            return None;
+        }
+
+        let outdir = self.profile_ctx.output_ctx.outdir.clone();
+
+        if self.profile.splits_subtitles()
+            && !Path::new(&outdir)
+                .join(crate::webvtt::PLAYLIST_FILE)
+                .is_file()
+        {
+            // a split which failed is retried on the next request.
+            if self.subtitle_split.as_ref().is_none_or(|x| x.is_finished()) {
+                let chunk_duration = self.chunk_size;
+                let duration = self.profile_ctx.input_ctx.duration;
+                // cues have to line up with the timestamps of the chunks they are shown along.
+                let offset = if self.profile_ctx.output_ctx.container == Some(Container::MpegTs) {
+                    TS_START_OFFSET
+                } else {
+                    0
+                };
+
+                self.subtitle_split = Some(tokio::task::spawn_blocking(move || {
+                    if let Err(e) =
+                        crate::webvtt::split_file(outdir, chunk_duration, duration, offset)
+                    {
+                        warn!(error = %e, "Failed to split subtitle into chunks.");
+                    }
+                }));
+            }
+
+            return None;
+        }
+
+        let file = format!("{}/{}", outdir, file);
         let path = Path::new(&file);
 
         // NOTE: This will not check if the ffmpeg process is dead, thus this will return immediately
@@ -332,44 +1295,804 @@ impl Session {
         None
     }
 
-    pub fn is_timeout(&self) -> bool {
-        self.current_chunk() > self.last_chunk + MAX_CHUNKS_AHEAD
+    /// Returns how many chunks ffmpeg has encoded ahead of the last chunk requested.
+    pub fn buffered_chunks(&self) -> u32 {
+        self.current_chunk().saturating_sub(self.last_chunk)
     }
 
     pub fn reset_timeout(&mut self, last_requested: u32) {
         self.last_chunk = last_requested;
-        self.hard_timeout = Instant::now() + Duration::from_secs(30 * 60);
+        self.last_request = Instant::now();
     }
 
     pub fn chunk_to_path(&self, chunk_num: u32) -> String {
-        format!("{}/{}.m4s", self.profile_ctx.output_ctx.outdir, chunk_num)
+        format!(
+            "{}/{}.{}",
+            self.rendition_dir(None),
+            chunk_num,
+            self.profile.container().segment_extension()
+        )
     }
 
     pub fn init_seg(&self) -> String {
-        format!(
-            "{}/{}_init.mp4",
-            self.profile_ctx.output_ctx.outdir,
-            self.start_num()
-        )
+        self.custom_init_seg(self.start_num())
     }
 
     pub fn custom_init_seg(&self, start_num: u32) -> String {
         format!(
-            "{}/{}_init.mp4",
-            self.profile_ctx.output_ctx.outdir, start_num
+            "{}/{}_init.{}",
+            self.rendition_dir(None),
+            start_num,
+            self.profile.container().init_extension()
         )
     }
 
+    /// Restart the session at `chunk` while keeping the init segment that is currently being
+    /// served. Whether the preserved init segment can actually be reused is checked once ffmpeg has
+    /// written the new one, see `resolve_init_seg`.
+    ///
+    /// ffmpeg is sent the kill signal and all the bookkeeping is done before we await the old
+    /// process, thus if this future gets dropped half way through ffmpeg still exits and the
+    /// session is left in a consistent state and will respawn ffmpeg on the next request.
+    pub async fn reset_preserving_init(&mut self, chunk: u32) {
+        if self.preserved_init.is_none() {
+            self.preserved_init = Some(self.start_num());
+        }
+
+        let process = self.real_process.take();
+        self.reset_to(chunk);
+
+        // We dont record the exit status here as we killed ffmpeg on purpose and dont want the
+        // next init request to think the profile failed.
+        if let Some(mut process) = process {
+            let _ = process.start_kill();
+            let _ = process.wait().await;
+        }
+    }
+
+    /// Moves every artifact of the session into `outdir` on a blocking task, the returned
+    /// receiver is published to once done. ffmpeg cant change where it writes to, thus a running
+    /// process is paused during the move and then keeps writing through a link left at the old
+    /// location, until it gets restarted. The session keeps using the old location until the
+    /// move is done, see `poll_relocation`.
+    pub fn relocate(
+        &mut self,
+        outdir: String,
+    ) -> watch::Receiver<Option<Result<(), NightfallError>>> {
+        let (tx, done) = watch::channel(None);
+        let linked = self.has_started && !self.is_dead();
+        let resume = linked && !self.is_throttled;
+
+        self.pause();
+        // the preview process gets killed on drop, its chunks are moved along with the rest.
+        self.preview = None;
+
+        let from = self.profile_ctx.output_ctx.outdir.clone();
+        let to = outdir.clone();
+
+        tokio::task::spawn_blocking(move || {
+            let result = move_dir(Path::new(&from), Path::new(&to), linked);
+            tx.send_replace(Some(result.map_err(NightfallError::from)));
+        });
+
+        self.relocating = Some(Relocating {
+            to: outdir,
+            done: done.clone(),
+            linked,
+            resume,
+        });
+
+        done
+    }
+
+    /// Returns whether the outdir is being moved, see `relocate`.
+    pub fn is_relocating(&self) -> bool {
+        self.relocating
+            .as_ref()
+            .is_some_and(|x| x.done.borrow().is_none())
+    }
+
+    /// Switches over to the new outdir once a relocation is done, and resumes ffmpeg if it was
+    /// paused for it. A failed relocation leaves the session where it was.
+    pub fn poll_relocation(&mut self) {
+        if self.relocating.is_none() || self.is_relocating() {
+            return;
+        }
+
+        let relocating = self.relocating.take().unwrap();
+        let result = relocating.done.borrow().clone().unwrap();
+
+        match result {
+            Ok(()) => {
+                let from =
+                    std::mem::replace(&mut self.profile_ctx.output_ctx.outdir, relocating.to);
+
+                if relocating.linked {
+                    self.relocated_from = Some(from);
+                }
+            }
+            Err(e) => warn!(session = %self.id, error = %e, "Failed to relocate session"),
+        }
+
+        if relocating.resume {
+            self.cont();
+        }
+    }
+
+    /// Restart the session at `chunk` after deleting every segment and init segment written so
+    /// far. Used when the encode parameters change, as old segments would no longer match.
+    pub async fn reset_discarding_segments(&mut self, chunk: u32) {
+        let process = self.real_process.take();
+        self.reset_to(chunk);
+        self.preserved_init = None;
+        self.patched_chunks.clear();
+        self.patched_renditions.clear();
+        self.hooked_chunks.clear();
+        self.completed_chunks.clear();
+
+        // We dont record the exit status here as we killed ffmpeg on purpose.
+        if let Some(mut process) = process {
+            let _ = process.start_kill();
+            let _ = process.wait().await;
+        }
+
+        let container = self.profile.container();
+        let extensions = [
+            container.segment_extension(),
+            container.init_extension(),
+            "m3u8",
+        ];
+
+        if let Ok(entries) = fs::read_dir(self.rendition_dir(None)) {
+            for path in entries.filter_map(Result::ok).map(|x| x.path()) {
+                let is_artifact = path
+                    .extension()
+                    .and_then(|x| x.to_str())
+                    .is_some_and(|x| extensions.contains(&x));
+
+                if is_artifact {
+                    let _ = fs::remove_file(path);
+                }
+            }
+        }
+    }
+
+    /// Returns the duration of `chunk` in seconds, as measured by ffmpeg once the chunk has been
+    /// written, see `refresh_segment_times`. Otherwise transmuxed chunks of a source with a
+    /// keyframe index are assumed to be cut on the source keyframes, see
+    /// `ProfileContext::chunk_start`, and every other chunk to be exactly `target_gop` seconds
+    /// long.
+    pub fn chunk_duration(&self, chunk: u32) -> f64 {
+        if let Some((_, duration)) = self.segment_times.get(&chunk) {
+            return *duration;
+        }
+
+        let is_keyframe_aligned = self.profile.profile_type() == ProfileType::Transmux
+            && self.profile_ctx.input_ctx.keyframes.is_some();
+
+        if is_keyframe_aligned {
+            return self.profile_ctx.chunk_duration(chunk);
+        }
+
+        self.profile_ctx.output_ctx.target_gop as f64
+    }
+
+    /// Returns the time in seconds `chunk` starts at, see `chunk_duration`.
+    pub fn chunk_start(&self, chunk: u32) -> f64 {
+        match self.segment_times.get(&chunk) {
+            Some((start, _)) => *start,
+            None => self.estimated_chunk_start(chunk),
+        }
+    }
+
+    /// Returns where `chunk` starts when ffmpeg gets started at it, ie. where the input is
+    /// seeked to.
+    fn estimated_chunk_start(&self, chunk: u32) -> f64 {
+        if self.profile.profile_type() == ProfileType::Transmux {
+            return self.profile_ctx.chunk_start(chunk);
+        }
+
+        chunk as f64 * self.profile_ctx.output_ctx.target_gop as f64
+    }
+
+    /// Reads the start and duration of the chunks ffmpeg wrote so far out of the playlist the hls
+    /// muxer keeps next to them. The muxer measures `hls_time` from the first packet of a run
+    /// rather than on a global grid, thus once ffmpeg got restarted at a chunk the following
+    /// ones dont start where `target_gop` puts them. The playlist is only read again once it
+    /// changed.
+    pub fn refresh_segment_times(&mut self) {
+        let path = format!("{}/playlist.m3u8", self.rendition_dir(None));
+        let stamp = match fs::metadata(&path) {
+            Ok(x) => (x.modified().unwrap_or(UNIX_EPOCH), x.len()),
+            Err(_) => return,
+        };
+
+        if self.segment_times_read == Some(stamp) {
+            return;
+        }
+
+        let playlist = match fs::read_to_string(&path) {
+            Ok(x) => x,
+            Err(_) => return,
+        };
+
+        self.segment_times_read = Some(stamp);
+
+        let segments = parse_segment_times(&playlist);
+        // every run starts on its first chunk, which is where the input got seeked to. Until the
+        // new run rewrites it, the playlist is the one of the run before.
+        let start = match segments.first() {
+            Some((first, _, _)) if *first == self.start_num() => self.estimated_chunk_start(*first),
+            _ => return,
+        };
+
+        for (chunk, offset, duration) in segments {
+            self.segment_times.insert(chunk, (start + offset, duration));
+            self.chunk_runs.insert(chunk, self.start_num());
+        }
+    }
+
+    /// Returns the sequence number the first fragment of `chunk` gets patched with. Parts get
+    /// patched on their own before their chunk is complete, thus the fragments of sessions
+    /// writing parts are numbered after their chunk rather than counted, leaving gaps which
+    /// ISO/IEC 14496-12 allows.
+    pub fn fragment_seq(&self, chunk: u32) -> u32 {
+        match self.profile_ctx.output_ctx.part_duration {
+            Some(_) => chunk.saturating_mul(FRAGMENTS_PER_CHUNK),
+            None => self.real_segment,
+        }
+    }
+
+    /// Returns the timescale decode times get normalized against, see
+    /// `ProfileContext::target_timescale`. Only the timescale of video tracks gets pinned.
+    pub fn target_timescale(&self) -> Option<u32> {
+        match self.profile.stream_type() {
+            StreamType::Video => self.profile_ctx.target_timescale,
+            _ => None,
+        }
+    }
+
+    pub fn playlist_path(&self) -> String {
+        format!("{}/index.m3u8", self.rendition_dir(None))
+    }
+
+    /// Patches every completed segment again in order, using the chunk index as the sequence
+    /// number and resetting the decode times to the presentation times. Partial segments are
+    /// skipped. Returns how many segments got patched.
+    pub async fn repatch(&mut self) -> usize {
+        let init = self.init_seg();
+        let timescale = tokio::task::spawn_blocking(move || init_segment_timescale(init))
+            .await
+            .ok()
+            .and_then(Result::ok)
+            .flatten()
+            .or(self.target_timescale());
+        let mut patched = 0;
+
+        for chunk in self.available_chunks() {
+            match repatch_segment(self.chunk_to_path(chunk), chunk, timescale).await {
+                Ok(seq) => {
+                    self.real_segment = self.real_segment.max(seq);
+                    patched += 1;
+                }
+                Err(e) => warn!(error = %e, chunk, "Failed to repatch segment."),
+            }
+        }
+
+        patched
+    }
+
+    /// Lists the chunks completed since the last call in the on-disk playlist, so that the outdir
+    /// can be served by a plain web server. Chunks of different ffmpeg runs are separated by
+    /// discontinuities, and the playlist is ended once ffmpeg finished.
+    ///
+    /// Chunks not handed out yet are patched first, the same way `StateManager::chunk_request`
+    /// patches them, on a background task which the next call picks up. Every chunk is thus
+    /// patched once, and a chunk being patched isnt handed out until done, see `is_patching`.
+    pub fn update_playlist(&mut self) {
+        self.refresh_segment_times();
+        self.poll_playlist_patch();
+
+        if self.playlist_patch.is_none() {
+            let chunks = self
+                .available_chunks()
+                .into_iter()
+                .filter(|x| !self.listed_chunks.contains_key(x))
+                .collect::<Vec<_>>();
+
+            if !chunks.is_empty() {
+                self.patch_for_playlist(chunks);
+            }
+        }
+
+        let chunks = self
+            .listed_chunks
+            .iter()
+            .map(|(&chunk, &start)| (chunk, start))
+            .collect::<Vec<_>>();
+        let playlist = self.render_playlist(&chunks, None);
+
+        if self.written_playlist.as_ref() == Some(&playlist) {
+            return;
+        }
+
+        self.written_playlist = Some(playlist.clone());
+
+        let path = self.playlist_path();
+        // write to a temporary file first so that the web server never serves a partial playlist.
+        tokio::task::spawn_blocking(move || {
+            let tmp = format!("{}.tmp", path);
+            if fs::write(&tmp, playlist).is_ok() {
+                let _ = fs::rename(&tmp, path);
+            }
+        });
+    }
+
+    /// Returns whether `chunk` is being patched for the on-disk playlist.
+    pub fn is_patching(&self, chunk: u32) -> bool {
+        self.playlist_patch
+            .as_ref()
+            .is_some_and(|x| x.chunks.contains(&chunk) && !self.patched_chunks.contains(&chunk))
+    }
+
+    fn patch_for_playlist(&mut self, chunks: Vec<u32>) {
+        let timescale = self.target_timescale();
+        // sessions writing parts number the fragments after their chunk, see `fragment_seq`.
+        let per_chunk = self.profile_ctx.output_ctx.part_duration.is_some();
+        let mut seq = chunks
+            .iter()
+            .find(|x| !self.patched_chunks.contains(x))
+            .map(|x| self.fragment_seq(*x))
+            .unwrap_or(self.real_segment);
+        let pending = chunks
+            .iter()
+            .filter(|x| !self.patched_chunks.contains(x))
+            .map(|&x| {
+                let start = self.run_start(x);
+                (x, self.chunk_to_path(x), start, self.custom_init_seg(start))
+            })
+            .collect::<Vec<_>>();
+        let (tx, done) = oneshot::channel();
+
+        tokio::spawn(async move {
+            let mut results = Vec::with_capacity(pending.len());
+
+            for (chunk, path, start, init) in pending {
+                if per_chunk {
+                    seq = chunk.saturating_mul(FRAGMENTS_PER_CHUNK);
+                }
+
+                let patched = match patch_segment(path.clone(), seq, timescale).await {
+                    // only the first chunk of a run can have its data left in the init segment,
+                    // which has to move into the chunk as the playlist lists it on its own.
+                    Err(NightfallError::PartialSegment(_)) if chunk == start => {
+                        patch_init_segment(init, path, seq, timescale).await
+                    }
+                    x => x,
+                };
+
+                if let Ok(next) = patched {
+                    seq = next;
+                }
+
+                results.push((chunk, patched));
+            }
+
+            let _ = tx.send(results);
+        });
+
+        self.playlist_patch = Some(PlaylistPatch { chunks, done });
+    }
+
+    /// Lists the chunks patched by the background task once it is done.
+    fn poll_playlist_patch(&mut self) {
+        let results = match self.playlist_patch.as_mut().map(|x| x.done.try_recv()) {
+            Some(Ok(x)) => x,
+            Some(Err(oneshot::error::TryRecvError::Empty)) | None => return,
+            Some(Err(oneshot::error::TryRecvError::Closed)) => Vec::new(),
+        };
+
+        let patch = self.playlist_patch.take().unwrap();
+
+        for (chunk, result) in results {
+            match result {
+                Ok(seq) => {
+                    self.patched_chunks.insert(chunk);
+                    self.real_segment = self.real_segment.max(seq);
+                }
+                Err(e) => warn!(error = %e, chunk, "Failed to patch segment for playlist."),
+            }
+        }
+
+        for chunk in patch.chunks {
+            // chunks which failed to patch are picked up again by the next call.
+            if self.patched_chunks.contains(&chunk) {
+                self.listed_chunks.insert(chunk, self.run_start(chunk));
+            }
+        }
+    }
+
+    /// Renders an EVENT playlist listing `chunks`, given in order as pairs of the chunk index and
+    /// of the chunk its init segment is named after. A discontinuity is inserted whenever either
+    /// doesnt follow the previous chunk, and the playlist is ended once ffmpeg finished.
+    ///
+    /// With `part_duration` the playlist gets the Low-Latency HLS tags, the parts of the last
+    /// chunks as well as of the chunk being written are listed as `N.M.m4s`.
+    fn render_playlist(&self, chunks: &[(u32, u32)], part_duration: Option<f32>) -> String {
+        let extension = self.profile.container().segment_extension();
+        let first = chunks.first().map(|(x, _)| *x).unwrap_or(0);
+        // chunks cut on source keyframes can run longer than `target_gop`.
+        let target_duration = chunks
+            .iter()
+            .map(|(x, _)| self.chunk_duration(*x).round() as u32)
+            .fold(self.profile_ctx.output_ctx.target_gop, u32::max);
+
+        let mut playlist = String::new();
+        let _ = writeln!(playlist, "#EXTM3U");
+        let _ = writeln!(playlist, "#EXT-X-VERSION:7");
+        let _ = writeln!(playlist, "#EXT-X-TARGETDURATION:{}", target_duration);
+        let _ = writeln!(playlist, "#EXT-X-PLAYLIST-TYPE:EVENT");
+        let _ = writeln!(playlist, "#EXT-X-MEDIA-SEQUENCE:{}", first);
+
+        if let Some(part_duration) = part_duration {
+            let _ = writeln!(
+                playlist,
+                "#EXT-X-SERVER-CONTROL:CAN-BLOCK-RELOAD=YES,PART-HOLD-BACK={:.3}",
+                part_duration * 3.0
+            );
+            let _ = writeln!(playlist, "#EXT-X-PART-INF:PART-TARGET={:.3}", part_duration);
+        }
+
+        // parts only have to be listed for the segments close to the live edge.
+        let parts_from = chunks.len().saturating_sub(3);
+        // whether the segments listed next are encrypted, see `SegmentKey::ext_x_key`.
+        let mut is_keyed = false;
+
+        let mut previous: Option<(u32, u32)> = None;
+        for (idx, &(chunk, start)) in chunks.iter().enumerate() {
+            let is_continuous = previous.is_some_and(|(c, s)| c + 1 == chunk && s == start);
+
+            if !is_continuous && previous.is_some() {
+                let _ = writeln!(playlist, "#EXT-X-DISCONTINUITY");
+            }
+
+            // MPEG-TS chunks carry everything needed to decode them.
+            if !is_continuous && self.profile.container().has_init_segment() {
+                // init segments stay in the clear, but would be decrypted with the key in effect.
+                if is_keyed {
+                    let _ = writeln!(playlist, "#EXT-X-KEY:METHOD=NONE");
+                    is_keyed = false;
+                }
+
+                let _ = writeln!(
+                    playlist,
+                    "#EXT-X-MAP:URI=\"{}_init.{}\"",
+                    start,
+                    self.profile.container().init_extension()
+                );
+            }
+
+            if let Some(key) = self.encryption {
+                let sequence = first + idx as u32;
+
+                if !is_keyed || chunk != sequence {
+                    let iv = Some(chunk).filter(|x| *x != sequence);
+                    let _ = writeln!(playlist, "{}", key.ext_x_key(KEY_URI, iv));
+                    is_keyed = true;
+                }
+            }
+
+            if let Some(part_duration) = part_duration.filter(|_| idx >= parts_from) {
+                let parts = self.chunk_parts(chunk).len();
+                self.render_parts(&mut playlist, chunk, parts, part_duration, true);
+            }
+
+            let _ = writeln!(playlist, "#EXTINF:{:.3},", self.chunk_duration(chunk));
+            let _ = writeln!(playlist, "{}.{}", chunk, extension);
+
+            previous = Some((chunk, start));
+        }
+
+        if self.is_dead() && self.exit_status.is_some_and(|x| x.success()) {
+            let _ = writeln!(playlist, "#EXT-X-ENDLIST");
+            return playlist;
+        }
+
+        let part_duration = match part_duration {
+            Some(x) => x,
+            None => return playlist,
+        };
+
+        // the chunk ffmpeg is writing right now, whose parts are listed ahead of the segment.
+        let start_num = self.start_num();
+        let next = (start_num..)
+            .take_while(|x| self.chunk_count().is_none_or(|count| *x < count))
+            .find(|x| !self.is_chunk_done(*x));
+
+        if let Some(next) = next {
+            let is_continuous = previous.is_some_and(|(chunk, _)| chunk + 1 == next);
+
+            if !is_continuous && previous.is_some() {
+                let _ = writeln!(playlist, "#EXT-X-DISCONTINUITY");
+            }
+
+            if !is_continuous && self.profile.container().has_init_segment() {
+                let _ = writeln!(
+                    playlist,
+                    "#EXT-X-MAP:URI=\"{}_init.{}\"",
+                    start_num,
+                    self.profile.container().init_extension()
+                );
+            }
+
+            let parts = self.chunk_parts(next).len();
+            self.render_parts(&mut playlist, next, parts, part_duration, false);
+
+            let _ = writeln!(
+                playlist,
+                "#EXT-X-PRELOAD-HINT:TYPE=PART,URI=\"{}.{}.{}\"",
+                next, parts, extension
+            );
+        }
+
+        playlist
+    }
+
+    /// Writes the `EXT-X-PART` tags of the first `parts` parts of `chunk`. The last part of a
+    /// complete chunk is whatever remains of the chunk duration.
+    fn render_parts(
+        &self,
+        playlist: &mut String,
+        chunk: u32,
+        parts: usize,
+        part_duration: f32,
+        is_complete: bool,
+    ) {
+        let extension = self.profile.container().segment_extension();
+        let chunk_duration = self.chunk_duration(chunk) as f32;
+
+        for part in 0..parts {
+            let duration = if is_complete && part + 1 == parts {
+                (chunk_duration - part_duration * part as f32).max(0.001)
+            } else {
+                part_duration
+            };
+            // only the first part of a chunk is guaranteed to start with a keyframe.
+            let independent = if part == 0 { ",INDEPENDENT=YES" } else { "" };
+
+            let _ = writeln!(
+                playlist,
+                "#EXT-X-PART:DURATION={:.3},URI=\"{}.{}.{}\"{}",
+                duration, chunk, part, extension, independent
+            );
+        }
+    }
+
+    /// Opens `chunk`, or the temporary file ffmpeg writes it into until it is complete. The file
+    /// stays readable if ffmpeg renames it in the meantime.
+    fn open_chunk(&self, chunk: u32) -> Option<File> {
+        let path = self.chunk_to_path(chunk);
+
+        if !self.is_chunk_done(chunk) {
+            if let Ok(file) = File::open(format!("{}.tmp", path)) {
+                return Some(file);
+            }
+        }
+
+        File::open(path).ok()
+    }
+
+    /// Returns the byte ranges of the parts of `chunk` written so far, see
+    /// `OutputCtx::part_duration`. Only the box headers are read.
+    pub fn chunk_parts(&self, chunk: u32) -> Vec<(u64, u64)> {
+        self.open_chunk(chunk)
+            .map(|mut x| file_fragment_ranges(&mut x))
+            .unwrap_or_default()
+    }
+
+    /// Returns part `part` of `chunk`, patched just like the whole chunk gets patched once
+    /// complete, or `None` if it hasnt been written yet.
+    pub fn read_part(&self, chunk: u32, part: u32) -> Result<Option<Vec<u8>>, NightfallError> {
+        let mut file = match self.open_chunk(chunk) {
+            Some(x) => x,
+            None => return Ok(None),
+        };
+
+        let (offset, len) = match file_fragment_ranges(&mut file).get(part as usize) {
+            Some(x) => *x,
+            None => return Ok(None),
+        };
+
+        let mut data = vec![0; len as usize];
+        file.seek(SeekFrom::Start(offset))?;
+        file.read_exact(&mut data)?;
+
+        // a chunk patched already is patched again with the same values, which changes nothing.
+        patch_fragment(
+            &data,
+            self.fragment_seq(chunk) + part,
+            self.target_timescale(),
+        )
+        .map(Some)
+    }
+
+    /// Returns an EVENT playlist listing the chunks that are done right now. Chunks share the
+    /// init segment of the ffmpeg run which wrote them, named after its first chunk, ex.
+    /// `5_init.mp4`, which is what `chunk_init_request` hands out for that chunk.
+    pub fn event_playlist(&self) -> String {
+        self.render_playlist(&self.done_chunk_runs(), None)
+    }
+
+    /// Same as `event_playlist` but with the Low-Latency HLS tags and parts, when the session
+    /// was created with `OutputCtx::part_duration`.
+    pub fn low_latency_playlist(&self) -> String {
+        self.render_playlist(
+            &self.done_chunk_runs(),
+            self.profile_ctx.output_ctx.part_duration,
+        )
+    }
+
+    /// Returns the chunks that are done right now, paired with the start number of the ffmpeg
+    /// run which wrote them.
+    fn done_chunk_runs(&self) -> Vec<(u32, u32)> {
+        self.available_chunks()
+            .into_iter()
+            .filter(|x| self.is_chunk_done(*x))
+            .map(|chunk| (chunk, self.run_start(chunk)))
+            .collect()
+    }
+
+    /// Returns the start number of the ffmpeg run which wrote `chunk`, as recorded by
+    /// `refresh_segment_times`. Chunks not listed by the hls muxer yet are assumed to come from
+    /// the current run when past its start, and from the run of the chunk before otherwise.
+    pub fn run_start(&self, chunk: u32) -> u32 {
+        if let Some(start) = self.chunk_runs.get(&chunk) {
+            return *start;
+        }
+
+        if chunk >= self.start_num() {
+            return self.start_num();
+        }
+
+        self.chunk_runs
+            .range(..chunk)
+            .next_back()
+            .map_or(chunk, |(_, start)| *start)
+    }
+
+    /// Returns the peak bitrate of the session in bits per second, as HLS variants have to
+    /// advertise, measured over the chunks written so far. The requested bitrate is an average,
+    /// thus only used as a floor, and the input bitrate is used before any chunk is written.
+    pub fn bandwidth(&self) -> u64 {
+        let peak = self
+            .available_chunks()
+            .into_iter()
+            .filter_map(|x| {
+                let bytes = fs::metadata(self.chunk_to_path(x)).ok()?.len();
+                let duration = self.chunk_duration(x);
+
+                (bytes > 0 && duration > 0.0).then(|| (bytes as f64 * 8.0 / duration) as u64)
+            })
+            .max();
+
+        match (peak, self.profile_ctx.output_ctx.bitrate) {
+            (Some(peak), bitrate) => peak.max(bitrate.unwrap_or(0)),
+            (None, Some(bitrate)) => bitrate,
+            (None, None) => self.profile_ctx.input_ctx.bitrate,
+        }
+    }
+
+    /// Returns the tags of the input stream the session outputs, if it was probed into
+    /// `InputCtx::audio_streams` or `InputCtx::subtitle_streams`.
+    pub fn stream_tags(&self) -> Option<&Tags> {
+        let input_ctx = &self.profile_ctx.input_ctx;
+        let streams = match self.profile.stream_type() {
+            StreamType::Audio => &input_ctx.audio_streams,
+            StreamType::Subtitle => &input_ctx.subtitle_streams,
+            _ => return None,
+        };
+
+        streams
+            .iter()
+            .find(|x| x.index as usize == input_ctx.stream)?
+            .tags
+            .as_ref()
+    }
+
+    /// Returns the RFC 6381 codecs string of the stream this session outputs, see
+    /// `hls::codecs_string`.
+    pub fn codecs(&self) -> Option<String> {
+        let init = fs::read(self.init_seg()).ok();
+        // trick-mode tracks are plain h264 with a keyframe for every frame.
+        let codec = match self.profile_ctx.output_ctx.codec.as_str() {
+            "trickplay" => "h264",
+            x => x,
+        };
+
+        codecs_string(codec, init.as_deref())
+    }
+
+    pub fn last_chunk(&self) -> u32 {
+        self.last_chunk
+    }
+
+    /// Returns the path of the init segment clients should use for `start_num`. If we preserved an
+    /// init segment across resets and it matches the one ffmpeg just wrote we keep returning the
+    /// preserved one, otherwise the preserved init segment is discarded.
+    pub fn resolve_init_seg(&mut self, start_num: u32) -> String {
+        let current = self.custom_init_seg(start_num);
+
+        if let Some(preserved) = self.preserved_init {
+            let preserved = self.custom_init_seg(preserved);
+
+            match init_segments_compatible(&preserved, &current) {
+                Ok(true) => return preserved,
+                Ok(false) => {
+                    debug!(session = %self.id, "Init segment changed, dropping preserved init.");
+                }
+                Err(e) => {
+                    debug!(session = %self.id, error = %e, "Failed to compare init segments.");
+                }
+            }
+
+            self.preserved_init = None;
+        }
+
+        current
+    }
+
+    /// Returns whether the init segment handed out to clients stays valid across resets.
+    pub fn preserves_init(&self) -> bool {
+        self.preserved_init.is_some()
+    }
+
+    /// Returns the size in bytes of everything written to the outdir of the session, as last
+    /// measured by `measure_outdir`.
+    pub fn outdir_size(&self) -> u64 {
+        self.outdir_size.load(Ordering::Relaxed)
+    }
+
+    /// Measures the size of the outdir on the blocking pool and caches it for `outdir_size`.
+    pub fn measure_outdir(&self) -> impl std::future::Future<Output = u64> + Send + 'static {
+        let outdir = self.profile_ctx.output_ctx.outdir.clone();
+        let cached = self.outdir_size.clone();
+
+        async move {
+            let size = tokio::task::spawn_blocking(move || dir_size(Path::new(&outdir)))
+                .await
+                .unwrap_or(0);
+
+            cached.store(size, Ordering::Relaxed);
+            size
+        }
+    }
+
     pub fn has_started(&self) -> bool {
         self.has_started
     }
 
     pub fn reset_to(&mut self, chunk: u32) {
+        // records which chunks the run ending here wrote, see `run_start`.
+        self.refresh_segment_times();
+
+        // the new run overwrites every chunk from `chunk` on, these have to be patched again.
+        self.playlist_patch = None;
+        self.listed_chunks.retain(|&x, _| x < chunk);
+        self.patched_chunks.retain(|&x| x < chunk);
+        for patched in self.patched_renditions.values_mut() {
+            patched.retain(|&x| x < chunk);
+        }
+        self.rendition_seqs.clear();
+        self.hooked_chunks.retain(|&x| x < chunk);
+        self.segment_times.retain(|&x, _| x < chunk);
+        self.reported_chunk = None;
         self.profile_ctx.output_ctx.start_num = chunk;
         self._process = None;
+        self._stderr = None;
         self.last_chunk = chunk;
         self.has_started = false;
         self.is_throttled = true;
+        self.is_preempted = false;
         self.real_segment = chunk;
         self.child_pid = None;
     }
@@ -385,53 +2108,225 @@ impl fmt::Debug for Session {
     }
 }
 
+/// Returns whether `name` is one of the files a session writes into its outdir.
+fn is_artifact(name: &str) -> bool {
+    let name = name.strip_suffix(".tmp").unwrap_or(name);
+    let files = [
+        STATE_FILE,
+        "playlist.m3u8",
+        "index.m3u8",
+        "media.mp4",
+        "timed_text.mp4",
+        "stream",
+        crate::webvtt::SOURCE_FILE,
+        crate::thumbnails::SPRITE_INDEX_FILE,
+        crate::thumbnails::BIF_FILE,
+    ];
+
+    if files.contains(&name) || (name.starts_with("ffmpeg_") && name.ends_with(".log")) {
+        return true;
+    }
+
+    // chunks along with their parts and copies, init segments, thumbnails and subtitle cues are
+    // all named after their index.
+    let stem = name.split('.').next().unwrap_or(name);
+    let stem = stem.strip_suffix("_init").unwrap_or(stem);
+
+    !stem.is_empty() && stem.bytes().all(|x| x.is_ascii_digit())
+}
+
 struct StdoutParser {
     id: String,
-    process_stdout: ChildStdout,
-    pid: u32,
+    process_stdout: ProcessOutput,
+    progress: watch::Sender<Option<Progress>>,
+    /// See `Session::last_activity`.
+    last_activity: Arc<AtomicU64>,
+    /// Duration of the input in seconds, used to tell how far along ffmpeg is.
+    duration: Option<f64>,
 }
 
 impl StdoutParser {
-    fn new(id: String, process_stdout: ChildStdout, pid: u32) -> Self {
+    fn new(
+        id: String,
+        process_stdout: ProcessOutput,
+        progress: watch::Sender<Option<Progress>>,
+        last_activity: Arc<AtomicU64>,
+        duration: Option<f64>,
+    ) -> Self {
         Self {
             id,
             process_stdout,
-            pid,
+            progress,
+            last_activity,
+            duration,
+        }
+    }
+
+    /// Builds a `Progress` out of the keys of the last report.
+    fn parse_progress(map: &HashMap<String, String>, duration: Option<f64>) -> Progress {
+        let out_time = map
+            .get("out_time_us")
+            .and_then(|x| x.parse::<i64>().ok())
+            .map(|x| Duration::from_micros(x.max(0) as u64));
+
+        Progress {
+            out_time,
+            speed: map
+                .get("speed")
+                .and_then(|x| x.trim_end_matches('x').parse().ok()),
+            frame: map.get("frame").and_then(|x| x.parse().ok()),
+            fraction: out_time
+                .zip(duration.filter(|x| *x > 0.0))
+                .map(|(t, d)| (t.as_secs_f64() / d).min(1.0)),
+            done: map.get("progress").is_some_and(|x| x == "end"),
         }
     }
 
     async fn handle(self) {
         let mut stdio = LinesStream::new(BufReader::new(self.process_stdout).lines());
         let mut map: HashMap<String, String> = HashMap::new();
+        let mut speed_ema: Option<f64> = None;
 
-        let interval = tokio::time::interval(Duration::from_millis(100));
-        tokio::pin!(interval);
+        // stdout gets closed once the process exits, which ends the stream.
+        while let Some(Ok(v)) = stdio.next().await {
+            let output: Vec<&str> = v.split('=').collect();
 
-        loop {
-            tokio::select! {
-                _ = interval.tick() => {
-                    if crate::utils::is_process_effectively_dead(self.pid) {
-                        break;
-                    }
-                },
+            // remove whitespace on both ends
+            map.insert(output[0].into(), output[1].trim_start().trim_end().into());
 
-                Some(Ok(v)) = stdio.next() => {
-                    let output: Vec<&str> = v.split('=').collect();
+            // ffmpeg ends every progress report with this key.
+            if output[0] == "progress" {
+                let now = SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .unwrap_or_default();
+                self.last_activity
+                    .store(now.as_millis() as u64, Ordering::Relaxed);
 
-                    // remove whitespace on both ends
-                    map.insert(output[0].into(), output[1].trim_start().trim_end().into());
-
-                    {
-                        let mut lock = STREAMING_SESSION.write().unwrap();
-                        let _ = lock.insert(self.id.clone(), map.clone());
-                    }
+                let progress = Self::parse_progress(&map, self.duration);
+                self.progress.send_replace(Some(progress));
+            }
 
-                    continue;
+            if output[0] == "speed" {
+                if let Ok(speed) = output[1].trim().trim_end_matches('x').parse::<f64>() {
+                    let ema = speed_ema.map_or(speed, |x| x * 0.8 + speed * 0.2);
+                    speed_ema = Some(ema);
+                    map.insert("speed_ema".into(), ema.to_string());
                 }
             }
+
+            let mut lock = STREAMING_SESSION.write().unwrap();
+            let _ = lock.insert(self.id.clone(), map.clone());
         }
 
         let mut lock = STREAMING_SESSION.write().unwrap();
         let _ = lock.remove(&self.id);
     }
 }
+
+/// Copies everything ffmpeg writes to stderr into the session log file.
+struct StderrDrain {
+    process_stderr: ProcessOutput,
+    log: tokio::fs::File,
+    dts_warnings: Arc<AtomicU64>,
+}
+
+impl StderrDrain {
+    fn new(
+        process_stderr: ProcessOutput,
+        log: tokio::fs::File,
+        dts_warnings: Arc<AtomicU64>,
+    ) -> Self {
+        Self {
+            process_stderr,
+            log,
+            dts_warnings,
+        }
+    }
+
+    async fn handle(self) {
+        let mut reader = BufReader::new(self.process_stderr);
+        let mut log = self.log;
+        let mut line = Vec::new();
+
+        // We keep reading even if writing to the log fails, the point is to never let the pipe
+        // fill up.
+        while let Ok(n) = reader.read_until(b'\n', &mut line).await {
+            if n == 0 {
+                break;
+            }
+
+            if is_dts_warning(&line) {
+                self.dts_warnings.fetch_add(1, Ordering::Relaxed);
+            }
+
+            let _ = log.write_all(&line).await;
+            line.clear();
+        }
+
+        let _ = log.flush().await;
+    }
+}
+
+/// Function moves the directory `from` to `to`, falling back to copying when they are on
+/// different filesystems. A link to `to` is left in place of `from` if `link` is set.
+fn move_dir(from: &Path, to: &Path, link: bool) -> io::Result<()> {
+    if !from.exists() {
+        return Ok(());
+    }
+
+    if let Some(parent) = to.parent() {
+        fs::create_dir_all(parent)?;
+    }
+
+    if fs::rename(from, to).is_err() {
+        copy_dir(from, to)?;
+        fs::remove_dir_all(from)?;
+    }
+
+    if link {
+        crate::utils::link_dir(to, from)?;
+    }
+
+    Ok(())
+}
+
+fn copy_dir(from: &Path, to: &Path) -> io::Result<()> {
+    fs::create_dir_all(to)?;
+
+    for entry in fs::read_dir(from)? {
+        let entry = entry?;
+        let target = to.join(entry.file_name());
+
+        if entry.file_type()?.is_dir() {
+            copy_dir(&entry.path(), &target)?;
+        } else {
+            fs::copy(entry.path(), target)?;
+        }
+    }
+
+    Ok(())
+}
+
+fn dir_size(dir: &Path) -> u64 {
+    let entries = match fs::read_dir(dir) {
+        Ok(x) => x,
+        Err(_) => return 0,
+    };
+
+    entries
+        .flatten()
+        .map(|entry| match entry.metadata() {
+            Ok(x) if x.is_dir() => dir_size(&entry.path()),
+            Ok(x) => x.len(),
+            Err(_) => 0,
+        })
+        .sum()
+}
+
+/// Returns whether a line of ffmpeg's stderr warns about non-monotonic timestamps, which usually
+/// means that packets got dropped or duplicated.
+fn is_dts_warning(line: &[u8]) -> bool {
+    let line = String::from_utf8_lossy(line);
+
+    line.contains("Non-monotonous DTS") || line.contains("non monotonically increasing dts")
+}
@@ -0,0 +1,477 @@
+use std::collections::hash_map::DefaultHasher;
+use std::fmt::Write as _;
+use std::fs;
+use std::hash::Hash;
+use std::hash::Hasher;
+use std::path::Path;
+use std::time::Duration;
+
+use crate::NightfallError;
+use crate::Result;
+
+use serde_derive::{Deserialize, Serialize};
+
+/// Name of the WebVTT file indexing the sprite sheets.
+pub const SPRITE_INDEX_FILE: &str = "thumbnails.vtt";
+/// Name of the BIF file holding every thumbnail.
+pub const BIF_FILE: &str = "thumbnails.bif";
+/// Name of the directory under `StateManager::outdir` screenshots are written into.
+pub const SCREENSHOT_DIR: &str = "screenshots";
+
+/// Magic number every BIF file starts with.
+const BIF_MAGIC: [u8; 8] = [0x89, 0x42, 0x49, 0x46, 0x0D, 0x0A, 0x1A, 0x0A];
+/// Size of the BIF header, the index follows it.
+const BIF_HEADER_SIZE: usize = 64;
+
+/// Niceness thumbnail jobs run with unless `ProfileContext::nice` says otherwise, so that they
+/// dont slow down the sessions players are waiting on.
+pub const THUMBNAIL_NICE: i32 = 10;
+
+/// What trickplay thumbnails are packaged as.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ThumbnailFormat {
+    /// JPEG sprite sheets of `columns`x`rows` thumbnails along with a WebVTT index pointing at
+    /// every thumbnail with a `#xywh=` fragment, as used by most web players.
+    #[default]
+    Sprite,
+    /// A single BIF file, as used by Roku and some TV players.
+    Bif,
+}
+
+/// How `ThumbnailProfile` generates trickplay thumbnails, see `OutputCtx::thumbnails`.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ThumbnailConfig {
+    pub format: ThumbnailFormat,
+    /// Seconds between two thumbnails.
+    pub interval: u32,
+    /// Width of a thumbnail in pixels, smaller inputs arent upscaled.
+    pub width: u32,
+    /// Layout of the sprite sheets, ignored by BIF.
+    pub columns: u32,
+    pub rows: u32,
+}
+
+impl Default for ThumbnailConfig {
+    fn default() -> Self {
+        Self {
+            format: ThumbnailFormat::default(),
+            interval: 2,
+            width: 320,
+            columns: 8,
+            rows: 6,
+        }
+    }
+}
+
+impl ThumbnailConfig {
+    /// Function checks that the config describes thumbnails that can be generated.
+    pub fn validate(&self) -> Result<()> {
+        if self.interval == 0 || self.width == 0 {
+            return Err(NightfallError::InvalidConfig(
+                "Thumbnail interval and width must be positive.".into(),
+            ));
+        }
+
+        if self.format == ThumbnailFormat::Sprite && (self.columns == 0 || self.rows == 0) {
+            return Err(NightfallError::InvalidConfig(
+                "Sprite sheets need at least one column and one row.".into(),
+            ));
+        }
+
+        Ok(())
+    }
+
+    /// Returns the ffmpeg video filter producing the images of this config.
+    pub fn filter(&self) -> String {
+        let mut filter = format!(
+            "fps=1/{},scale=min({}\\,iw):ow/dar",
+            self.interval, self.width
+        );
+
+        if self.format == ThumbnailFormat::Sprite {
+            let _ = write!(filter, ",tile={}x{}", self.columns, self.rows);
+        }
+
+        filter
+    }
+
+    /// Returns the name of the index file of this config, relative to the session outdir.
+    pub fn index_file(&self) -> &'static str {
+        match self.format {
+            ThumbnailFormat::Sprite => SPRITE_INDEX_FILE,
+            ThumbnailFormat::Bif => BIF_FILE,
+        }
+    }
+}
+
+/// Formats seconds as a WebVTT timestamp, `hh:mm:ss.ttt`.
+fn timestamp(seconds: f64) -> String {
+    let millis = (seconds * 1000.0).round() as u64;
+
+    format!(
+        "{:02}:{:02}:{:02}.{:03}",
+        millis / 3_600_000,
+        millis / 60_000 % 60,
+        millis / 1000 % 60,
+        millis % 1000
+    )
+}
+
+/// Returns the width and height of a JPEG image out of its SOF marker.
+fn jpeg_size(data: &[u8]) -> Option<(u32, u32)> {
+    if data.get(..2)? != [0xFF, 0xD8] {
+        return None;
+    }
+
+    let mut pos = 2;
+
+    loop {
+        let marker = data.get(pos..pos + 4)?;
+
+        if marker[0] != 0xFF {
+            return None;
+        }
+
+        // SOF0 to SOF15, except DHT, JPG and DAC which share the range.
+        if (0xC0..=0xCF).contains(&marker[1]) && ![0xC4, 0xC8, 0xCC].contains(&marker[1]) {
+            let sof = data.get(pos + 5..pos + 9)?;
+            let height = u16::from_be_bytes([sof[0], sof[1]]);
+            let width = u16::from_be_bytes([sof[2], sof[3]]);
+
+            return Some((width as u32, height as u32));
+        }
+
+        pos += 2 + u16::from_be_bytes([marker[2], marker[3]]) as usize;
+    }
+}
+
+/// Returns the names of the images ffmpeg wrote into `dir`, in order.
+fn images(dir: &Path) -> Result<Vec<String>> {
+    let mut images = fs::read_dir(dir)?
+        .filter_map(|x| x.ok())
+        .filter_map(|x| x.file_name().into_string().ok())
+        .filter(|x| x.ends_with(".jpg"))
+        .collect::<Vec<_>>();
+
+    // names are zero padded, thus sort in order.
+    images.sort();
+
+    Ok(images)
+}
+
+/// Function returns the WebVTT index of sprite sheets, with one cue per thumbnail pointing at its
+/// tile with a `#xywh=` fragment. Sheets are referenced by their name, which servers usually
+/// rewrite into the URL `StateManager::get_thumbnail` is served at.
+///
+/// # Arguments
+/// * `sheets` - name and size of every sprite sheet, in order.
+/// * `config` - config the sheets were generated with.
+/// * `duration` - duration of the media, if unknown every tile of the last sheet gets a cue.
+pub fn sprite_index(
+    sheets: &[(String, (u32, u32))],
+    config: &ThumbnailConfig,
+    duration: Option<f64>,
+) -> String {
+    let mut vtt = String::from("WEBVTT\n");
+    let per_sheet = config.columns * config.rows;
+    let interval = config.interval as f64;
+
+    for (i, (name, (width, height))) in sheets.iter().enumerate() {
+        let tile_width = width / config.columns;
+        let tile_height = height / config.rows;
+
+        for tile in 0..per_sheet {
+            let start = (i as u32 * per_sheet + tile) as f64 * interval;
+
+            if duration.is_some_and(|x| start >= x) {
+                return vtt;
+            }
+
+            let end = duration.map_or(start + interval, |x| x.min(start + interval));
+
+            let _ = write!(
+                vtt,
+                "\n{} --> {}\n{}#xywh={},{},{},{}\n",
+                timestamp(start),
+                timestamp(end),
+                name,
+                tile % config.columns * tile_width,
+                tile / config.columns * tile_height,
+                tile_width,
+                tile_height
+            );
+        }
+    }
+
+    vtt
+}
+
+/// Function packs JPEG images into a BIF file, the i-th image being shown from `i * interval`
+/// seconds on.
+pub fn bif(images: &[Vec<u8>], interval: u32) -> Vec<u8> {
+    let index_size = (images.len() + 1) * 8;
+    let mut out = Vec::with_capacity(
+        BIF_HEADER_SIZE + index_size + images.iter().map(Vec::len).sum::<usize>(),
+    );
+
+    out.extend_from_slice(&BIF_MAGIC);
+    // version
+    out.extend_from_slice(&0u32.to_le_bytes());
+    out.extend_from_slice(&(images.len() as u32).to_le_bytes());
+    // timestamps in the index are multiples of this many milliseconds.
+    out.extend_from_slice(&(interval * 1000).to_le_bytes());
+    out.resize(BIF_HEADER_SIZE, 0);
+
+    let mut offset = BIF_HEADER_SIZE + index_size;
+
+    for (i, image) in images.iter().enumerate() {
+        out.extend_from_slice(&(i as u32).to_le_bytes());
+        out.extend_from_slice(&(offset as u32).to_le_bytes());
+        offset += image.len();
+    }
+
+    // the last entry marks the end of the last image.
+    out.extend_from_slice(&u32::MAX.to_le_bytes());
+    out.extend_from_slice(&(offset as u32).to_le_bytes());
+
+    for image in images {
+        out.extend_from_slice(image);
+    }
+
+    out
+}
+
+/// Function writes the index of the thumbnails ffmpeg generated into `dir`, either the WebVTT
+/// index of the sprite sheets or the BIF file, and returns its path. Has to be called once ffmpeg
+/// is done, as the index only covers the images written so far.
+pub fn write_index(
+    dir: impl AsRef<Path>,
+    config: &ThumbnailConfig,
+    duration: Option<f64>,
+) -> Result<String> {
+    let dir = dir.as_ref();
+    let images = images(dir)?;
+
+    let index = match config.format {
+        ThumbnailFormat::Sprite => {
+            let sheets = images
+                .into_iter()
+                .map(|name| {
+                    let size = jpeg_size(&fs::read(dir.join(&name))?).ok_or_else(|| {
+                        NightfallError::ThumbnailIndexError(format!("{} isnt a valid JPEG.", name))
+                    })?;
+
+                    Ok((name, size))
+                })
+                .collect::<Result<Vec<_>>>()?;
+
+            sprite_index(&sheets, config, duration).into_bytes()
+        }
+        ThumbnailFormat::Bif => {
+            let images = images
+                .iter()
+                .map(|name| fs::read(dir.join(name)))
+                .collect::<std::io::Result<Vec<_>>>()?;
+
+            bif(&images, config.interval)
+        }
+    };
+
+    let path = dir.join(config.index_file());
+    let tmp = dir.join(format!("{}.tmp", config.index_file()));
+
+    // written through a temporary file so that a concurrent request never reads a partial index.
+    fs::write(&tmp, index)?;
+    fs::rename(&tmp, &path)?;
+
+    Ok(path.to_string_lossy().into_owned())
+}
+
+/// Options of the frames extracted by `StateManager::screenshot`.
+#[derive(Clone, Debug, Hash, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ScreenshotOptions {
+    /// Width of the image in pixels, smaller inputs arent upscaled. The input width is kept when
+    /// `None`.
+    pub width: Option<u32>,
+    /// JPEG quality on the scale of ffmpeg's `-q:v`, from 2, the best, to 31.
+    pub quality: u32,
+    /// Stream to take the frame from, the first video stream when `None`.
+    pub stream: Option<usize>,
+}
+
+impl Default for ScreenshotOptions {
+    fn default() -> Self {
+        Self {
+            width: None,
+            quality: 2,
+            stream: None,
+        }
+    }
+}
+
+impl ScreenshotOptions {
+    /// Function checks that the options can be passed on to ffmpeg.
+    pub fn validate(&self) -> Result<()> {
+        if !(2..=31).contains(&self.quality) {
+            return Err(NightfallError::InvalidConfig(format!(
+                "Screenshot quality must be between 2 and 31, got {}.",
+                self.quality
+            )));
+        }
+
+        if self.width == Some(0) {
+            return Err(NightfallError::InvalidConfig(
+                "Screenshot width must be positive.".into(),
+            ));
+        }
+
+        Ok(())
+    }
+
+    /// Returns the ffmpeg args extracting the frame at `timestamp` seconds of `file` into `out`.
+    pub fn args(&self, file: &str, timestamp: f64, out: &str) -> Vec<String> {
+        let stream = match self.stream {
+            Some(x) => format!("0:{}", x),
+            None => "0:v:0".into(),
+        };
+
+        let mut args = vec![
+            "-y".into(),
+            "-ss".into(),
+            timestamp.to_string(),
+            "-i".into(),
+            file.into(),
+            "-map".into(),
+            stream,
+            "-frames:v".into(),
+            "1".into(),
+        ];
+
+        if let Some(width) = self.width {
+            args.push("-vf".into());
+            args.push(format!("scale=min({}\\,iw):-2", width));
+        }
+
+        args.append(&mut vec![
+            "-q:v".into(),
+            self.quality.to_string(),
+            "-f".into(),
+            "image2".into(),
+            "-update".into(),
+            "1".into(),
+            "-loglevel".into(),
+            "error".into(),
+            out.into(),
+        ]);
+
+        args
+    }
+}
+
+/// Returns the name, without extension, screenshots of `file` at `timestamp` are cached under,
+/// so that the same frame is only extracted once.
+pub fn screenshot_name(file: &str, timestamp: f64, options: &ScreenshotOptions) -> String {
+    let mut hasher = DefaultHasher::new();
+
+    file.hash(&mut hasher);
+    // timestamps are only told apart down to the millisecond.
+    ((timestamp * 1000.0).round() as u64).hash(&mut hasher);
+    options.hash(&mut hasher);
+
+    format!("{:016x}", hasher.finish())
+}
+
+/// Function deletes the screenshots in `dir` written more than `max_age` ago.
+pub fn prune_screenshots(dir: impl AsRef<Path>, max_age: Duration) {
+    let entries = match fs::read_dir(dir) {
+        Ok(x) => x,
+        Err(_) => return,
+    };
+
+    for entry in entries.filter_map(|x| x.ok()) {
+        let is_stale = entry
+            .metadata()
+            .and_then(|x| x.modified())
+            .ok()
+            .and_then(|x| x.elapsed().ok())
+            .is_some_and(|x| x > max_age);
+
+        if is_stale {
+            let _ = fs::remove_file(entry.path());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn le_u32(data: &[u8], at: usize) -> u32 {
+        u32::from_le_bytes([data[at], data[at + 1], data[at + 2], data[at + 3]])
+    }
+
+    #[test]
+    fn jpeg_size_is_read_out_of_the_sof_marker() {
+        let mut jpeg = vec![0xFF, 0xD8];
+        // APP0 and DHT segments come before the frame header.
+        jpeg.extend_from_slice(&[0xFF, 0xE0, 0x00, 0x10]);
+        jpeg.extend_from_slice(&[0; 14]);
+        jpeg.extend_from_slice(&[0xFF, 0xC4, 0x00, 0x04, 0x00, 0x00]);
+        jpeg.extend_from_slice(&[0xFF, 0xC0, 0x00, 0x11, 0x08, 0x00, 0xB4, 0x01, 0x40]);
+        jpeg.extend_from_slice(&[0; 10]);
+
+        assert_eq!(jpeg_size(&jpeg), Some((320, 180)));
+        assert_eq!(jpeg_size(&jpeg[..jpeg.len() - 16]), None);
+        assert_eq!(jpeg_size(&jpeg[2..]), None);
+        assert_eq!(jpeg_size(&[]), None);
+    }
+
+    #[test]
+    fn sprite_index_points_at_every_tile() {
+        let config = ThumbnailConfig {
+            interval: 5,
+            columns: 2,
+            rows: 1,
+            ..Default::default()
+        };
+        let sheets = vec![
+            ("a.jpg".to_string(), (200, 100)),
+            ("b.jpg".to_string(), (200, 100)),
+        ];
+
+        assert_eq!(
+            sprite_index(&sheets, &config, Some(12.0)),
+            "WEBVTT\n\
+             \n00:00:00.000 --> 00:00:05.000\na.jpg#xywh=0,0,100,100\n\
+             \n00:00:05.000 --> 00:00:10.000\na.jpg#xywh=100,0,100,100\n\
+             \n00:00:10.000 --> 00:00:12.000\nb.jpg#xywh=0,0,100,100\n"
+        );
+
+        // without a duration every tile of the last sheet gets a cue.
+        let index = sprite_index(&sheets, &config, None);
+        assert_eq!(index.matches(" --> ").count(), 4);
+        assert!(index.ends_with("\n00:00:15.000 --> 00:00:20.000\nb.jpg#xywh=100,0,100,100\n"));
+    }
+
+    #[test]
+    fn bif_indexes_every_image() {
+        let out = bif(&[vec![1, 2, 3], vec![4, 5]], 2);
+
+        assert_eq!(out[..8], BIF_MAGIC);
+        // version, image count and the timestamp multiplier.
+        assert_eq!(le_u32(&out, 8), 0);
+        assert_eq!(le_u32(&out, 12), 2);
+        assert_eq!(le_u32(&out, 16), 2000);
+        assert!(out[20..BIF_HEADER_SIZE].iter().all(|x| *x == 0));
+
+        let index = (0..3)
+            .map(|i| {
+                let at = BIF_HEADER_SIZE + i * 8;
+                (le_u32(&out, at), le_u32(&out, at + 4))
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(index, vec![(0, 88), (1, 91), (u32::MAX, 93)]);
+
+        assert_eq!(out[88..], [1, 2, 3, 4, 5]);
+    }
+}
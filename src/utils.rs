@@ -23,6 +23,25 @@ cfg_if::cfg_if! {
             }
 
         }
+
+        /// Returns the 1 minute load average divided by the amount of cpus, or `None` where
+        /// `/proc/loadavg` isnt available.
+        pub fn cpu_load() -> Option<f64> {
+            let loadavg = std::fs::read_to_string("/proc/loadavg").ok()?;
+            let load: f64 = loadavg.split_whitespace().next()?.parse().ok()?;
+            let cpus = std::thread::available_parallelism().ok()?.get();
+
+            Some(load / cpus as f64)
+        }
+
+        /// Makes `link` point to the directory `target`.
+        pub fn link_dir(target: &std::path::Path, link: &std::path::Path) -> std::io::Result<()> {
+            std::os::unix::fs::symlink(target, link)
+        }
+
+        pub fn unlink_dir(link: &std::path::Path) -> std::io::Result<()> {
+            std::fs::remove_file(link)
+        }
     } else {
         use ntapi::ntpsapi::NtSuspendProcess;
         use ntapi::ntpsapi::NtResumeProcess;
@@ -75,5 +94,30 @@ cfg_if::cfg_if! {
                 exit_code != STILL_ACTIVE
             }
         }
+
+        pub fn cpu_load() -> Option<f64> {
+            None
+        }
+
+        /// Makes `link` point to the directory `target`. Needs the privilege to create symbolic
+        /// links, or developer mode.
+        pub fn link_dir(target: &std::path::Path, link: &std::path::Path) -> std::io::Result<()> {
+            std::os::windows::fs::symlink_dir(target, link)
+        }
+
+        pub fn unlink_dir(link: &std::path::Path) -> std::io::Result<()> {
+            std::fs::remove_dir(link)
+        }
+    }
+}
+
+/// Returns whether `copy` exists and was modified after `source`, ie. whether a copy derived from
+/// `source` is still up to date.
+pub fn is_newer(copy: &str, source: &str) -> bool {
+    let modified = |path: &str| std::fs::metadata(path).and_then(|x| x.modified()).ok();
+
+    match (modified(copy), modified(source)) {
+        (Some(copy), Some(source)) => copy >= source,
+        _ => false,
     }
 }
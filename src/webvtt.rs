@@ -0,0 +1,230 @@
+use std::fmt::Write as _;
+use std::fs;
+use std::path::Path;
+
+use crate::Result;
+
+/// Name of the file `SegmentedWebvttTranscodeProfile` writes the whole subtitle into.
+pub const SOURCE_FILE: &str = "subtitles.vtt";
+/// Name of the media playlist listing the WebVTT chunks.
+pub const PLAYLIST_FILE: &str = "playlist.m3u8";
+
+/// A single cue of a WebVTT file.
+#[derive(Clone, Debug, PartialEq)]
+struct Cue {
+    start: f64,
+    end: f64,
+    /// The whole cue block, identifier, timings and payload included.
+    block: String,
+}
+
+/// Parses a WebVTT timestamp, either `hh:mm:ss.ttt` or `mm:ss.ttt`, into seconds.
+fn parse_timestamp(timestamp: &str) -> Option<f64> {
+    let mut seconds = 0.0;
+
+    for part in timestamp.trim().split(':') {
+        seconds = seconds * 60.0 + part.parse::<f64>().ok()?;
+    }
+
+    Some(seconds)
+}
+
+/// Function returns the cues of a WebVTT file, ignoring the header as well as `NOTE`, `STYLE` and
+/// `REGION` blocks.
+fn parse_cues(vtt: &str) -> Vec<Cue> {
+    vtt.replace("\r\n", "\n")
+        .split("\n\n")
+        .filter_map(|block| {
+            let block = block.trim_matches('\n');
+            let timings = block.lines().find(|x| x.contains("-->"))?;
+            let (start, rest) = timings.split_once("-->")?;
+            // cue settings can follow the end timestamp.
+            let end = rest.split_whitespace().next()?;
+
+            Some(Cue {
+                start: parse_timestamp(start)?,
+                end: parse_timestamp(end)?,
+                block: block.to_string(),
+            })
+        })
+        .collect()
+}
+
+/// Function splits a WebVTT file into chunks of `chunk_duration` seconds, so that they line up
+/// with the video chunks of the same length. Cues spanning several chunks are repeated in each of
+/// them, cue timings are kept as is as they are relative to the start of the media.
+///
+/// # Arguments
+/// * `vtt` - the whole WebVTT file.
+/// * `chunk_duration` - duration of a chunk in seconds.
+/// * `duration` - duration of the media, if unknown the chunks stop after the last cue.
+/// * `offset` - timestamp the media starts at in the video chunks, in `TS_TIMESCALE`, ex.
+///   `TS_START_OFFSET` for MPEG-TS chunks.
+pub fn split(vtt: &str, chunk_duration: u32, duration: Option<f64>, offset: u64) -> Vec<String> {
+    let cues = parse_cues(vtt);
+    let chunk_duration = chunk_duration.max(1) as f64;
+    let duration = duration
+        .or_else(|| cues.iter().map(|x| x.end).reduce(f64::max))
+        .unwrap_or(0.0);
+    let count = (duration / chunk_duration).ceil().max(1.0) as usize;
+
+    (0..count)
+        .map(|idx| {
+            let start = idx as f64 * chunk_duration;
+            let end = start + chunk_duration;
+
+            // players map the cue timings onto the media timeline with this header.
+            let mut chunk = format!(
+                "WEBVTT\nX-TIMESTAMP-MAP=MPEGTS:{},LOCAL:00:00:00.000\n",
+                offset
+            );
+
+            for cue in cues.iter().filter(|x| x.start < end && x.end > start) {
+                chunk.push('\n');
+                chunk.push_str(&cue.block);
+                chunk.push('\n');
+            }
+
+            chunk
+        })
+        .collect()
+}
+
+/// Function returns a VOD media playlist listing `count` WebVTT chunks named `N.vtt`.
+pub fn playlist(count: usize, chunk_duration: u32, duration: Option<f64>) -> String {
+    let mut playlist = format!(
+        "#EXTM3U\n#EXT-X-VERSION:3\n#EXT-X-TARGETDURATION:{}\n#EXT-X-MEDIA-SEQUENCE:0\n#EXT-X-PLAYLIST-TYPE:VOD\n",
+        chunk_duration
+    );
+
+    for idx in 0..count {
+        let start = idx as f64 * chunk_duration as f64;
+        let length = duration
+            .map(|x| (x - start).clamp(0.0, chunk_duration as f64))
+            .unwrap_or(chunk_duration as f64);
+
+        let _ = write!(playlist, "#EXTINF:{:.3},\n{}.vtt\n", length, idx);
+    }
+
+    playlist.push_str("#EXT-X-ENDLIST\n");
+
+    playlist
+}
+
+/// Function splits `SOURCE_FILE` in `dir` into `N.vtt` chunks and writes `PLAYLIST_FILE` next to
+/// them, see `split`. The playlist is written last so that its presence means all chunks exist.
+pub fn split_file(
+    dir: impl AsRef<Path>,
+    chunk_duration: u32,
+    duration: Option<f64>,
+    offset: u64,
+) -> Result<()> {
+    let dir = dir.as_ref();
+    let vtt = fs::read_to_string(dir.join(SOURCE_FILE))?;
+    let chunks = split(&vtt, chunk_duration, duration, offset);
+
+    for (idx, chunk) in chunks.iter().enumerate() {
+        fs::write(dir.join(format!("{}.vtt", idx)), chunk)?;
+    }
+
+    fs::write(
+        dir.join(PLAYLIST_FILE),
+        playlist(chunks.len(), chunk_duration, duration),
+    )?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::patch::mpegts::TS_START_OFFSET;
+
+    const VTT: &str = "WEBVTT\r\n\r\nNOTE a comment\r\n\r\n1\r\n00:00:01.000 --> 00:00:03.500 line:90%\r\nfirst\r\n\r\n01:04.000 --> 01:07.000\r\nsecond\r\n";
+
+    #[test]
+    fn timestamps_are_parsed_with_and_without_hours() {
+        assert_eq!(parse_timestamp("01:02:03.500"), Some(3723.5));
+        assert_eq!(parse_timestamp(" 02:03.250 "), Some(123.25));
+        assert_eq!(parse_timestamp("00:xx.000"), None);
+    }
+
+    #[test]
+    fn cues_skip_the_header_and_notes() {
+        let cues = parse_cues(VTT);
+
+        assert_eq!(cues.len(), 2);
+        assert_eq!((cues[0].start, cues[0].end), (1.0, 3.5));
+        assert_eq!(
+            cues[0].block,
+            "1\n00:00:01.000 --> 00:00:03.500 line:90%\nfirst"
+        );
+        assert_eq!((cues[1].start, cues[1].end), (64.0, 67.0));
+    }
+
+    #[test]
+    fn cues_are_repeated_in_every_chunk_they_span() {
+        let chunks = split(VTT, 2, Some(68.0), 0);
+
+        assert_eq!(chunks.len(), 34);
+        assert!(chunks[0].contains("first") && chunks[1].contains("first"));
+        assert!(!chunks[2].contains("first"));
+        assert!(chunks[32].contains("second") && chunks[33].contains("second"));
+        assert!(!chunks[31].contains("second"));
+
+        // without a duration the chunks stop after the last cue.
+        assert_eq!(split(VTT, 2, None, 0).len(), 34);
+        assert_eq!(split("WEBVTT\n", 2, None, 0).len(), 1);
+    }
+
+    #[test]
+    fn timestamp_map_uses_the_offset_of_the_chunks() {
+        let chunks = split(VTT, 5, Some(10.0), TS_START_OFFSET);
+
+        assert!(chunks
+            .iter()
+            .all(|x| x.starts_with("WEBVTT\nX-TIMESTAMP-MAP=MPEGTS:126000,LOCAL:00:00:00.000\n")));
+
+        let chunks = split(VTT, 5, Some(10.0), 0);
+        assert!(chunks[0].starts_with("WEBVTT\nX-TIMESTAMP-MAP=MPEGTS:0,"));
+    }
+
+    #[test]
+    fn playlist_lists_every_chunk() {
+        assert_eq!(
+            playlist(3, 4, Some(10.5)),
+            "#EXTM3U\n#EXT-X-VERSION:3\n#EXT-X-TARGETDURATION:4\n#EXT-X-MEDIA-SEQUENCE:0\n\
+             #EXT-X-PLAYLIST-TYPE:VOD\n\
+             #EXTINF:4.000,\n0.vtt\n\
+             #EXTINF:4.000,\n1.vtt\n\
+             #EXTINF:2.500,\n2.vtt\n\
+             #EXT-X-ENDLIST\n"
+        );
+    }
+
+    #[test]
+    fn split_file_writes_the_chunks_and_playlist() {
+        let dir = std::env::temp_dir().join(format!(
+            "nightfall-webvtt-{}",
+            uuid::Uuid::new_v4().hyphenated()
+        ));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join(SOURCE_FILE), VTT).unwrap();
+
+        split_file(&dir, 30, Some(68.0), 0).unwrap();
+
+        assert_eq!(
+            fs::read_to_string(dir.join(PLAYLIST_FILE)).unwrap(),
+            playlist(3, 30, Some(68.0))
+        );
+        assert!(fs::read_to_string(dir.join("0.vtt"))
+            .unwrap()
+            .contains("first"));
+        assert!(fs::read_to_string(dir.join("2.vtt"))
+            .unwrap()
+            .contains("second"));
+
+        let _ = fs::remove_dir_all(dir);
+    }
+}